@@ -27,6 +27,54 @@ Or, request some specific settings:
 apiclient get settings.motd settings.kernel.lockdown
 ```
 
+Settings are printed as JSON by default.
+Use `--format` to choose another format: `json`, `toml`, `yaml`, `env`, or `flat`.
+TOML output is in the same form as user data, so it can be pasted into user data or given to `apiclient apply` unchanged:
+
+```shell
+apiclient get --format toml settings.ntp > ntp.toml
+apiclient apply ntp.toml
+```
+
+`env` prints a `NAME='value'` line for each setting, like `SETTINGS_MOTD='hi'`, for use in shell scripts.
+`flat` prints a `name=value` line for each setting, like `settings.motd="hi"`, and each line can be given to [set](#set-mode) as an argument:
+
+```shell
+apiclient get --format flat settings.ntp | xargs -d '\n' apiclient set
+```
+
+#### Queries
+
+To pick out parts of the result, give a query with `--query`.
+A query is a path through the settings, like `settings.ntp.time-servers[0]`, that can also:
+
+* select every member of a map or list with `*`, like `settings.host-containers.*.source`;
+* select the members that match a condition with `[?condition]`, like `[?enabled]`, `[?!enabled]`, or `[?source == "example:1"]`, using `==`, `!=`, `<`, `<=`, `>`, or `>=` and a JSON value;
+* build a map of several fields with `{...}`, like `{source, enabled}`.
+
+Names that contain other characters than letters, digits, `-`, and `_` are quoted, like `settings.kubernetes.node-labels."my.label"`.
+If you don't give any prefixes, `get` fetches only the settings that the query starts with.
+
+For example, this shows the source of each enabled host container:
+
+```shell
+apiclient get --query 'settings.host-containers[?enabled == true].{source}'
+```
+
+```json
+{
+  "admin": {
+    "source": "public.ecr.aws/bottlerocket/bottlerocket-admin:v0.11.0"
+  },
+  "control": {
+    "source": "public.ecr.aws/bottlerocket/bottlerocket-control:v0.7.0"
+  }
+}
+```
+
+Queries work with every output format.
+A query that matches nothing gives `null`.
+
 ### Set mode
 
 This allows you to change settings on the system.
@@ -75,6 +123,72 @@ You can use JSON form to set it:
 apiclient set --json '{"motd": "42"}'
 ```
 
+#### Previewing changes
+
+To see what a change would do before making it, add `--dry-run`.
+The settings are staged in a transaction and compared with the live settings, then the transaction is discarded, so nothing on the system changes.
+`apiclient apply` accepts `--dry-run` too.
+
+```shell
+apiclient set --dry-run motd="hi there"
+```
+
+Each added setting is shown with `+`, each changed setting with `~` and its old and new values, and each removed setting with `-`, followed by the services that would be restarted:
+
+```
+~ settings.motd = "Welcome to Bottlerocket!" -> "hi there"
+Services to restart: motd
+```
+
+For use in scripts or review tools, `--diff-format json` prints the same information as a JSON object with `added`, `changed`, `removed`, and `restarted_services` fields.
+
+### Transaction mode
+
+Changes made through the API are staged in a named transaction until the transaction is committed.
+`apiclient set` normally uses a new transaction for each call, and commits and applies it right away.
+To make several related changes at once, stage them in a transaction of your choosing with `--tx`, then commit them together:
+
+```shell
+apiclient set --tx ntp-change ntp.time-servers='["time.example.com"]'
+apiclient set --tx ntp-change motd="NTP servers changed"
+apiclient tx show ntp-change
+apiclient tx apply ntp-change
+```
+
+The `tx` subcommands let you inspect and manage pending transactions:
+
+* `apiclient tx list` lists pending transactions and how many settings each would change.
+* `apiclient tx show NAME` lists the settings pending in a transaction, with their values.
+* `apiclient tx commit NAME` commits a transaction, making its settings live without applying them to the system.
+* `apiclient tx apply NAME` commits a transaction and applies it, restarting affected services.
+  Without a name, `apiclient tx apply` applies settings that were committed but not yet applied.
+* `apiclient tx discard NAME` deletes a transaction without committing it.
+
+`list`, `show`, `commit`, and `discard` print a table by default; use `--format json` for JSON output.
+
+### Watch mode
+
+This prints settings changes as they happen, so scripts and agents, such as bootstrap containers, can react to them.
+It checks the settings under the given prefixes, or all settings if none are given, every few seconds, and prints a line of JSON for each setting that changed:
+
+```shell
+apiclient watch settings.motd settings.ntp
+```
+
+```json
+{"key":"settings.motd","old":"hi","new":"hello"}
+```
+
+Settings that were added have an `old` value of `null`, and settings that were removed have a `new` value of `null`.
+
+Use `--interval SECONDS` to change how often it checks.
+By default it runs until stopped, but you can use `--once` to exit after the first change, or `--timeout SECONDS` to exit after a time.
+With both, it exits with an error if nothing changed before the timeout, which makes it easy to wait for a change in a script:
+
+```shell
+apiclient watch --once --timeout 300 settings.motd
+```
+
 ### Update mode
 
 To start, you can check what updates are available:
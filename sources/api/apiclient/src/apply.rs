@@ -2,6 +2,7 @@
 //! TOML settings files, in the same format as user data, or the JSON equivalent.  The inputs are
 //! pulled and applied to the API server in a single transaction.
 
+use crate::diff::{self, SettingsDiff};
 use crate::rando;
 use futures::future::{join, ready, TryFutureExt};
 use futures::stream::{self, StreamExt};
@@ -17,11 +18,60 @@ pub async fn apply<P>(socket_path: P, input_sources: Vec<String>) -> Result<()>
 where
     P: AsRef<Path>,
 {
+    let changes = read_changes(&input_sources).await?;
+
+    // We use a specific transaction ID so we don't commit any other changes that may be pending.
+    let transaction = format!("apiclient-apply-{}", rando());
+    stage(&socket_path, changes, &transaction).await?;
+
+    // Commit the transaction and apply it to the system.
+    let uri = format!("/tx/commit_and_apply?tx={}", transaction);
+    let method = "POST";
+    let (_status, _body) = crate::raw_request(&socket_path, &uri, method, None)
+        .await
+        .context(error::CommitApplySnafu { uri })?;
+
+    Ok(())
+}
+
+/// Reads settings like `apply`, and stages them in a single transaction, but instead of
+/// committing it, returns the differences committing it would make to live settings.  The
+/// transaction is then discarded, so nothing is changed.
+pub async fn apply_dry_run<P>(socket_path: P, input_sources: Vec<String>) -> Result<SettingsDiff>
+where
+    P: AsRef<Path>,
+{
+    let changes = read_changes(&input_sources).await?;
+    let transaction = format!("apiclient-apply-{}", rando());
+
+    // Staging can fail after some changes were sent, so once we start, we discard the transaction
+    // whether or not we could stage and compare it.  The first failure is the one we report.
+    let diff = async {
+        stage(&socket_path, changes, &transaction).await?;
+        diff::preview_transaction(&socket_path, &transaction)
+            .await
+            .context(error::PreviewSnafu)
+    }
+    .await;
+    let uri = format!("/tx?tx={}", transaction);
+    let method = "DELETE";
+    let discarded = crate::raw_request(&socket_path, &uri, method, None)
+        .await
+        .context(error::DiscardSnafu { uri, method });
+
+    let diff = diff?;
+    discarded?;
+    Ok(diff)
+}
+
+/// Retrieves the given input sources and reformats them to JSON we can send to the API, returning
+/// each with its source.
+async fn read_changes(input_sources: &[String]) -> Result<Vec<(&String, String)>> {
     // We want to retrieve URIs in parallel because they're arbitrary and could be slow.  First, we
     // build a list of request futures, and we store the source of the data with the future for
     // inclusion in later error messages.
     let mut get_requests = Vec::with_capacity(input_sources.len());
-    for input_source in &input_sources {
+    for input_source in input_sources {
         let get_future = get(input_source);
         let info_future = ready(input_source);
         get_requests.push(join(info_future, get_future));
@@ -38,12 +88,16 @@ where
         let json = format_change(&response, input_source)?;
         changes.push((input_source, json));
     }
+    Ok(changes)
+}
 
-    // We use a specific transaction ID so we don't commit any other changes that may be pending.
-    let transaction = format!("apiclient-apply-{}", rando());
-
-    // Send the settings changes to the server in the same transaction.  (They're quick local
-    // requests, so don't add the complexity of making them run concurrently.)
+/// Sends the settings changes to the server in the given transaction.
+async fn stage<P>(socket_path: P, changes: Vec<(&String, String)>, transaction: &str) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    // (They're quick local requests, so don't add the complexity of making them run
+    // concurrently.)
     for (input_source, json) in changes {
         let uri = format!("/settings?tx={}", transaction);
         let method = "PATCH";
@@ -55,14 +109,6 @@ where
                 method,
             })?;
     }
-
-    // Commit the transaction and apply it to the system.
-    let uri = format!("/tx/commit_and_apply?tx={}", transaction);
-    let method = "POST";
-    let (_status, _body) = crate::raw_request(&socket_path, &uri, method, None)
-        .await
-        .context(error::CommitApplySnafu { uri })?;
-
     Ok(())
 }
 
@@ -155,6 +201,19 @@ mod error {
             source: Box<crate::Error>,
         },
 
+        #[snafu(display(
+            "Failed to discard transaction with {} request to '{}': {}",
+            method,
+            uri,
+            source
+        ))]
+        Discard {
+            method: String,
+            uri: String,
+            #[snafu(source(from(crate::Error, Box::new)))]
+            source: Box<crate::Error>,
+        },
+
         #[snafu(display("Failed to read given file '{}': {}", input_source, source))]
         FileRead {
             input_source: String,
@@ -211,6 +270,9 @@ mod error {
             source: Box<crate::Error>,
         },
 
+        #[snafu(display("Unable to compare changes with live settings: {}", source))]
+        Preview { source: crate::diff::Error },
+
         #[snafu(display("Failed {} request to '{}': {}", method, uri, source))]
         Reqwest {
             method: String,
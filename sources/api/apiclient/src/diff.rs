@@ -0,0 +1,287 @@
+//! The diff module compares two views of settings, like live settings and live settings with a
+//! pending transaction applied, and reports the settings that were added, changed, and removed.
+//!
+//! Settings are compared by their full dotted names, like `settings.ntp.time-servers`.  Lists are
+//! compared as whole values, the same way they're set.
+//!
+//! [`preview_transaction`] uses it to show what committing a pending transaction would do,
+//! including the services that would be restarted.
+
+use crate::get::merge_json;
+use datastore::{Key, KeyType};
+use serde::Serialize;
+use serde_json::Value;
+use snafu::ResultExt;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::path::Path;
+
+/// ValueChange holds the old and new values of a changed setting.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ValueChange {
+    pub old: Value,
+    pub new: Value,
+}
+
+/// SettingsDiff describes the differences between two views of settings.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SettingsDiff {
+    /// Settings that are only in the new view, with their values.
+    pub added: BTreeMap<String, Value>,
+    /// Settings whose values differ.
+    pub changed: BTreeMap<String, ValueChange>,
+    /// Settings that are only in the old view, with their values.
+    pub removed: BTreeMap<String, Value>,
+    /// Services that would be restarted to apply the differences, if they were looked up.
+    pub restarted_services: BTreeSet<String>,
+}
+
+impl SettingsDiff {
+    /// Returns true if there are no differences.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    /// Returns the names of every added, changed, or removed setting.
+    pub fn keys(&self) -> BTreeSet<&String> {
+        self.added
+            .keys()
+            .chain(self.changed.keys())
+            .chain(self.removed.keys())
+            .collect()
+    }
+}
+
+/// Lists each difference on its own line: `+` for added settings, `~` for changed settings, and
+/// `-` for removed settings, followed by the services that would be restarted.
+impl fmt::Display for SettingsDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return writeln!(f, "No changes");
+        }
+        for (key, value) in &self.added {
+            writeln!(f, "+ {} = {}", key, value)?;
+        }
+        for (key, change) in &self.changed {
+            writeln!(f, "~ {} = {} -> {}", key, change.old, change.new)?;
+        }
+        for (key, value) in &self.removed {
+            writeln!(f, "- {} = {}", key, value)?;
+        }
+        if !self.restarted_services.is_empty() {
+            let services: Vec<_> = self.restarted_services.iter().map(String::as_str).collect();
+            writeln!(f, "Services to restart: {}", services.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Flattens the given Value into a map of dotted setting names to values, with names starting
+/// with the given prefix, if any.  Objects are descended into; everything else, including lists,
+/// is a value.  Names with special characters are quoted, like `a."b.c"`, as they are in the API.
+pub fn flatten(value: &Value, prefix: Option<&str>) -> BTreeMap<String, Value> {
+    let mut segments: Vec<String> = match prefix {
+        Some(prefix) => match Key::new(KeyType::Data, prefix) {
+            Ok(key) => key.segments().clone(),
+            Err(_) => vec![prefix.to_string()],
+        },
+        None => Vec::new(),
+    };
+    let mut flat = BTreeMap::new();
+    flatten_into(value, &mut segments, &mut flat);
+    flat
+}
+
+fn flatten_into(value: &Value, segments: &mut Vec<String>, flat: &mut BTreeMap<String, Value>) {
+    match value {
+        Value::Object(map) => {
+            for (name, inner) in map {
+                segments.push(name.clone());
+                flatten_into(inner, segments, flat);
+                segments.pop();
+            }
+        }
+        // A null is the same as the setting not being there.
+        Value::Null => {}
+        _ if segments.is_empty() => {}
+        _ => {
+            flat.insert(key_name(segments), value.clone());
+        }
+    }
+}
+
+/// Returns the dotted name of the given segments, quoting them as needed.
+fn key_name(segments: &[String]) -> String {
+    match Key::from_segments(KeyType::Data, segments) {
+        Ok(key) => key.name().clone(),
+        // The API wouldn't have given us a key it can't represent, but don't fail over it.
+        Err(_) => segments.join("."),
+    }
+}
+
+/// Compares two views of settings.  Both Values are flattened with the given prefix, like
+/// "settings" for the response of a `/settings` request.  Services aren't looked up.
+pub fn diff_values(old: &Value, new: &Value, prefix: Option<&str>) -> SettingsDiff {
+    let old = flatten(old, prefix);
+    let mut new = flatten(new, prefix);
+
+    let mut diff = SettingsDiff::default();
+    for (key, old_value) in old {
+        match new.remove(&key) {
+            Some(new_value) if new_value == old_value => {}
+            Some(new_value) => {
+                diff.changed.insert(
+                    key,
+                    ValueChange {
+                        old: old_value,
+                        new: new_value,
+                    },
+                );
+            }
+            None => {
+                diff.removed.insert(key, old_value);
+            }
+        }
+    }
+    diff.added = new;
+    diff
+}
+
+/// Compares live settings with the settings that committing the given pending transaction would
+/// produce, and looks up the services that would be restarted.  The transaction isn't changed.
+pub async fn preview_transaction<P, S>(socket_path: P, transaction: S) -> Result<SettingsDiff>
+where
+    P: AsRef<Path>,
+    S: AsRef<str>,
+{
+    let live = get_json(&socket_path, "/settings".to_string()).await?;
+    let pending = get_json(
+        &socket_path,
+        format!("/tx?tx={}", encode(transaction.as_ref())),
+    )
+    .await?;
+
+    let mut committed = live.clone();
+    merge_json(&mut committed, pending);
+    let mut diff = diff_values(&live, &committed, Some("settings"));
+    diff.restarted_services = affected_services(&socket_path, diff.keys()).await?;
+    Ok(diff)
+}
+
+/// Returns the services affected by changes to the given settings.
+async fn affected_services<'a, P, I>(socket_path: P, keys: I) -> Result<BTreeSet<String>>
+where
+    P: AsRef<Path>,
+    I: IntoIterator<Item = &'a String>,
+{
+    let keys: Vec<_> = keys.into_iter().map(String::as_str).collect();
+    if keys.is_empty() {
+        return Ok(BTreeSet::new());
+    }
+    let uri = format!(
+        "/metadata/affected-services?keys={}",
+        encode(&keys.join(","))
+    );
+    let response = get_json(&socket_path, uri).await?;
+    let by_key: HashMap<String, Vec<String>> =
+        serde_json::from_value(response.clone()).context(error::ServicesSnafu { response })?;
+    Ok(by_key.into_values().flatten().collect())
+}
+
+/// Fetches the given URI from the API and parses the response as JSON.
+async fn get_json<P>(socket_path: P, uri: String) -> Result<Value>
+where
+    P: AsRef<Path>,
+{
+    let method = "GET";
+    let (_status, body) = crate::raw_request(&socket_path, &uri, method, None)
+        .await
+        .context(error::RequestSnafu { uri, method })?;
+    serde_json::from_str(&body).context(error::ResponseJsonSnafu { body })
+}
+
+/// Percent-encodes the given string for use in a query string.
+pub(crate) fn encode(s: &str) -> String {
+    url::form_urlencoded::byte_serialize(s.as_bytes()).collect()
+}
+
+mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Failed {} request to '{}': {}", method, uri, source))]
+        Request {
+            method: String,
+            uri: String,
+            #[snafu(source(from(crate::Error, Box::new)))]
+            source: Box<crate::Error>,
+        },
+
+        #[snafu(display("Response contained invalid JSON '{}' - {}", body, source))]
+        ResponseJson {
+            body: String,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Affected services response '{}' is invalid: {}", response, source))]
+        Services {
+            response: serde_json::Value,
+            source: serde_json::Error,
+        },
+    }
+}
+pub use error::Error;
+pub type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn flatten_quotes_names() {
+        let value = json!({"kubernetes": {"node-labels": {"my.label": "x"}}, "ntp": [1, 2]});
+        let flat = flatten(&value, Some("settings"));
+        assert_eq!(
+            flat.keys().collect::<Vec<_>>(),
+            [
+                "settings.kubernetes.node-labels.\"my.label\"",
+                "settings.ntp"
+            ]
+        );
+    }
+
+    #[test]
+    fn diff() {
+        let old = json!({"motd": "hi", "ntp": {"servers": ["a"]}, "gone": true});
+        let new = json!({"motd": "hi", "ntp": {"servers": ["a", "b"]}, "new": 1});
+        let diff = diff_values(&old, &new, Some("settings"));
+        assert_eq!(
+            diff.added,
+            BTreeMap::from([("settings.new".to_string(), json!(1))])
+        );
+        assert_eq!(
+            diff.changed,
+            BTreeMap::from([(
+                "settings.ntp.servers".to_string(),
+                ValueChange {
+                    old: json!(["a"]),
+                    new: json!(["a", "b"]),
+                }
+            )])
+        );
+        assert_eq!(
+            diff.removed,
+            BTreeMap::from([("settings.gone".to_string(), json!(true))])
+        );
+        assert_eq!(
+            diff.to_string(),
+            "+ settings.new = 1\n\
+             ~ settings.ntp.servers = [\"a\"] -> [\"a\",\"b\"]\n\
+             - settings.gone = true\n"
+        );
+        assert!(diff_values(&old, &old, None).is_empty());
+    }
+}
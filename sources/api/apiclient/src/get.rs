@@ -2,7 +2,7 @@ use snafu::{OptionExt, ResultExt};
 use std::path::Path;
 
 mod merge_json;
-use merge_json::merge_json;
+pub(crate) use merge_json::merge_json;
 
 /// Fetches the given prefixes from the API and merges them into a single Value.  (It's not
 /// expected that given prefixes would overlap, but if they do, later ones take precedence.)
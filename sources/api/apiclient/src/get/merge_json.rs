@@ -7,7 +7,7 @@ use serde_json::{map::Entry, Value};
 /// left side does not have the key from the right side, it's inserted, otherwise we recursively
 /// merge the values in each object for that key.
 // Logic and tests taken from storewolf::merge-toml, modified for serde_json.
-pub(super) fn merge_json(merge_into: &mut Value, merge_from: Value) {
+pub(crate) fn merge_json(merge_into: &mut Value, merge_from: Value) {
     match (merge_into, merge_from) {
         // If we see objects, we recursively merge each key.
         (Value::Object(merge_into), Value::Object(merge_from)) => {
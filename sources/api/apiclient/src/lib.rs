@@ -1,6 +1,6 @@
 //! The apiclient library provides high-level methods to interact with the Bottlerocket API.  See
-//! the documentation for submodules [`apply`], [`exec`], [`get`], [`reboot`], [`report`], [`set`],
-//! and [`update`] for high-level helpers.
+//! the documentation for submodules [`apply`], [`diff`], [`exec`], [`get`], [`query`], [`reboot`],
+//! [`render`], [`report`], [`set`], [`tx`], [`update`], and [`watch`] for high-level helpers.
 //!
 //! For more control, and to handle APIs without high-level wrappers, there are also 'raw' methods
 //! to query an HTTP API over a Unix-domain socket.
@@ -20,12 +20,17 @@ use snafu::{ensure, ResultExt};
 use std::{fmt, fmt::Display, path::Path};
 
 pub mod apply;
+pub mod diff;
 pub mod exec;
 pub mod get;
+pub mod query;
 pub mod reboot;
+pub mod render;
 pub mod report;
 pub mod set;
+pub mod tx;
 pub mod update;
+pub mod watch;
 
 mod error {
     use snafu::Snafu;
@@ -6,7 +6,9 @@
 // library calls based on the given flags, etc.)  The library modules contain the code for talking
 // to the API, which is intended to be reusable by other crates.
 
-use apiclient::{apply, exec, get, reboot, report, set, update, SettingsInput};
+use apiclient::{
+    apply, diff, exec, get, query, reboot, render, report, set, tx, update, watch, SettingsInput,
+};
 use log::{info, log_enabled, trace, warn};
 use serde::{Deserialize, Serialize};
 use simplelog::{
@@ -15,8 +17,10 @@ use simplelog::{
 use snafu::ResultExt;
 use std::env;
 use std::ffi::OsString;
+use std::io::{self, Write};
 use std::process;
 use std::str::FromStr;
+use std::time::Duration;
 use unindent::unindent;
 
 const DEFAULT_METHOD: &str = "GET";
@@ -46,14 +50,26 @@ enum Subcommand {
     Raw(RawArgs),
     Reboot(RebootArgs),
     Set(SetArgs),
+    Tx(TxSubcommand),
     Update(UpdateSubcommand),
     Report(ReportSubcommand),
+    Watch(WatchArgs),
 }
 
 /// Stores user-supplied arguments for the 'apply' subcommand.
 #[derive(Debug)]
 struct ApplyArgs {
     input_sources: Vec<String>,
+    dry_run: Option<DiffFormat>,
+}
+
+/// The format in which to print the changes a dry run would make.
+#[derive(Debug, Clone, Copy)]
+enum DiffFormat {
+    Text,
+    Json,
+    /// Text, then JSON.
+    Both,
 }
 
 /// Stores user-supplied arguments for the 'exec' subcommand.
@@ -66,7 +82,15 @@ struct ExecArgs {
 
 /// Stores user-supplied arguments for the 'get' subcommand.
 #[derive(Debug)]
-enum GetArgs {
+struct GetArgs {
+    query: GetQuery,
+    filter: Option<query::Query>,
+    format: render::Format,
+}
+
+/// Stores what the user asked the 'get' subcommand to fetch.
+#[derive(Debug)]
+enum GetQuery {
     Prefixes(Vec<String>),
     Uri(String),
 }
@@ -91,11 +115,64 @@ pub struct SetKeyPairSettings {
 
 /// Stores user-supplied arguments for the 'set' subcommand.
 #[derive(Debug)]
-enum SetArgs {
+struct SetArgs {
+    settings: SetSettings,
+    dry_run: Option<DiffFormat>,
+    transaction: Option<String>,
+}
+
+/// Stores the settings given to the 'set' subcommand.
+#[derive(Debug)]
+enum SetSettings {
     Simple(Vec<String>),
     Json(serde_json::Value),
 }
 
+/// Stores the 'tx' subcommand specified by the user.
+#[derive(Debug)]
+enum TxSubcommand {
+    List(TxListArgs),
+    Show(TxArgs),
+    Commit(TxArgs),
+    Apply(TxApplyArgs),
+    Discard(TxArgs),
+}
+
+/// The format in which to print transactions and changed settings.
+#[derive(Debug, Clone, Copy)]
+enum TxFormat {
+    Table,
+    Json,
+}
+
+/// Stores user-supplied arguments for the 'tx list' subcommand.
+#[derive(Debug)]
+struct TxListArgs {
+    format: TxFormat,
+}
+
+/// Stores user-supplied arguments for the 'tx' subcommands that act on a single transaction.
+#[derive(Debug)]
+struct TxArgs {
+    transaction: String,
+    format: TxFormat,
+}
+
+/// Stores user-supplied arguments for the 'tx apply' subcommand.
+#[derive(Debug)]
+struct TxApplyArgs {
+    transaction: Option<String>,
+}
+
+/// Stores user-supplied arguments for the 'watch' subcommand.
+#[derive(Debug)]
+struct WatchArgs {
+    prefixes: Vec<String>,
+    interval: Duration,
+    once: bool,
+    timeout: Option<Duration>,
+}
+
 /// Stores the 'update' subcommand specified by the user.
 #[derive(Debug)]
 enum UpdateSubcommand {
@@ -158,10 +235,16 @@ fn usage() -> ! {
                                        or from stdin.
             get                        Retrieve and print settings.
             set                        Changes settings and applies them to the system.
+            tx list                    Lists pending transactions.
+            tx show                    Prints the settings pending in a transaction.
+            tx commit                  Commits a pending transaction without applying it.
+            tx apply                   Applies committed settings to the system.
+            tx discard                 Deletes a pending transaction without committing it.
             update check               Prints information about available updates.
             update apply               Applies available updates.
             update cancel              Deactivates an applied update.
             reboot                     Reboots the host.
+            watch                      Prints settings changes as they happen.
             exec                       Execute a command in a host container.
             report cis                 Retrieve a Bottlerocket CIS benchmark compliance report.
             report cis-k8s             Retrieve a Kubernetes CIS benchmark compliance report.
@@ -176,6 +259,10 @@ fn usage() -> ! {
             [ URI ...]                 The list of URIs to TOML or JSON settings files that you
                                        want to apply to the system.  If no URI is specified, or
                                        if "-" is given, reads from stdin.
+            --dry-run                  Print the changes the settings would make, and the services
+                                       that would be restarted, without changing anything.
+            --diff-format FORMAT       Format of the --dry-run output: text, json, or both.
+                                       Default is both, the text followed by the JSON.
 
         reboot options:
             None.
@@ -187,6 +274,14 @@ fn usage() -> ! {
 
                                        If neither prefixes nor URI are specified, get will show
                                        settings and OS info.
+            -q, --query QUERY          Select parts of the result with a query, for example:
+                                          'settings.host-containers[?enabled == true].{{source}}'
+                                       If no prefixes or URI are specified, fetches the settings
+                                       the query starts with.
+            -f, --format FORMAT        Format of the output: json, toml, yaml, env, or flat.
+                                       toml output can be given to 'apply', and each line of
+                                       flat output not starting with '#' can be given to 'set'.
+                                       Default is json.
 
         set options:
             KEY=VALUE [KEY=VALUE ...]  The settings you want to set.  For example:
@@ -198,6 +293,36 @@ fn usage() -> ! {
                                        which can simplify setting multiple values, and is necessary
                                        for some numeric settings.  For example:
                                           -j '{{"kernel": {{"sysctl": {{"vm.max_map_count": "262144"}}}}}}'
+            --dry-run                  Print the changes the settings would make, and the services
+                                       that would be restarted, without changing anything.
+            --diff-format FORMAT       Format of the --dry-run output: text, json, or both.
+                                       Default is both, the text followed by the JSON.
+            --tx NAME                  Stage the settings in the named transaction instead of
+                                       committing and applying them.  Use 'tx commit' or
+                                       'tx apply' to make them live.
+
+        tx list options:
+            -f, --format FORMAT        Format of the output (table or json).  Default is table.
+
+        tx show, tx commit, and tx discard options:
+            NAME                       Required; the name of the transaction.
+            -f, --format FORMAT        Format of the output (table or json).  Default is table.
+
+        tx apply options:
+            [ NAME ]                   Commit the named transaction, then apply it.  If no name
+                                       is given, applies settings that were already committed.
+
+        watch options:
+            [ PREFIX [PREFIX ...] ]    The settings you want to watch.  Default: settings.
+            -i, --interval SECONDS     Time between checks for changes.  Default: {interval}
+            --once                     Exit after the first change.
+            --timeout SECONDS          Exit after this long.  With --once, it's an error if
+                                       nothing changed by then.
+
+                                       Each change is printed as a line of JSON with the
+                                       setting's name and its old and new values, for example:
+                                          {{"key":"settings.motd","old":"hi","new":"hello"}}
+                                       Added and removed settings have null old or new values.
 
         update check options:
             None.
@@ -226,6 +351,7 @@ fn usage() -> ! {
             -l, --level                CIS compliance level to report on (1 or 2). Default is 1."#,
         socket = constants::API_SOCKET,
         method = DEFAULT_METHOD,
+        interval = watch::DEFAULT_INTERVAL.as_secs(),
     );
     eprintln!("{}", unindent(msg));
     process::exit(2);
@@ -271,7 +397,8 @@ fn parse_args(args: env::Args) -> (Args, Subcommand) {
             }
 
             // Subcommands
-            "raw" | "apply" | "exec" | "get" | "reboot" | "report" | "set" | "update"
+            "raw" | "apply" | "exec" | "get" | "reboot" | "report" | "set" | "tx" | "update"
+            | "watch"
                 if subcommand.is_none() && !arg.starts_with('-') =>
             {
                 subcommand = Some(arg)
@@ -291,7 +418,9 @@ fn parse_args(args: env::Args) -> (Args, Subcommand) {
         Some("reboot") => (global_args, parse_reboot_args(subcommand_args)),
         Some("report") => (global_args, parse_report_args(subcommand_args)),
         Some("set") => (global_args, parse_set_args(subcommand_args)),
+        Some("tx") => (global_args, parse_tx_args(subcommand_args)),
         Some("update") => (global_args, parse_update_args(subcommand_args)),
+        Some("watch") => (global_args, parse_watch_args(subcommand_args)),
         _ => usage_msg("Missing or unknown subcommand"),
     }
 }
@@ -341,13 +470,21 @@ fn parse_raw_args(args: Vec<String>) -> Subcommand {
 /// Parses arguments for the 'apply' subcommand.
 fn parse_apply_args(args: Vec<String>) -> Subcommand {
     let mut input_sources = Vec::new();
+    let mut dry_run = false;
+    let mut diff_format = None;
 
-    for arg in args.into_iter() {
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
         match arg {
+            x if x == "--dry-run" => dry_run = true,
+
+            x if x == "--diff-format" => diff_format = Some(parse_diff_format(iter.next())),
+
             // Allow "-" for stdin, but we have no other parameters.
-            x if x.starts_with('-') && x != "-" => {
-                usage_msg("apiclient apply takes no parameters, just a list of URIs.")
-            }
+            x if x.starts_with('-') && x != "-" => usage_msg(
+                "apiclient apply takes no parameters other than --dry-run and --diff-format, \
+                 just a list of URIs.",
+            ),
 
             x => input_sources.push(x),
         }
@@ -358,7 +495,32 @@ fn parse_apply_args(args: Vec<String>) -> Subcommand {
         input_sources.push("-".to_string());
     }
 
-    Subcommand::Apply(ApplyArgs { input_sources })
+    Subcommand::Apply(ApplyArgs {
+        input_sources,
+        dry_run: dry_run_format(dry_run, diff_format),
+    })
+}
+
+/// Parses the argument to --diff-format.
+fn parse_diff_format(arg: Option<String>) -> DiffFormat {
+    match arg.as_deref() {
+        Some("text") => DiffFormat::Text,
+        Some("json") => DiffFormat::Json,
+        Some("both") => DiffFormat::Both,
+        Some(x) => usage_msg(format!(
+            "Unknown diff format '{}'; use text, json, or both",
+            x
+        )),
+        None => usage_msg("Did not give argument to --diff-format"),
+    }
+}
+
+/// Returns the format of dry run output, if a dry run was requested.
+fn dry_run_format(dry_run: bool, diff_format: Option<DiffFormat>) -> Option<DiffFormat> {
+    if !dry_run && diff_format.is_some() {
+        usage_msg("--diff-format can only be given with --dry-run");
+    }
+    dry_run.then(|| diff_format.unwrap_or(DiffFormat::Both))
 }
 
 /// Parses arguments for the 'exec' subcommand.
@@ -405,9 +567,35 @@ fn parse_exec_args(args: Vec<String>) -> Subcommand {
 fn parse_get_args(args: Vec<String>) -> Subcommand {
     let mut prefixes = vec![];
     let mut uri = None;
+    let mut format = render::Format::Json;
+    let mut filter: Option<query::Query> = None;
 
-    for arg in args.into_iter() {
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
         match &arg {
+            x if x == "-q" || x == "--query" => {
+                if filter.is_some() {
+                    usage_msg("You can only specify one query.");
+                }
+                let query_str = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to -q | --query"));
+                filter = Some(
+                    query_str
+                        .parse()
+                        .unwrap_or_else(|e: query::Error| usage_msg(e.to_string())),
+                );
+            }
+
+            x if x == "-f" || x == "--format" => {
+                let format_str = iter
+                    .next()
+                    .unwrap_or_else(|| usage_msg("Did not give argument to -f | --format"));
+                format = format_str
+                    .parse()
+                    .unwrap_or_else(|e: render::Error| usage_msg(e.to_string()));
+            }
+
             x if x.starts_with('-') => usage_msg(format!("Unknown argument '{}'", x)),
 
             x if x.starts_with('/') => {
@@ -421,23 +609,29 @@ fn parse_get_args(args: Vec<String>) -> Subcommand {
         }
     }
 
-    if let Some(uri) = uri {
+    let query = if let Some(uri) = uri {
         if !prefixes.is_empty() {
             usage_msg("You can specify prefixes or a URI, but not both.");
         }
-        Subcommand::Get(GetArgs::Uri(uri))
+        GetQuery::Uri(uri)
     } else if !prefixes.is_empty() {
         if uri.is_some() {
             usage_msg("You can specify prefixes or a URI, but not both.");
         }
-        Subcommand::Get(GetArgs::Prefixes(prefixes))
+        GetQuery::Prefixes(prefixes)
+    } else if let Some(prefix) = filter.as_ref().and_then(|f| f.prefix()) {
+        // Only fetch what the query could select.
+        GetQuery::Prefixes(vec![prefix])
     } else {
         // A reasonable default is showing OS info and settings.
-        Subcommand::Get(GetArgs::Prefixes(vec![
-            "os.".to_string(),
-            "settings.".to_string(),
-        ]))
-    }
+        GetQuery::Prefixes(vec!["os.".to_string(), "settings.".to_string()])
+    };
+
+    Subcommand::Get(GetArgs {
+        query,
+        filter,
+        format,
+    })
 }
 
 /// Parses arguments for the 'reboot' subcommand.
@@ -456,10 +650,24 @@ fn parse_reboot_args(args: Vec<String>) -> Subcommand {
 fn parse_set_args(args: Vec<String>) -> Subcommand {
     let mut simple = Vec::new();
     let mut json = None;
+    let mut dry_run = false;
+    let mut diff_format = None;
+    let mut transaction = None;
 
     let mut iter = args.into_iter();
     while let Some(arg) = iter.next() {
         match arg.as_ref() {
+            "--dry-run" => dry_run = true,
+
+            "--diff-format" => diff_format = Some(parse_diff_format(iter.next())),
+
+            "--tx" => {
+                transaction = Some(
+                    iter.next()
+                        .unwrap_or_else(|| usage_msg("Did not give argument to --tx")),
+                )
+            }
+
             "-j" | "--json" if json.is_some() => {
                 usage_msg(
                     "Can't specify the --json argument multiple times.  You can set as many \
@@ -496,7 +704,11 @@ fn parse_set_args(args: Vec<String>) -> Subcommand {
             }
 
             x if x.contains('=') => {
-                // Push each key=value pair to vector.
+                // Check the key here so a bad one is a usage error rather than an API error, then
+                // push each key=value pair to vector.
+                if let Err(e) = set::parse_key_value(x) {
+                    usage_msg(e.to_string());
+                }
                 simple.push(x.to_string());
             }
 
@@ -504,15 +716,177 @@ fn parse_set_args(args: Vec<String>) -> Subcommand {
         }
     }
 
-    if json.is_some() && !simple.is_empty() {
+    let settings = if json.is_some() && !simple.is_empty() {
         usage_msg("Cannot specify key=value pairs and --json settings with 'set'");
     } else if let Some(json) = json {
-        Subcommand::Set(SetArgs::Json(json))
+        SetSettings::Json(json)
     } else if !simple.is_empty() {
-        Subcommand::Set(SetArgs::Simple(simple))
+        SetSettings::Simple(simple)
     } else {
         usage_msg("Must specify key=value settings or --json settings with 'set'");
+    };
+
+    if dry_run && transaction.is_some() {
+        usage_msg("Cannot specify --dry-run and --tx with 'set'");
+    }
+
+    Subcommand::Set(SetArgs {
+        settings,
+        dry_run: dry_run_format(dry_run, diff_format),
+        transaction,
+    })
+}
+
+/// Parses the desired subcommand of 'tx'.
+fn parse_tx_args(args: Vec<String>) -> Subcommand {
+    let mut subcommand = None;
+    let mut subcommand_args = Vec::new();
+
+    for arg in args.into_iter() {
+        match arg.as_ref() {
+            // Subcommands
+            "list" | "show" | "commit" | "apply" | "discard" if subcommand.is_none() => {
+                subcommand = Some(arg)
+            }
+
+            // Other arguments are passed to the subcommand parser
+            _ => subcommand_args.push(arg),
+        }
+    }
+
+    let tx = match subcommand.as_deref() {
+        Some("list") => parse_tx_list_args(subcommand_args),
+        Some("show") => TxSubcommand::Show(parse_tx_arguments(subcommand_args)),
+        Some("commit") => TxSubcommand::Commit(parse_tx_arguments(subcommand_args)),
+        Some("apply") => parse_tx_apply_args(subcommand_args),
+        Some("discard") => TxSubcommand::Discard(parse_tx_arguments(subcommand_args)),
+        _ => usage_msg("Missing or unknown subcommand for 'tx'"),
+    };
+
+    Subcommand::Tx(tx)
+}
+
+/// Parses arguments for the 'tx list' subcommand.
+fn parse_tx_list_args(args: Vec<String>) -> TxSubcommand {
+    let mut format = TxFormat::Table;
+
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_ref() {
+            "-f" | "--format" => format = parse_tx_format(iter.next()),
+
+            x => usage_msg(format!("Unknown argument '{}'", x)),
+        }
+    }
+
+    TxSubcommand::List(TxListArgs { format })
+}
+
+/// Parses arguments for the 'tx' subcommands that act on a single transaction.
+fn parse_tx_arguments(args: Vec<String>) -> TxArgs {
+    let mut transaction = None;
+    let mut format = TxFormat::Table;
+
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_ref() {
+            "-f" | "--format" => format = parse_tx_format(iter.next()),
+
+            x if x.starts_with('-') => usage_msg(format!("Unknown argument '{}'", x)),
+
+            _ => {
+                if let Some(_existing_val) = transaction.replace(arg) {
+                    usage_msg("You can only specify one transaction.");
+                }
+            }
+        }
+    }
+
+    let transaction =
+        transaction.unwrap_or_else(|| usage_msg("Missing required argument 'transaction'"));
+    TxArgs {
+        transaction,
+        format,
+    }
+}
+
+/// Parses arguments for the 'tx apply' subcommand.
+fn parse_tx_apply_args(args: Vec<String>) -> TxSubcommand {
+    let mut transaction = None;
+
+    for arg in args.into_iter() {
+        match &arg {
+            x if x.starts_with('-') => usage_msg(format!("Unknown argument '{}'", x)),
+
+            _ => {
+                if let Some(_existing_val) = transaction.replace(arg) {
+                    usage_msg("You can only specify one transaction.");
+                }
+            }
+        }
+    }
+
+    TxSubcommand::Apply(TxApplyArgs { transaction })
+}
+
+/// Parses the argument to -f | --format for the 'tx' subcommands.
+fn parse_tx_format(arg: Option<String>) -> TxFormat {
+    match arg.as_deref() {
+        Some("table") => TxFormat::Table,
+        Some("json") => TxFormat::Json,
+        Some(x) => usage_msg(format!("Unknown format '{}'; use table or json", x)),
+        None => usage_msg("Did not give argument to -f | --format"),
+    }
+}
+
+/// Parses arguments for the 'watch' subcommand.
+fn parse_watch_args(args: Vec<String>) -> Subcommand {
+    let mut prefixes = Vec::new();
+    let mut interval = watch::DEFAULT_INTERVAL;
+    let mut once = false;
+    let mut timeout = None;
+
+    let mut iter = args.into_iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_ref() {
+            "-i" | "--interval" => interval = parse_seconds(iter.next(), "-i | --interval"),
+
+            "--once" => once = true,
+
+            "--timeout" => timeout = Some(parse_seconds(iter.next(), "--timeout")),
+
+            x if x.starts_with('-') => usage_msg(format!("Unknown argument '{}'", x)),
+
+            // All other arguments are settings prefixes to watch.
+            _ => prefixes.push(arg),
+        }
+    }
+
+    if prefixes.is_empty() {
+        prefixes.push("settings.".to_string());
     }
+
+    Subcommand::Watch(WatchArgs {
+        prefixes,
+        interval,
+        once,
+        timeout,
+    })
+}
+
+/// Parses a positive number of seconds given as the argument to the named option.
+fn parse_seconds(arg: Option<String>, option: &str) -> Duration {
+    let arg = arg.unwrap_or_else(|| usage_msg(format!("Did not give argument to {}", option)));
+    arg.parse::<f64>()
+        .ok()
+        .filter(|secs| *secs > 0.0)
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .unwrap_or_else(|| {
+            usage_msg(format!(
+                "Invalid argument to {}: '{}' is not a positive number of seconds",
+                option, arg
+            ))
+        })
 }
 
 /// Parses the desired subcommand of 'update'.
@@ -689,6 +1063,97 @@ async fn check(args: &Args) -> Result<String> {
     Ok(output)
 }
 
+/// Prints settings changes as they happen, until we've seen the first change, if `once` is set,
+/// or until the timeout, if any.
+async fn watch(args: &Args, watch_args: WatchArgs) -> Result<()> {
+    let mut watcher = watch::Watcher::new(&args.socket_path, watch_args.prefixes)
+        .with_interval(watch_args.interval);
+    let deadline = watch_args
+        .timeout
+        .map(|timeout| tokio::time::Instant::now() + timeout);
+
+    loop {
+        let result = match deadline {
+            Some(deadline) => match tokio::time::timeout_at(deadline, watcher.changes()).await {
+                Ok(result) => result,
+                Err(_) if watch_args.once => {
+                    return error::WatchTimeoutSnafu {
+                        timeout: watch_args.timeout.unwrap_or_default(),
+                    }
+                    .fail()
+                }
+                Err(_) => return Ok(()),
+            },
+            None => watcher.changes().await,
+        };
+
+        // Flush each batch of changes, so programs reading our output see them right away.
+        let mut stdout = io::stdout().lock();
+        for event in result.context(error::WatchSnafu)? {
+            let line = serde_json::to_string(&event).context(error::SerializeSnafu)?;
+            writeln!(stdout, "{}", line).context(error::StdoutSnafu)?;
+        }
+        stdout.flush().context(error::StdoutSnafu)?;
+
+        if watch_args.once {
+            return Ok(());
+        }
+    }
+}
+
+/// Prints the given rows under the given headers, with each column padded to line up.
+fn print_table(headers: &[&str], rows: Vec<Vec<String>>) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let headers = headers.iter().map(|h| h.to_string()).collect();
+    for row in std::iter::once(headers).chain(rows) {
+        let cells: Vec<_> = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{:width$}", cell, width = width))
+            .collect();
+        println!("{}", cells.join("  ").trim_end());
+    }
+}
+
+/// Prints the given value as pretty JSON.
+fn print_json<T: Serialize>(value: &T) -> Result<()> {
+    let pretty = serde_json::to_string_pretty(value).context(error::SerializeSnafu)?;
+    println!("{}", pretty);
+    Ok(())
+}
+
+/// Prints the names of the settings changed by a 'tx' subcommand in the requested format.
+fn print_keys(keys: &[String], format: TxFormat) -> Result<()> {
+    match format {
+        TxFormat::Table => {
+            let rows = keys.iter().map(|key| vec![key.clone()]).collect();
+            print_table(&["SETTING"], rows);
+        }
+        TxFormat::Json => print_json(&keys)?,
+    }
+    Ok(())
+}
+
+/// Prints the changes found by a dry run in the requested format.
+fn print_diff(diff: &diff::SettingsDiff, format: DiffFormat) -> Result<()> {
+    match format {
+        DiffFormat::Text => print!("{}", diff),
+        DiffFormat::Json => print_json(diff)?,
+        DiffFormat::Both => {
+            print!("{}", diff);
+            println!();
+            print_json(diff)?;
+        }
+    }
+    Ok(())
+}
+
 // =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=   =^..^=
 // Main dispatch
 
@@ -728,11 +1193,19 @@ async fn run() -> Result<()> {
             }
         }
 
-        Subcommand::Apply(apply) => {
-            apply::apply(&args.socket_path, apply.input_sources)
-                .await
-                .context(error::ApplySnafu)?;
-        }
+        Subcommand::Apply(apply) => match apply.dry_run {
+            Some(format) => {
+                let diff = apply::apply_dry_run(&args.socket_path, apply.input_sources)
+                    .await
+                    .context(error::ApplySnafu)?;
+                print_diff(&diff, format)?;
+            }
+            None => {
+                apply::apply(&args.socket_path, apply.input_sources)
+                    .await
+                    .context(error::ApplySnafu)?;
+            }
+        },
 
         Subcommand::Exec(exec) => {
             exec::exec(&args.socket_path, exec.command, exec.target, exec.tty)
@@ -741,14 +1214,19 @@ async fn run() -> Result<()> {
         }
 
         Subcommand::Get(get) => {
-            let result = match get {
-                GetArgs::Uri(uri) => get::get_uri(&args.socket_path, uri).await,
-                GetArgs::Prefixes(prefixes) => get::get_prefixes(&args.socket_path, prefixes).await,
+            let result = match get.query {
+                GetQuery::Uri(uri) => get::get_uri(&args.socket_path, uri).await,
+                GetQuery::Prefixes(prefixes) => {
+                    get::get_prefixes(&args.socket_path, prefixes).await
+                }
             };
-            let value = result.context(error::GetSnafu)?;
-            let pretty =
-                serde_json::to_string_pretty(&value).expect("JSON Value already validated as JSON");
-            println!("{}", pretty);
+            let mut value = result.context(error::GetSnafu)?;
+            if let Some(filter) = get.filter {
+                // Like a missing setting, a query that matches nothing is null.
+                value = filter.select(&value).unwrap_or(serde_json::Value::Null);
+            }
+            let output = render::render(&value, get.format).context(error::RenderSnafu)?;
+            print!("{}", output);
         }
 
         Subcommand::Reboot(_reboot) => {
@@ -758,8 +1236,8 @@ async fn run() -> Result<()> {
         }
 
         Subcommand::Set(set) => {
-            let settings = match set {
-                SetArgs::Simple(simple) => {
+            let settings = match set.settings {
+                SetSettings::Simple(simple) => {
                     trace!("User supplied Key Value settings {:#?}", simple);
                     // Construct the Key Pair struct.
                     let set_key_pair = SetKeyPairSettings {
@@ -769,18 +1247,93 @@ async fn run() -> Result<()> {
                         serde_json::to_string(&set_key_pair).context(error::SerializeSnafu)?;
                     SettingsInput::KeyPair(settings_string)
                 }
-                SetArgs::Json(json) => {
+                SetSettings::Json(json) => {
                     trace!("User supplied Json settings {:#?}", json);
                     // Convert JSON Value to a string.
                     SettingsInput::Json(json.to_string())
                 }
             };
 
-            set::set(&args.socket_path, settings)
-                .await
-                .context(error::SetSnafu)?;
+            match (set.dry_run, set.transaction) {
+                (Some(format), _) => {
+                    let diff = set::set_dry_run(&args.socket_path, settings)
+                        .await
+                        .context(error::SetSnafu)?;
+                    print_diff(&diff, format)?;
+                }
+                (None, Some(transaction)) => {
+                    set::stage(&args.socket_path, settings, &transaction)
+                        .await
+                        .context(error::SetSnafu)?;
+                    info!(
+                        "Settings staged in transaction '{}'; use 'apiclient tx commit' or \
+                         'apiclient tx apply' to make them live.",
+                        transaction
+                    );
+                }
+                (None, None) => {
+                    set::set(&args.socket_path, settings)
+                        .await
+                        .context(error::SetSnafu)?;
+                }
+            }
         }
 
+        Subcommand::Tx(subcommand) => match subcommand {
+            TxSubcommand::List(list) => {
+                let transactions = tx::list(&args.socket_path).await.context(error::TxSnafu)?;
+                match list.format {
+                    TxFormat::Table => {
+                        let rows = transactions
+                            .iter()
+                            .map(|t| vec![t.name.clone(), t.settings.len().to_string()])
+                            .collect();
+                        print_table(&["TRANSACTION", "SETTINGS"], rows);
+                    }
+                    TxFormat::Json => print_json(&transactions)?,
+                }
+            }
+
+            TxSubcommand::Show(show) => {
+                let transaction = tx::show(&args.socket_path, show.transaction)
+                    .await
+                    .context(error::TxSnafu)?;
+                match show.format {
+                    TxFormat::Table => {
+                        let rows = transaction
+                            .settings
+                            .iter()
+                            .map(|(key, value)| vec![key.clone(), value.to_string()])
+                            .collect();
+                        print_table(&["SETTING", "VALUE"], rows);
+                    }
+                    TxFormat::Json => print_json(&transaction)?,
+                }
+            }
+
+            TxSubcommand::Commit(commit) => {
+                let keys = tx::commit(&args.socket_path, &commit.transaction)
+                    .await
+                    .context(error::TxSnafu)?;
+                print_keys(&keys, commit.format)?;
+            }
+
+            TxSubcommand::Apply(apply) => {
+                let result = match apply.transaction {
+                    Some(transaction) => tx::commit_and_apply(&args.socket_path, transaction).await,
+                    None => tx::apply(&args.socket_path).await,
+                };
+                result.context(error::TxSnafu)?;
+            }
+
+            TxSubcommand::Discard(discard) => {
+                let keys = tx::discard(&args.socket_path, &discard.transaction)
+                    .await
+                    .context(error::TxSnafu)?;
+                print_keys(&keys, discard.format)?;
+            }
+        },
+
         Subcommand::Update(subcommand) => match subcommand {
             UpdateSubcommand::Check(_check) => {
                 check(&args).await?;
@@ -818,6 +1371,10 @@ async fn run() -> Result<()> {
             }
         },
 
+        Subcommand::Watch(watch_args) => {
+            watch(&args, watch_args).await?;
+        }
+
         Subcommand::Report(subcommand) => match subcommand {
             ReportSubcommand::Cis(cis_args) => {
                 let body = report::get_cis_report(
@@ -876,8 +1433,10 @@ async fn main() {
 }
 
 mod error {
-    use apiclient::{apply, exec, get, reboot, report, set, update};
+    use apiclient::{apply, exec, get, reboot, render, report, set, tx, update, watch};
     use snafu::Snafu;
+    use std::io;
+    use std::time::Duration;
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
@@ -905,6 +1464,9 @@ mod error {
             source: Box<apiclient::Error>,
         },
 
+        #[snafu(display("Failed to format output: {}", source))]
+        Render { source: render::Error },
+
         #[snafu(display("Failed to get report: {}", source))]
         Report { source: report::Error },
 
@@ -914,6 +1476,12 @@ mod error {
         #[snafu(display("Failed to change settings: {}", source))]
         Set { source: set::Error },
 
+        #[snafu(display("Failed to write output: {}", source))]
+        Stdout { source: io::Error },
+
+        #[snafu(display("Failed to manage transaction: {}", source))]
+        Tx { source: tx::Error },
+
         #[snafu(display("Failed to apply update: {}", source))]
         UpdateApply { source: update::Error },
 
@@ -922,6 +1490,117 @@ mod error {
 
         #[snafu(display("Failed to check for updates: {}", source))]
         UpdateCheck { source: update::Error },
+
+        #[snafu(display("Failed to watch settings: {}", source))]
+        Watch { source: watch::Error },
+
+        #[snafu(display("No settings changed within {:?}", timeout))]
+        WatchTimeout { timeout: Duration },
     }
 }
 type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn tx_list() {
+        assert!(matches!(
+            parse_tx_args(args(&["list"])),
+            Subcommand::Tx(TxSubcommand::List(TxListArgs {
+                format: TxFormat::Table
+            }))
+        ));
+        assert!(matches!(
+            parse_tx_args(args(&["list", "--format", "json"])),
+            Subcommand::Tx(TxSubcommand::List(TxListArgs {
+                format: TxFormat::Json
+            }))
+        ));
+    }
+
+    #[test]
+    fn tx_single() {
+        match parse_tx_args(args(&["show", "my-tx"])) {
+            Subcommand::Tx(TxSubcommand::Show(TxArgs {
+                transaction,
+                format: TxFormat::Table,
+            })) => assert_eq!(transaction, "my-tx"),
+            other => panic!("Unexpected subcommand {:?}", other),
+        }
+        // Options can come before the subcommand's name.
+        match parse_tx_args(args(&["-f", "json", "commit", "my-tx"])) {
+            Subcommand::Tx(TxSubcommand::Commit(TxArgs {
+                transaction,
+                format: TxFormat::Json,
+            })) => assert_eq!(transaction, "my-tx"),
+            other => panic!("Unexpected subcommand {:?}", other),
+        }
+        // A transaction named like a subcommand is still a transaction.
+        match parse_tx_args(args(&["discard", "list"])) {
+            Subcommand::Tx(TxSubcommand::Discard(TxArgs { transaction, .. })) => {
+                assert_eq!(transaction, "list")
+            }
+            other => panic!("Unexpected subcommand {:?}", other),
+        }
+    }
+
+    #[test]
+    fn tx_apply() {
+        assert!(matches!(
+            parse_tx_args(args(&["apply"])),
+            Subcommand::Tx(TxSubcommand::Apply(TxApplyArgs { transaction: None }))
+        ));
+        match parse_tx_args(args(&["apply", "my-tx"])) {
+            Subcommand::Tx(TxSubcommand::Apply(TxApplyArgs { transaction })) => {
+                assert_eq!(transaction.as_deref(), Some("my-tx"))
+            }
+            other => panic!("Unexpected subcommand {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dry_run_formats() {
+        assert!(matches!(
+            parse_set_args(args(&["--dry-run", "motd=hi"])),
+            Subcommand::Set(SetArgs {
+                dry_run: Some(DiffFormat::Both),
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse_set_args(args(&["--dry-run", "--diff-format", "json", "motd=hi"])),
+            Subcommand::Set(SetArgs {
+                dry_run: Some(DiffFormat::Json),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn set_tx() {
+        match parse_set_args(args(&["motd=hi", "--tx", "my-tx", "settings.hostname=h"])) {
+            Subcommand::Set(SetArgs {
+                settings: SetSettings::Simple(simple),
+                dry_run: None,
+                transaction,
+            }) => {
+                assert_eq!(simple, ["motd=hi", "settings.hostname=h"]);
+                assert_eq!(transaction.as_deref(), Some("my-tx"));
+            }
+            other => panic!("Unexpected subcommand {:?}", other),
+        }
+        assert!(matches!(
+            parse_set_args(args(&["motd=hi"])),
+            Subcommand::Set(SetArgs {
+                transaction: None,
+                ..
+            })
+        ));
+    }
+}
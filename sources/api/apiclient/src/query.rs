@@ -0,0 +1,563 @@
+//! The query module selects parts of settings, or any other Value returned by the API, using a
+//! small path language, so scripts can pick out what they need without other tools.
+//!
+//! A query is a series of steps, each applied to the result of the one before:
+//!
+//! * `name` or `.name` selects a field of a map.  Names with characters other than letters,
+//!   digits, `-`, and `_` are quoted, like `."my.label"`.
+//! * `[2]` selects an element of a list; `[-1]` is the last element.
+//! * `*` or `[*]` selects every member of a map or list.  The remaining steps are applied to each
+//!   member, and members they don't match are left out.  Maps keep their keys.
+//! * `[?condition]` selects the members of a map or list that match the condition, like `*`.  A
+//!   condition is a path relative to the member, like `enabled` or `source.tag`, which matches if
+//!   it's present and not `false` or `null`; `!path`, which matches otherwise; or a path, one of
+//!   `==`, `!=`, `<`, `<=`, `>`, `>=`, and a JSON value, like `enabled == true`.  A missing value
+//!   compares as `null`.
+//! * `{a, b.c}` builds a map from several relative paths, with the paths as keys.  Paths that
+//!   aren't present are left out.
+//!
+//! For example, this selects the source of each enabled host container, by name:
+//!
+//! ```text
+//! settings.host-containers[?enabled == true].{source}
+//! ```
+
+use datastore::{Key, KeyType};
+use serde_json::{Map, Value};
+use snafu::ensure;
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
+
+/// Query is a parsed query expression.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Query {
+    text: String,
+    steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Field(String),
+    Index(i64),
+    Wildcard,
+    Filter(Condition),
+    Project(Vec<(String, Vec<Step>)>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Condition {
+    negate: bool,
+    path: Vec<Step>,
+    comparison: Option<(Operator, Value)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Query {
+    /// Returns the part of the given Value selected by the query, or None if nothing matched.
+    pub fn select(&self, value: &Value) -> Option<Value> {
+        select(value, &self.steps)
+    }
+
+    /// Returns the dotted name of the fields the query starts with, if any.  Only settings under
+    /// this prefix can be selected, so it's all that has to be fetched from the API.
+    pub fn prefix(&self) -> Option<String> {
+        let segments: Vec<_> = self
+            .steps
+            .iter()
+            .map_while(|step| match step {
+                Step::Field(name) => Some(name.clone()),
+                _ => None,
+            })
+            .collect();
+        if segments.is_empty() {
+            return None;
+        }
+        Key::from_segments(KeyType::Data, &segments)
+            .ok()
+            .map(|key| key.name().clone())
+    }
+}
+
+impl FromStr for Query {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parser = Parser {
+            query: s,
+            chars: s.chars().collect(),
+            pos: 0,
+        };
+        let steps = parser.parse_query()?;
+        Ok(Query {
+            text: s.to_string(),
+            steps,
+        })
+    }
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// Parses the given query expression and returns the part of the given Value it selects, or None
+/// if nothing matched.
+pub fn query(value: &Value, expression: &str) -> Result<Option<Value>> {
+    Ok(expression.parse::<Query>()?.select(value))
+}
+
+fn select(value: &Value, steps: &[Step]) -> Option<Value> {
+    let (step, rest) = match steps.split_first() {
+        Some(split) => split,
+        None => return Some(value.clone()),
+    };
+    match step {
+        Step::Field(name) => select(value.as_object()?.get(name)?, rest),
+        Step::Index(index) => {
+            let list = value.as_array()?;
+            let index = if *index < 0 {
+                list.len() as i64 + index
+            } else {
+                *index
+            };
+            select(list.get(usize::try_from(index).ok()?)?, rest)
+        }
+        Step::Wildcard => select_members(value, rest, |_| true),
+        Step::Filter(condition) => select_members(value, rest, |member| condition.matches(member)),
+        Step::Project(fields) => {
+            let map: Map<_, _> = fields
+                .iter()
+                .filter_map(|(name, path)| Some((name.clone(), select(value, path)?)))
+                .collect();
+            select(&Value::Object(map), rest)
+        }
+    }
+}
+
+/// Applies the remaining steps to each member of the given map or list that passes the filter.
+fn select_members<F>(value: &Value, rest: &[Step], filter: F) -> Option<Value>
+where
+    F: Fn(&Value) -> bool,
+{
+    match value {
+        Value::Object(map) => Some(Value::Object(
+            map.iter()
+                .filter(|(_, member)| filter(member))
+                .filter_map(|(key, member)| Some((key.clone(), select(member, rest)?)))
+                .collect(),
+        )),
+        Value::Array(list) => Some(Value::Array(
+            list.iter()
+                .filter(|member| filter(member))
+                .filter_map(|member| select(member, rest))
+                .collect(),
+        )),
+        _ => None,
+    }
+}
+
+impl Condition {
+    fn matches(&self, value: &Value) -> bool {
+        let found = select(value, &self.path).unwrap_or(Value::Null);
+        let matched = match &self.comparison {
+            None => !matches!(found, Value::Null | Value::Bool(false)),
+            Some((operator, expected)) => operator.compare(&found, expected),
+        };
+        matched != self.negate
+    }
+}
+
+impl Operator {
+    fn compare(self, left: &Value, right: &Value) -> bool {
+        let ordering = match (left, right) {
+            // Compare numbers by value, so 1 and 1.0 are equal.
+            (Value::Number(l), Value::Number(r)) => l
+                .as_f64()
+                .zip(r.as_f64())
+                .and_then(|(l, r)| l.partial_cmp(&r)),
+            (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+            _ if left == right => Some(Ordering::Equal),
+            _ => None,
+        };
+        match self {
+            Operator::Eq => ordering == Some(Ordering::Equal),
+            Operator::Ne => ordering != Some(Ordering::Equal),
+            Operator::Lt => ordering == Some(Ordering::Less),
+            Operator::Le => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            Operator::Gt => ordering == Some(Ordering::Greater),
+            Operator::Ge => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+        }
+    }
+}
+
+/// Parser reads a query expression one character at a time.
+struct Parser<'a> {
+    query: &'a str,
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn parse_query(&mut self) -> Result<Vec<Step>> {
+        let mut steps = Vec::new();
+        self.skip_whitespace();
+        ensure!(!self.done(), self.error("query is empty"));
+        while !self.done() {
+            // Steps after the first are separated by dots, except for brackets.
+            let dotted = self.eat('.');
+            ensure!(
+                dotted || steps.is_empty() || self.peek() == Some('['),
+                self.error("expected '.' or '['")
+            );
+            match self.peek() {
+                Some('*') => {
+                    self.pos += 1;
+                    steps.push(Step::Wildcard);
+                }
+                Some('{') => steps.push(self.parse_projection()?),
+                Some('[') if !dotted => steps.push(self.parse_bracket()?),
+                _ => steps.push(Step::Field(self.parse_name()?)),
+            }
+            self.skip_whitespace();
+        }
+        Ok(steps)
+    }
+
+    /// Parses a path of fields and indexes, relative to a member, as in conditions and
+    /// projections.  Stops at anything else.
+    fn parse_relative_path(&mut self) -> Result<Vec<Step>> {
+        let mut steps = vec![Step::Field(self.parse_name()?)];
+        loop {
+            if self.eat('.') {
+                steps.push(Step::Field(self.parse_name()?));
+            } else if self.peek() == Some('[') {
+                match self.parse_bracket()? {
+                    step @ Step::Index(_) => steps.push(step),
+                    _ => {
+                        return self
+                            .error("only indexes are allowed in relative paths")
+                            .fail()
+                    }
+                }
+            } else {
+                return Ok(steps);
+            }
+        }
+    }
+
+    fn parse_bracket(&mut self) -> Result<Step> {
+        self.expect('[')?;
+        self.skip_whitespace();
+        let step = if self.eat('*') {
+            Step::Wildcard
+        } else if self.eat('?') {
+            Step::Filter(self.parse_condition()?)
+        } else {
+            let start = self.pos;
+            self.eat('-');
+            while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+                self.pos += 1;
+            }
+            let digits: String = self.chars[start..self.pos].iter().collect();
+            Step::Index(
+                digits
+                    .parse()
+                    .map_err(|_| self.error("expected an index, '*', or '?'").build())?,
+            )
+        };
+        self.skip_whitespace();
+        self.expect(']')?;
+        Ok(step)
+    }
+
+    fn parse_condition(&mut self) -> Result<Condition> {
+        self.skip_whitespace();
+        let negate = self.eat('!');
+        self.skip_whitespace();
+        let path = self.parse_relative_path()?;
+        self.skip_whitespace();
+
+        let operator = if self.eat_str("==") {
+            Operator::Eq
+        } else if self.eat_str("!=") {
+            Operator::Ne
+        } else if self.eat_str("<=") {
+            Operator::Le
+        } else if self.eat_str(">=") {
+            Operator::Ge
+        } else if self.eat('<') {
+            Operator::Lt
+        } else if self.eat('>') {
+            Operator::Gt
+        } else {
+            return Ok(Condition {
+                negate,
+                path,
+                comparison: None,
+            });
+        };
+        ensure!(!negate, self.error("'!' can't be used with a comparison"));
+
+        self.skip_whitespace();
+        let start = self.pos;
+        let literal = self.take_literal();
+        let value = serde_json::from_str(literal.trim()).map_err(|e| {
+            self.pos = start;
+            self.error(format!("invalid JSON value: {}", e)).build()
+        })?;
+        Ok(Condition {
+            negate,
+            path,
+            comparison: Some((operator, value)),
+        })
+    }
+
+    /// Returns the text up to the closing bracket of a condition, skipping brackets in strings.
+    fn take_literal(&mut self) -> String {
+        let start = self.pos;
+        let mut in_string = false;
+        while let Some(c) = self.peek() {
+            match c {
+                '\\' if in_string => self.pos += 1,
+                '"' => in_string = !in_string,
+                ']' if !in_string => break,
+                _ => {}
+            }
+            self.pos += 1;
+        }
+        self.pos = self.pos.min(self.chars.len());
+        self.chars[start..self.pos].iter().collect()
+    }
+
+    fn parse_projection(&mut self) -> Result<Step> {
+        self.expect('{')?;
+        let mut fields = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let start = self.pos;
+            let path = self.parse_relative_path()?;
+            let name: String = self.chars[start..self.pos].iter().collect();
+            fields.push((name, path));
+            self.skip_whitespace();
+            if !self.eat(',') {
+                break;
+            }
+        }
+        self.expect('}')?;
+        Ok(Step::Project(fields))
+    }
+
+    /// Parses a bare or quoted name.
+    fn parse_name(&mut self) -> Result<String> {
+        if self.eat('"') {
+            let mut name = String::new();
+            loop {
+                match self.peek() {
+                    Some('"') => break,
+                    Some('\\') => {
+                        self.pos += 1;
+                        name.extend(self.peek());
+                    }
+                    Some(c) => name.push(c),
+                    None => return self.error("unterminated quoted name").fail(),
+                }
+                self.pos += 1;
+            }
+            self.pos += 1;
+            return Ok(name);
+        }
+
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            self.pos += 1;
+        }
+        ensure!(self.pos > start, self.error("expected a name"));
+        Ok(self.chars[start..self.pos].iter().collect())
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn done(&self) -> bool {
+        self.pos >= self.chars.len()
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.peek() == Some(c) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_str(&mut self, s: &str) -> bool {
+        let len = s.chars().count();
+        if self.chars[self.pos..]
+            .iter()
+            .take(len)
+            .copied()
+            .eq(s.chars())
+        {
+            self.pos += len;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, c: char) -> Result<()> {
+        ensure!(self.eat(c), self.error(format!("expected '{}'", c)));
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    /// Returns a context selector for a parse error at the current position.
+    fn error<S: Into<String>>(&self, msg: S) -> error::ParseSnafu<String, usize, String> {
+        error::ParseSnafu {
+            query: self.query.to_string(),
+            position: self.pos,
+            msg: msg.into(),
+        }
+    }
+}
+
+mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Invalid query '{}' at character {}: {}", query, position, msg))]
+        Parse {
+            query: String,
+            position: usize,
+            msg: String,
+        },
+    }
+}
+pub use error::Error;
+pub type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn settings() -> Value {
+        json!({"settings": {
+            "motd": "hi",
+            "ntp": {"time-servers": ["a", "b", "c"]},
+            "kubernetes": {"node-labels": {"my.label": "x"}},
+            "host-containers": {
+                "admin": {"enabled": false, "source": "admin:1", "superpowered": true},
+                "control": {"enabled": true, "source": "control:1", "superpowered": false},
+                "extra": {"source": "extra:1"},
+            },
+        }})
+    }
+
+    fn q(expression: &str) -> Option<Value> {
+        query(&settings(), expression).unwrap()
+    }
+
+    #[test]
+    fn paths() {
+        assert_eq!(q("settings.motd"), Some(json!("hi")));
+        assert_eq!(q("settings.ntp.time-servers[1]"), Some(json!("b")));
+        assert_eq!(q("settings.ntp.time-servers[-1]"), Some(json!("c")));
+        assert_eq!(q("settings.ntp.time-servers[3]"), None);
+        assert_eq!(
+            q(r#"settings.kubernetes.node-labels."my.label""#),
+            Some(json!("x"))
+        );
+        assert_eq!(q("settings.missing"), None);
+        assert_eq!(q("settings.motd.more"), None);
+    }
+
+    #[test]
+    fn wildcards_and_filters() {
+        assert_eq!(
+            q("settings.host-containers.*.enabled"),
+            Some(json!({"admin": false, "control": true}))
+        );
+        assert_eq!(
+            q("settings.host-containers[?enabled == true].{source}"),
+            Some(json!({"control": {"source": "control:1"}}))
+        );
+        assert_eq!(
+            q("settings.host-containers[?!enabled].source"),
+            Some(json!({"admin": "admin:1", "extra": "extra:1"}))
+        );
+        assert_eq!(
+            q("settings.host-containers[?enabled != true].{source, superpowered}"),
+            Some(json!({
+                "admin": {"source": "admin:1", "superpowered": true},
+                "extra": {"source": "extra:1"},
+            }))
+        );
+        assert_eq!(
+            query(&json!([{"n": 1}, {"n": 2.0}, {"n": 3}]), "[?n >= 2].n").unwrap(),
+            Some(json!([2.0, 3]))
+        );
+        assert_eq!(
+            query(
+                &json!([{"tag": "a]b"}, {"tag": "c"}]),
+                r#"[?tag == "a]b"].tag"#
+            )
+            .unwrap(),
+            Some(json!(["a]b"]))
+        );
+    }
+
+    #[test]
+    fn prefix() {
+        let prefix = |s: &str| s.parse::<Query>().unwrap().prefix();
+        assert_eq!(
+            prefix("settings.host-containers[?enabled].source").as_deref(),
+            Some("settings.host-containers")
+        );
+        assert_eq!(
+            prefix(r#"settings.kubernetes.node-labels."my.label""#).as_deref(),
+            Some(r#"settings.kubernetes.node-labels."my.label""#)
+        );
+        assert_eq!(prefix("*.motd"), None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        for bad in [
+            "",
+            "settings.",
+            "settings..motd",
+            "settings[x]",
+            "settings[?a == nope]",
+            "settings[?!a == 1]",
+            "settings{a",
+            r#"settings."motd"#,
+            "settings motd",
+        ] {
+            bad.parse::<Query>().unwrap_err();
+        }
+    }
+}
@@ -0,0 +1,371 @@
+//! The render module prints settings, or any other Value returned by the API, in formats that are
+//! convenient to read or to feed back into the system.
+//!
+//! * `json` is pretty-printed JSON.
+//! * `toml` is a TOML document; settings rendered this way can be given to `apiclient apply`, or
+//!   used as user data, unchanged.
+//! * `yaml` is a YAML document.
+//! * `env` is a line of `NAME='value'` for each setting, like `SETTINGS_MOTD='hi'`, that a shell
+//!   can source.  It's an error if two settings would get the same variable name.
+//! * `flat` is a line of `name=value` for each setting, like `settings.motd=hi`, that can be given
+//!   to `apiclient set` as an argument.  Values `set` can't take that way, like lists, are shown
+//!   as JSON on a line commented out with '#'.
+//!
+//! Nulls are treated as missing values, as they are by the API, so they're left out of every
+//! format but `json`.
+
+use crate::diff::flatten;
+use serde_json::Value;
+use snafu::{OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// The formats in which settings can be rendered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Toml,
+    Yaml,
+    Env,
+    Flat,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Format::Json),
+            "toml" => Ok(Format::Toml),
+            "yaml" => Ok(Format::Yaml),
+            "env" => Ok(Format::Env),
+            "flat" => Ok(Format::Flat),
+            _ => error::UnknownFormatSnafu { format: s }.fail(),
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Format::Json => "json",
+            Format::Toml => "toml",
+            Format::Yaml => "yaml",
+            Format::Env => "env",
+            Format::Flat => "flat",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Renders the given Value in the given format.  The output ends with a newline, unless it's
+/// empty.
+pub fn render(value: &Value, format: Format) -> Result<String> {
+    match format {
+        Format::Json => {
+            let pretty = serde_json::to_string_pretty(value).context(error::JsonSnafu)?;
+            Ok(format!("{}\n", pretty))
+        }
+        Format::Toml => render_toml(value),
+        Format::Yaml => Ok(render_yaml(value)),
+        Format::Env => render_env(value),
+        Format::Flat => Ok(render_flat(value)),
+    }
+}
+
+fn render_toml(value: &Value) -> Result<String> {
+    // TOML has no null, and its documents are tables.
+    let value = without_nulls(value);
+    let table = value.as_object().context(error::TomlTableSnafu)?;
+    toml::to_string(table).context(error::TomlSnafu)
+}
+
+/// Returns a copy of the given Value with nulls removed from objects and lists.
+fn without_nulls(value: &Value) -> Value {
+    match value {
+        Value::Object(map) => Value::Object(
+            map.iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| (k.clone(), without_nulls(v)))
+                .collect(),
+        ),
+        Value::Array(list) => Value::Array(
+            list.iter()
+                .filter(|v| !v.is_null())
+                .map(without_nulls)
+                .collect(),
+        ),
+        _ => value.clone(),
+    }
+}
+
+fn render_yaml(value: &Value) -> String {
+    let value = without_nulls(value);
+    let mut output = String::new();
+    for line in yaml_lines(&value) {
+        output.push_str(&line);
+        output.push('\n');
+    }
+    output
+}
+
+/// Returns the lines of the YAML block representing the given Value, without indentation.
+fn yaml_lines(value: &Value) -> Vec<String> {
+    let mut lines = Vec::new();
+    match value {
+        Value::Object(map) if !map.is_empty() => {
+            for (key, inner) in map {
+                let key = yaml_key(key);
+                if is_yaml_block(inner) {
+                    lines.push(format!("{}:", key));
+                    lines.extend(yaml_lines(inner).into_iter().map(|l| format!("  {}", l)));
+                } else {
+                    lines.push(format!("{}: {}", key, yaml_scalar(inner)));
+                }
+            }
+        }
+        Value::Array(list) if !list.is_empty() => {
+            for inner in list {
+                if is_yaml_block(inner) {
+                    // The first line of the block goes on the same line as the list marker.
+                    for (i, line) in yaml_lines(inner).into_iter().enumerate() {
+                        let marker = if i == 0 { "- " } else { "  " };
+                        lines.push(format!("{}{}", marker, line));
+                    }
+                } else {
+                    lines.push(format!("- {}", yaml_scalar(inner)));
+                }
+            }
+        }
+        _ => lines.push(yaml_scalar(value)),
+    }
+    lines
+}
+
+/// Non-empty objects and lists are written as indented blocks; everything else fits on a line.
+fn is_yaml_block(value: &Value) -> bool {
+    match value {
+        Value::Object(map) => !map.is_empty(),
+        Value::Array(list) => !list.is_empty(),
+        _ => false,
+    }
+}
+
+fn yaml_scalar(value: &Value) -> String {
+    match value {
+        // JSON strings are valid YAML double-quoted strings, and quoting every string means they
+        // can't be mistaken for numbers or booleans.
+        Value::String(s) => Value::String(s.clone()).to_string(),
+        Value::Object(_) => "{}".to_string(),
+        Value::Array(_) => "[]".to_string(),
+        _ => value.to_string(),
+    }
+}
+
+/// Returns the given mapping key, quoted unless it's plainly a string to YAML.
+fn yaml_key(key: &str) -> String {
+    let plain = key.starts_with(|c: char| c.is_ascii_alphabetic())
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./".contains(c))
+        && !matches!(
+            key.to_ascii_lowercase().as_str(),
+            "true" | "false" | "yes" | "no" | "on" | "off" | "y" | "n" | "null"
+        );
+    if plain {
+        key.to_string()
+    } else {
+        Value::String(key.to_string()).to_string()
+    }
+}
+
+fn render_env(value: &Value) -> Result<String> {
+    let mut output = String::new();
+    // Different names can be mangled into the same variable name, and a shell would only keep
+    // the last one, so remember which setting each variable came from.
+    let mut names: HashMap<String, String> = HashMap::new();
+    for (key, inner) in flatten(value, None) {
+        // Only letters, digits, and underscores are safe in variable names.
+        let name = key
+            .to_ascii_uppercase()
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .filter(|part| !part.is_empty())
+            .collect::<Vec<_>>()
+            .join("_");
+        if let Some(first) = names.get(&name) {
+            return error::EnvNameCollisionSnafu {
+                name,
+                first: first.clone(),
+                second: key,
+            }
+            .fail();
+        }
+        names.insert(name.clone(), key);
+        let value = match inner {
+            Value::String(s) => s,
+            other => other.to_string(),
+        };
+        output.push_str(&format!("{}='{}'\n", name, value.replace('\'', r"'\''")));
+    }
+    Ok(output)
+}
+
+fn render_flat(value: &Value) -> String {
+    let mut output = String::new();
+    for (key, inner) in flatten(value, None) {
+        // `set` gives the API everything after the '=' as a string, and the API converts it to
+        // the setting's type; that only works for scalars, and only on a single line.
+        let line = match inner {
+            Value::String(s) if !s.contains(['\n', '\r']) => format!("{}={}", key, s),
+            Value::Bool(_) | Value::Number(_) => format!("{}={}", key, inner),
+            other => format!("# {}={}", key, other),
+        };
+        output.push_str(&line);
+        output.push('\n');
+    }
+    output
+}
+
+mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Unable to serialize JSON: {}", source))]
+        Json { source: serde_json::Error },
+
+        #[snafu(display("Unable to serialize TOML: {}", source))]
+        Toml { source: toml::ser::Error },
+
+        #[snafu(display(
+            "Settings '{}' and '{}' would both be rendered as variable {}",
+            first,
+            second,
+            name
+        ))]
+        EnvNameCollision {
+            name: String,
+            first: String,
+            second: String,
+        },
+
+        #[snafu(display("Only objects (maps) can be rendered as TOML"))]
+        TomlTable,
+
+        #[snafu(display("Unknown format '{}'; use json, toml, yaml, env, or flat", format))]
+        UnknownFormat { format: String },
+    }
+}
+pub use error::Error;
+pub type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::set;
+    use serde_json::json;
+
+    fn settings() -> Value {
+        json!({"settings": {
+            "motd": "it's \"42\"",
+            "kernel": {"sysctl": {"vm.max_map_count": "262144"}},
+            "ntp": {"time-servers": ["a", "b"]},
+            "kubernetes": {"node-labels": {"my.label": "x"}, "max-pods": 110},
+            "host-containers": {},
+            "gone": null,
+        }})
+    }
+
+    #[test]
+    fn toml_round_trips() {
+        let output = render(&settings(), Format::Toml).unwrap();
+        let parsed: Value = toml::from_str(&output).unwrap();
+        assert_eq!(parsed, without_nulls(&settings()));
+        render(&json!([1]), Format::Toml).unwrap_err();
+    }
+
+    #[test]
+    fn yaml() {
+        let value =
+            json!({"settings": {"motd": "on", "true": 1, "list": [{"a": [1, 2], "b": {}}]}});
+        assert_eq!(
+            render(&value, Format::Yaml).unwrap(),
+            "settings:\n  \
+               list:\n    \
+                 - a:\n        \
+                     - 1\n        \
+                     - 2\n      \
+                   b: {}\n  \
+               motd: \"on\"\n  \
+               \"true\": 1\n"
+        );
+    }
+
+    #[test]
+    fn env() {
+        assert_eq!(
+            render(&settings(), Format::Env).unwrap(),
+            "SETTINGS_KERNEL_SYSCTL_VM_MAX_MAP_COUNT='262144'\n\
+             SETTINGS_KUBERNETES_MAX_PODS='110'\n\
+             SETTINGS_KUBERNETES_NODE_LABELS_MY_LABEL='x'\n\
+             SETTINGS_MOTD='it'\\''s \"42\"'\n\
+             SETTINGS_NTP_TIME_SERVERS='[\"a\",\"b\"]'\n"
+        );
+    }
+
+    #[test]
+    fn env_name_collision() {
+        let value = json!({"settings": {"a-b": "1", "a.b": "2"}});
+        render(&value, Format::Env).unwrap_err();
+    }
+
+    #[test]
+    fn flat() {
+        assert_eq!(
+            render(&settings(), Format::Flat).unwrap(),
+            "settings.kernel.sysctl.\"vm.max_map_count\"=262144\n\
+             settings.kubernetes.max-pods=110\n\
+             settings.kubernetes.node-labels.\"my.label\"=x\n\
+             settings.motd=it's \"42\"\n\
+             # settings.ntp.time-servers=[\"a\",\"b\"]\n"
+        );
+        let value = json!({"settings": {"motd": "two\nlines", "enabled": true}});
+        assert_eq!(
+            render(&value, Format::Flat).unwrap(),
+            "settings.enabled=true\n# settings.motd=\"two\\nlines\"\n"
+        );
+    }
+
+    #[test]
+    fn flat_round_trips_through_set() {
+        let expected = flatten(&settings(), None);
+        let output = render(&settings(), Format::Flat).unwrap();
+        let (commented, lines): (Vec<_>, Vec<_>) = output.lines().partition(|l| l.starts_with('#'));
+        assert_eq!(commented, ["# settings.ntp.time-servers=[\"a\",\"b\"]"]);
+        assert_eq!(lines.len(), expected.len() - 1);
+        for line in lines {
+            let (key, value) = set::parse_key_value(line).unwrap();
+            let expected = match &expected[key.name()] {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            assert_eq!(value, expected);
+        }
+    }
+
+    #[test]
+    fn format_names() {
+        for format in [
+            Format::Json,
+            Format::Toml,
+            Format::Yaml,
+            Format::Env,
+            Format::Flat,
+        ] {
+            assert_eq!(format.to_string().parse::<Format>().unwrap(), format);
+        }
+        "xml".parse::<Format>().unwrap_err();
+    }
+}
@@ -1,5 +1,7 @@
+use crate::diff::{self, SettingsDiff};
 use crate::{rando, SettingsInput};
-use snafu::ResultExt;
+use datastore::{Key, KeyType};
+use snafu::{OptionExt, ResultExt};
 use std::path::Path;
 
 /// Changes the requested settings through the API, then commits and applies the transaction
@@ -12,8 +14,54 @@ where
 {
     // We use a specific transaction ID so we don't commit any other changes that may be pending.
     let transaction = format!("apiclient-set-{}", rando());
+    stage(&socket_path, settings, &transaction).await?;
 
-    // Send the settings changes to the server.
+    // Commit the transaction and apply it to the system.
+    let uri = format!("/tx/commit_and_apply?tx={}", transaction);
+    let method = "POST";
+    let (_status, _body) = crate::raw_request(&socket_path, &uri, method, None)
+        .await
+        .context(error::RequestSnafu { uri, method })?;
+
+    Ok(())
+}
+
+/// Stages the requested settings changes in a new transaction, like `set`, and returns the
+/// differences committing them would make to live settings.  The transaction is then discarded,
+/// so nothing is changed.
+pub async fn set_dry_run<P>(socket_path: P, settings: SettingsInput) -> Result<SettingsDiff>
+where
+    P: AsRef<Path>,
+{
+    let transaction = format!("apiclient-set-{}", rando());
+
+    // Staging can fail after the request was sent, so once we start, we discard the transaction
+    // whether or not we could stage and compare it.  The first failure is the one we report.
+    let diff = async {
+        stage(&socket_path, settings, &transaction).await?;
+        diff::preview_transaction(&socket_path, &transaction)
+            .await
+            .context(error::PreviewSnafu)
+    }
+    .await;
+    let uri = format!("/tx?tx={}", diff::encode(&transaction));
+    let method = "DELETE";
+    let discarded = crate::raw_request(&socket_path, &uri, method, None)
+        .await
+        .context(error::RequestSnafu { uri, method });
+
+    let diff = diff?;
+    discarded?;
+    Ok(diff)
+}
+
+/// Sends the settings changes to the server in the given transaction, without committing it.
+/// Changes staged in the same transaction can be committed together with the `tx` module.
+pub async fn stage<P>(socket_path: P, settings: SettingsInput, transaction: &str) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let transaction = diff::encode(transaction);
     let (uri, settings_data) = match settings {
         SettingsInput::KeyPair(value) => (format!("/settings/keypair?tx={}", transaction), value),
         SettingsInput::Json(value) => (format!("/settings?tx={}", transaction), value),
@@ -22,26 +70,47 @@ where
     let (_status, _body) = crate::raw_request(&socket_path, &uri, method, Some(settings_data))
         .await
         .context(error::RequestSnafu { uri, method })?;
-
-    // Commit the transaction and apply it to the system.
-    let uri = format!("/tx/commit_and_apply?tx={}", transaction);
-    let method = "POST";
-    let (_status, _body) = crate::raw_request(&socket_path, &uri, method, None)
-        .await
-        .context(error::RequestSnafu { uri, method })?;
-
     Ok(())
 }
 
+/// Splits a `key=value` argument to `set` into the setting's key and its value.  Keys can't
+/// contain '=', so the value is everything after the first one.  The value is given to the API
+/// as a string, which the API converts to the setting's type.  The "settings." prefix is
+/// optional, as it is for the API.
+pub fn parse_key_value(arg: &str) -> Result<(Key, String)> {
+    let (name, value) = arg.split_once('=').context(error::KeyValueSnafu { arg })?;
+    let key = Key::new(KeyType::Data, name).context(error::InvalidKeySnafu { name })?;
+    let key = if key.starts_with_segments(&["settings"]) {
+        key
+    } else {
+        let mut segments = vec!["settings".to_string()];
+        segments.extend(key.segments().iter().cloned());
+        Key::from_segments(KeyType::Data, &segments).context(error::InvalidKeySnafu { name })?
+    };
+    Ok((key, value.to_string()))
+}
+
 mod error {
     use snafu::Snafu;
 
     #[derive(Debug, Snafu)]
     #[snafu(visibility(pub(super)))]
     pub enum Error {
+        #[snafu(display("Invalid setting name '{}': {}", name, source))]
+        InvalidKey {
+            name: String,
+            source: datastore::Error,
+        },
+
+        #[snafu(display("Expected KEY=VALUE, got '{}'", arg))]
+        KeyValue { arg: String },
+
         #[snafu(display("Unable to serialize data: {}", source))]
         Serialize { source: serde_json::Error },
 
+        #[snafu(display("Unable to compare changes with live settings: {}", source))]
+        Preview { source: crate::diff::Error },
+
         #[snafu(display("Failed {} request to '{}': {}", method, uri, source))]
         Request {
             method: String,
@@ -53,3 +122,23 @@ mod error {
 }
 pub use error::Error;
 pub type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn key_value() {
+        let (key, value) = parse_key_value("motd=a=b").unwrap();
+        assert_eq!(key.name(), "settings.motd");
+        assert_eq!(value, "a=b");
+
+        let (key, value) =
+            parse_key_value(r#"settings.kubernetes.node-labels."my.label"="#).unwrap();
+        assert_eq!(key.name(), r#"settings.kubernetes.node-labels."my.label""#);
+        assert_eq!(value, "");
+
+        parse_key_value("motd").unwrap_err();
+        parse_key_value("mo td=hi").unwrap_err();
+    }
+}
@@ -0,0 +1,191 @@
+//! The tx module lists, shows, commits, applies, and discards pending transactions.
+//!
+//! Changes made through the API are staged in a named transaction until it's committed.  Staging
+//! changes across several requests, say with `set::stage`, and then committing them together
+//! lets you make related changes at once.
+
+use crate::diff::{encode, flatten};
+use serde::Serialize;
+use serde_json::Value;
+use snafu::ResultExt;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Transaction describes a pending transaction and the settings it would change.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Transaction {
+    pub name: String,
+    /// The pending settings, by full dotted name, like `settings.motd`.
+    pub settings: BTreeMap<String, Value>,
+}
+
+/// Returns each pending transaction, sorted by name.
+pub async fn list<P>(socket_path: P) -> Result<Vec<Transaction>>
+where
+    P: AsRef<Path>,
+{
+    let response = request(&socket_path, "/tx/list".to_string(), "GET").await?;
+    let mut names: Vec<String> =
+        serde_json::from_value(response.clone()).context(error::ResponseFormatSnafu {
+            what: "transaction list",
+            response,
+        })?;
+    names.sort();
+
+    let mut transactions = Vec::with_capacity(names.len());
+    for name in names {
+        transactions.push(show(&socket_path, name).await?);
+    }
+    Ok(transactions)
+}
+
+/// Returns the given pending transaction.  A transaction with no pending settings is empty, not
+/// an error.
+pub async fn show<P, S>(socket_path: P, transaction: S) -> Result<Transaction>
+where
+    P: AsRef<Path>,
+    S: Into<String>,
+{
+    let name = transaction.into();
+    let uri = tx_uri("/tx", &name);
+    let pending = request(&socket_path, uri, "GET").await?;
+    Ok(Transaction {
+        settings: flatten(&pending, Some("settings")),
+        name,
+    })
+}
+
+/// Commits the given transaction, making its settings live, without applying them to the system.
+/// Returns the names of the settings that changed.
+pub async fn commit<P, S>(socket_path: P, transaction: S) -> Result<Vec<String>>
+where
+    P: AsRef<Path>,
+    S: AsRef<str>,
+{
+    let uri = tx_uri("/tx/commit", transaction.as_ref());
+    let response = request(&socket_path, uri, "POST").await?;
+    changed_keys(response)
+}
+
+/// Applies committed settings to the system, restarting the services they affect.
+pub async fn apply<P>(socket_path: P) -> Result<()>
+where
+    P: AsRef<Path>,
+{
+    let uri = "/tx/apply";
+    let method = "POST";
+    let (_status, _body) = crate::raw_request(&socket_path, uri, method, None)
+        .await
+        .context(error::RequestSnafu { uri, method })?;
+    Ok(())
+}
+
+/// Commits the given transaction and applies it to the system.
+pub async fn commit_and_apply<P, S>(socket_path: P, transaction: S) -> Result<()>
+where
+    P: AsRef<Path>,
+    S: AsRef<str>,
+{
+    let uri = tx_uri("/tx/commit_and_apply", transaction.as_ref());
+    let method = "POST";
+    let (_status, _body) = crate::raw_request(&socket_path, &uri, method, None)
+        .await
+        .context(error::RequestSnafu { uri, method })?;
+    Ok(())
+}
+
+/// Deletes the given transaction without committing it.  Returns the names of the settings that
+/// were pending.
+pub async fn discard<P, S>(socket_path: P, transaction: S) -> Result<Vec<String>>
+where
+    P: AsRef<Path>,
+    S: AsRef<str>,
+{
+    let uri = tx_uri("/tx", transaction.as_ref());
+    let response = request(&socket_path, uri, "DELETE").await?;
+    changed_keys(response)
+}
+
+/// Returns the URI for the given path that acts on the given transaction.
+fn tx_uri(path: &str, transaction: &str) -> String {
+    format!("{}?tx={}", path, encode(transaction))
+}
+
+/// Makes the given request, and parses the response as JSON.
+async fn request<P>(socket_path: P, uri: String, method: &str) -> Result<Value>
+where
+    P: AsRef<Path>,
+{
+    let (_status, body) = crate::raw_request(&socket_path, &uri, method, None)
+        .await
+        .context(error::RequestSnafu { uri, method })?;
+    serde_json::from_str(&body).context(error::ResponseJsonSnafu { body })
+}
+
+/// Parses a response listing the settings changed by a request, sorted by name.
+fn changed_keys(response: Value) -> Result<Vec<String>> {
+    let mut keys: Vec<String> =
+        serde_json::from_value(response.clone()).context(error::ResponseFormatSnafu {
+            what: "changed keys",
+            response,
+        })?;
+    keys.sort();
+    Ok(keys)
+}
+
+mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Failed {} request to '{}': {}", method, uri, source))]
+        Request {
+            method: String,
+            uri: String,
+            #[snafu(source(from(crate::Error, Box::new)))]
+            source: Box<crate::Error>,
+        },
+
+        #[snafu(display("Response contained invalid JSON '{}' - {}", body, source))]
+        ResponseJson {
+            body: String,
+            source: serde_json::Error,
+        },
+
+        #[snafu(display("Response '{}' is not a valid {}: {}", response, what, source))]
+        ResponseFormat {
+            what: String,
+            response: serde_json::Value,
+            source: serde_json::Error,
+        },
+    }
+}
+pub use error::Error;
+pub type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn uris() {
+        assert_eq!(tx_uri("/tx", "default"), "/tx?tx=default");
+        assert_eq!(tx_uri("/tx/commit", "my tx"), "/tx/commit?tx=my+tx");
+        assert_eq!(
+            tx_uri("/tx/commit_and_apply", "a&tx=b"),
+            "/tx/commit_and_apply?tx=a%26tx%3Db"
+        );
+    }
+
+    #[test]
+    fn changed_keys_are_sorted() {
+        let response = json!(["settings.motd", "settings.hostname"]);
+        assert_eq!(
+            changed_keys(response).unwrap(),
+            ["settings.hostname", "settings.motd"]
+        );
+        changed_keys(json!({"settings.motd": "hi"})).unwrap_err();
+    }
+}
@@ -0,0 +1,152 @@
+//! The watch module polls the API for settings and reports each setting that changes, so
+//! programs can react to settings changes as they happen.
+//!
+//! A Watcher fetches the given prefixes when it starts, and then again at each interval, and
+//! compares each result with the one before.  Changes that are undone between polls aren't seen.
+
+use crate::diff::diff_values;
+use crate::get::get_prefixes;
+use serde::Serialize;
+use serde_json::Value;
+use snafu::ResultExt;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// The default time between polls.
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// ChangeEvent describes a change to a single setting.  A setting that was added has an old
+/// value of null, and a setting that was removed has a new value of null.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct ChangeEvent {
+    pub key: String,
+    pub old: Value,
+    pub new: Value,
+}
+
+/// Returns a ChangeEvent for each setting that differs between the given Values, in order of
+/// setting name.  The Values are results of `get::get_prefixes`, so names start with their
+/// outermost key, like `settings.motd`.
+pub fn changes(old: &Value, new: &Value) -> Vec<ChangeEvent> {
+    let diff = diff_values(old, new, None);
+    let added = diff.added.into_iter().map(|(key, new)| ChangeEvent {
+        key,
+        old: Value::Null,
+        new,
+    });
+    let changed = diff.changed.into_iter().map(|(key, change)| ChangeEvent {
+        key,
+        old: change.old,
+        new: change.new,
+    });
+    let removed = diff.removed.into_iter().map(|(key, old)| ChangeEvent {
+        key,
+        old,
+        new: Value::Null,
+    });
+
+    let mut events: Vec<_> = added.chain(changed).chain(removed).collect();
+    events.sort_by(|a, b| a.key.cmp(&b.key));
+    events
+}
+
+/// Watcher polls the API for the settings under the given prefixes.
+#[derive(Debug)]
+pub struct Watcher {
+    socket_path: PathBuf,
+    prefixes: Vec<String>,
+    interval: Duration,
+    current: Option<Value>,
+}
+
+impl Watcher {
+    pub fn new<P>(socket_path: P, prefixes: Vec<String>) -> Self
+    where
+        P: AsRef<Path>,
+    {
+        Self {
+            socket_path: socket_path.as_ref().to_path_buf(),
+            prefixes,
+            interval: DEFAULT_INTERVAL,
+            current: None,
+        }
+    }
+
+    /// Sets the time between polls.
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Polls until any of the watched settings change, and returns the changes.  The first call
+    /// fetches the settings to compare against before it starts polling.
+    pub async fn changes(&mut self) -> Result<Vec<ChangeEvent>> {
+        loop {
+            if self.current.is_some() {
+                tokio::time::sleep(self.interval).await;
+            }
+            let latest = get_prefixes(&self.socket_path, self.prefixes.clone())
+                .await
+                .context(error::GetSnafu)?;
+            let events = self
+                .current
+                .as_ref()
+                .map(|current| changes(current, &latest))
+                .unwrap_or_default();
+            self.current = Some(latest);
+            if !events.is_empty() {
+                return Ok(events);
+            }
+        }
+    }
+}
+
+mod error {
+    use snafu::Snafu;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub enum Error {
+        #[snafu(display("Failed to get settings: {}", source))]
+        Get { source: crate::get::Error },
+    }
+}
+pub use error::Error;
+pub type Result<T> = std::result::Result<T, error::Error>;
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn change_events() {
+        let old = json!({"settings": {"motd": "hi", "ntp": {"time-servers": ["a"]}, "b": 1}});
+        let new = json!({"settings": {"motd": "hi", "ntp": {"time-servers": ["b"]}, "a": 2}});
+        assert_eq!(
+            changes(&old, &new),
+            [
+                ChangeEvent {
+                    key: "settings.a".to_string(),
+                    old: Value::Null,
+                    new: json!(2),
+                },
+                ChangeEvent {
+                    key: "settings.b".to_string(),
+                    old: json!(1),
+                    new: Value::Null,
+                },
+                ChangeEvent {
+                    key: "settings.ntp.time-servers".to_string(),
+                    old: json!(["a"]),
+                    new: json!(["b"]),
+                },
+            ]
+        );
+        assert_eq!(
+            serde_json::to_string(&changes(&old, &new)[0]).unwrap(),
+            r#"{"key":"settings.a","old":null,"new":2}"#
+        );
+        assert!(changes(&old, &old).is_empty());
+    }
+}
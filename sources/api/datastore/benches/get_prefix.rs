@@ -0,0 +1,82 @@
+//! Compares get_prefix latency between FilesystemDataStore and LogDataStore on a data store
+//! shaped like a host with many sysctl, label, and taint settings.
+//!
+//! Run with `cargo bench -p datastore`.
+use datastore::{Committed, DataStore, FilesystemDataStore, Key, KeyType, LogDataStore};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::time::{Duration, Instant};
+
+/// Number of keys in each of the benchmarked maps.
+const KEYS_PER_MAP: usize = 1000;
+/// Number of timed get_prefix calls for each prefix.
+const ITERATIONS: u32 = 20;
+
+/// Fills the data store with live keys under a few large maps and some unrelated settings.
+fn populate<D: DataStore>(datastore: &mut D) {
+    for map in [
+        "kernel.sysctl",
+        "kubernetes.node-labels",
+        "kubernetes.node-taints",
+    ] {
+        for i in 0..KEYS_PER_MAP {
+            let key = Key::new(KeyType::Data, format!("settings.{}.key{}", map, i)).unwrap();
+            datastore
+                .set_key(&key, format!("\"value{}\"", i), &Committed::Live)
+                .unwrap();
+        }
+    }
+    for i in 0..100 {
+        let key = Key::new(KeyType::Data, format!("settings.other{}.enabled", i)).unwrap();
+        datastore.set_key(&key, "true", &Committed::Live).unwrap();
+    }
+}
+
+/// Returns the mean time of a get_prefix call for the given prefix.
+fn time_get_prefix<D: DataStore>(datastore: &D, prefix: &str) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        let found = datastore.get_prefix(prefix, &Committed::Live).unwrap();
+        assert!(!found.is_empty());
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn bench<D: DataStore>(name: &str, datastore: &D) {
+    for prefix in ["settings.kernel.sysctl", "settings.other1", "settings."] {
+        println!(
+            "{:<12} get_prefix({:<24}) {:>12?}",
+            name,
+            prefix,
+            time_get_prefix(datastore, prefix)
+        );
+    }
+}
+
+fn remove(path: &Path) {
+    let _ = fs::remove_dir_all(path);
+    let _ = fs::remove_file(path);
+}
+
+fn main() {
+    let base = env::temp_dir().join(format!("datastore-bench-{}", process::id()));
+    let fs_path = base.join("filesystem");
+    let log_path = base.join("log");
+    remove(&base);
+    fs::create_dir_all(&base).unwrap();
+
+    let mut filesystem = FilesystemDataStore::create(&fs_path).unwrap();
+    populate(&mut filesystem);
+    bench("filesystem", &filesystem);
+
+    let mut log = LogDataStore::new(&log_path).unwrap();
+    populate(&mut log);
+    // Reopen so the benchmark reads from a freshly compacted log, as a host would after boot.
+    drop(log);
+    let log = LogDataStore::new(&log_path).unwrap();
+    bench("log", &log);
+
+    remove(&base);
+}
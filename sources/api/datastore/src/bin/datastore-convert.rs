@@ -0,0 +1,175 @@
+//! datastore-convert
+//!
+//! Copies a data store from one on-disk format to another, e.g. from a FilesystemDataStore
+//! directory to a LogDataStore file.  History isn't copied; see `datastore::convert`.  A
+//! FilesystemDataStore source is opened read-only, so it's left exactly as it was.
+//!
+//! Sensitive values in a FilesystemDataStore are decrypted with the key given by
+//! `--encryption-key-file`, and encrypted again in a FilesystemDataStore target.  A LogDataStore
+//! can't encrypt values, so encrypted data stores can't be converted to one.
+use argh::FromArgs;
+use datastore::{convert, DataStore, EncryptionKey, FilesystemDataStore, LogDataStore};
+use snafu::{ensure, ResultExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+/// The on-disk formats we can convert between.
+#[derive(Debug, Clone, Copy)]
+enum Format {
+    Filesystem,
+    Log,
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "filesystem" => Ok(Format::Filesystem),
+            "log" => Ok(Format::Log),
+            _ => Err(format!(
+                "unknown format '{}', expected filesystem or log",
+                s
+            )),
+        }
+    }
+}
+
+/// Copies live data, pending transactions, and metadata between data store formats
+#[derive(Debug, FromArgs)]
+struct Args {
+    /// format of the source data store: filesystem|log
+    #[argh(option)]
+    from: Format,
+
+    /// path to the source data store
+    #[argh(option)]
+    source: PathBuf,
+
+    /// format of the target data store: filesystem|log
+    #[argh(option)]
+    to: Format,
+
+    /// path to the target data store, which must not exist yet
+    #[argh(option)]
+    target: PathBuf,
+
+    /// file holding the key that encrypts sensitive values in filesystem data stores
+    #[argh(option)]
+    encryption_key_file: Option<PathBuf>,
+}
+
+/// Reads the encryption key, if one was given.
+fn read_key(encryption_key_file: &Option<PathBuf>) -> Result<Option<EncryptionKey>> {
+    encryption_key_file
+        .as_ref()
+        .map(|path| EncryptionKey::from_file(path).context(error::EncryptionKeySnafu { path }))
+        .transpose()
+}
+
+/// Opens a filesystem data store with the encryption key, if one was given.
+fn with_key(
+    datastore: FilesystemDataStore,
+    encryption_key_file: &Option<PathBuf>,
+) -> Result<FilesystemDataStore> {
+    Ok(match read_key(encryption_key_file)? {
+        Some(key) => datastore.with_encryption_key(key),
+        None => datastore,
+    })
+}
+
+/// Opens the target in the given format and copies the source into it.
+fn copy_to<S: DataStore>(
+    source: &S,
+    format: Format,
+    path: &Path,
+    encryption_key_file: &Option<PathBuf>,
+) -> Result<()> {
+    match format {
+        Format::Filesystem => {
+            let target = FilesystemDataStore::create(path).context(error::OpenSnafu { path })?;
+            let mut target = with_key(target, encryption_key_file)?;
+            convert::copy_data(source, &mut target)
+        }
+        Format::Log => {
+            let mut target = LogDataStore::new(path).context(error::OpenSnafu { path })?;
+            convert::copy_data(source, &mut target)
+        }
+    }
+    .context(error::CopySnafu)
+}
+
+fn run() -> Result<()> {
+    let args: Args = argh::from_env();
+    ensure!(
+        args.source.exists(),
+        error::MissingSourceSnafu { path: &args.source }
+    );
+    ensure!(
+        !args.target.exists(),
+        error::TargetExistsSnafu { path: &args.target }
+    );
+    ensure!(
+        !(matches!(args.to, Format::Log) && args.encryption_key_file.is_some()),
+        error::UnencryptedTargetSnafu
+    );
+
+    match args.from {
+        Format::Filesystem => {
+            let source = FilesystemDataStore::open_read_only(&args.source)
+                .context(error::OpenSnafu { path: &args.source })?;
+            let source = with_key(source, &args.encryption_key_file)?;
+            copy_to(&source, args.to, &args.target, &args.encryption_key_file)
+        }
+        Format::Log => {
+            let source =
+                LogDataStore::new(&args.source).context(error::OpenSnafu { path: &args.source })?;
+            copy_to(&source, args.to, &args.target, &args.encryption_key_file)
+        }
+    }
+}
+
+// Returning a Result from main makes it print a Debug representation of the error, but with Snafu
+// we have nice Display representations of the error, so we wrap "main" (run) and print any error.
+// https://github.com/shepmaster/snafu/issues/110
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(super) enum Error {
+        #[snafu(display("Source data store '{}' does not exist", path.display()))]
+        MissingSource { path: PathBuf },
+
+        #[snafu(display("Target data store '{}' already exists", path.display()))]
+        TargetExists { path: PathBuf },
+
+        #[snafu(display("Log data stores can't encrypt sensitive values"))]
+        UnencryptedTarget,
+
+        #[snafu(display("Unable to read encryption key '{}': {}", path.display(), source))]
+        EncryptionKey {
+            path: PathBuf,
+            source: datastore::Error,
+        },
+
+        #[snafu(display("Unable to open data store '{}': {}", path.display(), source))]
+        Open {
+            path: PathBuf,
+            source: datastore::Error,
+        },
+
+        #[snafu(display("Unable to copy data store: {}", source))]
+        Copy { source: datastore::Error },
+    }
+}
+type Result<T> = std::result::Result<T, error::Error>;
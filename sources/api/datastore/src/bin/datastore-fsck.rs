@@ -0,0 +1,85 @@
+//! datastore-fsck
+//!
+//! Checks a FilesystemDataStore for corruption, and optionally quarantines bad entries.  See
+//! `datastore::fsck` for the checks.
+//!
+//! Exits 0 if the data store is clean or every problem was repaired, 2 if problems remain, and 1
+//! if the check couldn't be run.
+use argh::FromArgs;
+use datastore::fsck::Checker;
+use snafu::ResultExt;
+use std::path::PathBuf;
+use std::process;
+use std::time::Duration;
+
+/// Checks a data store for corruption
+#[derive(Debug, FromArgs)]
+struct Args {
+    /// path to the data store
+    #[argh(option)]
+    datastore_path: PathBuf,
+
+    /// quarantine bad entries and remove empty directories
+    #[argh(switch)]
+    repair: bool,
+
+    /// where to move bad entries when repairing; defaults to "quarantine" in the data store
+    #[argh(option)]
+    quarantine_path: Option<PathBuf>,
+
+    /// seconds to wait for the data store lock
+    #[argh(option, default = "30")]
+    lock_timeout: u64,
+}
+
+/// Runs the check, returning true if no problems remain.
+fn run() -> Result<bool> {
+    let args: Args = argh::from_env();
+    let mut checker = Checker::new(&args.datastore_path)
+        .with_lock_timeout(Duration::from_secs(args.lock_timeout));
+    if args.repair {
+        let quarantine_path = args
+            .quarantine_path
+            .unwrap_or_else(|| args.datastore_path.join("quarantine"));
+        checker = checker.repair(quarantine_path);
+    }
+
+    let report = checker.run().context(error::CheckSnafu {
+        path: &args.datastore_path,
+    })?;
+    for problem in &report.problems {
+        let status = if problem.repaired { " (repaired)" } else { "" };
+        println!("{}: {}{}", problem.path.display(), problem.kind, status);
+    }
+    Ok(report.problems.iter().all(|p| p.repaired))
+}
+
+// Returning a Result from main makes it print a Debug representation of the error, but with Snafu
+// we have nice Display representations of the error, so we wrap "main" (run) and print any error.
+// https://github.com/shepmaster/snafu/issues/110
+fn main() {
+    match run() {
+        Ok(true) => {}
+        Ok(false) => process::exit(2),
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(super) enum Error {
+        #[snafu(display("Unable to check data store '{}': {}", path.display(), source))]
+        Check {
+            path: PathBuf,
+            source: datastore::Error,
+        },
+    }
+}
+type Result<T> = std::result::Result<T, error::Error>;
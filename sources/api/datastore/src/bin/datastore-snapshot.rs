@@ -0,0 +1,189 @@
+//! datastore-snapshot
+//!
+//! Exports a FilesystemDataStore to a snapshot document, or imports a snapshot into an empty one.
+//! See `datastore::snapshot` for what's included.  Exporting opens the data store read-only, so
+//! it's left exactly as it was.
+use argh::FromArgs;
+use datastore::lock::DEFAULT_LOCK_TIMEOUT;
+use datastore::snapshot::{Snapshot, SnapshotFormat};
+use datastore::{EncryptionKey, FilesystemDataStore, LockedDataStore};
+use snafu::ResultExt;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+
+/// Wrapper so we can parse SnapshotFormat from arguments.
+#[derive(Debug, Clone, Copy)]
+struct Format(SnapshotFormat);
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Format(SnapshotFormat::Json)),
+            "toml" => Ok(Format(SnapshotFormat::Toml)),
+            _ => Err(format!("unknown format '{}', expected json or toml", s)),
+        }
+    }
+}
+
+/// Exports or imports data store snapshots
+#[derive(Debug, FromArgs)]
+struct Args {
+    /// path to the data store
+    #[argh(option)]
+    datastore_path: PathBuf,
+
+    /// snapshot format: json|toml
+    #[argh(option, default = "Format(SnapshotFormat::Json)")]
+    format: Format,
+
+    /// file holding the key that encrypts sensitive values in the data store
+    #[argh(option)]
+    encryption_key_file: Option<PathBuf>,
+
+    #[argh(subcommand)]
+    subcommand: Subcommand,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand)]
+enum Subcommand {
+    Export(ExportArgs),
+    Import(ImportArgs),
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "export")]
+/// Write a snapshot of the data store
+struct ExportArgs {
+    /// file to write the snapshot to; defaults to stdout
+    #[argh(option)]
+    output: Option<PathBuf>,
+
+    /// include the values of sensitive keys rather than redacting them
+    #[argh(switch)]
+    include_sensitive: bool,
+}
+
+#[derive(Debug, FromArgs)]
+#[argh(subcommand, name = "import")]
+/// Restore a snapshot into an empty data store, creating it if needed
+struct ImportArgs {
+    /// file to read the snapshot from; defaults to stdin
+    #[argh(option)]
+    input: Option<PathBuf>,
+}
+
+/// Adds the encryption key to the data store at the given path, if one was given.
+fn with_key(
+    datastore: FilesystemDataStore,
+    path: &Path,
+    encryption_key_file: &Option<PathBuf>,
+) -> Result<FilesystemDataStore> {
+    match encryption_key_file {
+        Some(key_path) => {
+            let key = EncryptionKey::from_file(key_path).context(error::OpenSnafu { path })?;
+            Ok(datastore.with_encryption_key(key))
+        }
+        None => Ok(datastore),
+    }
+}
+
+fn run() -> Result<()> {
+    let args: Args = argh::from_env();
+    let path = &args.datastore_path;
+    let format = args.format.0;
+
+    match args.subcommand {
+        Subcommand::Export(ExportArgs {
+            output,
+            include_sensitive,
+        }) => {
+            // Exporting doesn't change the data store, not even to recover or upgrade it.
+            let datastore =
+                FilesystemDataStore::open_read_only(path).context(error::OpenSnafu { path })?;
+            let datastore = with_key(datastore, path, &args.encryption_key_file)?;
+            let snapshot = if include_sensitive {
+                Snapshot::capture_unredacted(&datastore)
+            } else {
+                Snapshot::capture(&datastore)
+            }
+            .context(error::SnapshotSnafu)?;
+            let serialized = snapshot.to_string(format).context(error::SnapshotSnafu)?;
+            match output {
+                Some(output) => {
+                    fs::write(&output, serialized).context(error::WriteSnafu { path: output })?
+                }
+                None => print!("{}", serialized),
+            }
+        }
+        Subcommand::Import(ImportArgs { input }) => {
+            let serialized = match input {
+                Some(input) => {
+                    fs::read_to_string(&input).context(error::ReadSnafu { path: input })?
+                }
+                None => {
+                    let mut s = String::new();
+                    io::stdin()
+                        .read_to_string(&mut s)
+                        .context(error::ReadSnafu { path: "-" })?;
+                    s
+                }
+            };
+            let snapshot = Snapshot::from_str(&serialized, format).context(error::SnapshotSnafu)?;
+
+            if !path.join("live").exists() {
+                FilesystemDataStore::create(path).context(error::OpenSnafu { path })?;
+            }
+            // Like any writer, we complete an interrupted commit or upgrade the format first.
+            let datastore = LockedDataStore::open(path, DEFAULT_LOCK_TIMEOUT)
+                .map(LockedDataStore::into_inner)
+                .context(error::OpenSnafu { path })?;
+            let mut datastore = with_key(datastore, path, &args.encryption_key_file)?;
+            snapshot
+                .restore(&mut datastore)
+                .context(error::SnapshotSnafu)?;
+        }
+    }
+    Ok(())
+}
+
+// Returning a Result from main makes it print a Debug representation of the error, but with Snafu
+// we have nice Display representations of the error, so we wrap "main" (run) and print any error.
+// https://github.com/shepmaster/snafu/issues/110
+fn main() {
+    if let Err(e) = run() {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+}
+
+mod error {
+    use snafu::Snafu;
+    use std::io;
+    use std::path::PathBuf;
+
+    #[derive(Debug, Snafu)]
+    #[snafu(visibility(pub(super)))]
+    pub(super) enum Error {
+        #[snafu(display("Unable to open data store '{}': {}", path.display(), source))]
+        Open {
+            path: PathBuf,
+            source: datastore::Error,
+        },
+
+        #[snafu(display("{}", source))]
+        Snapshot { source: datastore::Error },
+
+        #[snafu(display("Unable to read '{}': {}", path.display(), source))]
+        Read { path: PathBuf, source: io::Error },
+
+        #[snafu(display("Unable to write '{}': {}", path.display(), source))]
+        Write { path: PathBuf, source: io::Error },
+    }
+}
+type Result<T> = std::result::Result<T, error::Error>;
@@ -0,0 +1,450 @@
+//! A conformance suite that any DataStore implementation can run, to check that it behaves like
+//! the built-in ones.
+//!
+//! The suite is available with the "conformance" feature.  Call `run_all` from a test with a
+//! function that returns a new, empty data store each time it's called; each check gets its own
+//! data store.  Checks panic with a description of the difference they found, so they can be
+//! used directly in tests.  The individual checks are public too, so an implementation that
+//! doesn't support everything can run the ones it does.
+//!
+//! The built-in implementations run the suite in their unit tests.
+
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+
+use super::{Committed, DataStore, Key, KeyType};
+
+/// A check run against a new data store.
+pub type Check<D> = fn(&mut D);
+
+/// Returns every check in the suite, by name.
+pub fn checks<D: DataStore>() -> Vec<(&'static str, Check<D>)> {
+    vec![
+        ("prefix_semantics", prefix_semantics::<D>),
+        ("quoted_keys", quoted_keys::<D>),
+        ("metadata_inheritance", metadata_inheritance::<D>),
+        ("transaction_isolation", transaction_isolation::<D>),
+        ("commit_return_values", commit_return_values::<D>),
+        ("delete_return_values", delete_return_values::<D>),
+        ("metadata_tombstones", metadata_tombstones::<D>),
+        ("shape_changes", shape_changes::<D>),
+        ("revert_keeps_metadata", revert_keeps_metadata::<D>),
+        ("transaction_created", transaction_created::<D>),
+        ("error_cases", error_cases::<D>),
+    ]
+}
+
+/// Runs every check in the suite, each against a new data store from `new_datastore`.
+pub fn run_all<D, F>(mut new_datastore: F)
+where
+    D: DataStore,
+    F: FnMut() -> D,
+{
+    for (name, check) in checks::<D>() {
+        log::debug!("Running conformance check {}", name);
+        check(&mut new_datastore());
+    }
+}
+
+fn data(name: &str) -> Key {
+    Key::new(KeyType::Data, name).unwrap()
+}
+
+fn meta(name: &str) -> Key {
+    Key::new(KeyType::Meta, name).unwrap()
+}
+
+fn keys(names: &[&str]) -> HashSet<Key> {
+    names.iter().map(|name| data(name)).collect()
+}
+
+fn pending(tx: &str) -> Committed {
+    Committed::Pending { tx: tx.into() }
+}
+
+/// Prefixes are matched against key names as strings, so "settings.a" matches "settings.ab" as
+/// well as "settings.a.b".  An empty prefix matches everything.
+///
+/// (A key can't have both a value and keys under it, so we don't check that.)
+pub fn prefix_semantics<D: DataStore>(d: &mut D) {
+    for name in ["settings.a.b", "settings.a.c", "settings.ab", "services.x"] {
+        d.set_key(&data(name), "1", &Committed::Live).unwrap();
+    }
+
+    let listed = d
+        .list_populated_keys("settings.a", &Committed::Live)
+        .unwrap();
+    assert_eq!(
+        listed,
+        keys(&["settings.a.b", "settings.a.c", "settings.ab"]),
+        "prefix 'settings.a'"
+    );
+    let listed = d
+        .list_populated_keys("settings.a.", &Committed::Live)
+        .unwrap();
+    assert_eq!(
+        listed,
+        keys(&["settings.a.b", "settings.a.c"]),
+        "prefix 'settings.a.'"
+    );
+    let listed = d.list_populated_keys("", &Committed::Live).unwrap();
+    assert_eq!(listed.len(), 4, "empty prefix");
+    let listed = d.list_populated_keys("nothing", &Committed::Live).unwrap();
+    assert!(listed.is_empty(), "unmatched prefix");
+
+    let values = d.get_prefix("settings.ab", &Committed::Live).unwrap();
+    assert_eq!(values.len(), 1, "get_prefix 'settings.ab'");
+    assert_eq!(values[&data("settings.ab")], "1");
+
+    // Metadata is listed by the prefix of the data key it's attached to.
+    d.set_metadata(&meta("m"), &data("settings.ab"), "1", &Committed::Live)
+        .unwrap();
+    d.set_metadata(&meta("m"), &data("services.x"), "1", &Committed::Live)
+        .unwrap();
+    let listed = d
+        .list_populated_metadata("settings.", &Committed::Live, &None::<&str>)
+        .unwrap();
+    assert_eq!(
+        listed.keys().cloned().collect::<HashSet<_>>(),
+        keys(&["settings.ab"]),
+        "metadata prefix 'settings.'"
+    );
+}
+
+/// Quoted segments, which can hold the separator, survive storage and listing.
+pub fn quoted_keys<D: DataStore>(d: &mut D) {
+    let quoted = data("settings.labels.\"x.y/z\"");
+    assert_eq!(quoted.segments().len(), 3);
+    d.set_key(&quoted, "\"v\"", &Committed::Live).unwrap();
+    d.set_metadata(&meta("m"), &quoted, "\"mv\"", &Committed::Live)
+        .unwrap();
+
+    assert_eq!(
+        d.get_key(&quoted, &Committed::Live).unwrap().as_deref(),
+        Some("\"v\"")
+    );
+    let listed = d
+        .list_populated_keys("settings.labels", &Committed::Live)
+        .unwrap();
+    assert_eq!(listed, HashSet::from([quoted.clone()]));
+    let listed = listed.into_iter().next().unwrap();
+    assert_eq!(listed.segments(), quoted.segments());
+    assert_eq!(
+        d.get_metadata(&meta("m"), &quoted, &Committed::Live)
+            .unwrap()
+            .as_deref(),
+        Some("\"mv\"")
+    );
+    // The quoted segment is one segment, so its parts aren't keys.
+    assert_eq!(
+        d.get_key(&data("settings.labels.x"), &Committed::Live)
+            .unwrap(),
+        None
+    );
+}
+
+/// Metadata applies to the keys under the data key it's set on, and the closest setting wins.
+/// `get_metadata_raw` doesn't inherit.
+pub fn metadata_inheritance<D: DataStore>(d: &mut D) {
+    let m = meta("affected-services");
+    d.set_metadata(&m, &data("settings"), "\"outer\"", &Committed::Live)
+        .unwrap();
+    d.set_metadata(&m, &data("settings.a"), "\"inner\"", &Committed::Live)
+        .unwrap();
+
+    let get = |name: &str| d.get_metadata(&m, &data(name), &Committed::Live).unwrap();
+    assert_eq!(get("settings.b").as_deref(), Some("\"outer\""));
+    assert_eq!(get("settings.a").as_deref(), Some("\"inner\""));
+    assert_eq!(get("settings.a.b.c").as_deref(), Some("\"inner\""));
+    assert_eq!(get("services.a"), None);
+    assert_eq!(
+        d.get_metadata_raw(&m, &data("settings.a.b"), &Committed::Live)
+            .unwrap(),
+        None
+    );
+
+    d.unset_metadata(&m, &data("settings.a"), &Committed::Live)
+        .unwrap();
+    assert_eq!(
+        d.get_metadata(&m, &data("settings.a.b"), &Committed::Live)
+            .unwrap()
+            .as_deref(),
+        Some("\"outer\"")
+    );
+}
+
+/// Pending data is only visible in its own transaction until it's committed, and committing one
+/// transaction doesn't affect another.
+pub fn transaction_isolation<D: DataStore>(d: &mut D) {
+    let a = data("settings.a");
+    let b = data("settings.b");
+    d.set_key(&a, "\"live\"", &Committed::Live).unwrap();
+    d.set_key(&a, "\"one\"", &pending("one")).unwrap();
+    d.set_key(&b, "\"two\"", &pending("two")).unwrap();
+
+    assert_eq!(
+        d.get_key(&a, &Committed::Live).unwrap().as_deref(),
+        Some("\"live\"")
+    );
+    assert_eq!(d.get_key(&a, &pending("two")).unwrap(), None);
+    assert_eq!(d.get_key(&b, &pending("one")).unwrap(), None);
+    assert!(!d.key_populated(&b, &Committed::Live).unwrap());
+    assert_eq!(
+        d.list_transactions().unwrap(),
+        HashSet::from(["one".to_string(), "two".to_string()])
+    );
+
+    d.commit_transaction("one").unwrap();
+    assert_eq!(
+        d.get_key(&a, &Committed::Live).unwrap().as_deref(),
+        Some("\"one\"")
+    );
+    assert_eq!(d.get_key(&a, &pending("one")).unwrap(), None);
+    assert_eq!(
+        d.get_key(&b, &pending("two")).unwrap().as_deref(),
+        Some("\"two\"")
+    );
+    assert_eq!(
+        d.list_transactions().unwrap(),
+        HashSet::from(["two".to_string()])
+    );
+
+    // Reading a transaction that doesn't exist is like reading an empty one.
+    assert!(d
+        .list_populated_keys("", &pending("none"))
+        .unwrap()
+        .is_empty());
+}
+
+/// Committing returns the live keys that changed, including keys removed by tombstones, and
+/// applies every pending settings key and metadata.  Pending data keys outside "settings" are
+/// discarded with the transaction rather than committed.
+pub fn commit_return_values<D: DataStore>(d: &mut D) {
+    assert!(d.commit_transaction("none").unwrap().is_empty());
+
+    d.set_key(&data("settings.old.x"), "1", &Committed::Live)
+        .unwrap();
+    d.set_key(&data("settings.a"), "1", &pending("tx")).unwrap();
+    d.set_key(&data("settings.b"), "1", &pending("tx")).unwrap();
+    d.set_key(&data("services.s.restart"), "1", &pending("tx"))
+        .unwrap();
+    d.set_tombstone(&data("settings.old"), "tx").unwrap();
+    // Tombstones on keys that aren't live don't change anything.
+    d.set_tombstone(&data("settings.missing"), "tx").unwrap();
+    let changed = d.commit_transaction("tx").unwrap();
+    assert_eq!(
+        changed,
+        keys(&["settings.a", "settings.b", "settings.old.x"])
+    );
+    assert!(!d
+        .key_populated(&data("settings.old.x"), &Committed::Live)
+        .unwrap());
+    assert!(d
+        .key_populated(&data("settings.b"), &Committed::Live)
+        .unwrap());
+    assert!(!d
+        .key_populated(&data("services.s.restart"), &Committed::Live)
+        .unwrap());
+    assert!(d.list_transactions().unwrap().is_empty());
+
+    // Metadata alone is applied, but changes no data keys.
+    d.set_metadata(&meta("m"), &data("settings.a"), "1", &pending("meta"))
+        .unwrap();
+    assert!(d.commit_transaction("meta").unwrap().is_empty());
+    assert_eq!(
+        d.get_metadata(&meta("m"), &data("settings.a"), &Committed::Live)
+            .unwrap()
+            .as_deref(),
+        Some("1")
+    );
+}
+
+/// Deleting returns the pending settings keys and tombstoned keys, and discards the whole
+/// transaction.
+pub fn delete_return_values<D: DataStore>(d: &mut D) {
+    assert!(d.delete_transaction("none").unwrap().is_empty());
+
+    d.set_key(&data("settings.a"), "1", &Committed::Live)
+        .unwrap();
+    d.set_key(&data("settings.b"), "1", &pending("tx")).unwrap();
+    d.set_key(&data("settings.c"), "1", &pending("tx")).unwrap();
+    d.set_key(&data("services.s.restart"), "1", &pending("tx"))
+        .unwrap();
+    d.set_tombstone(&data("settings.a"), "tx").unwrap();
+    d.set_metadata(&meta("m"), &data("settings.b"), "1", &pending("tx"))
+        .unwrap();
+    let deleted = d.delete_transaction("tx").unwrap();
+    assert_eq!(deleted, keys(&["settings.a", "settings.b", "settings.c"]));
+
+    assert!(d.list_transactions().unwrap().is_empty());
+    assert!(d.list_tombstones("tx").unwrap().is_empty());
+    assert!(d
+        .list_populated_metadata("", &pending("tx"), &None::<&str>)
+        .unwrap()
+        .is_empty());
+    assert!(d
+        .key_populated(&data("settings.a"), &Committed::Live)
+        .unwrap());
+    assert!(d.commit_transaction("tx").unwrap().is_empty());
+}
+
+/// Removing metadata in a pending transaction removes the live metadata when the transaction is
+/// committed, unless it's set again first.  Deleting the transaction discards the removal.
+pub fn metadata_tombstones<D: DataStore>(d: &mut D) {
+    let m = meta("affected-services");
+    let (a, b) = (data("settings.a"), data("settings.b"));
+    for key in [&a, &b] {
+        d.set_metadata(&m, key, "[]", &Committed::Live).unwrap();
+    }
+    d.unset_metadata(&m, &a, &pending("tx")).unwrap();
+    d.unset_metadata(&m, &b, &pending("tx")).unwrap();
+    d.set_metadata(&m, &b, "[\"x\"]", &pending("tx")).unwrap();
+    assert_eq!(
+        d.list_metadata_tombstones("tx").unwrap(),
+        HashMap::from([(a.clone(), HashSet::from([m.clone()]))])
+    );
+    assert!(d.list_transactions().unwrap().contains("tx"));
+    assert_eq!(
+        d.get_metadata_raw(&m, &a, &Committed::Live)
+            .unwrap()
+            .as_deref(),
+        Some("[]")
+    );
+
+    assert!(d.commit_transaction("tx").unwrap().is_empty());
+    assert_eq!(d.get_metadata_raw(&m, &a, &Committed::Live).unwrap(), None);
+    assert_eq!(
+        d.get_metadata_raw(&m, &b, &Committed::Live)
+            .unwrap()
+            .as_deref(),
+        Some("[\"x\"]")
+    );
+    assert!(d.list_metadata_tombstones("tx").unwrap().is_empty());
+
+    d.unset_metadata(&m, &b, &pending("discard")).unwrap();
+    d.delete_transaction("discard").unwrap();
+    assert!(d.list_metadata_tombstones("discard").unwrap().is_empty());
+    assert!(d
+        .get_metadata_raw(&m, &b, &Committed::Live)
+        .unwrap()
+        .is_some());
+}
+
+/// A key can't have both a value and keys under it, so setting keys replaces keys of the other
+/// shape.  A list of structures does this when it goes from empty, stored as "[]", to having
+/// elements, stored under their indexes, and back.  In a transaction, old live keys are removed
+/// when it's committed.
+pub fn shape_changes<D: DataStore>(d: &mut D) {
+    let empty = HashMap::from([(data("settings.list"), "[]")]);
+    let full = HashMap::from([(data("settings.list.0.name"), "\"a\"")]);
+    let live_keys = |d: &D| {
+        d.list_populated_keys("settings.list", &Committed::Live)
+            .unwrap()
+    };
+
+    d.set_keys(&empty, &Committed::Live).unwrap();
+    d.set_keys(&full, &Committed::Live).unwrap();
+    assert_eq!(live_keys(d), keys(&["settings.list.0.name"]));
+    d.set_keys(&empty, &Committed::Live).unwrap();
+    assert_eq!(live_keys(d), keys(&["settings.list"]));
+
+    d.set_keys(&full, &pending("tx")).unwrap();
+    assert_eq!(live_keys(d), keys(&["settings.list"]));
+    assert_eq!(
+        d.commit_transaction("tx").unwrap(),
+        keys(&["settings.list", "settings.list.0.name"])
+    );
+    assert_eq!(live_keys(d), keys(&["settings.list.0.name"]));
+
+    // Changing shape more than once in a transaction leaves only the last shape.
+    d.set_keys(&empty, &pending("tx")).unwrap();
+    d.set_keys(&full, &pending("tx")).unwrap();
+    d.set_keys(&empty, &pending("tx")).unwrap();
+    assert_eq!(
+        d.list_populated_keys("settings.list", &pending("tx"))
+            .unwrap(),
+        keys(&["settings.list"])
+    );
+    d.commit_transaction("tx").unwrap();
+    assert_eq!(live_keys(d), keys(&["settings.list"]));
+    assert_eq!(
+        d.get_key(&data("settings.list"), &Committed::Live)
+            .unwrap()
+            .as_deref(),
+        Some("[]")
+    );
+}
+
+/// A transaction's creation time is set by its first write, whether data, metadata, or a
+/// tombstone, and is forgotten when it's committed or deleted.
+pub fn transaction_created<D: DataStore>(d: &mut D) {
+    assert_eq!(d.transaction_created("none").unwrap(), None);
+    let before = SystemTime::now();
+    d.set_key(&data("settings.a"), "1", &pending("data"))
+        .unwrap();
+    d.set_metadata(&meta("m"), &data("settings.a"), "1", &pending("meta"))
+        .unwrap();
+    d.set_tombstone(&data("settings.a"), "tombstone").unwrap();
+    // Writing to live data doesn't create a transaction.
+    d.set_key(&data("settings.b"), "1", &Committed::Live)
+        .unwrap();
+
+    for tx in ["data", "meta", "tombstone"] {
+        let created = d.transaction_created(tx).unwrap().expect(tx);
+        assert!(created >= before, "{}", tx);
+    }
+    let created = d.transaction_created("data").unwrap();
+    d.set_key(&data("settings.c"), "1", &pending("data"))
+        .unwrap();
+    assert_eq!(d.transaction_created("data").unwrap(), created);
+
+    d.commit_transaction("data").unwrap();
+    d.delete_transaction("meta").unwrap();
+    assert_eq!(d.transaction_created("data").unwrap(), None);
+    assert_eq!(d.transaction_created("meta").unwrap(), None);
+    assert!(d.transaction_created("tombstone").unwrap().is_some());
+}
+
+/// Reverting restores data keys, but leaves metadata as it is, since it isn't part of
+/// generations.
+pub fn revert_keeps_metadata<D: DataStore>(d: &mut D) {
+    let (a, m) = (data("settings.a"), meta("m"));
+    d.set_key(&a, "1", &pending("one")).unwrap();
+    d.commit_transaction("one").unwrap();
+    d.set_key(&a, "2", &pending("two")).unwrap();
+    d.set_metadata(&m, &a, "2", &pending("two")).unwrap();
+    d.commit_transaction("two").unwrap();
+    d.set_metadata(&m, &a, "live", &Committed::Live).unwrap();
+
+    let changed = d.revert_to_generation(1).unwrap();
+    assert_eq!(changed, keys(&["settings.a"]));
+    assert_eq!(
+        d.get_key(&a, &Committed::Live).unwrap().as_deref(),
+        Some("1")
+    );
+    assert_eq!(
+        d.get_metadata(&m, &a, &Committed::Live).unwrap().as_deref(),
+        Some("live")
+    );
+}
+
+/// Operations that can't succeed return errors, rather than panicking or doing something else.
+pub fn error_cases<D: DataStore>(d: &mut D) {
+    // There's nothing to revert to before any commits, or past the latest generation.
+    assert!(d.revert_to_generation(1).is_err(), "revert with no history");
+    d.set_key(&data("settings.a"), "1", &pending("tx")).unwrap();
+    d.commit_transaction("tx").unwrap();
+    assert!(d.revert_to_generation(2).is_err(), "revert to the future");
+    assert!(d.revert_to_generation(1).unwrap().is_empty());
+
+    // Unsetting what isn't set isn't an error.
+    d.unset_key(&data("settings.missing"), &Committed::Live)
+        .unwrap();
+    d.unset_metadata(&meta("m"), &data("settings.a"), &Committed::Live)
+        .unwrap();
+    assert_eq!(
+        d.get_key(&data("settings.missing"), &Committed::Live)
+            .unwrap(),
+        None
+    );
+}
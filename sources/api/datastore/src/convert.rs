@@ -0,0 +1,120 @@
+//! Copies the contents of one data store into another, for example to convert between the
+//! on-disk formats of FilesystemDataStore and LogDataStore.
+//!
+//! Live data and metadata, and pending transactions (including their tombstones, metadata, and
+//! metadata tombstones), are copied.
+//! History - generations and the journal - is specific to the source data store and isn't copied;
+//! the target starts its own history from the copy.
+
+use log::debug;
+
+use super::{Committed, DataStore, Result};
+
+/// Copies live data, pending transactions, and metadata from `source` into `target`.  Existing
+/// data in `target` with the same keys is overwritten.
+///
+/// Metadata is copied before data, so a target that encrypts sensitive values knows which ones
+/// they are.
+pub fn copy_data<S, T>(source: &S, target: &mut T) -> Result<()>
+where
+    S: DataStore,
+    T: DataStore,
+{
+    copy_metadata(source, target, &Committed::Live)?;
+    let live = source.get_prefix("", &Committed::Live)?;
+    debug!("Copying {} live keys", live.len());
+    for (key, value) in live {
+        target.set_key(&key, value, &Committed::Live)?;
+    }
+
+    for tx in source.list_transactions()? {
+        let committed = Committed::Pending { tx: tx.clone() };
+        copy_metadata(source, target, &committed)?;
+        let pending = source.get_prefix("", &committed)?;
+        debug!(
+            "Copying {} keys pending in transaction '{}'",
+            pending.len(),
+            tx
+        );
+        for (key, value) in pending {
+            target.set_key(&key, value, &committed)?;
+        }
+        for key in source.list_tombstones(&tx)? {
+            target.set_tombstone(&key, tx.as_str())?;
+        }
+        for (data_key, meta_keys) in source.list_metadata_tombstones(&tx)? {
+            for metadata_key in meta_keys {
+                target.unset_metadata(&metadata_key, &data_key, &committed)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies the live or pending metadata of `source` into `target`.
+fn copy_metadata<S, T>(source: &S, target: &mut T, committed: &Committed) -> Result<()>
+where
+    S: DataStore,
+    T: DataStore,
+{
+    for (data_key, meta_map) in source.get_metadata_prefix("", committed, &None::<&str>)? {
+        for (metadata_key, value) in meta_map {
+            target.set_metadata(&metadata_key, &data_key, value, committed)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryDataStore;
+    use crate::{Key, KeyType};
+
+    #[test]
+    fn copies_everything_but_history() {
+        let a = Key::new(KeyType::Data, "settings.a").unwrap();
+        let b = Key::new(KeyType::Data, "settings.b").unwrap();
+        let meta = Key::new(KeyType::Meta, "affected-services").unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+
+        let mut source = MemoryDataStore::new();
+        source.set_key(&a, "1", &pending).unwrap();
+        source.commit_transaction("tx").unwrap();
+        source.set_key(&a, "2", &pending).unwrap();
+        source.set_tombstone(&b, "tx").unwrap();
+        source
+            .set_metadata(&meta, &a, "[]", &Committed::Live)
+            .unwrap();
+        source.set_metadata(&meta, &b, "[]", &pending).unwrap();
+        source.unset_metadata(&meta, &a, &pending).unwrap();
+
+        let mut target = MemoryDataStore::new();
+        copy_data(&source, &mut target).unwrap();
+        assert_eq!(
+            target.get_key(&a, &Committed::Live).unwrap(),
+            Some("1".to_string())
+        );
+        assert_eq!(target.get_key(&a, &pending).unwrap(), Some("2".to_string()));
+        assert_eq!(
+            target.list_tombstones("tx").unwrap(),
+            source.list_tombstones("tx").unwrap()
+        );
+        assert_eq!(
+            target.list_metadata_tombstones("tx").unwrap(),
+            source.list_metadata_tombstones("tx").unwrap()
+        );
+        assert_eq!(
+            target
+                .get_metadata_raw(&meta, &a, &Committed::Live)
+                .unwrap(),
+            Some("[]".to_string())
+        );
+        assert_eq!(
+            target.get_metadata_raw(&meta, &b, &pending).unwrap(),
+            Some("[]".to_string())
+        );
+        assert!(target.list_generations().unwrap().is_empty());
+    }
+}
@@ -11,8 +11,11 @@ pub enum Error {
     #[snafu(display("Error during deserialization: {}", msg))]
     Message { msg: String },
 
-    #[snafu(display("Error deserializing scalar value: {}", source))]
-    DeserializeScalar { source: ScalarError },
+    #[snafu(display("Error deserializing scalar value of '{}': {}", key, source))]
+    DeserializeScalar { key: String, source: ScalarError },
+
+    #[snafu(display("Error deserializing '{}': {}", key, msg))]
+    InvalidStructure { key: String, msg: String },
 
     #[snafu(display(
         "Data store deserializer must be used on a struct, or you must give a prefix"
@@ -32,6 +35,12 @@ pub enum Error {
         source: Box<DataStoreError>,
     },
 
+    #[snafu(display("List '{}' has non-numeric index '{}'", list, index))]
+    InvalidListIndex { list: String, index: String },
+
+    #[snafu(display("List element '{}' has no value", key))]
+    MissingListElement { key: String },
+
     #[snafu(display("Prefix '{}' is not a valid key: {}", prefix, source))]
     InvalidPrefix {
         prefix: String,
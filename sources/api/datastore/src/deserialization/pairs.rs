@@ -32,11 +32,14 @@
 //! would be "a.b", so we know we should look for "a.b.c" in our input mapping.
 
 use log::{error, trace};
-use serde::de::{value::MapDeserializer, IntoDeserializer, Visitor};
+use serde::de::{
+    value::{MapDeserializer, SeqDeserializer},
+    IntoDeserializer, Visitor,
+};
 use serde::{forward_to_deserialize_any, Deserialize};
-use snafu::ResultExt;
+use snafu::{ensure, OptionExt, ResultExt};
 use std::borrow::Borrow;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::hash::Hash;
 
 use super::{error, Error, Result};
@@ -107,9 +110,10 @@ where
 /// ValueDeserializer is what interfaces with serde's MapDeserializer, which expects to receive a
 /// key name and a deserializer for it on each iteration, i.e. for each field.  Based on whether
 /// the key name has a dot, we know if we need to recurse again or just deserialize a final value,
-/// which we represent as the two arms of the enum.
+/// which we represent as the two arms of the enum.  Scalars keep their key so errors can say
+/// which value was bad.
 enum ValueDeserializer<'de, K, S, BH> {
-    Scalar(ScalarDeserializer<'de>),
+    Scalar(Key, ScalarDeserializer<'de>),
     Compound(CompoundDeserializer<'de, K, S, BH>),
 }
 
@@ -128,11 +132,11 @@ where
         V: Visitor<'de>,
     {
         match self {
-            ValueDeserializer::Scalar(mut scalar_deserializer) => {
+            ValueDeserializer::Scalar(key, mut scalar_deserializer) => {
                 trace!("Handing off to scalar deserializer for deserialize_any");
                 scalar_deserializer
                     .deserialize_any(visitor)
-                    .context(error::DeserializeScalarSnafu)
+                    .context(error::DeserializeScalarSnafu { key: key.name() })
             }
             ValueDeserializer::Compound(compound_deserializer) => {
                 compound_deserializer.deserialize_map(visitor)
@@ -147,11 +151,11 @@ where
         V: Visitor<'de>,
     {
         match self {
-            ValueDeserializer::Scalar(mut scalar_deserializer) => {
+            ValueDeserializer::Scalar(key, mut scalar_deserializer) => {
                 trace!("Handing off to scalar deserializer for deserialize_option");
                 scalar_deserializer
                     .deserialize_option(visitor)
-                    .context(error::DeserializeScalarSnafu)
+                    .context(error::DeserializeScalarSnafu { key: key.name() })
             }
             ValueDeserializer::Compound(compound_deserializer) => {
                 compound_deserializer.deserialize_option(visitor)
@@ -159,9 +163,28 @@ where
         }
     }
 
+    /// Lists of scalars are stored as a single scalar value, but lists of compound values are
+    /// stored under their indexes, so we need to tell them apart here.
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        match self {
+            ValueDeserializer::Scalar(key, mut scalar_deserializer) => {
+                trace!("Handing off to scalar deserializer for deserialize_seq");
+                scalar_deserializer
+                    .deserialize_seq(visitor)
+                    .context(error::DeserializeScalarSnafu { key: key.name() })
+            }
+            ValueDeserializer::Compound(compound_deserializer) => {
+                compound_deserializer.deserialize_seq(visitor)
+            }
+        }
+    }
+
     forward_to_deserialize_any! {
         bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
-        bytes byte_buf unit unit_struct newtype_struct seq tuple
+        bytes byte_buf unit unit_struct newtype_struct tuple
         tuple_struct map struct enum identifier ignored_any
     }
 }
@@ -270,10 +293,14 @@ where
         // and handled all of "a" from the first one.
         let mut structs_done = HashSet::new();
 
+        // Errors from serde itself, like missing fields, don't know where they are, so we add our
+        // path to them.
+        let struct_path = self.path.clone();
+
         // As mentioned above, MapDeserializer does a lot of nice work for us.  We just need to
         // give it an iterator that yields (key, deserializer) pairs.  The nested deserializers
         // have the appropriate 'path' and a subset of 'keys' so they can do their job.
-        visitor.visit_map(MapDeserializer::new(self.keys.iter().filter_map(|key| {
+        let result = visitor.visit_map(MapDeserializer::new(self.keys.iter().filter_map(|key| {
             let mut segments: VecDeque<_> = key.segments().clone().into();
             // Inside this filter_map closure, we can't return early from the outer function, so we
             // log an error and skip the key.  Errors in this path are generally logic errors
@@ -358,12 +385,18 @@ where
                     path
                 );
                 let val = self.map.get(&path)?;
-                Some((
-                    struct_name,
-                    ValueDeserializer::Scalar(deserializer_for_scalar(val.as_ref())),
-                ))
+                let deserializer = deserializer_for_scalar(val.as_ref());
+                Some((struct_name, ValueDeserializer::Scalar(path, deserializer)))
+            }
+        })));
+        match (result, struct_path) {
+            (Err(Error::Message { msg }), Some(path)) => error::InvalidStructureSnafu {
+                key: path.name(),
+                msg,
             }
-        })))
+            .fail(),
+            (result, _) => result,
+        }
     }
 
     /// We use deserialize_map for all maps, including top-level maps, but to allow top-level maps
@@ -388,6 +421,77 @@ where
         visitor.visit_some(self)
     }
 
+    /// Lists of compound values are stored with the index of each element as a key segment, e.g.
+    /// "a.list.0.x" and "a.list.1.x".  We group the keys by index and hand the elements to serde's
+    /// SeqDeserializer in index order.  Indexes must count up from 0 without gaps, written the way
+    /// serialization writes them; a gap means keys were left behind by an earlier, longer list,
+    /// so we'd rather fail than guess.
+    fn deserialize_seq<V>(self, visitor: V) -> Result<V::Value>
+    where
+        V: Visitor<'de>,
+    {
+        let path = match self.path {
+            Some(path) => path,
+            None => return bad_root(),
+        };
+
+        let mut elements: BTreeMap<usize, HashSet<Key>> = BTreeMap::new();
+        for key in &self.keys {
+            let segment = &key.segments()[0];
+            let index = segment
+                .parse::<usize>()
+                .ok()
+                .filter(|index| index.to_string() == *segment)
+                .context(error::InvalidListIndexSnafu {
+                    list: path.name(),
+                    index: segment,
+                })?;
+            let element_keys = elements.entry(index).or_default();
+            if key.segments().len() > 1 {
+                element_keys.insert(key.strip_prefix_segments(&[segment]).context(
+                    error::StripPrefixSnafu {
+                        prefix: segment,
+                        name: key.name(),
+                    },
+                )?);
+            }
+        }
+
+        let mut values = Vec::with_capacity(elements.len());
+        for (expected, (index, keys)) in elements.into_iter().enumerate() {
+            ensure!(
+                index == expected,
+                error::MissingListElementSnafu {
+                    key: format!("{}.{}", path.name(), expected),
+                }
+            );
+            let index = index.to_string();
+            let element_path = path
+                .append_segments(&[&index])
+                .context(error::InvalidPrefixSnafu { prefix: &index })?;
+            if keys.is_empty() {
+                trace!("List element '{}' is scalar", element_path);
+                let val = self
+                    .map
+                    .get(&element_path)
+                    .context(error::MissingListElementSnafu {
+                        key: element_path.name(),
+                    })?;
+                let deserializer = deserializer_for_scalar(val.as_ref());
+                values.push(ValueDeserializer::Scalar(element_path, deserializer));
+            } else {
+                trace!("List element '{}' is compound", element_path);
+                values.push(ValueDeserializer::Compound(CompoundDeserializer::new(
+                    self.map,
+                    keys,
+                    Some(element_path),
+                )));
+            }
+        }
+
+        visitor.visit_seq(SeqDeserializer::new(values.into_iter()))
+    }
+
     /// Scalar types, and compound types we can't use at the root, are forwarded here to be
     /// rejected.  (Compound types need to have a name to serve at the root level.)
     fn deserialize_any<V>(self, visitor: V) -> Result<V::Value>
@@ -405,7 +509,7 @@ where
     // function above that will reject them.
     forward_to_deserialize_any! {
         bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
-        bytes byte_buf unit unit_struct newtype_struct seq tuple
+        bytes byte_buf unit unit_struct newtype_struct tuple
         tuple_struct enum identifier ignored_any
     }
 }
@@ -489,6 +593,89 @@ mod test {
         );
     }
 
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Hosts {
+        list: Vec<Host>,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Host {
+        name: String,
+        aliases: Vec<String>,
+    }
+
+    #[test]
+    fn compound_list_works() {
+        let hosts: Hosts = from_map(&hashmap! {
+            key!("hosts.list.1.name") => "\"c\"".to_string(),
+            key!("hosts.list.1.aliases") => "[]".to_string(),
+            key!("hosts.list.0.name") => "\"a\"".to_string(),
+            key!("hosts.list.0.aliases") => "[\"b\"]".to_string(),
+        })
+        .unwrap();
+        assert_eq!(
+            hosts,
+            Hosts {
+                list: vec![
+                    Host {
+                        name: "a".to_string(),
+                        aliases: vec!["b".to_string()]
+                    },
+                    Host {
+                        name: "c".to_string(),
+                        aliases: vec![]
+                    },
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn compound_list_needs_contiguous_indexes() {
+        let hosts: Result<Hosts, Error> = from_map(&hashmap! {
+            key!("hosts.list.0.name") => "\"a\"".to_string(),
+            key!("hosts.list.0.aliases") => "[]".to_string(),
+            key!("hosts.list.2.name") => "\"c\"".to_string(),
+            key!("hosts.list.2.aliases") => "[]".to_string(),
+        });
+        let err = hosts.unwrap_err();
+        assert!(err.to_string().contains("hosts.list.1"), "{}", err);
+
+        // Indexes are compared as written, so "01" doesn't stand in for "1".
+        let hosts: Result<Hosts, Error> = from_map(&hashmap! {
+            key!("hosts.list.0.name") => "\"a\"".to_string(),
+            key!("hosts.list.0.aliases") => "[]".to_string(),
+            key!("hosts.list.01.name") => "\"b\"".to_string(),
+            key!("hosts.list.01.aliases") => "[]".to_string(),
+        });
+        hosts.unwrap_err();
+    }
+
+    #[test]
+    fn compound_list_orders_indexes_numerically() {
+        let mut map = HashMap::new();
+        for i in 0..12 {
+            map.insert(
+                key!(&format!("hosts.list.{}.name", i)),
+                format!("\"{}\"", i),
+            );
+            map.insert(key!(&format!("hosts.list.{}.aliases", i)), "[]".to_string());
+        }
+        let hosts: Hosts = from_map(&map).unwrap();
+        let names: Vec<String> = hosts.list.into_iter().map(|host| host.name).collect();
+        let expected: Vec<String> = (0..12).map(|i| i.to_string()).collect();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn compound_list_needs_indexes() {
+        let hosts: Result<Hosts, Error> = from_map(&hashmap! {
+            key!("hosts.list.first.name") => "\"a\"".to_string(),
+            key!("hosts.list.first.aliases") => "[]".to_string(),
+        });
+        hosts.unwrap_err();
+    }
+
     #[test]
     fn map_doesnt_work_at_root() {
         let a: Result<HashMap<String, String>, Error> = from_map(&hashmap! {
@@ -512,6 +699,25 @@ mod test {
         );
     }
 
+    #[test]
+    fn errors_name_the_key() {
+        let b: Result<B, Error> = from_map(&hashmap! {
+            key!("b.a") => "\"fine\"".to_string(),
+            key!("b.b") => "\"not a bool\"".to_string(),
+        });
+        let message = b.unwrap_err().to_string();
+        assert!(message.contains("'b.b'"), "{}", message);
+
+        let a: Result<A, Error> = from_map(&hashmap! {
+            key!("a.name") => "\"name\"".to_string(),
+            key!("a.list") => "[]".to_string(),
+            key!("a.map.a") => "\"map\"".to_string(),
+            key!("a.nested.a") => "\"nested\"".to_string(),
+        });
+        let message = a.unwrap_err().to_string();
+        assert!(message.contains("'a.nested'"), "{}", message);
+    }
+
     #[derive(Debug, Deserialize, PartialEq)]
     struct Bad {
         id: u64,
@@ -0,0 +1,253 @@
+//! Compares a pending transaction with live data, to show what committing it would do.
+//!
+//! The comparison follows the same rules as `commit_transaction`: tombstones remove the live keys
+//! under them, and pending keys are set on top, so a key that's both tombstoned and set is
+//! changed rather than removed.  Pending keys whose values match live data aren't reported.
+//! Metadata follows the same rules, so metadata removed by the transaction doesn't apply.
+//!
+//! Values of sensitive keys are redacted, but their changes are still reported.
+
+use std::collections::{HashMap, HashSet};
+
+use super::sensitive::{SensitiveKeys, REDACTED_VALUE};
+use super::{tombstoned_keys, Committed, DataStore, Key, KeyType, Result};
+
+/// ValueChange holds the live and pending values of a changed key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueChange {
+    pub old: String,
+    pub new: String,
+}
+
+/// TransactionDiff describes the changes a pending transaction would make to live data.  Values
+/// are serialized, as stored in the data store.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransactionDiff {
+    /// Keys that aren't in live data, with their new values.
+    pub added: HashMap<Key, String>,
+    /// Keys whose live values would change.
+    pub changed: HashMap<Key, ValueChange>,
+    /// Keys that would be removed from live data, with their old values.
+    pub removed: HashMap<Key, String>,
+    /// The metadata that applies to each added, changed, or removed key once the transaction is
+    /// committed, including metadata inherited from earlier in the tree, like affected-services.
+    /// Metadata set in the transaction overrides live metadata.
+    pub metadata: HashMap<Key, HashMap<Key, String>>,
+}
+
+impl TransactionDiff {
+    /// Returns true if committing the transaction wouldn't change live data.
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.changed.is_empty() && self.removed.is_empty()
+    }
+
+    /// Returns every key that would change, whether added, changed, or removed.
+    pub fn keys(&self) -> HashSet<&Key> {
+        self.added
+            .keys()
+            .chain(self.changed.keys())
+            .chain(self.removed.keys())
+            .collect()
+    }
+}
+
+/// Builds the diff for `DataStore::diff_transaction`.
+pub(crate) fn diff_transaction<D, S1, S2>(
+    datastore: &D,
+    transaction: S1,
+    prefix: S2,
+) -> Result<TransactionDiff>
+where
+    D: DataStore + ?Sized,
+    S1: AsRef<str>,
+    S2: AsRef<str>,
+{
+    let pending = Committed::Pending {
+        tx: transaction.as_ref().to_string(),
+    };
+    let pending_data = datastore.get_prefix(&prefix, &pending)?;
+    let tombstones = datastore.list_tombstones(transaction.as_ref())?;
+    let mut live_data = datastore.get_prefix(&prefix, &Committed::Live)?;
+
+    let mut diff = TransactionDiff::default();
+    for (key, new) in pending_data {
+        match live_data.remove(&key) {
+            Some(old) if old == new => {}
+            Some(old) => {
+                diff.changed.insert(key, ValueChange { old, new });
+            }
+            None => {
+                diff.added.insert(key, new);
+            }
+        }
+    }
+    // Anything left in live data wasn't set in the transaction, so it's only removed if
+    // tombstoned.
+    for key in tombstoned_keys(live_data.keys(), &tombstones) {
+        if let Some(old) = live_data.remove(&key) {
+            diff.removed.insert(key, old);
+        }
+    }
+
+    // Metadata can be set on any prefix of a key, so we check each metadata key that's in use.
+    let mut metadata_keys = HashSet::new();
+    for committed in [&Committed::Live, &pending] {
+        metadata_keys.extend(
+            datastore
+                .list_populated_metadata("", committed, &None::<&str>)?
+                .into_values()
+                .flatten(),
+        );
+    }
+    let metadata_tombstones = datastore.list_metadata_tombstones(transaction.as_ref())?;
+    let mut metadata = HashMap::new();
+    for key in diff.keys() {
+        let mut key_metadata = HashMap::new();
+        for metadata_key in &metadata_keys {
+            if let Some(value) =
+                committed_metadata(datastore, metadata_key, key, &pending, &metadata_tombstones)?
+            {
+                key_metadata.insert(metadata_key.clone(), value);
+            }
+        }
+        if !key_metadata.is_empty() {
+            metadata.insert(key.clone(), key_metadata);
+        }
+    }
+    diff.metadata = metadata;
+
+    // Values are compared before they're redacted, so changes are still seen.
+    let sensitive = SensitiveKeys::load(datastore, &pending)?;
+    sensitive.redact(&mut diff.added);
+    sensitive.redact(&mut diff.removed);
+    for (key, change) in diff.changed.iter_mut() {
+        if sensitive.contains(key) {
+            change.old = REDACTED_VALUE.to_string();
+            change.new = REDACTED_VALUE.to_string();
+        }
+    }
+
+    Ok(diff)
+}
+
+/// Returns the value a metadata key would have for the given data key once the pending
+/// transaction is committed, with the same inheritance as `DataStore::get_metadata`.
+fn committed_metadata<D>(
+    datastore: &D,
+    metadata_key: &Key,
+    data_key: &Key,
+    pending: &Committed,
+    metadata_tombstones: &HashMap<Key, HashSet<Key>>,
+) -> Result<Option<String>>
+where
+    D: DataStore + ?Sized,
+{
+    let mut result = None;
+    let mut current_path = Vec::new();
+    for component in data_key.segments() {
+        current_path.push(component);
+        let prefix = Key::from_segments(KeyType::Data, &current_path)?;
+        let removed = metadata_tombstones
+            .get(&prefix)
+            .map(|meta_keys| meta_keys.contains(metadata_key))
+            .unwrap_or(false);
+        let value = match datastore.get_metadata_raw(metadata_key, &prefix, pending)? {
+            Some(value) => Some(value),
+            None if removed => None,
+            None => datastore.get_metadata_raw(metadata_key, &prefix, &Committed::Live)?,
+        };
+        if value.is_some() {
+            result = value;
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryDataStore;
+    use crate::KeyType;
+    use maplit::hashmap;
+
+    #[test]
+    fn diff() {
+        let mut m = MemoryDataStore::new();
+        let key = |name: &str| Key::new(KeyType::Data, name).unwrap();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        for (name, value) in [
+            ("settings.ntp.servers", "[\"a\"]"),
+            ("settings.motd", "\"hi\""),
+            ("settings.labels.a", "\"1\""),
+            ("settings.labels.b", "\"2\""),
+            ("settings.same", "true"),
+        ] {
+            m.set_key(&key(name), value, &Committed::Live).unwrap();
+        }
+        m.set_key(&key("settings.ntp.servers"), "[\"b\"]", &pending)
+            .unwrap();
+        m.set_key(&key("settings.labels.b"), "\"3\"", &pending)
+            .unwrap();
+        m.set_key(&key("settings.new"), "1", &pending).unwrap();
+        m.set_key(&key("settings.same"), "true", &pending).unwrap();
+        m.set_tombstone(&key("settings.labels"), tx).unwrap();
+
+        let services = Key::new(KeyType::Meta, "affected-services").unwrap();
+        m.set_metadata(
+            &services,
+            &key("settings.ntp"),
+            "[\"chronyd\"]",
+            &Committed::Live,
+        )
+        .unwrap();
+        m.set_metadata(
+            &services,
+            &key("settings.labels"),
+            "[\"kubelet\"]",
+            &pending,
+        )
+        .unwrap();
+        // Metadata removed by the transaction doesn't apply.
+        m.set_metadata(&services, &key("settings.new"), "[\"x\"]", &Committed::Live)
+            .unwrap();
+        m.unset_metadata(&services, &key("settings.new"), &pending)
+            .unwrap();
+
+        let diff = m.diff_transaction(tx, "").unwrap();
+        assert_eq!(diff.added, hashmap!(key("settings.new") => "1".to_string()));
+        assert_eq!(
+            diff.changed,
+            hashmap!(
+                key("settings.ntp.servers") => ValueChange {
+                    old: "[\"a\"]".to_string(),
+                    new: "[\"b\"]".to_string(),
+                },
+                key("settings.labels.b") => ValueChange {
+                    old: "\"2\"".to_string(),
+                    new: "\"3\"".to_string(),
+                },
+            )
+        );
+        assert_eq!(
+            diff.removed,
+            hashmap!(key("settings.labels.a") => "\"1\"".to_string())
+        );
+        assert_eq!(
+            diff.metadata,
+            hashmap!(
+                key("settings.ntp.servers") => hashmap!(
+                    services.clone() => "[\"chronyd\"]".to_string()
+                ),
+                key("settings.labels.a") => hashmap!(
+                    services.clone() => "[\"kubelet\"]".to_string()
+                ),
+                key("settings.labels.b") => hashmap!(services => "[\"kubelet\"]".to_string()),
+            )
+        );
+
+        let diff = m.diff_transaction(tx, "settings.labels").unwrap();
+        assert_eq!(diff.keys().len(), 2);
+        assert!(m.diff_transaction("other", "").unwrap().is_empty());
+    }
+}
@@ -2,7 +2,7 @@ use snafu::Snafu;
 use std::io;
 use std::path::PathBuf;
 
-use super::{serialization, ScalarError};
+use super::{deserialization, serialization, ScalarError};
 
 /// Possible errors from datastore operations.
 #[derive(Debug, Snafu)]
@@ -17,6 +17,15 @@ pub enum Error {
     #[snafu(display("Error serializing scalar {}: {} ", given, source))]
     SerializeScalar { given: String, source: ScalarError },
 
+    #[snafu(display("Unable to deserialize value of '{}': {}", key, source))]
+    DeserializeKey { key: String, source: ScalarError },
+
+    #[snafu(display("Unable to deserialize '{}': {}", prefix, source))]
+    DeserializePrefix {
+        prefix: String,
+        source: deserialization::Error,
+    },
+
     #[snafu(display("Key would traverse outside data store: {}", name))]
     PathTraversal { name: String },
 
@@ -59,6 +68,166 @@ pub enum Error {
 
     #[snafu(display("Key name beyond maximum length {}: {}", name, max))]
     KeyTooLong { name: String, max: usize },
+
+    #[snafu(display("Unable to serialize commit intent: {}", source))]
+    CommitIntentSerialize { source: serde_json::Error },
+
+    #[snafu(display("Commit intent at '{}' is invalid: {}", path.display(), source))]
+    CommitIntentParse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Unable to serialize generation record: {}", source))]
+    GenerationSerialize { source: serde_json::Error },
+
+    #[snafu(display("Generation record at '{}' is invalid: {}", path.display(), source))]
+    GenerationParse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Generation {} is not available to revert to", id))]
+    GenerationUnavailable { id: u64 },
+
+    #[snafu(display("Unable to lock '{}': {}", path.display(), source))]
+    Lock { path: PathBuf, source: nix::Error },
+
+    #[snafu(display(
+        "Timed out after {:?} waiting for lock on '{}': {}",
+        timeout,
+        path.display(),
+        holder
+    ))]
+    LockTimeout {
+        path: PathBuf,
+        timeout: std::time::Duration,
+        holder: String,
+    },
+
+    #[snafu(display(
+        "Data store at '{}' has format version {}, but only versions up to {} are supported; it may have been written by newer software",
+        path.display(),
+        version,
+        supported
+    ))]
+    UnsupportedFormatVersion {
+        path: PathBuf,
+        version: u32,
+        supported: u32,
+    },
+
+    #[snafu(display("No data store format upgrade is registered from version {}", from))]
+    MissingFormatUpgrade { from: u32 },
+
+    #[snafu(display(
+        "Can't downgrade data store at '{}' to format version {}: {}",
+        path.display(),
+        version,
+        reason
+    ))]
+    FormatDowngrade {
+        path: PathBuf,
+        version: u32,
+        reason: String,
+    },
+
+    #[snafu(display("A data store already exists at '{}'", path.display()))]
+    DataStoreExists { path: PathBuf },
+
+    #[snafu(display(
+        "Can't open data store at '{}' because {}; open it with LockedDataStore::open first",
+        path.display(),
+        reason
+    ))]
+    NeedsRecovery { path: PathBuf, reason: String },
+
+    #[snafu(display("Data store at '{}' was opened read-only", path.display()))]
+    ReadOnly { path: PathBuf },
+
+    #[snafu(display("Unable to serialize tombstones: {}", source))]
+    TombstonesSerialize { source: serde_json::Error },
+
+    #[snafu(display("Tombstones at '{}' are invalid: {}", path.display(), source))]
+    TombstonesParse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Unable to serialize transaction creation time: {}", source))]
+    TransactionCreatedSerialize { source: serde_json::Error },
+
+    #[snafu(display("Transaction creation time at '{}' is invalid: {}", path.display(), source))]
+    TransactionCreatedParse {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Unable to serialize journal record: {}", source))]
+    JournalSerialize { source: serde_json::Error },
+
+    #[snafu(display("Journal at '{}' is invalid at line {}: {}", path.display(), line, source))]
+    JournalParse {
+        path: PathBuf,
+        line: usize,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Unable to serialize log record: {}", source))]
+    LogRecordSerialize { source: serde_json::Error },
+
+    #[snafu(display("Log at '{}' has an invalid record at offset {}: {}", path.display(), pos, source))]
+    LogRecordParse {
+        path: PathBuf,
+        pos: usize,
+        source: serde_json::Error,
+    },
+
+    #[snafu(display("Unable to serialize snapshot as JSON: {}", source))]
+    SnapshotJsonSerialize { source: serde_json::Error },
+
+    #[snafu(display("Unable to serialize snapshot as TOML: {}", source))]
+    SnapshotTomlSerialize { source: toml::ser::Error },
+
+    #[snafu(display("Unable to parse JSON snapshot: {}", source))]
+    SnapshotJsonParse { source: serde_json::Error },
+
+    #[snafu(display("Unable to parse TOML snapshot: {}", source))]
+    SnapshotTomlParse { source: toml::de::Error },
+
+    #[snafu(display(
+        "Snapshot format version {} is not supported, expected {}",
+        version,
+        supported
+    ))]
+    SnapshotVersion { version: u32, supported: u32 },
+
+    #[snafu(display("Can only restore a snapshot into an empty data store"))]
+    SnapshotTargetNotEmpty,
+
+    #[snafu(display(
+        "Encryption key at '{}' is {} bytes, expected {}",
+        path.display(),
+        len,
+        expected
+    ))]
+    EncryptionKeyLength {
+        path: PathBuf,
+        len: usize,
+        expected: usize,
+    },
+
+    #[snafu(display("Unable to generate encryption key for '{}'", path.display()))]
+    EncryptionKeyGenerate { path: PathBuf },
+
+    #[snafu(display("Unable to encrypt value of '{}'", key))]
+    Encrypt { key: String },
+
+    #[snafu(display("Unable to decrypt value of '{}'", key))]
+    Decrypt { key: String },
+
+    #[snafu(display("Value of '{}' is encrypted, but no encryption key was given", key))]
+    MissingEncryptionKey { key: String },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
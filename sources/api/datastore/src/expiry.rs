@@ -0,0 +1,131 @@
+//! Lists pending transactions with their age and size, and expires the ones that have been left
+//! behind.
+//!
+//! Clients create transactions with random names, so a client that's interrupted between setting
+//! keys and committing leaves a transaction that nothing will commit or delete.  Expiring
+//! transactions older than a threshold cleans these up, without affecting transactions that are
+//! still in use.
+//!
+//! A transaction's age is measured from its creation, as reported by `transaction_created`.
+//! Transactions with no known creation time aren't expired.  Creation times in the future, say
+//! after the clock was changed, count as an age of zero.
+
+use log::info;
+use std::time::{Duration, SystemTime};
+
+use super::{Committed, DataStore, Result};
+
+/// TransactionInfo describes a pending transaction.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TransactionInfo {
+    pub name: String,
+    /// When the transaction was created, if known.
+    pub created: Option<SystemTime>,
+    /// How long ago the transaction was created, as of when it was listed.
+    pub age: Option<Duration>,
+    /// The number of data keys set in the transaction.
+    pub key_count: usize,
+    /// The number of keys the transaction would remove.
+    pub tombstone_count: usize,
+}
+
+/// Returns information about each pending transaction, oldest first.  Transactions with no known
+/// creation time come last.
+pub fn list_transaction_info<D>(datastore: &D) -> Result<Vec<TransactionInfo>>
+where
+    D: DataStore + ?Sized,
+{
+    let now = SystemTime::now();
+    let mut infos = Vec::new();
+    for name in datastore.list_transactions()? {
+        let pending = Committed::Pending { tx: name.clone() };
+        let created = datastore.transaction_created(&name)?;
+        infos.push(TransactionInfo {
+            created,
+            age: created.map(|created| now.duration_since(created).unwrap_or_default()),
+            key_count: datastore.list_populated_keys("", &pending)?.len(),
+            tombstone_count: datastore.list_tombstones(&name)?.len(),
+            name,
+        });
+    }
+    infos.sort_by(|a, b| {
+        (a.created.is_none(), a.created, &a.name).cmp(&(b.created.is_none(), b.created, &b.name))
+    });
+    Ok(infos)
+}
+
+/// Deletes the pending transactions older than the given age, and returns them, oldest first.
+/// With `dry_run`, returns the transactions that would be deleted without deleting them.
+pub fn expire_transactions<D>(
+    datastore: &mut D,
+    older_than: Duration,
+    dry_run: bool,
+) -> Result<Vec<TransactionInfo>>
+where
+    D: DataStore + ?Sized,
+{
+    let expired: Vec<_> = list_transaction_info(datastore)?
+        .into_iter()
+        .filter(|info| info.age.map(|age| age > older_than).unwrap_or(false))
+        .collect();
+    if dry_run {
+        return Ok(expired);
+    }
+
+    for info in &expired {
+        info!(
+            "Expiring transaction '{}' created {:?} ago with {} keys",
+            info.name,
+            info.age.unwrap_or_default(),
+            info.key_count
+        );
+        datastore.delete_transaction(info.name.as_str())?;
+    }
+    Ok(expired)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryDataStore;
+    use crate::{Key, KeyType};
+
+    #[test]
+    fn expire() {
+        let mut m = MemoryDataStore::new();
+        let key = Key::new(KeyType::Data, "settings.a").unwrap();
+        let now = SystemTime::now();
+        for (tx, age) in [("old", 7200), ("new", 60)] {
+            m.set_key(&key, "1", &Committed::Pending { tx: tx.into() })
+                .unwrap();
+            m.set_transaction_created(tx, now - Duration::from_secs(age));
+        }
+        m.set_tombstone(&key, "old").unwrap();
+
+        let infos = list_transaction_info(&m).unwrap();
+        assert_eq!(
+            infos.iter().map(|i| i.name.as_str()).collect::<Vec<_>>(),
+            ["old", "new"]
+        );
+        assert_eq!(infos[0].key_count, 1);
+        assert_eq!(infos[0].tombstone_count, 1);
+        assert!(infos[0].age.unwrap() >= Duration::from_secs(7200));
+
+        let hour = Duration::from_secs(3600);
+        let expired = expire_transactions(&mut m, hour, true).unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].name, "old");
+        assert_eq!(m.list_transactions().unwrap().len(), 2);
+
+        let expired = expire_transactions(&mut m, hour, false).unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(
+            m.list_transactions()
+                .unwrap()
+                .into_iter()
+                .collect::<Vec<_>>(),
+            ["new"]
+        );
+        assert_eq!(m.transaction_created("old").unwrap(), None);
+    }
+}
@@ -0,0 +1,121 @@
+//! A change feed lets other processes watch a FilesystemDataStore for changes to live data.
+//!
+//! Each commit or revert that changes live data increments the data store's change counter, kept
+//! in a small file under the base path.  A ChangeFeed remembers the last counter value it saw and
+//! polls the file for a new one, so watching doesn't require a lock or a walk of the data store.
+//! The counter is updated after live data, so once a change is seen, the new data can be read.
+//!
+//! The feed only says that something changed, and how many changes there have been.  Watchers
+//! that need to know which keys changed can read the journal, or just reload what they use.
+
+use log::trace;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use super::filesystem::read_change_counter;
+use super::Result;
+
+/// How often `ChangeFeed::wait` checks for a change, unless configured otherwise.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// ChangeFeed watches the change counter of a FilesystemDataStore.
+#[derive(Debug)]
+pub struct ChangeFeed {
+    base_path: PathBuf,
+    last_seen: u64,
+    poll_interval: Duration,
+}
+
+impl ChangeFeed {
+    /// Starts watching the data store at the given base path.  Only changes made after this call
+    /// are reported.
+    pub fn new<P: AsRef<Path>>(base_path: P) -> Result<Self> {
+        let base_path = base_path.as_ref().to_path_buf();
+        let last_seen = read_change_counter(&base_path)?;
+        Ok(Self {
+            base_path,
+            last_seen,
+            poll_interval: DEFAULT_POLL_INTERVAL,
+        })
+    }
+
+    /// Sets how often `wait` checks for a change.
+    pub fn with_poll_interval(mut self, poll_interval: Duration) -> Self {
+        self.poll_interval = poll_interval;
+        self
+    }
+
+    /// Returns the last change counter value seen by the feed.
+    pub fn last_seen(&self) -> u64 {
+        self.last_seen
+    }
+
+    /// Checks for changes since the last one seen, returning the new change counter value if
+    /// there were any.  The difference from the previous value is the number of changes.
+    pub fn poll(&mut self) -> Result<Option<u64>> {
+        let counter = read_change_counter(&self.base_path)?;
+        if counter == self.last_seen {
+            return Ok(None);
+        }
+        trace!(
+            "Change counter of {} went from {} to {}",
+            self.base_path.display(),
+            self.last_seen,
+            counter
+        );
+        self.last_seen = counter;
+        Ok(Some(counter))
+    }
+
+    /// Waits up to the given timeout for a change, returning the new change counter value, or
+    /// None if there wasn't a change in time.
+    pub fn wait(&mut self, timeout: Duration) -> Result<Option<u64>> {
+        let start = Instant::now();
+        loop {
+            if let Some(counter) = self.poll()? {
+                return Ok(Some(counter));
+            }
+            let elapsed = start.elapsed();
+            if elapsed >= timeout {
+                return Ok(None);
+            }
+            thread::sleep(self.poll_interval.min(timeout - elapsed));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scratch::TestPath;
+    use crate::{Committed, DataStore, FilesystemDataStore, Key, KeyType};
+
+    #[test]
+    fn sees_commits_and_reverts() {
+        let dir = TestPath::datastore("commits-and-reverts");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let mut feed = ChangeFeed::new(&dir.0)
+            .unwrap()
+            .with_poll_interval(Duration::from_millis(1));
+        assert_eq!(feed.last_seen(), 0);
+        assert_eq!(feed.poll().unwrap(), None);
+
+        let key = Key::new(KeyType::Data, "settings.a").unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+        for value in ["1", "2"] {
+            f.set_key(&key, value, &pending).unwrap();
+            f.commit_transaction("tx").unwrap();
+        }
+        assert_eq!(feed.poll().unwrap(), Some(2));
+        assert_eq!(feed.poll().unwrap(), None);
+
+        // Committing an empty transaction isn't a change.
+        f.commit_transaction("tx").unwrap();
+        assert_eq!(feed.wait(Duration::from_millis(5)).unwrap(), None);
+
+        f.revert_to_generation(1).unwrap();
+        assert_eq!(feed.wait(Duration::from_secs(1)).unwrap(), Some(3));
+        assert_eq!(f.change_counter().unwrap(), 3);
+    }
+}
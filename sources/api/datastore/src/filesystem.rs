@@ -3,40 +3,651 @@
 //!
 //! Data is kept in files with paths resembling the keys, e.g. a/b/c for a.b.c, and metadata is
 //! kept in a suffixed file next to the data, e.g. a/b/c.meta for metadata "meta" about a.b.c
+//!
+//! Segments too long for a file name, with room left for a metadata suffix, are split into
+//! pieces, each in its own path component; all but the last piece are marked as continuing into
+//! the next component.  Names that fit are stored as they always have been.  Encoded metadata
+//! names are limited to MAX_ENCODED_METADATA_NAME_LENGTH bytes so the room needed is known.
+//!
+//! Commits are made crash-safe with a write-ahead intent record.  Before live data is touched, the
+//! full set of changes is written to a single intent file, which is synced and then atomically
+//! renamed into place.  If we're interrupted while applying the changes, the next
+//! `LockedDataStore::open` finds the intent and finishes the commit, holding the exclusive lock.
+//! If we're interrupted before the intent is in place, the commit never happened, and the pending
+//! transaction is left as it was.  `FilesystemDataStore::new` never writes, so it refuses to open
+//! a data store with an intent to finish.
+//!
+//! Each commit also records a generation under generations/<id>.json, holding the prior values of
+//! the changed keys.  Reverting to an earlier generation goes through the same intent mechanism.
+//!
+//! The journal is kept in a single file of JSON lines, appended to as changes are made.
+//!
+//! Each commit or revert that changes live data also increments a counter in the change-counter
+//! file, so other processes can watch for changes by reading one small file; see `feed`.
+//!
+//! Given an encryption key, values of sensitive keys are encrypted in their files; see
+//! `sensitive`.  Commits and reverts move stored values around without decrypting them, and
+//! sensitive values are redacted in the journal.
 
-use log::{debug, error, trace};
+use log::{debug, error, info, trace, warn};
 use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
 use snafu::{ensure, OptionExt, ResultExt};
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs;
-use std::io;
+use std::io::{self, BufRead, Write};
 use std::path::{self, Path, PathBuf};
+use std::time::SystemTime;
 use walkdir::{DirEntry, WalkDir};
 
+use super::format::{self, CURRENT_FORMAT_VERSION};
+use super::journal::{self, JournalEntry, JournalFilter, JournalRecord, Operation};
 use super::key::{Key, KeyType};
-use super::{error, Committed, DataStore, Result};
+use super::lock::{FileLock, LockMode};
+use super::sensitive::{self, EncryptionKey, SensitiveKeys};
+use super::{
+    check_revert_target, error, tombstoned_keys, Committed, DataStore, Generation, KeyPattern,
+    Result, DEFAULT_GENERATION_LIMIT,
+};
+
+pub(crate) const METADATA_KEY_PREFIX: &str = ".";
+
+/// The name of the file, directly under the base path, that holds the intent record of a commit
+/// that's in progress.
+pub(crate) const COMMIT_INTENT_FILE: &str = "commit-intent.json";
+
+/// Suffix of the files, under the generations directory, that record each generation.
+const GENERATION_FILE_SUFFIX: &str = ".json";
+
+/// Name of the file, under a pending transaction's directory, that lists the keys to remove when
+/// the transaction is committed.  See ENCODE_CHARACTERS for why it starts with '~'.
+pub(crate) const TOMBSTONES_FILE: &str = "~tombstones";
+
+/// Name of the file, under a pending transaction's directory, that lists the metadata to remove
+/// when the transaction is committed, as a map of data key names to metadata key names.  See
+/// ENCODE_CHARACTERS for why it starts with '~'.
+pub(crate) const METADATA_TOMBSTONES_FILE: &str = "~metadata-tombstones";
+
+/// Name of the file, under a pending transaction's directory, that records when the transaction
+/// was created.  Transactions created before we recorded this use their directory's modification
+/// time instead.  See ENCODE_CHARACTERS for why it starts with '~'.
+pub(crate) const CREATED_FILE: &str = "~created";
+
+/// Name of the file, under the base path, that holds the change journal.
+const JOURNAL_FILE: &str = "journal";
+
+/// Name of the file, under the generations directory, holding the latest generation ID.  This is
+/// kept separately from the records so IDs aren't reused after reverting.
+const LATEST_GENERATION_FILE: &str = "latest";
+
+/// Name of the file, under the base path, holding the number of commits and reverts that have
+/// changed live data.
+pub(crate) const CHANGE_COUNTER_FILE: &str = "change-counter";
+
+/// The usual file name limit.
+const MAX_PATH_COMPONENT_LENGTH: usize = 255;
 
-const METADATA_KEY_PREFIX: &str = ".";
+/// Longest encoded metadata key name we can store, so there's a known amount of room to leave
+/// for a metadata suffix after a data segment.
+const MAX_ENCODED_METADATA_NAME_LENGTH: usize = 64;
+
+/// Encoded segments longer than this are split into pieces, so that the file name of the last
+/// piece, plus a metadata suffix and TEMP_FILE_SUFFIX, is within the file name limit.
+const MAX_SEGMENT_COMPONENT_LENGTH: usize = MAX_PATH_COMPONENT_LENGTH
+    - METADATA_KEY_PREFIX.len()
+    - MAX_ENCODED_METADATA_NAME_LENGTH
+    - TEMP_FILE_SUFFIX.len();
+
+/// Length of each piece of a split segment.  It's under MAX_SEGMENT_COMPONENT_LENGTH, so the last
+/// piece has room for suffixes too.
+const SEGMENT_PIECE_LENGTH: usize = 128;
+
+/// Prefix of a path component holding a piece of a segment that continues in the next
+/// component.  See ENCODE_CHARACTERS for why it starts with '~'.
+pub(crate) const CONTINUATION_PREFIX: &str = "~+";
+
+/// Suffix for temporary files used to atomically replace a file.  See ENCODE_CHARACTERS for why
+/// it has a '~'.
+pub(crate) const TEMP_FILE_SUFFIX: &str = "~tmp";
 
 // This describes the set of characters we encode when making the filesystem path for a given key.
 // Any non-ASCII characters, plus these ones, will be encoded.
 // We start off very strict (anything not alphanumeric) and remove characters we'll allow.
 // To make inspecting the filesystem easier, we allow any filesystem-safe characters that are
 // allowed in a Key.
+// The '~' character is always percent-encoded, so it never appears in a key path.  That frees up
+// names with '~' for the data store's own files, like TOMBSTONES_FILE, and for markers in paths,
+// like CONTINUATION_PREFIX and TEMP_FILE_SUFFIX, without any chance of mistaking them for keys.
 const ENCODE_CHARACTERS: &AsciiSet = &NON_ALPHANUMERIC.remove(b'_').remove(b'-');
 
 #[derive(Debug)]
 pub struct FilesystemDataStore {
+    base_path: PathBuf,
     live_path: PathBuf,
     pending_base_path: PathBuf,
+    generations_path: PathBuf,
+    journal_path: PathBuf,
+    generation_limit: usize,
+    encryption_key: Option<EncryptionKey>,
+    read_only: bool,
+}
+
+/// CommitIntent is the write-ahead record of a change to live data, whether that's a commit or a
+/// revert.  It holds everything needed to apply the change, so an interrupted change can be
+/// completed later.
+///
+/// Keys are stored by name because Key can't be deserialized without knowing its type.
+#[derive(Debug, Serialize, Deserialize)]
+struct CommitIntent {
+    /// The pending transaction being committed; reverts don't have one.
+    transaction: Option<String>,
+    /// New live values for data keys; None means the key is removed.
+    changes: HashMap<String, Option<String>>,
+    /// New live metadata from the pending transaction, by data key name and then metadata key
+    /// name.
+    #[serde(default)]
+    metadata: HashMap<String, HashMap<String, String>>,
+    /// Live metadata removed by the pending transaction, by data key name.
+    #[serde(default)]
+    removed_metadata: HashMap<String, Vec<String>>,
+    /// The generation record describing this commit, if it's a commit.
+    generation: Option<GenerationRecord>,
+    /// Generations that no longer apply after this change, either because they were reverted
+    /// or because they're beyond the retention limit.
+    forget_generations: Vec<u64>,
+    /// The latest generation ID after this change, if it changes.
+    latest_generation: Option<u64>,
+    /// Journal records describing this change.
+    journal: Vec<JournalRecord>,
+    /// The value of the change counter after this change, if it changes live data.
+    #[serde(default)]
+    change_counter: Option<u64>,
+}
+
+/// GenerationRecord is the on-disk form of a Generation.
+#[derive(Debug, Serialize, Deserialize)]
+struct GenerationRecord {
+    id: u64,
+    transaction: String,
+    prior: HashMap<String, Option<String>>,
+}
+
+impl From<&Generation> for GenerationRecord {
+    fn from(generation: &Generation) -> Self {
+        Self {
+            id: generation.id,
+            transaction: generation.transaction.clone(),
+            prior: generation
+                .prior
+                .iter()
+                .map(|(key, value)| (key.name().clone(), value.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl GenerationRecord {
+    fn into_generation(self) -> Result<Generation> {
+        let mut prior = HashMap::new();
+        for (name, value) in self.prior {
+            prior.insert(Key::new(KeyType::Data, name)?, value);
+        }
+        Ok(Generation {
+            id: self.id,
+            transaction: self.transaction,
+            prior,
+        })
+    }
 }
 
 impl FilesystemDataStore {
-    pub fn new<P: AsRef<Path>>(base_path: P) -> FilesystemDataStore {
+    /// Opens the datastore at the given path, without changing anything on disk.  If a previous
+    /// commit was interrupted, or the data store has an older format version, it has to be
+    /// recovered first, which `LockedDataStore::open` does while holding the exclusive lock; until
+    /// then we return an error.  If it has a newer format version, we return an error rather than
+    /// risk misreading it.
+    pub fn new<P: AsRef<Path>>(base_path: P) -> Result<FilesystemDataStore> {
+        Self::open_recovered(base_path, false)
+    }
+
+    /// Opens the datastore at the given path for tools that only read it; writes return an error.
+    /// Like `new`, it fails if the data store needs to be recovered first.
+    pub fn open_read_only<P: AsRef<Path>>(base_path: P) -> Result<FilesystemDataStore> {
+        Self::open_recovered(base_path, true)
+    }
+
+    fn open_recovered<P: AsRef<Path>>(
+        base_path: P,
+        read_only: bool,
+    ) -> Result<FilesystemDataStore> {
+        let datastore = Self::at(base_path, read_only);
+        let version = format::check_format_version(&datastore.base_path)?;
+        ensure!(
+            !datastore.needs_upgrade(version),
+            error::NeedsRecoverySnafu {
+                path: &datastore.base_path,
+                reason: format!(
+                    "its format version {} needs to be upgraded to {}",
+                    version, CURRENT_FORMAT_VERSION
+                ),
+            }
+        );
+        ensure!(
+            !datastore.intent_path().exists(),
+            error::NeedsRecoverySnafu {
+                path: &datastore.base_path,
+                reason: "an interrupted commit needs to be completed",
+            }
+        );
+        Ok(datastore)
+    }
+
+    /// Completes or discards an interrupted commit, and upgrades an older format version, so the
+    /// data store at the given path can be opened.  Nothing else can safely use the data store
+    /// while this changes it, so the caller must hold the exclusive lock on its lock file.
+    pub(crate) fn recover<P: AsRef<Path>>(base_path: P, lock: &FileLock) -> Result<()> {
+        debug_assert_eq!(lock.mode(), LockMode::Exclusive);
+        let datastore = Self::at(base_path, false);
+        let version = format::check_format_version(&datastore.base_path)?;
+        datastore.finish_interrupted_commit()?;
+        if datastore.needs_upgrade(version) {
+            format::set_format_version(&datastore.base_path, CURRENT_FORMAT_VERSION)?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether a data store with the given format version needs to be upgraded.  There's
+    /// nothing to upgrade until something's been written.
+    fn needs_upgrade(&self, version: u32) -> bool {
+        version < CURRENT_FORMAT_VERSION && self.live_path.exists()
+    }
+
+    fn at<P: AsRef<Path>>(base_path: P, read_only: bool) -> FilesystemDataStore {
         FilesystemDataStore {
+            base_path: base_path.as_ref().to_path_buf(),
             live_path: base_path.as_ref().join("live"),
             pending_base_path: base_path.as_ref().join("pending"),
+            generations_path: base_path.as_ref().join("generations"),
+            journal_path: base_path.as_ref().join(JOURNAL_FILE),
+            generation_limit: DEFAULT_GENERATION_LIMIT,
+            encryption_key: None,
+            read_only,
+        }
+    }
+
+    /// Creates an empty datastore at the given path, recording the current format version, and
+    /// opens it.  Fails if there's already a datastore there.
+    pub fn create<P: AsRef<Path>>(base_path: P) -> Result<FilesystemDataStore> {
+        let base_path = base_path.as_ref();
+        let live_path = base_path.join("live");
+        ensure!(
+            !live_path.exists(),
+            error::DataStoreExistsSnafu { path: base_path }
+        );
+        fs::create_dir_all(&live_path).context(error::IoSnafu { path: &live_path })?;
+        format::write_format_version(base_path, CURRENT_FORMAT_VERSION)?;
+        Self::new(base_path)
+    }
+
+    /// Sets the number of committed generations to retain for reverting.  Older generations are
+    /// forgotten on the next commit.
+    pub fn with_generation_limit(mut self, limit: usize) -> Self {
+        self.generation_limit = limit;
+        self
+    }
+
+    /// Sets the key used to encrypt sensitive values as they're set, and to decrypt them as
+    /// they're read.  Without one, sensitive values are stored as they're given, and reading an
+    /// encrypted value fails.
+    pub fn with_encryption_key(mut self, encryption_key: EncryptionKey) -> Self {
+        self.encryption_key = Some(encryption_key);
+        self
+    }
+
+    /// Returns the number of commits and reverts that have changed live data.  Other processes
+    /// can use a `feed::ChangeFeed` to watch it.
+    pub fn change_counter(&self) -> Result<u64> {
+        read_change_counter(&self.base_path)
+    }
+
+    /// Returns an error if the data store was opened read-only.
+    fn ensure_writable(&self) -> Result<()> {
+        ensure!(
+            !self.read_only,
+            error::ReadOnlySnafu {
+                path: &self.base_path
+            }
+        );
+        Ok(())
+    }
+
+    /// Returns the value of a data key as it's stored, without decrypting it.
+    fn get_key_raw(&self, key: &Key, committed: &Committed) -> Result<Option<String>> {
+        let path = self.data_path(key, committed)?;
+        read_file_for_key(key, &path)
+    }
+
+    /// Returns the values of the data keys under the given prefix as they're stored, without
+    /// decrypting them.
+    fn get_prefix_raw(&self, prefix: &str, committed: &Committed) -> Result<HashMap<Key, String>> {
+        let mut result = HashMap::new();
+        for key in self.list_populated_keys(prefix, committed)? {
+            let value = self
+                .get_key_raw(&key, committed)?
+                .context(error::ListedKeyNotPresentSnafu { key: key.name() })?;
+            result.insert(key, value);
+        }
+        Ok(result)
+    }
+
+    /// Encrypts the stored values of sensitive keys that are still plaintext because they were
+    /// set before the key was marked sensitive.  Does nothing without an encryption key.
+    fn encrypt_sensitive_values(&self, committed: &Committed) -> Result<()> {
+        let encryption_key = match &self.encryption_key {
+            Some(encryption_key) => encryption_key,
+            None => return Ok(()),
+        };
+        let sensitive = SensitiveKeys::load(self, committed)?;
+        for key in self.list_populated_keys("", committed)? {
+            if !sensitive.contains(&key) {
+                continue;
+            }
+            if let Some(value) = self.get_key_raw(&key, committed)? {
+                if !sensitive::is_encrypted(&value) {
+                    debug!("Encrypting newly sensitive key {}", key.name());
+                    let encrypted = encryption_key.encrypt(&key, &value)?;
+                    write_file_atomic(&self.data_path(&key, committed)?, encrypted)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the path to the intent record of a commit in progress.
+    fn intent_path(&self) -> PathBuf {
+        self.base_path.join(COMMIT_INTENT_FILE)
+    }
+
+    /// Returns the path to the record of the given generation.
+    fn generation_path(&self, id: u64) -> PathBuf {
+        self.generations_path
+            .join(format!("{}{}", id, GENERATION_FILE_SUFFIX))
+    }
+
+    /// Returns the ID of the latest generation, or 0 if there's never been one.
+    fn latest_generation(&self) -> Result<u64> {
+        let path = self.generations_path.join(LATEST_GENERATION_FILE);
+        let latest_str = match fs::read_to_string(&path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e).context(error::IoSnafu { path }),
+        };
+        latest_str
+            .trim()
+            .parse()
+            .ok()
+            .context(error::CorruptionSnafu {
+                msg: "invalid latest generation ID",
+                path,
+            })
+    }
+
+    /// Finishes any commit that was interrupted after its intent was recorded, and removes any
+    /// partially written intent, which means the commit never started.
+    fn finish_interrupted_commit(&self) -> Result<()> {
+        let intent_path = self.intent_path();
+        let temp_path = temp_path_for(&intent_path);
+        if temp_path.exists() {
+            warn!(
+                "Discarding incomplete commit intent at {}",
+                temp_path.display()
+            );
+            fs::remove_file(&temp_path).context(error::IoSnafu { path: &temp_path })?;
+        }
+
+        let intent_str = match fs::read_to_string(&intent_path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).context(error::IoSnafu { path: intent_path }),
+        };
+        let intent: CommitIntent = serde_json::from_str(&intent_str)
+            .context(error::CommitIntentParseSnafu { path: &intent_path })?;
+
+        warn!(
+            "Found interrupted change to live data (transaction: {:?}), completing it",
+            intent.transaction
+        );
+        self.apply_intent(&intent)
+    }
+
+    /// Durably records the intent to commit.  Once this returns, the commit will be completed
+    /// even if we're interrupted.
+    fn write_intent(&self, intent: &CommitIntent) -> Result<()> {
+        let intent_str =
+            serde_json::to_string(intent).context(error::CommitIntentSerializeSnafu)?;
+        write_file_atomic(&self.intent_path(), intent_str)?;
+        sync_dir(&self.base_path)
+    }
+
+    /// Applies a recorded commit intent to live data, then removes the pending transaction and
+    /// the intent itself.  Every step is idempotent, so this is safe to repeat if interrupted.
+    fn apply_intent(&self, intent: &CommitIntent) -> Result<()> {
+        // Record the generation first, so it's available for reverting as soon as live data
+        // starts to change.
+        if let Some(record) = &intent.generation {
+            debug!("Recording generation {}", record.id);
+            let record_str =
+                serde_json::to_string(record).context(error::GenerationSerializeSnafu)?;
+            write_file_atomic(&self.generation_path(record.id), record_str)?;
+            sync_dir(&self.generations_path)?;
+        }
+
+        debug!("Writing pending keys to live");
+        let mut changed_dirs = HashSet::new();
+        // Removals go first, so a key that replaces a removed key of a different shape, like
+        // "a.b.0.c" replacing "a.b", doesn't find a file where it needs a directory.
+        let mut changes: Vec<_> = intent.changes.iter().collect();
+        changes.sort_by_key(|(_, value)| value.is_some());
+        for (name, value) in changes {
+            let key = Key::new(KeyType::Data, name)?;
+            let path = self.data_path(&key, &Committed::Live)?;
+            match value {
+                Some(value) => {
+                    write_file_atomic(&path, value)?;
+                    info!("Committed data key {}", key.name());
+                }
+                None => {
+                    self.delete_key_path(&path, &Committed::Live)?;
+                    info!("Removed data key {}", key.name());
+                }
+            }
+            if let Some(parent) = path.parent() {
+                changed_dirs.insert(parent.to_path_buf());
+            }
+        }
+        for (data_name, meta_map) in &intent.metadata {
+            let data_key = Key::new(KeyType::Data, data_name)?;
+            for (meta_name, value) in meta_map {
+                let metadata_key = Key::new(KeyType::Meta, meta_name)?;
+                let path = self.metadata_path(&metadata_key, &data_key, &Committed::Live)?;
+                write_file_atomic(&path, value)?;
+                info!(
+                    "Committed metadata key {} for data key {}",
+                    metadata_key.name(),
+                    data_key.name()
+                );
+                if let Some(parent) = path.parent() {
+                    changed_dirs.insert(parent.to_path_buf());
+                }
+            }
+        }
+        for (data_name, meta_names) in &intent.removed_metadata {
+            let data_key = Key::new(KeyType::Data, data_name)?;
+            for meta_name in meta_names {
+                let metadata_key = Key::new(KeyType::Meta, meta_name)?;
+                let path = self.metadata_path(&metadata_key, &data_key, &Committed::Live)?;
+                self.delete_key_path(&path, &Committed::Live)?;
+                info!(
+                    "Removed metadata key {} for data key {}",
+                    metadata_key.name(),
+                    data_key.name()
+                );
+                if let Some(parent) = path.parent() {
+                    changed_dirs.insert(parent.to_path_buf());
+                }
+            }
+        }
+        for dir in changed_dirs {
+            // Removing the last key in a directory removes the directory, too.
+            if dir.exists() {
+                sync_dir(&dir)?;
+            }
+        }
+
+        // If we're repeating an interrupted change, we may have already journaled it; we'd rather
+        // have a duplicate entry than a missing one.
+        self.append_journal(&intent.journal)?;
+
+        // The counter is written after live data, so watchers that see it change can read the new
+        // data.  It's an absolute value, so writing it again after an interruption is harmless.
+        if let Some(counter) = intent.change_counter {
+            write_file_atomic(
+                &self.base_path.join(CHANGE_COUNTER_FILE),
+                counter.to_string(),
+            )?;
+        }
+
+        if let Some(latest) = intent.latest_generation {
+            let path = self.generations_path.join(LATEST_GENERATION_FILE);
+            write_file_atomic(&path, latest.to_string())?;
+            sync_dir(&self.generations_path)?;
+        }
+
+        for id in &intent.forget_generations {
+            debug!("Forgetting generation {}", id);
+            let path = self.generation_path(*id);
+            if let Err(e) = fs::remove_file(&path) {
+                // A previous attempt may have already removed it.
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e).context(error::IoSnafu { path });
+                }
+            }
+        }
+
+        if let Some(transaction) = &intent.transaction {
+            debug!("Removing old pending keys");
+            let pending = Committed::Pending {
+                tx: transaction.clone(),
+            };
+            let path = self.base_path(&pending);
+            if let Err(e) = fs::remove_dir_all(&path) {
+                // A previous attempt may have already removed it.
+                if e.kind() != io::ErrorKind::NotFound {
+                    return Err(e).context(error::IoSnafu { path });
+                }
+            }
+        }
+
+        debug!("Removing commit intent");
+        let intent_path = self.intent_path();
+        fs::remove_file(&intent_path).context(error::IoSnafu { path: intent_path })?;
+        sync_dir(&self.base_path)
+    }
+
+    /// Returns the path to the list of tombstones for the given pending transaction.
+    fn tombstones_path<S: AsRef<str>>(&self, transaction: S) -> PathBuf {
+        let pending = Committed::Pending {
+            tx: transaction.as_ref().to_string(),
+        };
+        self.base_path(&pending).join(TOMBSTONES_FILE)
+    }
+
+    /// Returns the path to the list of metadata tombstones for the given pending transaction.
+    fn metadata_tombstones_path<S: AsRef<str>>(&self, transaction: S) -> PathBuf {
+        let pending = Committed::Pending {
+            tx: transaction.as_ref().to_string(),
+        };
+        self.base_path(&pending).join(METADATA_TOMBSTONES_FILE)
+    }
+
+    /// Reads the metadata tombstones of the given pending transaction, by name.
+    fn read_metadata_tombstones<S: AsRef<str>>(
+        &self,
+        transaction: S,
+    ) -> Result<BTreeMap<String, BTreeSet<String>>> {
+        let path = self.metadata_tombstones_path(transaction);
+        let names_str = match fs::read_to_string(&path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+            Err(e) => return Err(e).context(error::IoSnafu { path }),
+        };
+        serde_json::from_str(&names_str).context(error::TombstonesParseSnafu { path })
+    }
+
+    /// Replaces the metadata tombstones of the given pending transaction, removing the file if
+    /// there are none.
+    fn write_metadata_tombstones<S: AsRef<str>>(
+        &self,
+        transaction: S,
+        names: &BTreeMap<String, BTreeSet<String>>,
+    ) -> Result<()> {
+        let path = self.metadata_tombstones_path(transaction);
+        if names.is_empty() {
+            return match fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => {
+                    Err(e).context(error::IoSnafu { path })
+                }
+                _ => Ok(()),
+            };
+        }
+        let names_str = serde_json::to_string(names).context(error::TombstonesSerializeSnafu)?;
+        write_file_atomic(&path, names_str)
+    }
+
+    /// Returns the path to the creation time of the given pending transaction.
+    fn created_path<S: AsRef<str>>(&self, transaction: S) -> PathBuf {
+        let pending = Committed::Pending {
+            tx: transaction.as_ref().to_string(),
+        };
+        self.base_path(&pending).join(CREATED_FILE)
+    }
+
+    /// Records the creation time of the given transaction, if it's pending and doesn't exist yet.
+    /// This is called before each write to a pending transaction.
+    fn note_created(&self, committed: &Committed) -> Result<()> {
+        let tx = match committed {
+            Committed::Pending { tx } => tx,
+            Committed::Live => return Ok(()),
+        };
+        if self.base_path(committed).exists() {
+            return Ok(());
+        }
+        let created = serde_json::to_string(&SystemTime::now())
+            .context(error::TransactionCreatedSerializeSnafu)?;
+        write_file_atomic(&self.created_path(tx), created)
+    }
+
+    /// Appends the given records to the journal, one JSON object per line.
+    fn append_journal(&self, records: &[JournalRecord]) -> Result<()> {
+        if records.is_empty() {
+            return Ok(());
         }
+        let mut lines = String::new();
+        for record in records {
+            let line = serde_json::to_string(record).context(error::JournalSerializeSnafu)?;
+            lines.push_str(&line);
+            lines.push('\n');
+        }
+
+        let path = &self.journal_path;
+        let mut f = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .context(error::IoSnafu { path })?;
+        f.write_all(lines.as_bytes())
+            .context(error::IoSnafu { path })?;
+        f.sync_data().context(error::IoSnafu { path })
     }
 
     /// Returns the appropriate filesystem path for pending or live data.
@@ -55,7 +666,11 @@ impl FilesystemDataStore {
         let base_path = self.base_path(committed);
 
         // Encode key segments so they're filesystem-safe
-        let encoded: Vec<_> = key.segments().iter().map(encode_path_component).collect();
+        let encoded: Vec<_> = key
+            .segments()
+            .iter()
+            .flat_map(encode_segment_components)
+            .collect();
         // Join segments with filesystem separator to get path underneath data store
         let path_suffix = encoded.join(path::MAIN_SEPARATOR_STR);
 
@@ -99,6 +714,13 @@ impl FilesystemDataStore {
             })?;
 
         let encoded_meta = encode_path_component(raw_key_name);
+        ensure!(
+            encoded_meta.len() <= MAX_ENCODED_METADATA_NAME_LENGTH,
+            error::KeyTooLongSnafu {
+                name: metadata_key.name(),
+                max: MAX_ENCODED_METADATA_NAME_LENGTH,
+            }
+        );
         path_str.push(METADATA_KEY_PREFIX);
         path_str.push(encoded_meta);
 
@@ -116,7 +738,7 @@ impl FilesystemDataStore {
     /// error for trying to remove an empty directory is not specific, and we don't want to rely
     /// on platform-specific error codes or the error description.  We could check the directory
     /// contents ourself, but it would be more complex and subject to timing issues.)
-    fn delete_key_path<P>(&mut self, path: P, committed: &Committed) -> Result<()>
+    fn delete_key_path<P>(&self, path: P, committed: &Committed) -> Result<()>
     where
         P: AsRef<Path>,
     {
@@ -180,8 +802,71 @@ fn encode_path_component<S: AsRef<str>>(segment: S) -> String {
     encoded.to_string()
 }
 
+/// Encodes a key segment as one or more path components.  Segments whose encoding is too long for
+/// a file name, with room for suffixes, are split into pieces, marking all but the last with
+/// CONTINUATION_PREFIX.
+fn encode_segment_components<S: AsRef<str>>(segment: S) -> Vec<String> {
+    let encoded = encode_path_component(segment);
+    if encoded.len() <= MAX_SEGMENT_COMPONENT_LENGTH {
+        return vec![encoded];
+    }
+
+    // The encoding is ASCII, so we can split it anywhere; pieces are joined before decoding.
+    let pieces: Vec<&[u8]> = encoded.as_bytes().chunks(SEGMENT_PIECE_LENGTH).collect();
+    let last = pieces.len() - 1;
+    pieces
+        .into_iter()
+        .enumerate()
+        .map(|(i, piece)| {
+            let piece = String::from_utf8_lossy(piece);
+            if i == last {
+                piece.into_owned()
+            } else {
+                format!("{}{}", CONTINUATION_PREFIX, piece)
+            }
+        })
+        .collect()
+}
+
+/// Joins the pieces of segments that were split by `encode_segment_components`, returning the
+/// still-encoded segments, or None if the components end partway through a segment.
+pub(crate) fn join_segment_pieces<'a, I>(components: I) -> Option<Vec<String>>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut segments = Vec::new();
+    let mut pieces = String::new();
+    for component in components {
+        match component.strip_prefix(CONTINUATION_PREFIX) {
+            Some(piece) => pieces.push_str(piece),
+            None => {
+                pieces.push_str(component);
+                segments.push(std::mem::take(&mut pieces));
+            }
+        }
+    }
+    pieces.is_empty().then_some(segments)
+}
+
+/// Decodes the path components of a data key into its segments.
+fn decode_segment_components<'a, I, P>(components: I, path: P) -> Result<Vec<String>>
+where
+    I: IntoIterator<Item = &'a str>,
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    join_segment_pieces(components)
+        .context(error::CorruptionSnafu {
+            path,
+            msg: "path ends with a partial segment",
+        })?
+        .iter()
+        .map(|segment| decode_path_component(segment, path))
+        .collect()
+}
+
 /// Decodes a path component, removing the encoding that's applied to make it filesystem-safe.
-fn decode_path_component<S, P>(segment: S, path: P) -> Result<String>
+pub(crate) fn decode_path_component<S, P>(segment: S, path: P) -> Result<String>
 where
     S: AsRef<str>,
     P: AsRef<Path>,
@@ -207,7 +892,14 @@ fn read_file_for_key(key: &Key, path: &Path) -> Result<Option<String>> {
     match fs::read_to_string(path) {
         Ok(s) => Ok(Some(s)),
         Err(e) => {
-            if e.kind() == io::ErrorKind::NotFound {
+            // A directory holds the keys under this one, and a file partway down the path is a
+            // key above this one; either way, this key has no value.
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound
+                    | io::ErrorKind::NotADirectory
+                    | io::ErrorKind::IsADirectory
+            ) {
                 return Ok(None);
             }
 
@@ -231,6 +923,120 @@ fn write_file_mkdir<S: AsRef<str>>(path: PathBuf, data: S) -> Result<()> {
     fs::write(&path, data.as_ref().as_bytes()).context(error::IoSnafu { path: &path })
 }
 
+/// Reads the change counter of the data store at the given base path, or 0 if there's never been
+/// a change.
+pub(crate) fn read_change_counter(base_path: &Path) -> Result<u64> {
+    let path = base_path.join(CHANGE_COUNTER_FILE);
+    let counter_str = match fs::read_to_string(&path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e).context(error::IoSnafu { path }),
+    };
+    counter_str
+        .trim()
+        .parse()
+        .ok()
+        .context(error::CorruptionSnafu {
+            msg: "invalid change counter",
+            path,
+        })
+}
+
+/// Converts a map keyed by Key into one keyed by name, for serialization.
+fn names_to_values(map: HashMap<Key, Option<String>>) -> HashMap<String, Option<String>> {
+    map.into_iter()
+        .map(|(key, value)| (key.name().clone(), value))
+        .collect()
+}
+
+/// Returns the path of the temporary file used when atomically replacing the given path.
+fn temp_path_for(path: &Path) -> PathBuf {
+    let mut temp_path = path.as_os_str().to_os_string();
+    temp_path.push(TEMP_FILE_SUFFIX);
+    temp_path.into()
+}
+
+/// Helper for durably replacing a file.  The data is written to a temporary file and synced before
+/// being renamed over the target, so the target holds either the old or the new data, never a
+/// mix.  The caller is responsible for syncing the parent directory to make the rename durable.
+pub(crate) fn write_file_atomic<S: AsRef<str>>(path: &Path, data: S) -> Result<()> {
+    let dirname = path.parent().with_context(|| error::InternalSnafu {
+        msg: format!(
+            "Given path to write without proper prefix: {}",
+            path.display()
+        ),
+    })?;
+    fs::create_dir_all(dirname).context(error::IoSnafu { path: dirname })?;
+
+    let temp_path = temp_path_for(path);
+    let mut file = fs::File::create(&temp_path).context(error::IoSnafu { path: &temp_path })?;
+    file.write_all(data.as_ref().as_bytes())
+        .context(error::IoSnafu { path: &temp_path })?;
+    file.sync_all()
+        .context(error::IoSnafu { path: &temp_path })?;
+
+    fs::rename(&temp_path, path).context(error::IoSnafu { path })
+}
+
+/// Moves each data and metadata file of the data store at the given base path, live and pending,
+/// to the path the current layout gives its key, for format upgrades that change the layout.
+/// Files that are already in place are left alone, so this is safe to rerun.
+pub(crate) fn relocate_key_files(base_path: &Path) -> Result<()> {
+    let datastore = FilesystemDataStore::at(base_path, false);
+    let mut states = vec![Committed::Live];
+    states.extend(
+        datastore
+            .list_transactions()?
+            .into_iter()
+            .map(|tx| Committed::Pending { tx }),
+    );
+
+    for committed in states {
+        let root = datastore.base_path(&committed);
+        if !root.exists() {
+            continue;
+        }
+        // Find everything to move before moving anything, so we don't disturb the walk.
+        let mut moves = Vec::new();
+        for entry in WalkDir::new(&root) {
+            let entry = entry.context(error::ListKeysSnafu)?;
+            let key_path = match KeyPath::from_entry(&entry, &root)? {
+                Some(key_path) => key_path,
+                None => continue,
+            };
+            let target = match &key_path.metadata_key {
+                Some(metadata_key) => {
+                    datastore.metadata_path(metadata_key, &key_path.data_key, &committed)?
+                }
+                None => datastore.data_path(&key_path.data_key, &committed)?,
+            };
+            if target != entry.path() {
+                moves.push((entry.into_path(), target));
+            }
+        }
+
+        for (from, to) in moves {
+            debug!("Moving {} to {}", from.display(), to.display());
+            let dir = to.parent().context(error::InternalSnafu {
+                msg: format!("Key path has no parent: {}", to.display()),
+            })?;
+            fs::create_dir_all(dir).context(error::IoSnafu { path: dir })?;
+            fs::rename(&from, &to).context(error::IoSnafu { path: &from })?;
+            sync_dir(dir)?;
+            // The file's gone, but this removes the directories it leaves empty.
+            datastore.delete_key_path(&from, &committed)?;
+        }
+    }
+    Ok(())
+}
+
+/// Syncs a directory so that changes to its entries, like renames, are durable.
+pub(crate) fn sync_dir(path: &Path) -> Result<()> {
+    fs::File::open(path)
+        .and_then(|dir| dir.sync_all())
+        .context(error::IoSnafu { path })
+}
+
 /// KeyPath represents the filesystem path to a data or metadata key, relative to the base path of
 /// the live or pending data store.  For example, the data key "settings.a.b" would be
 /// "settings/a/b" and the metadata key "meta1" for "settings.a.b" would be "settings/a/b.meta1".
@@ -284,10 +1090,8 @@ impl KeyPath {
             msg: "KeyPath given empty path",
         })?;
         // Turn the data path into a dotted key
-        let data_segments = data_key_raw
-            .split(path::MAIN_SEPARATOR)
-            .map(|s| decode_path_component(s, path))
-            .collect::<Result<Vec<_>>>()?;
+        let data_segments =
+            decode_segment_components(data_key_raw.split(path::MAIN_SEPARATOR), path)?;
         let data_key = Key::from_segments(KeyType::Data, &data_segments)?;
 
         // If we have a metadata portion, make that a Key too
@@ -323,6 +1127,77 @@ fn find_populated_key_paths<S: AsRef<str>>(
     prefix: S,
     committed: &Committed,
 ) -> Result<HashSet<KeyPath>> {
+    let prefix = prefix.as_ref();
+    walk_key_paths(datastore, key_type, None, committed, |data_key| {
+        let matches = data_key.name().starts_with(prefix);
+        if !matches {
+            trace!(
+                "Discarded key whose data_key '{}' doesn't start with prefix '{}'",
+                data_key,
+                prefix
+            );
+        }
+        matches
+    })
+}
+
+/// Helper to walk through the filesystem to find populated keys of the given type whose data keys
+/// match the given pattern.  Only the part of the tree under the pattern's literal prefix is
+/// walked.
+fn find_matching_key_paths(
+    datastore: &FilesystemDataStore,
+    key_type: KeyType,
+    pattern: &KeyPattern,
+    committed: &Committed,
+) -> Result<HashSet<KeyPath>> {
+    let start = pattern.literal_prefix();
+    walk_key_paths(datastore, key_type, start, committed, |data_key| {
+        pattern.matches(data_key)
+    })
+}
+
+/// Groups the given metadata key paths by data key, keeping only metadata keys with the given
+/// name, if any.
+fn metadata_by_data_key<S: AsRef<str>>(
+    key_paths: HashSet<KeyPath>,
+    metadata_key_name: &Option<S>,
+) -> Result<HashMap<Key, HashSet<Key>>> {
+    // For each file on disk, check the user's conditions, and add it to our output
+    let mut result = HashMap::new();
+    for key_path in key_paths {
+        let data_key = key_path.data_key;
+        let meta_key = key_path.metadata_key.context(error::InternalSnafu {
+            msg: format!("Found meta key path with no dot: {}", data_key),
+        })?;
+
+        // If the user requested specific metadata, move to the next key unless it matches.
+        if let Some(name) = metadata_key_name {
+            if name.as_ref() != meta_key.name() {
+                continue;
+            }
+        }
+
+        // Insert into output if we met the requested conditions; don't add an entry for
+        // the data key unless we did find some metadata.
+        let data_entry = result.entry(data_key).or_insert_with(HashSet::new);
+        data_entry.insert(meta_key);
+    }
+    Ok(result)
+}
+
+/// Walks the filesystem to find populated keys of the given type whose data keys are accepted by
+/// the given filter.  If a start key is given, only that key, its metadata, and the keys under it
+/// are visited.
+fn walk_key_paths<F>(
+    datastore: &FilesystemDataStore,
+    key_type: KeyType,
+    start: Option<&Key>,
+    committed: &Committed,
+    filter: F,
+) -> Result<HashSet<KeyPath>>
+where
+    F: Fn(&Key) -> bool,
+{
     // Find the base path for our search, and confirm it exists.
     let base = datastore.base_path(committed);
     if !base.exists() {
@@ -346,31 +1221,57 @@ fn find_populated_key_paths<S: AsRef<str>>(
         }
     }
 
-    // Walk through the filesystem.
-    let walker = WalkDir::new(&base)
-        .follow_links(false) // shouldn't be links...
-        .same_file_system(true); // shouldn't be filesystems to cross...
+    // If we have a start key, we walk its parent directory, but only look at the entries for the
+    // start key itself: its data file or directory, and its metadata files.
+    let (root, start_name) = match start {
+        Some(key) => {
+            let path = datastore.data_path(key, committed)?;
+            let name = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .map(str::to_string)
+                .context(error::InternalSnafu {
+                    msg: format!("Data path has no file name: {}", path.display()),
+                })?;
+            let parent = path.parent().unwrap_or(&base).to_path_buf();
+            (parent, Some(name))
+        }
+        None => (base.clone(), None),
+    };
+    if !root.exists() {
+        trace!("Nothing to list under missing path {}", root.display());
+        return Ok(HashSet::new());
+    }
+
+    // Walk through the filesystem.
+    let walker = WalkDir::new(&root)
+        .follow_links(false) // shouldn't be links...
+        .same_file_system(true) // shouldn't be filesystems to cross...
+        .into_iter()
+        .filter_entry(|entry| match &start_name {
+            Some(start_name) if entry.depth() == 1 => entry
+                .file_name()
+                .to_str()
+                .map(|name| {
+                    name == start_name
+                        || name.starts_with(&format!("{}{}", start_name, METADATA_KEY_PREFIX))
+                })
+                .unwrap_or(false),
+            _ => true,
+        });
 
     let mut key_paths = HashSet::new();
     trace!(
         "Starting walk of filesystem to list {:?} key paths under {}",
         key_type,
-        base.display()
+        root.display()
     );
 
     // For anything we find, confirm it matches the user's filters, and add it to results.
     for entry in walker {
         let entry = entry.context(error::ListKeysSnafu)?;
         if let Some(kp) = KeyPath::from_entry(&entry, &base)? {
-            if !kp.data_key.name().starts_with(prefix.as_ref()) {
-                trace!(
-                    "Discarded {:?} key whose data_key '{}' doesn't start with prefix '{}'",
-                    kp.key_type(),
-                    kp.data_key,
-                    prefix.as_ref()
-                );
-                continue;
-            } else if kp.key_type() != key_type {
+            if kp.key_type() != key_type || !filter(&kp.data_key) {
                 continue;
             }
 
@@ -387,7 +1288,8 @@ impl DataStore for FilesystemDataStore {
     fn key_populated(&self, key: &Key, committed: &Committed) -> Result<bool> {
         let path = self.data_path(key, committed)?;
 
-        Ok(path.exists())
+        // A directory only holds the keys under this one.
+        Ok(path.is_file())
     }
 
     /// Returns the set of all data keys that are currently populated in the datastore, that
@@ -409,10 +1311,11 @@ impl DataStore for FilesystemDataStore {
     /// Returns a mapping of the data keys to the set of populated metadata keys for each.
     ///
     /// Note: The data keys do not need to be populated themselves; sometimes metadata is used
-    /// to help generate the data, for example.  (Committed status is then irrelevant, too.)
+    /// to help generate the data, for example.
     fn list_populated_metadata<S1, S2>(
         &self,
         prefix: S1,
+        committed: &Committed,
         metadata_key_name: &Option<S2>,
     ) -> Result<HashMap<Key, HashSet<Key>>>
     where
@@ -420,94 +1323,338 @@ impl DataStore for FilesystemDataStore {
         S2: AsRef<str>,
     {
         // Find metadata key paths on disk
-        let key_paths = find_populated_key_paths(self, KeyType::Meta, prefix, &Committed::Live)?;
-
-        // For each file on disk, check the user's conditions, and add it to our output
-        let mut result = HashMap::new();
-        for key_path in key_paths {
-            let data_key = key_path.data_key;
-            let meta_key = key_path.metadata_key.context(error::InternalSnafu {
-                msg: format!("Found meta key path with no dot: {}", data_key),
-            })?;
+        let key_paths = find_populated_key_paths(self, KeyType::Meta, prefix, committed)?;
+        metadata_by_data_key(key_paths, metadata_key_name)
+    }
 
-            // If the user requested specific metadata, move to the next key unless it matches.
-            if let Some(name) = metadata_key_name {
-                if name.as_ref() != meta_key.name() {
-                    continue;
-                }
-            }
+    fn list_matching_keys(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        let key_paths = find_matching_key_paths(self, KeyType::Data, pattern, committed)?;
+        Ok(key_paths.into_iter().map(|kp| kp.data_key).collect())
+    }
 
-            // Insert into output if we met the requested conditions; don't add an entry for
-            // the data key unless we did find some metadata.
-            let data_entry = result.entry(data_key).or_insert_with(HashSet::new);
-            data_entry.insert(meta_key);
-        }
-        Ok(result)
+    fn list_matching_metadata<S>(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+        metadata_key_name: &Option<S>,
+    ) -> Result<HashMap<Key, HashSet<Key>>>
+    where
+        S: AsRef<str>,
+    {
+        let key_paths = find_matching_key_paths(self, KeyType::Meta, pattern, committed)?;
+        metadata_by_data_key(key_paths, metadata_key_name)
     }
 
     fn get_key(&self, key: &Key, committed: &Committed) -> Result<Option<String>> {
-        let path = self.data_path(key, committed)?;
-        read_file_for_key(key, &path)
+        let value = match self.get_key_raw(key, committed)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        match &self.encryption_key {
+            Some(encryption_key) => encryption_key.decrypt(key, value).map(Some),
+            None => {
+                ensure!(
+                    !sensitive::is_encrypted(&value),
+                    error::MissingEncryptionKeySnafu { key: key.name() }
+                );
+                Ok(Some(value))
+            }
+        }
     }
 
+    /// Values of sensitive keys are encrypted if we have an encryption key.
     fn set_key<S: AsRef<str>>(&mut self, key: &Key, value: S, committed: &Committed) -> Result<()> {
+        self.ensure_writable()?;
         let path = self.data_path(key, committed)?;
-        write_file_mkdir(path, value)
+        self.note_created(committed)?;
+        match &self.encryption_key {
+            Some(encryption_key) if sensitive::is_sensitive(self, key, committed)? => {
+                let encrypted = encryption_key.encrypt(key, value.as_ref())?;
+                write_file_mkdir(path, encrypted)
+            }
+            _ => write_file_mkdir(path, value),
+        }
     }
 
     fn unset_key(&mut self, key: &Key, committed: &Committed) -> Result<()> {
+        self.ensure_writable()?;
         let path = self.data_path(key, committed)?;
         self.delete_key_path(path, committed)
     }
 
-    fn get_metadata_raw(&self, metadata_key: &Key, data_key: &Key) -> Result<Option<String>> {
-        let path = self.metadata_path(metadata_key, data_key, &Committed::Live)?;
+    fn get_metadata_raw(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>> {
+        let path = self.metadata_path(metadata_key, data_key, committed)?;
         read_file_for_key(metadata_key, &path)
     }
 
+    /// Pending metadata is written under the transaction's directory, like pending data, and
+    /// only live changes are journaled.
     fn set_metadata<S: AsRef<str>>(
         &mut self,
         metadata_key: &Key,
         data_key: &Key,
         value: S,
+        committed: &Committed,
     ) -> Result<()> {
-        let path = self.metadata_path(metadata_key, data_key, &Committed::Live)?;
-        write_file_mkdir(path, value)
+        self.ensure_writable()?;
+        let old_value = self.get_metadata_raw(metadata_key, data_key, committed)?;
+        let path = self.metadata_path(metadata_key, data_key, committed)?;
+        self.note_created(committed)?;
+        write_file_mkdir(path, value.as_ref())?;
+        if metadata_key.name() == sensitive::SENSITIVE_METADATA_KEY {
+            self.encrypt_sensitive_values(committed)?;
+        }
+        if let Committed::Pending { tx } = committed {
+            // Setting metadata again cancels its removal.
+            let mut tombstones = self.read_metadata_tombstones(tx)?;
+            let removed = tombstones
+                .get_mut(data_key.name())
+                .map(|names| names.remove(metadata_key.name()))
+                .unwrap_or(false);
+            if removed {
+                tombstones.retain(|_, names| !names.is_empty());
+                self.write_metadata_tombstones(tx, &tombstones)?;
+            }
+            return Ok(());
+        }
+
+        let entry = journal::metadata_entry(
+            SystemTime::now(),
+            None,
+            metadata_key,
+            data_key,
+            old_value,
+            Some(value.as_ref().to_string()),
+        );
+        self.append_journal(&[JournalRecord::from(&entry)])
     }
 
-    fn unset_metadata(&mut self, metadata_key: &Key, data_key: &Key) -> Result<()> {
-        let path = self.metadata_path(metadata_key, data_key, &Committed::Live)?;
-        self.delete_key_path(path, &Committed::Live)
+    fn unset_metadata(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<()> {
+        self.ensure_writable()?;
+        let path = self.metadata_path(metadata_key, data_key, committed)?;
+        if let Committed::Pending { tx } = committed {
+            // Removing pending metadata also removes the live metadata when it's committed.
+            self.note_created(committed)?;
+            self.delete_key_path(path, committed)?;
+            let mut tombstones = self.read_metadata_tombstones(tx)?;
+            let added = tombstones
+                .entry(data_key.name().clone())
+                .or_default()
+                .insert(metadata_key.name().clone());
+            if added {
+                self.write_metadata_tombstones(tx, &tombstones)?;
+            }
+            return Ok(());
+        }
+        let old_value = self.get_metadata_raw(metadata_key, data_key, committed)?;
+        self.delete_key_path(path, committed)?;
+
+        // Only journal actual removals.
+        if old_value.is_some() {
+            let entry = journal::metadata_entry(
+                SystemTime::now(),
+                None,
+                metadata_key,
+                data_key,
+                old_value,
+                None,
+            );
+            self.append_journal(&[JournalRecord::from(&entry)])?;
+        }
+        Ok(())
     }
 
-    /// We commit by copying pending keys to live, then removing pending.  Something smarter (lock,
-    /// atomic flip, etc.) will be required to make the server concurrent.
+    /// We commit by recording the pending keys in an intent file, copying them to live, then
+    /// removing pending and the intent.  See the module docs for how this survives interruption.
     fn commit_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
     where
         S: Into<String> + AsRef<str>,
     {
+        self.ensure_writable()?;
+        let transaction = transaction.into();
         let pending = Committed::Pending {
-            tx: transaction.into(),
+            tx: transaction.clone(),
         };
-        // Get data for changed keys
-        let pending_data = self.get_prefix("settings.", &pending)?;
+        // Get data for changed keys; encrypted values are copied as they are.
+        let pending_data = self.get_prefix_raw("settings.", &pending)?;
+        let tombstones = self.list_tombstones(&transaction)?;
+        let pending_metadata = self.get_metadata_prefix("", &pending, &None::<&str>)?;
+        let metadata_tombstones = self.read_metadata_tombstones(&transaction)?;
 
         // Nothing to do if no keys are present in pending
-        if pending_data.is_empty() {
+        if pending_data.is_empty()
+            && tombstones.is_empty()
+            && pending_metadata.is_empty()
+            && metadata_tombstones.is_empty()
+        {
             return Ok(Default::default());
         }
 
+        // Find the live keys removed by tombstones; pending keys are set afterward, so they
+        // override removals.
+        let mut changes: HashMap<Key, Option<String>> = HashMap::new();
+        if !tombstones.is_empty() {
+            let live_keys = self.list_populated_keys("", &Committed::Live)?;
+            for key in tombstoned_keys(&live_keys, &tombstones) {
+                changes.insert(key, None);
+            }
+        }
+        // Pending values set before their key was marked sensitive are encrypted as they're
+        // committed.
+        let sensitive = SensitiveKeys::load(self, &pending)?;
+        for (key, value) in pending_data {
+            let value = match &self.encryption_key {
+                Some(encryption_key)
+                    if sensitive.contains(&key) && !sensitive::is_encrypted(&value) =>
+                {
+                    encryption_key.encrypt(&key, &value)?
+                }
+                _ => value,
+            };
+            changes.insert(key, Some(value));
+        }
+
         // Save Keys for return value
-        let pending_keys: HashSet<Key> = pending_data.keys().cloned().collect();
+        let pending_keys: HashSet<Key> = changes.keys().cloned().collect();
+        // Only metadata that's live can be removed.
+        let mut removed_metadata: Vec<(Key, Key, String)> = Vec::new();
+        for (data_name, meta_names) in &metadata_tombstones {
+            let data_key = Key::new(KeyType::Data, data_name)?;
+            for meta_name in meta_names {
+                let metadata_key = Key::new(KeyType::Meta, meta_name)?;
+                if let Some(old_value) =
+                    self.get_metadata_raw(&metadata_key, &data_key, &Committed::Live)?
+                {
+                    removed_metadata.push((data_key.clone(), metadata_key, old_value));
+                }
+            }
+        }
+        let change_counter =
+            if changes.is_empty() && pending_metadata.is_empty() && removed_metadata.is_empty() {
+                None
+            } else {
+                Some(self.change_counter()? + 1)
+            };
 
-        // Apply changes to live
-        debug!("Writing pending keys to live");
-        self.set_keys(&pending_data, &Committed::Live)?;
+        // Save the current values of the changed keys so the commit can be reverted.
+        let generations = self.list_generations()?;
+        let mut prior = HashMap::new();
+        for key in &pending_keys {
+            prior.insert(key.clone(), self.get_key_raw(key, &Committed::Live)?);
+        }
+        let now = SystemTime::now();
+        let mut journal =
+            journal::data_entries(now, Some(&transaction), Operation::Commit, &prior, &changes);
+        sensitive.redact_journal(&mut journal);
+
+        // Metadata doesn't have generations, but its changes are journaled with the transaction.
+        let mut metadata_changes: Vec<(&Key, &Key, &String)> = pending_metadata
+            .iter()
+            .flat_map(|(data_key, meta_map)| {
+                meta_map
+                    .iter()
+                    .map(move |(meta_key, value)| (data_key, meta_key, value))
+            })
+            .collect();
+        metadata_changes.sort_by(|a, b| (a.0.name(), a.1.name()).cmp(&(b.0.name(), b.1.name())));
+        for (data_key, metadata_key, value) in metadata_changes {
+            let old_value = self.get_metadata_raw(metadata_key, data_key, &Committed::Live)?;
+            journal.push(journal::metadata_entry(
+                now,
+                Some(&transaction),
+                metadata_key,
+                data_key,
+                old_value,
+                Some(value.clone()),
+            ));
+        }
+        let mut removed_metadata_names: HashMap<String, Vec<String>> = HashMap::new();
+        for (data_key, metadata_key, old_value) in removed_metadata {
+            journal.push(journal::metadata_entry(
+                now,
+                Some(&transaction),
+                &metadata_key,
+                &data_key,
+                Some(old_value),
+                None,
+            ));
+            removed_metadata_names
+                .entry(data_key.name().clone())
+                .or_default()
+                .push(metadata_key.name().clone());
+        }
+        let id = self.latest_generation()? + 1;
+        // Make room for the new generation within the retention limit.
+        let excess = (generations.len() + 1).saturating_sub(self.generation_limit);
+        let mut forget_generations: Vec<u64> =
+            generations.iter().take(excess).map(|g| g.id).collect();
+        // A transaction that only removes keys that aren't in live changes nothing, so there's
+        // nothing to revert.
+        let (generation, latest_generation) = if self.generation_limit > 0 && !changes.is_empty() {
+            let record = GenerationRecord {
+                id,
+                transaction: transaction.clone(),
+                prior: names_to_values(prior),
+            };
+            (Some(record), Some(id))
+        } else {
+            // Without a new generation there's no room to make, unless we keep none at all, in
+            // which case generations kept under an earlier limit are forgotten too.
+            if self.generation_limit > 0 {
+                forget_generations.clear();
+            }
+            (None, None)
+        };
 
-        // Remove pending
-        debug!("Removing old pending keys");
-        let path = self.base_path(&pending);
-        fs::remove_dir_all(&path).context(error::IoSnafu { path })?;
+        let marks_sensitive = pending_metadata
+            .values()
+            .flat_map(|meta_map| meta_map.keys())
+            .any(|meta_key| meta_key.name() == sensitive::SENSITIVE_METADATA_KEY);
+
+        // Record what we're about to do, then do it.
+        let intent = CommitIntent {
+            transaction: Some(transaction),
+            changes: names_to_values(changes),
+            metadata: pending_metadata
+                .into_iter()
+                .map(|(data_key, meta_map)| {
+                    let meta_map = meta_map
+                        .into_iter()
+                        .map(|(meta_key, value)| (meta_key.name().clone(), value))
+                        .collect();
+                    (data_key.name().clone(), meta_map)
+                })
+                .collect(),
+            removed_metadata: removed_metadata_names,
+            generation,
+            forget_generations,
+            latest_generation,
+            journal: journal.iter().map(JournalRecord::from).collect(),
+            change_counter,
+        };
+        debug!(
+            "Recording intent to commit transaction '{}'",
+            intent.transaction.as_deref().unwrap_or_default()
+        );
+        self.write_intent(&intent)?;
+        self.apply_intent(&intent)?;
+        if marks_sensitive {
+            self.encrypt_sensitive_values(&Committed::Live)?;
+        }
 
         Ok(pending_keys)
     }
@@ -516,15 +1663,17 @@ impl DataStore for FilesystemDataStore {
     where
         S: Into<String> + AsRef<str>,
     {
+        self.ensure_writable()?;
         let pending = Committed::Pending {
-            tx: transaction.into(),
+            tx: transaction.as_ref().to_string(),
         };
-        // Get changed keys so we can return the list
-        let pending_data = self.get_prefix("settings.", &pending)?;
+        // Get changed keys so we can log them and return them
+        let mut pending_keys = self.list_populated_keys("settings.", &pending)?;
+        let tombstones = self.list_tombstones(transaction.as_ref())?;
 
-        // Pull out just the keys so we can log them and return them
-        let pending_keys = pending_data.into_keys().collect();
         debug!("Found pending keys: {:?}", &pending_keys);
+        debug!("Found tombstones: {:?}", &tombstones);
+        pending_keys.extend(tombstones);
 
         // Delete pending from the filesystem, same as a commit
         let path = self.base_path(&pending);
@@ -581,15 +1730,225 @@ impl DataStore for FilesystemDataStore {
 
         Ok(transactions)
     }
+
+    /// The creation time is stored in the transaction's directory.  If it's missing, because the
+    /// transaction was created by an older version, we use the directory's modification time,
+    /// which is no earlier than its creation.
+    fn transaction_created<S: AsRef<str>>(&self, transaction: S) -> Result<Option<SystemTime>> {
+        let path = self.created_path(&transaction);
+        match fs::read_to_string(&path) {
+            Ok(s) => {
+                let created = serde_json::from_str(&s)
+                    .context(error::TransactionCreatedParseSnafu { path })?;
+                return Ok(Some(created));
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context(error::IoSnafu { path }),
+        }
+
+        let pending = Committed::Pending {
+            tx: transaction.as_ref().to_string(),
+        };
+        let dir = self.base_path(&pending);
+        match fs::metadata(&dir).and_then(|m| m.modified()) {
+            Ok(modified) => Ok(Some(modified)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).context(error::IoSnafu { path: dir }),
+        }
+    }
+
+    /// Tombstones are stored as a JSON list of key names in the transaction's directory.
+    fn set_tombstone<S>(&mut self, key: &Key, transaction: S) -> Result<()>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        self.ensure_writable()?;
+        let mut names: Vec<String> = self
+            .list_tombstones(transaction.as_ref())?
+            .into_iter()
+            .map(|k| k.name().clone())
+            .collect();
+        if names.contains(key.name()) {
+            return Ok(());
+        }
+        names.push(key.name().clone());
+        names.sort();
+        self.note_created(&Committed::Pending {
+            tx: transaction.as_ref().to_string(),
+        })?;
+
+        let path = self.tombstones_path(transaction);
+        let names_str = serde_json::to_string(&names).context(error::TombstonesSerializeSnafu)?;
+        write_file_atomic(&path, names_str)
+    }
+
+    fn list_tombstones<S: AsRef<str>>(&self, transaction: S) -> Result<HashSet<Key>> {
+        let path = self.tombstones_path(transaction);
+        let names_str = match fs::read_to_string(&path) {
+            Ok(s) => s,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+            Err(e) => return Err(e).context(error::IoSnafu { path }),
+        };
+        let names: Vec<String> =
+            serde_json::from_str(&names_str).context(error::TombstonesParseSnafu { path })?;
+        names
+            .into_iter()
+            .map(|name| Key::new(KeyType::Data, name))
+            .collect()
+    }
+
+    fn list_metadata_tombstones<S: AsRef<str>>(
+        &self,
+        transaction: S,
+    ) -> Result<HashMap<Key, HashSet<Key>>> {
+        let mut result = HashMap::new();
+        for (data_name, meta_names) in self.read_metadata_tombstones(transaction)? {
+            let meta_keys = meta_names
+                .into_iter()
+                .map(|name| Key::new(KeyType::Meta, name))
+                .collect::<Result<HashSet<Key>>>()?;
+            result.insert(Key::new(KeyType::Data, data_name)?, meta_keys);
+        }
+        Ok(result)
+    }
+
+    /// Generations are recorded as files named by their ID under the generations directory.
+    fn list_generations(&self) -> Result<Vec<Generation>> {
+        let entries = match fs::read_dir(&self.generations_path) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => {
+                return Err(e).context(error::IoSnafu {
+                    path: &self.generations_path,
+                })
+            }
+        };
+
+        let mut generations = Vec::new();
+        for entry in entries {
+            let entry = entry.context(error::IoSnafu {
+                path: &self.generations_path,
+            })?;
+            let path = entry.path();
+            // Skip anything that isn't a generation record, like an interrupted write.
+            let is_record = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_suffix(GENERATION_FILE_SUFFIX))
+                .map(|id| id.parse::<u64>().is_ok())
+                .unwrap_or(false);
+            if !is_record {
+                trace!("Skipping non-generation file {}", path.display());
+                continue;
+            }
+
+            let record_str = fs::read_to_string(&path).context(error::IoSnafu { path: &path })?;
+            let record: GenerationRecord = serde_json::from_str(&record_str)
+                .context(error::GenerationParseSnafu { path: &path })?;
+            generations.push(record.into_generation()?);
+        }
+
+        generations.sort_by_key(|g| g.id);
+        Ok(generations)
+    }
+
+    fn revert_to_generation(&mut self, id: u64) -> Result<HashSet<Key>> {
+        self.ensure_writable()?;
+        let generations = self.list_generations()?;
+        let latest = self.latest_generation()?;
+        check_revert_target(&generations, latest, id)?;
+
+        // Walk back from the newest generation; for keys changed more than once, the value from
+        // the oldest reverted generation wins.
+        let mut changes = HashMap::new();
+        let mut forget_generations = Vec::new();
+        for generation in generations.iter().rev().take_while(|g| g.id > id) {
+            for (key, value) in &generation.prior {
+                changes.insert(key.clone(), value.clone());
+            }
+            forget_generations.push(generation.id);
+        }
+        if changes.is_empty() {
+            return Ok(HashSet::new());
+        }
+
+        let changed_keys: HashSet<Key> = changes.keys().cloned().collect();
+        let mut prior = HashMap::new();
+        for key in &changed_keys {
+            prior.insert(key.clone(), self.get_key_raw(key, &Committed::Live)?);
+        }
+        let mut journal =
+            journal::data_entries(SystemTime::now(), None, Operation::Revert, &prior, &changes);
+        SensitiveKeys::load(self, &Committed::Live)?.redact_journal(&mut journal);
+
+        let intent = CommitIntent {
+            transaction: None,
+            changes: names_to_values(changes),
+            metadata: HashMap::new(),
+            removed_metadata: HashMap::new(),
+            generation: None,
+            forget_generations,
+            latest_generation: Some(id),
+            journal: journal.iter().map(JournalRecord::from).collect(),
+            change_counter: Some(self.change_counter()? + 1),
+        };
+        debug!("Recording intent to revert to generation {}", id);
+        self.write_intent(&intent)?;
+        self.apply_intent(&intent)?;
+
+        Ok(changed_keys)
+    }
+
+    /// The journal is stored as lines of JSON, so we read it line by line.  A partial last line
+    /// means we were interrupted while appending; it's skipped.
+    fn read_journal(&self, filter: &JournalFilter) -> Result<Vec<JournalEntry>> {
+        let path = &self.journal_path;
+        let f = match fs::File::open(path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e).context(error::IoSnafu { path }),
+        };
+
+        let mut entries = Vec::new();
+        let mut lines = io::BufReader::new(f).lines().enumerate().peekable();
+        while let Some((index, line)) = lines.next() {
+            let line = line.context(error::IoSnafu { path })?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: JournalRecord = match serde_json::from_str(&line) {
+                Ok(record) => record,
+                Err(e) if lines.peek().is_none() => {
+                    warn!("Skipping incomplete last journal line: {}", e);
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e).context(error::JournalParseSnafu {
+                        path,
+                        line: index + 1,
+                    })
+                }
+            };
+            let entry = record.into_entry()?;
+            if filter.matches(&entry) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::lock::{LockedDataStore, DEFAULT_LOCK_TIMEOUT};
+    use crate::scratch::TestPath;
+    use maplit::{hashmap, hashset};
+    use std::time::Duration;
 
     #[test]
     fn data_path() {
-        let f = FilesystemDataStore::new("/base");
+        let f = FilesystemDataStore::new("/base").unwrap();
         let key = Key::new(KeyType::Data, "a.b.c").unwrap();
 
         let tx = "test transaction";
@@ -605,9 +1964,84 @@ mod test {
         assert_eq!(live.into_os_string(), "/base/live/a/b/c");
     }
 
+    #[test]
+    fn long_segments() {
+        let dir = TestPath::datastore("long-segments");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let long = "a".repeat(300);
+        let key = Key::new(KeyType::Data, format!("settings.\"{}.x\".b", long)).unwrap();
+        let meta = Key::new(KeyType::Meta, "affected-services").unwrap();
+
+        // The long segment becomes several components, none too long for a file name.
+        let path = f.data_path(&key, &Committed::Live).unwrap();
+        let relative = path.strip_prefix(&f.live_path).unwrap();
+        let components: Vec<_> = relative.iter().map(|c| c.to_str().unwrap()).collect();
+        assert_eq!(components.len(), 5);
+        assert!(components[1].starts_with(CONTINUATION_PREFIX));
+        assert!(components[2].starts_with(CONTINUATION_PREFIX));
+        assert!(components[3].ends_with("%2Ex"));
+        assert!(components
+            .iter()
+            .all(|c| c.len() <= MAX_PATH_COMPONENT_LENGTH));
+
+        let pending = Committed::Pending { tx: "tx".into() };
+        f.set_key(&key, "1", &pending).unwrap();
+        f.set_metadata(&meta, &key, "[]", &pending).unwrap();
+        f.commit_transaction("tx").unwrap();
+        assert_eq!(
+            f.list_populated_keys("settings.", &Committed::Live)
+                .unwrap(),
+            hashset! {key.clone()}
+        );
+        assert_eq!(
+            f.get_key(&key, &Committed::Live).unwrap(),
+            Some("1".to_string())
+        );
+        assert_eq!(
+            f.get_metadata(&meta, &key, &Committed::Live).unwrap(),
+            Some("[]".to_string())
+        );
+        assert!(crate::fsck::Checker::new(&dir.0).run().unwrap().is_clean());
+
+        // A path can't end partway through a segment.
+        decode_segment_components(["~+abc"], "x").unwrap_err();
+    }
+
+    #[test]
+    fn metadata_on_segments_near_limit() {
+        let dir = TestPath::datastore("segments-near-limit");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let meta = Key::new(KeyType::Meta, "setting-generator").unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+        for len in [240, 250, 255] {
+            let key = Key::new(KeyType::Data, format!("settings.{}", "a".repeat(len))).unwrap();
+            f.set_key(&key, "1", &pending).unwrap();
+            f.set_metadata(&meta, &key, "\"x\"", &pending).unwrap();
+            f.commit_transaction("tx").unwrap();
+            assert_eq!(
+                f.get_metadata(&meta, &key, &Committed::Live)
+                    .unwrap()
+                    .as_deref(),
+                Some("\"x\"")
+            );
+            f.set_metadata(&meta, &key, "\"y\"", &Committed::Live)
+                .unwrap();
+            assert_eq!(
+                f.get_key(&key, &Committed::Live).unwrap().as_deref(),
+                Some("1")
+            );
+        }
+
+        // Metadata names are limited so there's always room for them.
+        let long_meta = Key::new(KeyType::Meta, "m".repeat(65)).unwrap();
+        let key = Key::new(KeyType::Data, "settings.a").unwrap();
+        f.set_metadata(&long_meta, &key, "1", &Committed::Live)
+            .unwrap_err();
+    }
+
     #[test]
     fn metadata_path() {
-        let f = FilesystemDataStore::new("/base");
+        let f = FilesystemDataStore::new("/base").unwrap();
         let data_key = Key::new(KeyType::Data, "a.b.c").unwrap();
         let md_key = Key::new(KeyType::Meta, "my-metadata").unwrap();
 
@@ -647,4 +2081,511 @@ mod test {
         // Invalid UTF-8
         decode_path_component("%C3%28", "").unwrap_err();
     }
+
+    #[test]
+    fn commit_applies_pending() {
+        let dir = TestPath::datastore("commit-applies-pending");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let key = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+        f.set_key(&key, "\"value\"", &pending).unwrap();
+
+        let changed = f.commit_transaction("tx").unwrap();
+        assert_eq!(changed, HashSet::from([key.clone()]));
+        assert_eq!(
+            f.get_key(&key, &Committed::Live).unwrap(),
+            Some("\"value\"".to_string())
+        );
+        assert!(!f.key_populated(&key, &pending).unwrap());
+        assert!(!f.intent_path().exists());
+    }
+
+    #[test]
+    fn pending_metadata() {
+        let dir = TestPath::datastore("pending-metadata");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let key = Key::new(KeyType::Data, "settings.a").unwrap();
+        let meta = Key::new(KeyType::Meta, "affected-services").unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+        f.set_key(&key, "1", &pending).unwrap();
+        f.set_metadata(&meta, &key, "[\"a\"]", &pending).unwrap();
+        assert_eq!(
+            f.list_populated_metadata("", &pending, &None::<&str>)
+                .unwrap(),
+            hashmap!(key.clone() => hashset!(meta.clone()))
+        );
+        assert_eq!(f.get_metadata(&meta, &key, &Committed::Live).unwrap(), None);
+
+        f.commit_transaction("tx").unwrap();
+        assert_eq!(
+            f.get_metadata(&meta, &key, &Committed::Live).unwrap(),
+            Some("[\"a\"]".to_string())
+        );
+        let entries = f.read_journal(&JournalFilter::new()).unwrap();
+        let metadata_entry = entries
+            .iter()
+            .find(|e| e.operation == Operation::SetMetadata)
+            .unwrap();
+        assert_eq!(metadata_entry.transaction.as_deref(), Some("tx"));
+
+        // Metadata alone makes a transaction, and deleting it discards the metadata.
+        f.set_metadata(&meta, &key, "[\"b\"]", &pending).unwrap();
+        assert_eq!(f.list_transactions().unwrap(), hashset!("tx".to_string()));
+        f.delete_transaction("tx").unwrap();
+        assert_eq!(
+            f.get_metadata(&meta, &key, &Committed::Live).unwrap(),
+            Some("[\"a\"]".to_string())
+        );
+    }
+
+    #[test]
+    fn recovery_completes_commit() {
+        let dir = TestPath::datastore("recovery-completes-commit");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let key = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+        f.set_key(&key, "\"value\"", &pending).unwrap();
+
+        // Simulate an interruption right after the intent is recorded.
+        let pending_data = f.get_prefix("settings.", &pending).unwrap();
+        let intent = CommitIntent {
+            transaction: Some("tx".to_string()),
+            changes: pending_data
+                .into_iter()
+                .map(|(key, value)| (key.name().clone(), Some(value)))
+                .collect(),
+            metadata: HashMap::new(),
+            removed_metadata: HashMap::new(),
+            generation: None,
+            forget_generations: Vec::new(),
+            latest_generation: None,
+            journal: Vec::new(),
+            change_counter: None,
+        };
+        f.write_intent(&intent).unwrap();
+
+        // Opening without the lock can't complete the commit, so it refuses, and leaves it alone.
+        for open in [
+            FilesystemDataStore::new,
+            FilesystemDataStore::open_read_only,
+        ] {
+            let err = open(&dir.0).unwrap_err();
+            assert!(matches!(err, error::Error::NeedsRecovery { .. }), "{}", err);
+        }
+        assert!(f.intent_path().exists());
+        assert!(f.key_populated(&key, &pending).unwrap());
+
+        let f = LockedDataStore::open(&dir.0, DEFAULT_LOCK_TIMEOUT)
+            .unwrap()
+            .into_inner();
+        assert_eq!(
+            f.get_key(&key, &Committed::Live).unwrap(),
+            Some("\"value\"".to_string())
+        );
+        assert!(!f.key_populated(&key, &pending).unwrap());
+        assert!(!f.intent_path().exists());
+    }
+
+    #[test]
+    fn read_only() {
+        let dir = TestPath::datastore("read-only");
+        let key = Key::new(KeyType::Data, "settings.a").unwrap();
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        f.set_key(&key, "1", &Committed::Live).unwrap();
+
+        let mut f = FilesystemDataStore::open_read_only(&dir.0).unwrap();
+        assert_eq!(
+            f.get_key(&key, &Committed::Live).unwrap(),
+            Some("1".to_string())
+        );
+        let pending = Committed::Pending { tx: "tx".into() };
+        let err = f.set_key(&key, "2", &pending).unwrap_err();
+        assert!(matches!(err, error::Error::ReadOnly { .. }), "{}", err);
+        f.set_tombstone(&key, "tx").unwrap_err();
+        f.commit_transaction("tx").unwrap_err();
+        assert!(f.list_transactions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn recovery_discards_partial_intent() {
+        let dir = TestPath::datastore("recovery-discards-partial-intent");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let key = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+        f.set_key(&key, "\"value\"", &pending).unwrap();
+
+        // Simulate an interruption while the intent was being written.
+        let temp_path = temp_path_for(&f.intent_path());
+        fs::write(&temp_path, "{\"transaction\": \"tx\", \"da").unwrap();
+
+        let f = LockedDataStore::open(&dir.0, DEFAULT_LOCK_TIMEOUT)
+            .unwrap()
+            .into_inner();
+        assert!(!temp_path.exists());
+        assert!(!f.key_populated(&key, &Committed::Live).unwrap());
+        assert!(f.key_populated(&key, &pending).unwrap());
+    }
+
+    #[test]
+    fn revert_to_generation() {
+        let dir = TestPath::datastore("revert-to-generation");
+        let mut f = FilesystemDataStore::new(&dir.0)
+            .unwrap()
+            .with_generation_limit(2);
+        let a = Key::new(KeyType::Data, "settings.a").unwrap();
+        let b = Key::new(KeyType::Data, "settings.b").unwrap();
+        let commit = |f: &mut FilesystemDataStore, tx: &str, key: &Key, value: &str| {
+            let pending = Committed::Pending { tx: tx.into() };
+            f.set_key(key, value, &pending).unwrap();
+            f.commit_transaction(tx).unwrap();
+        };
+        commit(&mut f, "one", &a, "1");
+        commit(&mut f, "two", &a, "2");
+        commit(&mut f, "three", &b, "3");
+
+        // The oldest generation was pruned, so we can't go back before it.
+        let generations = f.list_generations().unwrap();
+        let ids: Vec<u64> = generations.iter().map(|g| g.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert_eq!(generations[1].transaction, "three");
+        assert_eq!(generations[1].prior, HashMap::from([(b.clone(), None)]));
+        f.revert_to_generation(0).unwrap_err();
+
+        let changed = f.revert_to_generation(1).unwrap();
+        assert_eq!(changed, HashSet::from([a.clone(), b.clone()]));
+        assert_eq!(
+            f.get_key(&a, &Committed::Live).unwrap(),
+            Some("1".to_string())
+        );
+        assert_eq!(f.get_key(&b, &Committed::Live).unwrap(), None);
+        assert!(f.list_generations().unwrap().is_empty());
+
+        // New commits continue from the generation we reverted to.
+        commit(&mut f, "four", &b, "4");
+        let ids: Vec<u64> = f.list_generations().unwrap().iter().map(|g| g.id).collect();
+        assert_eq!(ids, vec![2]);
+
+        // With a limit of 0, the next commit forgets any generations that were kept.
+        let mut f = f.with_generation_limit(0);
+        commit(&mut f, "five", &b, "5");
+        assert!(f.list_generations().unwrap().is_empty());
+        f.revert_to_generation(1).unwrap_err();
+    }
+
+    #[test]
+    fn journal_records_changes() {
+        let dir = TestPath::datastore("journal-records-changes");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let a = Key::new(KeyType::Data, "settings.a").unwrap();
+        let b = Key::new(KeyType::Data, "settings.b").unwrap();
+        let meta = Key::new(KeyType::Meta, "affected-services").unwrap();
+        for (tx, value) in [("one", "1"), ("two", "2")] {
+            let pending = Committed::Pending { tx: tx.into() };
+            f.set_key(&a, value, &pending).unwrap();
+            f.set_key(&b, value, &pending).unwrap();
+            f.commit_transaction(tx).unwrap();
+        }
+        f.set_metadata(&meta, &a, "[]", &Committed::Live).unwrap();
+        f.unset_metadata(&meta, &a, &Committed::Live).unwrap();
+
+        let entries = f
+            .read_journal(&JournalFilter::new().prefix("settings.a"))
+            .unwrap();
+        let summary: Vec<_> = entries
+            .iter()
+            .map(|e| {
+                (
+                    e.transaction.as_deref(),
+                    e.operation,
+                    e.old_value.as_deref(),
+                    e.new_value.as_deref(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (Some("one"), Operation::Commit, None, Some("1")),
+                (Some("two"), Operation::Commit, Some("1"), Some("2")),
+                (None, Operation::SetMetadata, None, Some("[]")),
+                (None, Operation::UnsetMetadata, Some("[]"), None),
+            ]
+        );
+        assert_eq!(entries[2].metadata_key, Some(meta));
+
+        // A partial line at the end, from an interrupted append, is skipped.
+        let mut journal = fs::OpenOptions::new()
+            .append(true)
+            .open(&f.journal_path)
+            .unwrap();
+        journal.write_all(b"{\"timestamp\":").unwrap();
+        assert_eq!(f.read_journal(&JournalFilter::new()).unwrap().len(), 6);
+    }
+
+    #[test]
+    fn format_version() {
+        let dir = TestPath::datastore("format-version");
+        // TestPath makes an unversioned data store, in the original layout.
+        FilesystemDataStore::create(&dir.0).unwrap_err();
+        FilesystemDataStore::new(&dir.0).unwrap();
+        fs::remove_dir_all(&dir.0).unwrap();
+
+        FilesystemDataStore::create(&dir.0).unwrap();
+        assert_eq!(
+            format::read_format_version(&dir.0).unwrap(),
+            CURRENT_FORMAT_VERSION
+        );
+
+        let newer = CURRENT_FORMAT_VERSION + 1;
+        fs::write(dir.0.join(format::FORMAT_VERSION_FILE), newer.to_string()).unwrap();
+        let err = FilesystemDataStore::new(&dir.0).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains(&format!("format version {}", newer)),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn transaction_created_without_file() {
+        let dir = TestPath::datastore("created");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let k = Key::new(KeyType::Data, "settings.a").unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+        f.set_key(&k, "1", &pending).unwrap();
+        let created = f.transaction_created("tx").unwrap().unwrap();
+        assert_eq!(f.list_populated_keys("", &pending).unwrap(), hashset![k]);
+
+        // Transactions from older versions don't have the file, so we use the directory's time.
+        fs::remove_file(f.created_path("tx")).unwrap();
+        let modified = f.transaction_created("tx").unwrap().unwrap();
+        assert!(modified + Duration::from_secs(2) >= created);
+    }
+
+    #[test]
+    fn marking_existing_values_sensitive() {
+        let dir = TestPath::datastore("marking-existing-values-sensitive");
+        let key_path = dir.0.join("encryption-key");
+        let mut f = FilesystemDataStore::new(&dir.0)
+            .unwrap()
+            .with_encryption_key(EncryptionKey::generate_file(&key_path).unwrap());
+        let sensitive = Key::new(KeyType::Meta, sensitive::SENSITIVE_METADATA_KEY).unwrap();
+        let data = |name| Key::new(KeyType::Data, name).unwrap();
+        let pending = |tx: &str| Committed::Pending { tx: tx.into() };
+        let stored = |f: &FilesystemDataStore, key: &Key, committed: &Committed| {
+            fs::read_to_string(f.data_path(key, committed).unwrap()).unwrap()
+        };
+
+        // Values set before their key is marked, live or pending, are encrypted once it is.
+        f.set_key(&data("settings.a.live"), "1", &Committed::Live)
+            .unwrap();
+        f.set_key(&data("settings.a.pending"), "2", &pending("values"))
+            .unwrap();
+        f.set_metadata(&sensitive, &data("settings.a"), "true", &pending("mark"))
+            .unwrap();
+        f.set_key(&data("settings.a.marked"), "3", &pending("mark"))
+            .unwrap();
+        assert!(sensitive::is_encrypted(&stored(
+            &f,
+            &data("settings.a.marked"),
+            &pending("mark")
+        )));
+        assert!(!sensitive::is_encrypted(&stored(
+            &f,
+            &data("settings.a.live"),
+            &Committed::Live
+        )));
+        f.commit_transaction("mark").unwrap();
+        f.commit_transaction("values").unwrap();
+        for (name, value) in [
+            ("settings.a.live", "1"),
+            ("settings.a.pending", "2"),
+            ("settings.a.marked", "3"),
+        ] {
+            let key = data(name);
+            assert!(sensitive::is_encrypted(&stored(&f, &key, &Committed::Live)));
+            assert_eq!(
+                f.get_key(&key, &Committed::Live).unwrap().as_deref(),
+                Some(value)
+            );
+        }
+
+        // The same goes for live metadata, and for pending values in the marking transaction.
+        f.set_key(&data("settings.b"), "4", &Committed::Live)
+            .unwrap();
+        f.set_metadata(&sensitive, &data("settings.b"), "true", &Committed::Live)
+            .unwrap();
+        assert!(sensitive::is_encrypted(&stored(
+            &f,
+            &data("settings.b"),
+            &Committed::Live
+        )));
+        f.set_key(&data("settings.c"), "5", &pending("tx")).unwrap();
+        f.set_metadata(&sensitive, &data("settings.c"), "true", &pending("tx"))
+            .unwrap();
+        assert!(sensitive::is_encrypted(&stored(
+            &f,
+            &data("settings.c"),
+            &pending("tx")
+        )));
+    }
+
+    #[test]
+    fn sensitive_values() {
+        let dir = TestPath::datastore("sensitive-values");
+        let key_path = dir.0.join("encryption-key");
+        let mut f = FilesystemDataStore::new(&dir.0)
+            .unwrap()
+            .with_encryption_key(EncryptionKey::generate_file(&key_path).unwrap());
+        let secret = Key::new(KeyType::Data, "settings.secret").unwrap();
+        let sensitive = Key::new(KeyType::Meta, sensitive::SENSITIVE_METADATA_KEY).unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+        f.set_metadata(&sensitive, &secret, "true", &pending)
+            .unwrap();
+        f.set_key(&secret, "\"hunter2\"", &pending).unwrap();
+        f.commit_transaction("tx").unwrap();
+
+        // The value is encrypted on disk, but reads see the original.
+        let path = f.data_path(&secret, &Committed::Live).unwrap();
+        let stored = fs::read_to_string(path).unwrap();
+        assert!(sensitive::is_encrypted(&stored));
+        assert_eq!(
+            f.get_key(&secret, &Committed::Live).unwrap(),
+            Some("\"hunter2\"".to_string())
+        );
+
+        // The journal doesn't show it, before or after a revert.
+        f.revert_to_generation(0).unwrap();
+        for entry in f.read_journal(&JournalFilter::new()).unwrap() {
+            if entry.metadata_key.is_none() {
+                assert!(entry
+                    .old_value
+                    .iter()
+                    .chain(&entry.new_value)
+                    .all(|v| v == sensitive::REDACTED_VALUE));
+            }
+        }
+
+        // Values that look like the encryption marker are still just values.
+        let motd = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let lookalike = "\"encrypted:v1:hello\"";
+        f.set_key(&motd, lookalike, &Committed::Live).unwrap();
+        assert_eq!(
+            f.get_key(&motd, &Committed::Live).unwrap().as_deref(),
+            Some(lookalike)
+        );
+
+        // Without the key, encrypted values can't be read, but others can.
+        f.set_key(&secret, "\"hunter3\"", &Committed::Live).unwrap();
+        let f = FilesystemDataStore::new(&dir.0).unwrap();
+        f.get_key(&secret, &Committed::Live).unwrap_err();
+        assert_eq!(
+            f.get_key(&motd, &Committed::Live).unwrap().as_deref(),
+            Some(lookalike)
+        );
+    }
+
+    #[test]
+    fn tombstones() {
+        let dir = TestPath::datastore("tombstones");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let a = Key::new(KeyType::Data, "settings.a.x").unwrap();
+        let ab = Key::new(KeyType::Data, "settings.ab").unwrap();
+        let b = Key::new(KeyType::Data, "settings.b").unwrap();
+        f.set_key(&a, "1", &Committed::Live).unwrap();
+        f.set_key(&ab, "2", &Committed::Live).unwrap();
+
+        let tx = "tx";
+        let pending = Committed::Pending { tx: tx.into() };
+        let tombstone = Key::new(KeyType::Data, "settings.a").unwrap();
+        f.set_tombstone(&tombstone, tx).unwrap();
+        assert_eq!(
+            f.list_transactions().unwrap(),
+            HashSet::from([tx.to_string()])
+        );
+        // The tombstone list isn't mistaken for a key.
+        assert!(f.get_prefix("", &pending).unwrap().is_empty());
+        f.set_key(&b, "3", &pending).unwrap();
+
+        let changed = f.commit_transaction(tx).unwrap();
+        assert_eq!(changed, HashSet::from([a.clone(), b]));
+        assert!(!f.key_populated(&a, &Committed::Live).unwrap());
+        assert!(f.key_populated(&ab, &Committed::Live).unwrap());
+        assert!(f.list_tombstones(tx).unwrap().is_empty());
+
+        // Reverting restores removed keys.
+        f.revert_to_generation(0).unwrap();
+        assert!(f.key_populated(&a, &Committed::Live).unwrap());
+
+        f.set_tombstone(&ab, tx).unwrap();
+        assert_eq!(f.delete_transaction(tx).unwrap(), HashSet::from([ab]));
+        assert!(f.list_transactions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn matching_keys() {
+        let dir = TestPath::datastore("matching-keys");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let key = |name: &str| Key::new(KeyType::Data, name).unwrap();
+        for name in [
+            "settings.host-containers.admin.enabled",
+            "settings.host-containers.admin.source",
+            "settings.host-containers.control.enabled",
+            "settings.host-containers-other.x.enabled",
+            "settings.labels.\"example.com/role\"",
+        ] {
+            f.set_key(&key(name), "true", &Committed::Live).unwrap();
+        }
+        let services = Key::new(KeyType::Meta, "affected-services").unwrap();
+        for name in ["settings.host-containers", "settings.labels"] {
+            f.set_metadata(&services, &key(name), "[]", &Committed::Live)
+                .unwrap();
+        }
+
+        let pattern = KeyPattern::new("settings.host-containers.*.enabled").unwrap();
+        assert_eq!(
+            f.list_matching_keys(&pattern, &Committed::Live).unwrap(),
+            hashset!(
+                key("settings.host-containers.admin.enabled"),
+                key("settings.host-containers.control.enabled"),
+            )
+        );
+        let pattern = KeyPattern::new("settings.labels.\"*\"").unwrap();
+        assert_eq!(
+            f.get_matching(&pattern, &Committed::Live).unwrap(),
+            hashmap!(key("settings.labels.\"example.com/role\"") => "true".to_string())
+        );
+
+        // Metadata on the pattern's literal prefix is found, but not on similarly named keys.
+        let pattern = KeyPattern::new("settings.host-containers").unwrap();
+        assert_eq!(
+            f.list_matching_metadata(&pattern, &Committed::Live, &None::<&str>)
+                .unwrap(),
+            hashmap!(key("settings.host-containers") => hashset!(services.clone()))
+        );
+        let pattern = KeyPattern::new("settings.*").unwrap();
+        assert_eq!(
+            f.list_matching_metadata(&pattern, &Committed::Live, &Some("affected-services"))
+                .unwrap()
+                .len(),
+            2
+        );
+        let pattern = KeyPattern::new("settings.missing.**").unwrap();
+        assert!(f
+            .list_matching_keys(&pattern, &Committed::Live)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn conformance() {
+        let mut dirs = Vec::new();
+        crate::conformance::run_all(|| {
+            let dir = TestPath::datastore("conformance");
+            let f = FilesystemDataStore::new(&dir.0).unwrap();
+            dirs.push(dir);
+            f
+        });
+    }
 }
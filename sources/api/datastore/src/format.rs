@@ -0,0 +1,340 @@
+//! On-disk format versions of FilesystemDataStore, and the upgrades between them.
+//!
+//! The format version is kept in a file directly under the base path.  Data stores created before
+//! we recorded it don't have one, and have the original layout, which is version 1.  Opening a
+//! data store with a newer version than this code supports is an error, rather than a chance to
+//! misread or damage it.  Opening one with an older version upgrades it in place.
+//!
+//! Format upgrades are distinct from settings migrations.  Migrations change the settings stored
+//! in a data store, and are chosen by the OS version being moved to; format upgrades change how
+//! any data store is laid out on disk, like how keys are encoded, and are chosen by the format
+//! version recorded in the data store.
+//!
+//! Each upgrade moves the data store from one version to the next, and has a matching downgrade,
+//! so a data store can be moved back to a version that older software understands before rolling
+//! back to it.  After each step, the new version is recorded atomically.  If we're interrupted
+//! during a step, the version isn't recorded and the step is run again, so upgrades and
+//! downgrades must be safe to rerun on a partly converted data store.
+
+use log::info;
+use snafu::{ensure, OptionExt, ResultExt};
+use std::fs;
+use std::io;
+use std::path::Path;
+use walkdir::WalkDir;
+
+use super::filesystem::{
+    relocate_key_files, sync_dir, write_file_atomic, COMMIT_INTENT_FILE, CONTINUATION_PREFIX,
+    METADATA_TOMBSTONES_FILE, TEMP_FILE_SUFFIX, TOMBSTONES_FILE,
+};
+use super::{error, sensitive, Result};
+
+/// Name of the file, under the base path, that holds the format version.
+pub const FORMAT_VERSION_FILE: &str = "format-version";
+
+/// The format version that data stores are created with and upgraded to.
+///
+/// Version 2 splits segments that are too long for a file name, once there's room for metadata
+/// and temporary file suffixes, into pieces in separate directories.  It also adds things older
+/// software doesn't know about: tombstones, metadata tombstones, and creation times in pending
+/// transactions; generations, the journal, and the change counter; and encrypted values.
+pub const CURRENT_FORMAT_VERSION: u32 = 2;
+
+/// The format version of data stores created before the format version was recorded.
+pub const UNVERSIONED_FORMAT_VERSION: u32 = 1;
+
+/// FormatUpgrade converts a data store, given by its base path, from one format version to the
+/// next, and back.
+#[derive(Debug, Clone, Copy)]
+pub struct FormatUpgrade {
+    /// The version this upgrades from; it upgrades to the next version.
+    pub from: u32,
+    /// A short description of the change, for logging.
+    pub description: &'static str,
+    pub upgrade: fn(&Path) -> Result<()>,
+    pub downgrade: fn(&Path) -> Result<()>,
+}
+
+/// The registered format upgrades, in order.  Changing the format means increasing
+/// CURRENT_FORMAT_VERSION and adding the upgrade from the previous version here.
+pub static FORMAT_UPGRADES: &[FormatUpgrade] = &[FormatUpgrade {
+    from: 1,
+    description: "split long segments, and add history, tombstones, and encryption",
+    upgrade: upgrade_to_2,
+    downgrade: downgrade_from_2,
+}];
+
+/// Moves keys to where version 2 puts them.  Segments that are too long for a file name couldn't
+/// be stored at all in version 1, but data stores written before the version was recorded may
+/// have segments that were split with less room for suffixes, or not split when they need to be
+/// now.  Everything else version 2 adds is new files that don't need converting.
+fn upgrade_to_2(base_path: &Path) -> Result<()> {
+    relocate_key_files(base_path)
+}
+
+/// Checks that version 1 software can use the data store.  It would misread split segments and
+/// encrypted values, it would ignore tombstones when committing, and it wouldn't complete an
+/// interrupted commit, so we refuse if there are any of those.  It ignores the files for
+/// history and creation times, so they're left in place for when the data store is upgraded
+/// again.
+fn downgrade_from_2(base_path: &Path) -> Result<()> {
+    let refuse = |reason: String| {
+        error::FormatDowngradeSnafu {
+            path: base_path,
+            version: 1u32,
+            reason,
+        }
+        .fail()
+    };
+    if base_path.join(COMMIT_INTENT_FILE).exists() {
+        return refuse("it has an interrupted commit to complete".to_string());
+    }
+
+    let pending_path = base_path.join("pending");
+    for root in [base_path.join("live"), pending_path.clone()] {
+        if !root.exists() {
+            continue;
+        }
+        for entry in WalkDir::new(&root).min_depth(1) {
+            let entry = entry.context(error::ListKeysSnafu)?;
+            let path = entry.path();
+            let name = entry.file_name().to_string_lossy();
+            if name.starts_with(CONTINUATION_PREFIX) {
+                return refuse(format!("'{}' is part of a split segment", path.display()));
+            }
+            if !entry.file_type().is_file() || name.ends_with(TEMP_FILE_SUFFIX) {
+                continue;
+            }
+            if root == pending_path && entry.depth() == 2 {
+                if name == TOMBSTONES_FILE || name == METADATA_TOMBSTONES_FILE {
+                    return refuse(format!("'{}' holds tombstones", path.display()));
+                }
+                if name.starts_with('~') {
+                    continue;
+                }
+            }
+            let value = fs::read_to_string(path).context(error::IoSnafu { path })?;
+            if sensitive::is_encrypted(&value) {
+                return refuse(format!("'{}' holds an encrypted value", path.display()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns the format version of the data store at the given base path.
+pub fn read_format_version(base_path: &Path) -> Result<u32> {
+    let path = base_path.join(FORMAT_VERSION_FILE);
+    let version_str = match fs::read_to_string(&path) {
+        Ok(s) => s,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(UNVERSIONED_FORMAT_VERSION),
+        Err(e) => return Err(e).context(error::IoSnafu { path }),
+    };
+    version_str
+        .trim()
+        .parse()
+        .ok()
+        .context(error::CorruptionSnafu {
+            msg: "invalid format version",
+            path,
+        })
+}
+
+/// Durably records the format version of the data store at the given base path.
+pub(crate) fn write_format_version(base_path: &Path, version: u32) -> Result<()> {
+    write_file_atomic(
+        &base_path.join(FORMAT_VERSION_FILE),
+        format!("{}\n", version),
+    )?;
+    sync_dir(base_path)
+}
+
+/// Returns the format version of the data store at the given base path, or an error if it's
+/// newer than we support.
+pub fn check_format_version(base_path: &Path) -> Result<u32> {
+    let version = read_format_version(base_path)?;
+    ensure!(
+        version <= CURRENT_FORMAT_VERSION,
+        error::UnsupportedFormatVersionSnafu {
+            path: base_path,
+            version,
+            supported: CURRENT_FORMAT_VERSION,
+        }
+    );
+    Ok(version)
+}
+
+/// Upgrades or downgrades the data store at the given base path to the given format version, one
+/// step at a time.  The data store must not be in use.
+pub fn set_format_version(base_path: &Path, target: u32) -> Result<()> {
+    set_format_version_with(base_path, target, FORMAT_UPGRADES)
+}
+
+fn set_format_version_with(
+    base_path: &Path,
+    target: u32,
+    upgrades: &[FormatUpgrade],
+) -> Result<()> {
+    // Versions the registry can downgrade from are supported here too.
+    let latest = upgrades
+        .iter()
+        .map(|u| u.from + 1)
+        .fold(CURRENT_FORMAT_VERSION, u32::max);
+    let mut version = read_format_version(base_path)?;
+    ensure!(
+        version <= latest,
+        error::UnsupportedFormatVersionSnafu {
+            path: base_path,
+            version,
+            supported: latest,
+        }
+    );
+
+    let find = |from: u32| {
+        upgrades
+            .iter()
+            .find(|u| u.from == from)
+            .context(error::MissingFormatUpgradeSnafu { from })
+    };
+    while version < target {
+        let step = find(version)?;
+        info!(
+            "Upgrading data store format from version {} to {}: {}",
+            version,
+            version + 1,
+            step.description
+        );
+        (step.upgrade)(base_path)?;
+        version += 1;
+        write_format_version(base_path, version)?;
+    }
+    while version > target {
+        let step = find(version - 1)?;
+        info!(
+            "Downgrading data store format from version {} to {}: {}",
+            version,
+            version - 1,
+            step.description
+        );
+        (step.downgrade)(base_path)?;
+        version -= 1;
+        write_format_version(base_path, version)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scratch::TestPath;
+    use crate::{Committed, DataStore, Error, FilesystemDataStore, Key, KeyType};
+
+    fn add_marker(base_path: &Path) -> Result<()> {
+        fs::write(base_path.join("marker"), "").context(error::IoSnafu { path: base_path })
+    }
+
+    fn remove_marker(base_path: &Path) -> Result<()> {
+        fs::remove_file(base_path.join("marker")).context(error::IoSnafu { path: base_path })
+    }
+
+    #[test]
+    fn upgrade_and_downgrade() {
+        let dir = TestPath::dir("format");
+        let base = &dir.0;
+        assert_eq!(
+            read_format_version(base).unwrap(),
+            UNVERSIONED_FORMAT_VERSION
+        );
+        write_format_version(base, CURRENT_FORMAT_VERSION).unwrap();
+
+        let next = CURRENT_FORMAT_VERSION + 1;
+        let upgrades = [FormatUpgrade {
+            from: CURRENT_FORMAT_VERSION,
+            description: "add a marker",
+            upgrade: add_marker,
+            downgrade: remove_marker,
+        }];
+        set_format_version_with(base, next, &upgrades).unwrap();
+        assert_eq!(read_format_version(base).unwrap(), next);
+        assert!(base.join("marker").exists());
+        // This code doesn't know the next version.
+        check_format_version(base).unwrap_err();
+        // Without a registered upgrade, we can't get past it.
+        set_format_version_with(base, next + 1, &upgrades).unwrap_err();
+
+        set_format_version_with(base, CURRENT_FORMAT_VERSION, &upgrades).unwrap();
+        assert_eq!(read_format_version(base).unwrap(), CURRENT_FORMAT_VERSION);
+        assert!(!base.join("marker").exists());
+        check_format_version(base).unwrap();
+
+        fs::write(base.join(FORMAT_VERSION_FILE), "one").unwrap();
+        read_format_version(base).unwrap_err();
+    }
+
+    #[test]
+    fn version_2() {
+        let dir = TestPath::dir("version-2");
+        let base = &dir.0;
+        // A segment that fit in a file name in version 1, but needs to be split in version 2 to
+        // leave room for suffixes.
+        let long = "a".repeat(200);
+        let key = Key::new(KeyType::Data, format!("settings.{}", long)).unwrap();
+        let meta = Key::new(KeyType::Meta, "setting-generator").unwrap();
+        let live = base.join("live").join("settings");
+        let pending = base.join("pending").join("tx").join("settings");
+        for dir in [&live, &pending] {
+            fs::create_dir_all(dir).unwrap();
+            fs::write(dir.join(&long), "\"value\"").unwrap();
+        }
+        fs::write(live.join(format!("{}.setting-generator", long)), "\"gen\"").unwrap();
+
+        // Opening doesn't upgrade; that takes the exclusive lock.
+        let err = FilesystemDataStore::new(base).unwrap_err();
+        assert!(matches!(err, Error::NeedsRecovery { .. }), "{}", err);
+        set_format_version(base, 2).unwrap();
+        assert!(!live.join(&long).exists());
+        // Upgrades are safe to rerun.
+        upgrade_to_2(base).unwrap();
+        let f = FilesystemDataStore::new(base).unwrap();
+        let tx = Committed::Pending { tx: "tx".into() };
+        for committed in [&Committed::Live, &tx] {
+            assert_eq!(
+                f.get_key(&key, committed).unwrap().as_deref(),
+                Some("\"value\"")
+            );
+        }
+        assert_eq!(
+            f.get_metadata_raw(&meta, &key, &Committed::Live)
+                .unwrap()
+                .as_deref(),
+            Some("\"gen\"")
+        );
+
+        // Version 1 software can't read split segments, tombstones, or encrypted values.
+        let mut f = f;
+        let refused = || {
+            let err = set_format_version(base, 1).unwrap_err();
+            assert!(
+                matches!(err, error::Error::FormatDowngrade { .. }),
+                "{}",
+                err
+            );
+            assert_eq!(read_format_version(base).unwrap(), 2);
+        };
+        refused();
+        f.unset_key(&key, &Committed::Live).unwrap();
+        f.unset_metadata(&meta, &key, &Committed::Live).unwrap();
+        f.delete_transaction("tx").unwrap();
+        let other = Key::new(KeyType::Data, "settings.other").unwrap();
+        f.set_tombstone(&other, "tx").unwrap();
+        refused();
+        f.delete_transaction("tx").unwrap();
+        f.set_key(&other, "encrypted:v1:AAAA", &Committed::Live)
+            .unwrap();
+        refused();
+        f.set_key(&other, "1", &Committed::Live).unwrap();
+
+        set_format_version(base, 1).unwrap();
+        assert_eq!(read_format_version(base).unwrap(), 1);
+    }
+}
@@ -0,0 +1,587 @@
+//! This module checks the on-disk structure of a FilesystemDataStore for corruption, and can
+//! optionally repair it by moving bad entries aside into a quarantine directory.
+//!
+//! The live data store and each pending transaction are walked, and each entry is checked for:
+//! * file names that aren't UTF-8, or whose percent-encoding doesn't decode to UTF-8
+//! * data files whose path doesn't make a valid data key
+//! * metadata files whose data key or metadata key is invalid
+//! * data values that aren't valid JSON scalars, and metadata values that aren't valid JSON
+//! * empty directories, which are normally removed along with their last key
+//! * orphaned pending transactions, that aren't directories, have invalid names, or are empty
+//! * temporary files left behind by an interrupted write
+//!
+//! Checking takes a shared lock on the data store's lock file, so it's safe to run against a data
+//! store that's in use through a `LockedDataStore`.  Repairing takes an exclusive lock.  Neither
+//! completes interrupted commits or upgrades the format, the way `LockedDataStore::open` does,
+//! and data stores with a newer format than we support are refused.
+//!
+//! Quarantined entries keep their path relative to the base of the data store, under the given
+//! quarantine directory, so they can be inspected or restored by hand.  Empty directories are
+//! removed rather than quarantined.
+
+use log::{debug, info, warn};
+use snafu::ResultExt;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use super::filesystem::{
+    decode_path_component, join_segment_pieces, CREATED_FILE, METADATA_KEY_PREFIX,
+    METADATA_TOMBSTONES_FILE, TEMP_FILE_SUFFIX, TOMBSTONES_FILE,
+};
+use super::format;
+use super::lock::{FileLock, LockMode, DEFAULT_LOCK_TIMEOUT, LOCK_FILE};
+use super::{error, sensitive, Key, KeyType, Result};
+
+/// ProblemKind describes what's wrong with an entry in the data store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProblemKind {
+    /// The live data directory doesn't exist.
+    MissingLive,
+    /// The file name isn't valid UTF-8.
+    NonUnicodePath,
+    /// A path component doesn't percent-decode to UTF-8.
+    InvalidEncoding { segment: String },
+    /// The path of a data file doesn't make a valid data key.
+    InvalidDataKey { msg: String },
+    /// The path of a metadata file doesn't make a valid data key and metadata key.
+    InvalidMetadataKey { msg: String },
+    /// The file contents aren't valid UTF-8.
+    NonUnicodeValue,
+    /// The file contents aren't a valid value for the file.
+    InvalidValue { msg: String },
+    /// A directory has no keys in it.
+    EmptyDirectory,
+    /// A pending transaction that can't be used.
+    OrphanedTransaction { reason: String },
+    /// A temporary file left behind by an interrupted write.
+    StaleTempFile,
+    /// Something other than a file or directory, like a symlink.
+    UnexpectedEntry,
+}
+
+impl fmt::Display for ProblemKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProblemKind::MissingLive => write!(f, "live data directory is missing"),
+            ProblemKind::NonUnicodePath => write!(f, "file name is not valid UTF-8"),
+            ProblemKind::InvalidEncoding { segment } => {
+                write!(f, "invalid percent-encoding in '{}'", segment)
+            }
+            ProblemKind::InvalidDataKey { msg } => write!(f, "invalid data key: {}", msg),
+            ProblemKind::InvalidMetadataKey { msg } => write!(f, "invalid metadata key: {}", msg),
+            ProblemKind::NonUnicodeValue => write!(f, "value is not valid UTF-8"),
+            ProblemKind::InvalidValue { msg } => write!(f, "invalid value: {}", msg),
+            ProblemKind::EmptyDirectory => write!(f, "empty directory"),
+            ProblemKind::OrphanedTransaction { reason } => {
+                write!(f, "orphaned pending transaction: {}", reason)
+            }
+            ProblemKind::StaleTempFile => write!(f, "stale temporary file"),
+            ProblemKind::UnexpectedEntry => write!(f, "not a regular file or directory"),
+        }
+    }
+}
+
+/// Problem is a single issue found in the data store.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// The path of the bad entry.
+    pub path: PathBuf,
+    pub kind: ProblemKind,
+    /// Whether the entry was quarantined or removed.
+    pub repaired: bool,
+}
+
+/// Report lists the problems found by a check.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    pub problems: Vec<Problem>,
+}
+
+impl Report {
+    /// Returns true if no problems were found.
+    pub fn is_clean(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checker checks a FilesystemDataStore, and optionally repairs it.  By default, it only reports
+/// problems.
+#[derive(Debug, Clone)]
+pub struct Checker {
+    base_path: PathBuf,
+    quarantine_path: Option<PathBuf>,
+    lock_timeout: Duration,
+}
+
+impl Checker {
+    /// Creates a Checker for the FilesystemDataStore at the given base path.
+    pub fn new<P: AsRef<Path>>(base_path: P) -> Self {
+        Self {
+            base_path: base_path.as_ref().to_path_buf(),
+            quarantine_path: None,
+            lock_timeout: DEFAULT_LOCK_TIMEOUT,
+        }
+    }
+
+    /// Repairs problems by moving bad entries into the given directory.  It should be outside the
+    /// live and pending directories, and on the same filesystem as the data store.
+    pub fn repair<P: AsRef<Path>>(mut self, quarantine_path: P) -> Self {
+        self.quarantine_path = Some(quarantine_path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets how long to wait for the data store's lock.
+    pub fn with_lock_timeout(mut self, timeout: Duration) -> Self {
+        self.lock_timeout = timeout;
+        self
+    }
+
+    /// Checks the data store, repairing problems if requested, and returns what was found.
+    pub fn run(&self) -> Result<Report> {
+        let mode = match self.quarantine_path {
+            Some(_) => LockMode::Exclusive,
+            None => LockMode::Shared,
+        };
+        let _lock = FileLock::acquire(&self.base_path.join(LOCK_FILE), mode, self.lock_timeout)?;
+        // Newer formats may have entries we'd mistake for problems.
+        format::check_format_version(&self.base_path)?;
+
+        let mut report = Report::default();
+        let live_path = self.live_path();
+        if live_path.is_dir() {
+            self.check_tree(&live_path, &live_path, &mut report)?;
+        } else {
+            self.found(&live_path, ProblemKind::MissingLive, false, &mut report);
+        }
+
+        let pending_path = self.base_path.join("pending");
+        match fs::read_dir(&pending_path) {
+            Ok(entries) => {
+                for entry in entries {
+                    let entry = entry.context(error::IoSnafu {
+                        path: &pending_path,
+                    })?;
+                    self.check_transaction(&entry.path(), &mut report)?;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e).context(error::IoSnafu { path: pending_path }),
+        }
+
+        info!(
+            "Found {} problems in data store at {}",
+            report.problems.len(),
+            self.base_path.display()
+        );
+        Ok(report)
+    }
+
+    fn live_path(&self) -> PathBuf {
+        self.base_path.join("live")
+    }
+
+    /// Checks a single pending transaction directory.
+    fn check_transaction(&self, path: &Path, report: &mut Report) -> Result<()> {
+        let orphaned = |reason: &str| ProblemKind::OrphanedTransaction {
+            reason: reason.to_string(),
+        };
+
+        let file_type = fs::symlink_metadata(path)
+            .context(error::IoSnafu { path })?
+            .file_type();
+        if !file_type.is_dir() {
+            return self.quarantine(path, orphaned("not a directory"), report);
+        }
+        let name_valid = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .map(|name| decode_path_component(name, path).is_ok())
+            .unwrap_or(false);
+        if !name_valid {
+            return self.quarantine(path, orphaned("invalid transaction name"), report);
+        }
+
+        if !self.check_tree(path, path, report)? {
+            return self.quarantine(path, orphaned("no keys or tombstones"), report);
+        }
+        Ok(())
+    }
+
+    /// Checks everything under the given directory, where `root` is the base of the live or
+    /// pending data.  Returns whether anything is left in the directory.
+    fn check_tree(&self, root: &Path, dir: &Path, report: &mut Report) -> Result<bool> {
+        let mut occupied = false;
+        for entry in fs::read_dir(dir).context(error::IoSnafu { path: dir })? {
+            let entry = entry.context(error::IoSnafu { path: dir })?;
+            let path = entry.path();
+            let file_type = entry.file_type().context(error::IoSnafu { path: &path })?;
+
+            let name = match entry.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => {
+                    self.quarantine(&path, ProblemKind::NonUnicodePath, report)?;
+                    continue;
+                }
+            };
+
+            let problem = if name.ends_with(TEMP_FILE_SUFFIX) {
+                Some(ProblemKind::StaleTempFile)
+            } else if file_type.is_dir() {
+                match decode_path_component(&name, &path) {
+                    Ok(_) => {
+                        if self.check_tree(root, &path, report)? {
+                            occupied = true;
+                        } else {
+                            self.remove_empty_dir(&path, report)?;
+                        }
+                        continue;
+                    }
+                    Err(_) => Some(ProblemKind::InvalidEncoding { segment: name }),
+                }
+            } else if !file_type.is_file() {
+                Some(ProblemKind::UnexpectedEntry)
+            } else if name == TOMBSTONES_FILE && dir == root && *root != self.live_path() {
+                // Directly under a pending transaction, the tombstones file is expected.
+                check_tombstones(&path)?
+            } else if name == CREATED_FILE && dir == root && *root != self.live_path() {
+                check_created(&path)?
+            } else if name == METADATA_TOMBSTONES_FILE && dir == root && *root != self.live_path() {
+                check_metadata_tombstones(&path)?
+            } else {
+                check_key_file(root, &path)?
+            };
+
+            match problem {
+                Some(kind) => self.quarantine(&path, kind, report)?,
+                None => occupied = true,
+            }
+        }
+        Ok(occupied)
+    }
+
+    /// Records a problem, and if repairing, moves the entry into the quarantine directory.
+    fn quarantine(&self, path: &Path, kind: ProblemKind, report: &mut Report) -> Result<()> {
+        let quarantine_path = match &self.quarantine_path {
+            Some(quarantine_path) => quarantine_path,
+            None => {
+                self.found(path, kind, false, report);
+                return Ok(());
+            }
+        };
+
+        let relative = path
+            .strip_prefix(&self.base_path)
+            .context(error::PathSnafu)?;
+        let target = quarantine_path.join(relative);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent).context(error::IoSnafu { path: parent })?;
+        }
+        fs::rename(path, &target).context(error::IoSnafu { path })?;
+        info!(
+            "Quarantined {} ({}) to {}",
+            path.display(),
+            kind,
+            target.display()
+        );
+        self.found(path, kind, true, report);
+        Ok(())
+    }
+
+    /// Records an empty directory, and if repairing, removes it.
+    fn remove_empty_dir(&self, path: &Path, report: &mut Report) -> Result<()> {
+        let repaired = self.quarantine_path.is_some();
+        if repaired {
+            fs::remove_dir(path).context(error::IoSnafu { path })?;
+            info!("Removed empty directory {}", path.display());
+        }
+        self.found(path, ProblemKind::EmptyDirectory, repaired, report);
+        Ok(())
+    }
+
+    fn found(&self, path: &Path, kind: ProblemKind, repaired: bool, report: &mut Report) {
+        warn!("{}: {}", path.display(), kind);
+        report.problems.push(Problem {
+            path: path.to_path_buf(),
+            kind,
+            repaired,
+        });
+    }
+}
+
+/// Checks the name and contents of a data or metadata file, returning any problem.
+fn check_key_file(root: &Path, path: &Path) -> Result<Option<ProblemKind>> {
+    let relative = path.strip_prefix(root).context(error::PathSnafu)?;
+    // We only get here with UTF-8 file names, and directories we've already checked.
+    let relative = match relative.to_str() {
+        Some(relative) => relative,
+        None => return Ok(Some(ProblemKind::NonUnicodePath)),
+    };
+
+    // Dots are always encoded in key paths, so the first one starts the metadata key.
+    let (data_part, metadata_part) = match relative.split_once(METADATA_KEY_PREFIX) {
+        Some((data, meta)) => (data, Some(meta)),
+        None => (relative, None),
+    };
+    // Long segments are split across components, so we join them before decoding.
+    let encoded = match join_segment_pieces(data_part.split(std::path::MAIN_SEPARATOR)) {
+        Some(encoded) => encoded,
+        None => {
+            return Ok(Some(ProblemKind::InvalidEncoding {
+                segment: data_part.to_string(),
+            }))
+        }
+    };
+    let mut segments = Vec::new();
+    for segment in encoded {
+        match decode_path_component(&segment, path) {
+            Ok(decoded) => segments.push(decoded),
+            Err(_) => return Ok(Some(ProblemKind::InvalidEncoding { segment })),
+        }
+    }
+    let data_key = Key::from_segments(KeyType::Data, &segments);
+
+    let bytes = fs::read(path).context(error::IoSnafu { path })?;
+    let value = match String::from_utf8(bytes) {
+        Ok(value) => value,
+        Err(_) => return Ok(Some(ProblemKind::NonUnicodeValue)),
+    };
+
+    match metadata_part {
+        None => {
+            if let Err(e) = data_key {
+                return Ok(Some(ProblemKind::InvalidDataKey { msg: e.to_string() }));
+            }
+            Ok(check_data_value(&value).map(|msg| ProblemKind::InvalidValue { msg }))
+        }
+        Some(metadata_name) => {
+            let metadata_key = decode_path_component(metadata_name, path)
+                .and_then(|name| Key::new(KeyType::Meta, name));
+            if let Err(e) = data_key.and(metadata_key) {
+                return Ok(Some(ProblemKind::InvalidMetadataKey { msg: e.to_string() }));
+            }
+            // Metadata values are arbitrary JSON, e.g. a setting-generator can be an object.
+            Ok(serde_json::from_str::<serde_json::Value>(&value)
+                .err()
+                .map(|e| ProblemKind::InvalidValue { msg: e.to_string() }))
+        }
+    }
+}
+
+/// Checks that a data value is a JSON scalar, or a list of them, returning a description of the
+/// problem if not.  Compound values are stored under separate keys, so objects are invalid.
+/// Encrypted values can't be checked without the key, so they're accepted as they are.
+fn check_data_value(value: &str) -> Option<String> {
+    use serde_json::Value;
+
+    if sensitive::is_encrypted(value) {
+        return None;
+    }
+
+    fn compound(value: &Value) -> bool {
+        match value {
+            Value::Object(_) => true,
+            Value::Array(elements) => elements.iter().any(compound),
+            _ => false,
+        }
+    }
+
+    match serde_json::from_str::<Value>(value) {
+        Ok(parsed) if compound(&parsed) => Some("value contains a JSON object".to_string()),
+        Ok(_) => None,
+        Err(e) => Some(e.to_string()),
+    }
+}
+
+/// Checks the tombstones file of a pending transaction, returning any problem.
+fn check_tombstones(path: &Path) -> Result<Option<ProblemKind>> {
+    let bytes = fs::read(path).context(error::IoSnafu { path })?;
+    let names: Vec<String> = match serde_json::from_slice(&bytes) {
+        Ok(names) => names,
+        Err(e) => return Ok(Some(ProblemKind::InvalidValue { msg: e.to_string() })),
+    };
+    for name in names {
+        if let Err(e) = Key::new(KeyType::Data, &name) {
+            return Ok(Some(ProblemKind::InvalidValue { msg: e.to_string() }));
+        }
+    }
+    debug!("Tombstones at {} are valid", path.display());
+    Ok(None)
+}
+
+/// Checks the metadata tombstones file of a pending transaction, returning any problem.
+fn check_metadata_tombstones(path: &Path) -> Result<Option<ProblemKind>> {
+    let bytes = fs::read(path).context(error::IoSnafu { path })?;
+    let names: HashMap<String, Vec<String>> = match serde_json::from_slice(&bytes) {
+        Ok(names) => names,
+        Err(e) => return Ok(Some(ProblemKind::InvalidValue { msg: e.to_string() })),
+    };
+    for (data_name, meta_names) in names {
+        let keys = Key::new(KeyType::Data, &data_name).and_then(|_| {
+            meta_names
+                .iter()
+                .try_for_each(|name| Key::new(KeyType::Meta, name).map(|_| ()))
+        });
+        if let Err(e) = keys {
+            return Ok(Some(ProblemKind::InvalidValue { msg: e.to_string() }));
+        }
+    }
+    debug!("Metadata tombstones at {} are valid", path.display());
+    Ok(None)
+}
+
+/// Checks the creation time file of a pending transaction, returning any problem.
+fn check_created(path: &Path) -> Result<Option<ProblemKind>> {
+    let bytes = fs::read(path).context(error::IoSnafu { path })?;
+    if let Err(e) = serde_json::from_slice::<SystemTime>(&bytes) {
+        return Ok(Some(ProblemKind::InvalidValue { msg: e.to_string() }));
+    }
+    debug!("Creation time at {} is valid", path.display());
+    Ok(None)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scratch::TestPath;
+    use crate::sensitive::SENSITIVE_METADATA_KEY;
+    use crate::{Committed, DataStore, EncryptionKey, FilesystemDataStore};
+    use std::os::unix::ffi::OsStrExt;
+
+    /// Returns the problem kinds in the report, by path relative to the given base.
+    fn kinds(report: &Report, base: &Path) -> Vec<(String, ProblemKind)> {
+        let mut kinds: Vec<_> = report
+            .problems
+            .iter()
+            .map(|p| {
+                let relative = p.path.strip_prefix(base).unwrap();
+                (relative.to_string_lossy().into_owned(), p.kind.clone())
+            })
+            .collect();
+        kinds.sort_by(|a, b| a.0.cmp(&b.0));
+        kinds
+    }
+
+    #[test]
+    fn clean_datastore() {
+        let dir = TestPath::new("clean");
+        let mut f = FilesystemDataStore::new(&dir.0).unwrap();
+        let key = Key::new(KeyType::Data, "settings.\"a.b\".c").unwrap();
+        let meta = Key::new(KeyType::Meta, "setting-generator").unwrap();
+        let pending = Committed::Pending { tx: "tx 1".into() };
+        f.set_key(&key, "\"x\"", &Committed::Live).unwrap();
+        f.set_key(&key, "[1, 2]", &pending).unwrap();
+        f.set_tombstone(&key, "tx 1").unwrap();
+        let services = Key::new(KeyType::Meta, "affected-services").unwrap();
+        f.unset_metadata(&services, &key, &pending).unwrap();
+        f.set_metadata(&meta, &key, "{\"command\": \"x\"}", &Committed::Live)
+            .unwrap();
+        f.set_metadata(&meta, &key, "{\"command\": \"y\"}", &pending)
+            .unwrap();
+
+        // Encrypted values aren't JSON, but they're expected.
+        let key_dir = TestPath::dir("clean-key");
+        let encryption_key = EncryptionKey::generate_file(key_dir.0.join("key")).unwrap();
+        let mut f = f.with_encryption_key(encryption_key);
+        let secret = Key::new(KeyType::Data, "settings.secret").unwrap();
+        let sensitive = Key::new(KeyType::Meta, SENSITIVE_METADATA_KEY).unwrap();
+        f.set_metadata(&sensitive, &secret, "true", &Committed::Live)
+            .unwrap();
+        f.set_key(&secret, "\"hunter2\"", &Committed::Live).unwrap();
+
+        let report = Checker::new(&dir.0).run().unwrap();
+        assert!(report.is_clean(), "{:?}", report);
+    }
+
+    #[test]
+    fn finds_and_quarantines_problems() {
+        let dir = TestPath::new("problems");
+        let base = &dir.0;
+        FilesystemDataStore::new(base).unwrap();
+        let live = base.join("live");
+        let settings = live.join("settings");
+        fs::create_dir_all(settings.join("empty")).unwrap();
+        fs::write(settings.join("good"), "1").unwrap();
+        fs::write(settings.join("object"), "{\"a\": 1}").unwrap();
+        fs::write(settings.join("bad-json"), "not json").unwrap();
+        fs::write(settings.join("bad%FF"), "1").unwrap();
+        fs::write(settings.join("good.bad%20meta"), "1").unwrap();
+        fs::write(settings.join("good~tmp"), "1").unwrap();
+        fs::write(
+            settings.join(std::ffi::OsStr::from_bytes(b"non-utf8-\xff")),
+            "1",
+        )
+        .unwrap();
+        fs::write(settings.join("binary"), b"\xff\xfe").unwrap();
+        fs::create_dir_all(base.join("pending/empty-tx")).unwrap();
+
+        let report = Checker::new(base).run().unwrap();
+        let expected = vec![
+            (
+                "live/settings/bad%FF".to_string(),
+                ProblemKind::InvalidEncoding {
+                    segment: "bad%FF".to_string(),
+                },
+            ),
+            (
+                "live/settings/bad-json".to_string(),
+                ProblemKind::InvalidValue {
+                    msg: "expected ident at line 1 column 2".to_string(),
+                },
+            ),
+            (
+                "live/settings/binary".to_string(),
+                ProblemKind::NonUnicodeValue,
+            ),
+            (
+                "live/settings/empty".to_string(),
+                ProblemKind::EmptyDirectory,
+            ),
+            (
+                "live/settings/good~tmp".to_string(),
+                ProblemKind::StaleTempFile,
+            ),
+            (
+                "live/settings/non-utf8-\u{fffd}".to_string(),
+                ProblemKind::NonUnicodePath,
+            ),
+            (
+                "live/settings/object".to_string(),
+                ProblemKind::InvalidValue {
+                    msg: "value contains a JSON object".to_string(),
+                },
+            ),
+            (
+                "pending/empty-tx".to_string(),
+                ProblemKind::OrphanedTransaction {
+                    reason: "no keys or tombstones".to_string(),
+                },
+            ),
+        ];
+        let found = kinds(&report, base);
+        // The metadata key error message comes from Key, so just check the kind.
+        assert!(found
+            .iter()
+            .any(|(path, kind)| path == "live/settings/good.bad%20meta"
+                && matches!(kind, ProblemKind::InvalidMetadataKey { .. })));
+        let found: Vec<_> = found
+            .into_iter()
+            .filter(|(path, _)| path != "live/settings/good.bad%20meta")
+            .collect();
+        assert_eq!(found, expected);
+        assert!(report.problems.iter().all(|p| !p.repaired));
+
+        let quarantine = base.join("quarantine");
+        let report = Checker::new(base).repair(&quarantine).run().unwrap();
+        assert!(report.problems.iter().all(|p| p.repaired));
+        assert!(quarantine.join("live/settings/object").exists());
+        assert!(quarantine.join("pending/empty-tx").exists());
+        assert!(!settings.join("empty").exists());
+        assert!(settings.join("good").exists());
+
+        assert!(Checker::new(base).run().unwrap().is_clean());
+    }
+}
@@ -0,0 +1,241 @@
+//! The journal is an append-only record of changes made to live data and metadata, for auditing.
+//!
+//! Implementations of DataStore add entries when transactions are committed, generations are
+//! reverted, and metadata is set or unset.  Entries can be read back with
+//! `DataStore::read_journal`, filtered by key prefix and time range.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+use super::{Key, KeyType, Result};
+
+/// Operation is the kind of change that a journal entry records.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Operation {
+    /// A pending transaction was committed to live data.
+    Commit,
+    /// Live data was reverted to an earlier generation.
+    Revert,
+    /// Metadata was set on a data key.
+    SetMetadata,
+    /// Metadata was removed from a data key.
+    UnsetMetadata,
+}
+
+/// JournalEntry records the change of a single data or metadata key.
+#[derive(Debug, Clone, PartialEq)]
+pub struct JournalEntry {
+    /// When the change was made.
+    pub timestamp: SystemTime,
+    /// The transaction that was committed, if the change came from one.
+    pub transaction: Option<String>,
+    pub operation: Operation,
+    /// The data key that changed, or that the metadata key belongs to.
+    pub data_key: Key,
+    /// The metadata key that changed, for metadata operations.
+    pub metadata_key: Option<Key>,
+    /// The serialized value before the change; None means it wasn't set.
+    pub old_value: Option<String>,
+    /// The serialized value after the change; None means it was removed.
+    pub new_value: Option<String>,
+}
+
+/// JournalFilter selects journal entries when reading the journal.  By default, every entry
+/// matches.
+#[derive(Debug, Clone, Default)]
+pub struct JournalFilter {
+    prefix: Option<String>,
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+}
+
+impl JournalFilter {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Only match entries whose data key names start with the given prefix.
+    pub fn prefix<S: Into<String>>(mut self, prefix: S) -> Self {
+        self.prefix = Some(prefix.into());
+        self
+    }
+
+    /// Only match entries made at or after the given time.
+    pub fn since(mut self, since: SystemTime) -> Self {
+        self.since = Some(since);
+        self
+    }
+
+    /// Only match entries made before the given time.
+    pub fn until(mut self, until: SystemTime) -> Self {
+        self.until = Some(until);
+        self
+    }
+
+    /// Returns whether the given entry is selected by this filter.
+    pub fn matches(&self, entry: &JournalEntry) -> bool {
+        if let Some(prefix) = &self.prefix {
+            if !entry.data_key.name().starts_with(prefix.as_str()) {
+                return false;
+            }
+        }
+        if let Some(since) = self.since {
+            if entry.timestamp < since {
+                return false;
+            }
+        }
+        if let Some(until) = self.until {
+            if entry.timestamp >= until {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Builds the journal entries for a change to live data, given the prior and new values of the
+/// changed data keys.  Entries are sorted by key name so the journal reads predictably.
+pub(crate) fn data_entries(
+    timestamp: SystemTime,
+    transaction: Option<&str>,
+    operation: Operation,
+    prior: &HashMap<Key, Option<String>>,
+    changes: &HashMap<Key, Option<String>>,
+) -> Vec<JournalEntry> {
+    let mut entries: Vec<JournalEntry> = changes
+        .iter()
+        .map(|(key, new_value)| JournalEntry {
+            timestamp,
+            transaction: transaction.map(str::to_string),
+            operation,
+            data_key: key.clone(),
+            metadata_key: None,
+            old_value: prior.get(key).cloned().flatten(),
+            new_value: new_value.clone(),
+        })
+        .collect();
+    entries.sort_by(|a, b| a.data_key.name().cmp(b.data_key.name()));
+    entries
+}
+
+/// Builds the journal entry for a change to live metadata, either made directly or by committing
+/// the given transaction.
+pub(crate) fn metadata_entry(
+    timestamp: SystemTime,
+    transaction: Option<&str>,
+    metadata_key: &Key,
+    data_key: &Key,
+    old_value: Option<String>,
+    new_value: Option<String>,
+) -> JournalEntry {
+    let operation = if new_value.is_some() {
+        Operation::SetMetadata
+    } else {
+        Operation::UnsetMetadata
+    };
+    JournalEntry {
+        timestamp,
+        transaction: transaction.map(str::to_string),
+        operation,
+        data_key: data_key.clone(),
+        metadata_key: Some(metadata_key.clone()),
+        old_value,
+        new_value,
+    }
+}
+
+/// JournalRecord is the serialized form of a JournalEntry.  Keys are stored by name because Key
+/// can't be deserialized without knowing its type.
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct JournalRecord {
+    timestamp: SystemTime,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    transaction: Option<String>,
+    operation: Operation,
+    data_key: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    metadata_key: Option<String>,
+    old_value: Option<String>,
+    new_value: Option<String>,
+}
+
+impl From<&JournalEntry> for JournalRecord {
+    fn from(entry: &JournalEntry) -> Self {
+        Self {
+            timestamp: entry.timestamp,
+            transaction: entry.transaction.clone(),
+            operation: entry.operation,
+            data_key: entry.data_key.name().clone(),
+            metadata_key: entry.metadata_key.as_ref().map(|k| k.name().clone()),
+            old_value: entry.old_value.clone(),
+            new_value: entry.new_value.clone(),
+        }
+    }
+}
+
+impl JournalRecord {
+    pub(crate) fn into_entry(self) -> Result<JournalEntry> {
+        let metadata_key = match self.metadata_key {
+            Some(name) => Some(Key::new(KeyType::Meta, name)?),
+            None => None,
+        };
+        Ok(JournalEntry {
+            timestamp: self.timestamp,
+            transaction: self.transaction,
+            operation: self.operation,
+            data_key: Key::new(KeyType::Data, self.data_key)?,
+            metadata_key,
+            old_value: self.old_value,
+            new_value: self.new_value,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use maplit::hashmap;
+    use std::time::Duration;
+
+    #[test]
+    fn filter() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let a = Key::new(KeyType::Data, "settings.a.b").unwrap();
+        let b = Key::new(KeyType::Data, "settings.b").unwrap();
+        let changes = hashmap!(a => Some("1".to_string()), b => None);
+        let entries = data_entries(start, Some("tx"), Operation::Commit, &hashmap!(), &changes);
+
+        let prefix = JournalFilter::new().prefix("settings.a");
+        let matched: Vec<_> = entries.iter().filter(|e| prefix.matches(e)).collect();
+        assert_eq!(matched.len(), 1);
+        assert_eq!(matched[0].data_key.name(), "settings.a.b");
+
+        let later = JournalFilter::new().since(start + Duration::from_secs(1));
+        assert!(!entries.iter().any(|e| later.matches(e)));
+        let window = JournalFilter::new()
+            .since(start)
+            .until(start + Duration::from_secs(1));
+        assert!(entries.iter().all(|e| window.matches(e)));
+    }
+
+    #[test]
+    fn record_round_trip() {
+        let data_key = Key::new(KeyType::Data, "settings.a").unwrap();
+        let metadata_key = Key::new(KeyType::Meta, "affected-services").unwrap();
+        let entry = metadata_entry(
+            SystemTime::now(),
+            Some("tx"),
+            &metadata_key,
+            &data_key,
+            None,
+            Some("[]".to_string()),
+        );
+        assert_eq!(entry.operation, Operation::SetMetadata);
+
+        let record_str = serde_json::to_string(&JournalRecord::from(&entry)).unwrap();
+        let record: JournalRecord = serde_json::from_str(&record_str).unwrap();
+        assert_eq!(record.into_entry().unwrap(), entry);
+    }
+}
@@ -12,9 +12,9 @@ pub const KEY_SEPARATOR: char = '.';
 // String refs are more convenient for some Rust functions
 pub const KEY_SEPARATOR_STR: &str = ".";
 
-/// Maximum key name length matches the maximum filename length of 255; if we need to have longer
-/// keys (up to 4096) we could make prefixes not count against this limit.
-const MAX_KEY_NAME_LENGTH: usize = 255;
+/// Maximum key name length matches the usual maximum path length of 4096.  FilesystemDataStore
+/// splits segments that are too long for a file name across path components.
+const MAX_KEY_NAME_LENGTH: usize = 4096;
 
 /// KeyType represents whether we want to check a Key as a data key or metadata key.
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
@@ -231,7 +231,7 @@ impl Key {
 
     /// Determines whether a character is acceptable within a segment of a key name.  This is
     /// separate from quoting; if a character isn't valid, it isn't valid quoted, either.
-    fn valid_character(c: char) -> bool {
+    pub(crate) fn valid_character(c: char) -> bool {
         matches!(c, 'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '/')
     }
 
@@ -242,6 +242,15 @@ impl Key {
     /// * a.b.c -> ["a", "b", "c"]
     /// * "a.b".c -> ["a.b", "c"]
     fn parse_name_segments<S: AsRef<str>>(name: S) -> Result<Vec<String>> {
+        Self::parse_segments(name, Self::valid_character)
+    }
+
+    /// Parses a name into segments like `parse_name_segments`, accepting the characters allowed
+    /// by the given check.  This lets patterns share the quoting rules of key names.
+    pub(crate) fn parse_segments<S: AsRef<str>>(
+        name: S,
+        valid_character: fn(char) -> bool,
+    ) -> Result<Vec<String>> {
         let name = name.as_ref();
 
         ensure!(
@@ -284,7 +293,7 @@ impl Key {
                 }
             } else {
                 // Not a special character; make sure it's a valid part of a name segment.
-                if Self::valid_character(c) {
+                if valid_character(c) {
                     segment.push(c);
                 } else {
                     return error::InvalidKeySnafu {
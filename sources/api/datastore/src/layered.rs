@@ -0,0 +1,476 @@
+//! This module provides a DataStore made of layers, so we can tell where each value came from:
+//! for example, variant defaults at the bottom, generated values above them, and the values set
+//! by the user on top.
+//!
+//! Only the top layer is writable.  Live reads resolve top-down, so a value in a higher layer
+//! overrides the same key in lower layers, and `get_prefix_with_provenance` reports which layer
+//! supplied each value.  Writes, pending transactions, commits, generations, and the journal all
+//! belong to the top layer; lower layers don't have pending transactions, so pending reads only
+//! look at the top layer.
+//!
+//! Removing a key from the top layer's live data - with `reset_to_default`, `unset_key`, or a
+//! committed tombstone - removes the override, so the value from the layers below shows through
+//! again.  Keys can't be removed from lower layers.
+//!
+//! Metadata is layered the same way.
+
+use log::debug;
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+
+use super::journal::{JournalEntry, JournalFilter};
+use super::{tombstoned_keys, Committed, DataStore, Generation, Key, KeyPattern, Result};
+
+/// Layer is a named data store in a LayeredDataStore.
+#[derive(Debug)]
+struct Layer<D> {
+    name: String,
+    datastore: D,
+}
+
+/// SourcedValue is a value along with the name of the layer that supplied it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourcedValue {
+    pub value: String,
+    pub layer: String,
+}
+
+/// LayeredDataStore stacks read-only layers of type L beneath a writable top layer of type T.
+#[derive(Debug)]
+pub struct LayeredDataStore<T, L = T> {
+    top: Layer<T>,
+    // Read-only layers, lowest first.
+    layers: Vec<Layer<L>>,
+}
+
+impl<T: DataStore, L: DataStore> LayeredDataStore<T, L> {
+    /// Creates a layered data store with the given writable top layer and no other layers.
+    pub fn new<S: Into<String>>(name: S, top: T) -> Self {
+        Self {
+            top: Layer {
+                name: name.into(),
+                datastore: top,
+            },
+            layers: Vec::new(),
+        }
+    }
+
+    /// Adds a read-only layer above the layers added so far, and below the top layer.
+    pub fn with_layer<S: Into<String>>(mut self, name: S, layer: L) -> Self {
+        self.layers.push(Layer {
+            name: name.into(),
+            datastore: layer,
+        });
+        self
+    }
+
+    /// Returns the names of the layers, top first.
+    pub fn layer_names(&self) -> Vec<&str> {
+        let mut names = vec![self.top.name.as_str()];
+        names.extend(self.layers.iter().rev().map(|layer| layer.name.as_str()));
+        names
+    }
+
+    /// Returns the writable top layer.
+    pub fn top(&self) -> &T {
+        &self.top.datastore
+    }
+
+    /// Returns the name of the layer that supplies the live value of the given key, if any layer
+    /// has it.
+    pub fn provenance(&self, key: &Key) -> Result<Option<&str>> {
+        if self.top.datastore.key_populated(key, &Committed::Live)? {
+            return Ok(Some(&self.top.name));
+        }
+        for layer in self.layers.iter().rev() {
+            if layer.datastore.key_populated(key, &Committed::Live)? {
+                return Ok(Some(&layer.name));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Retrieves the live values of all keys starting with the given prefix, like `get_prefix`,
+    /// along with the name of the layer that supplied each one.
+    pub fn get_prefix_with_provenance<S: AsRef<str>>(
+        &self,
+        find_prefix: S,
+    ) -> Result<HashMap<Key, SourcedValue>> {
+        let mut result = HashMap::new();
+        // Lower layers go first, so higher layers replace their values.
+        for layer in &self.layers {
+            let values = layer.datastore.get_prefix(&find_prefix, &Committed::Live)?;
+            insert_sourced(&mut result, values, &layer.name);
+        }
+        let values = self
+            .top
+            .datastore
+            .get_prefix(&find_prefix, &Committed::Live)?;
+        insert_sourced(&mut result, values, &self.top.name);
+        Ok(result)
+    }
+
+    /// Removes the top layer's live values for the given key and any keys under it, so the values
+    /// from lower layers apply again.  Returns the removed keys.
+    pub fn reset_to_default(&mut self, key: &Key) -> Result<HashSet<Key>> {
+        let live_keys = self
+            .top
+            .datastore
+            .list_populated_keys(key.name(), &Committed::Live)?;
+        let removed = tombstoned_keys(&live_keys, &HashSet::from([key.clone()]));
+        debug!(
+            "Resetting {} keys under {} in layer '{}'",
+            removed.len(),
+            key,
+            self.top.name
+        );
+        self.top.datastore.unset_keys(&removed, &Committed::Live)?;
+        Ok(removed)
+    }
+
+    /// Returns the results of the given query on each layer that's visible with the given
+    /// Committed, lowest first: every layer for live data, and just the top layer for pending.
+    fn query_layers<R, FT, FL>(&self, committed: &Committed, top: FT, lower: FL) -> Result<Vec<R>>
+    where
+        FT: Fn(&T) -> Result<R>,
+        FL: Fn(&L) -> Result<R>,
+    {
+        let mut results = Vec::new();
+        if *committed == Committed::Live {
+            for layer in &self.layers {
+                results.push(lower(&layer.datastore)?);
+            }
+        }
+        results.push(top(&self.top.datastore)?);
+        Ok(results)
+    }
+}
+
+/// Adds values from the given layer to the result map, replacing values from lower layers.
+fn insert_sourced(
+    result: &mut HashMap<Key, SourcedValue>,
+    values: HashMap<Key, String>,
+    layer: &str,
+) {
+    for (key, value) in values {
+        let sourced = SourcedValue {
+            value,
+            layer: layer.to_string(),
+        };
+        result.insert(key, sourced);
+    }
+}
+
+/// Merges the populated metadata listed by each layer.
+fn merge_metadata(results: Vec<HashMap<Key, HashSet<Key>>>) -> HashMap<Key, HashSet<Key>> {
+    let mut merged: HashMap<Key, HashSet<Key>> = HashMap::new();
+    for (data_key, meta_keys) in results.into_iter().flatten() {
+        merged.entry(data_key).or_default().extend(meta_keys);
+    }
+    merged
+}
+
+// Reads of live data merge the layers, with higher layers winning; everything else goes to the
+// top layer.
+impl<T: DataStore, L: DataStore> DataStore for LayeredDataStore<T, L> {
+    fn key_populated(&self, key: &Key, committed: &Committed) -> Result<bool> {
+        let results = self.query_layers(
+            committed,
+            |top| top.key_populated(key, committed),
+            |layer| layer.key_populated(key, &Committed::Live),
+        )?;
+        Ok(results.into_iter().any(|populated| populated))
+    }
+
+    fn list_populated_keys<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        let results = self.query_layers(
+            committed,
+            |top| top.list_populated_keys(&prefix, committed),
+            |layer| layer.list_populated_keys(&prefix, &Committed::Live),
+        )?;
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    fn list_populated_metadata<S1, S2>(
+        &self,
+        prefix: S1,
+        committed: &Committed,
+        metadata_key_name: &Option<S2>,
+    ) -> Result<HashMap<Key, HashSet<Key>>>
+    where
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        let results = self.query_layers(
+            committed,
+            |top| top.list_populated_metadata(&prefix, committed, metadata_key_name),
+            |layer| layer.list_populated_metadata(&prefix, &Committed::Live, metadata_key_name),
+        )?;
+        Ok(merge_metadata(results))
+    }
+
+    fn get_key(&self, key: &Key, committed: &Committed) -> Result<Option<String>> {
+        let results = self.query_layers(
+            committed,
+            |top| top.get_key(key, committed),
+            |layer| layer.get_key(key, &Committed::Live),
+        )?;
+        Ok(results.into_iter().flatten().last())
+    }
+
+    fn set_key<S: AsRef<str>>(&mut self, key: &Key, value: S, committed: &Committed) -> Result<()> {
+        self.top.datastore.set_key(key, value, committed)
+    }
+
+    fn unset_key(&mut self, key: &Key, committed: &Committed) -> Result<()> {
+        self.top.datastore.unset_key(key, committed)
+    }
+
+    fn get_metadata_raw(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>> {
+        let results = self.query_layers(
+            committed,
+            |top| top.get_metadata_raw(metadata_key, data_key, committed),
+            |layer| layer.get_metadata_raw(metadata_key, data_key, &Committed::Live),
+        )?;
+        Ok(results.into_iter().flatten().last())
+    }
+
+    fn set_metadata<S: AsRef<str>>(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        value: S,
+        committed: &Committed,
+    ) -> Result<()> {
+        self.top
+            .datastore
+            .set_metadata(metadata_key, data_key, value, committed)
+    }
+
+    fn unset_metadata(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<()> {
+        self.top
+            .datastore
+            .unset_metadata(metadata_key, data_key, committed)
+    }
+
+    fn set_tombstone<S>(&mut self, key: &Key, transaction: S) -> Result<()>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        self.top.datastore.set_tombstone(key, transaction)
+    }
+
+    fn list_tombstones<S: AsRef<str>>(&self, transaction: S) -> Result<HashSet<Key>> {
+        self.top.datastore.list_tombstones(transaction)
+    }
+
+    fn list_metadata_tombstones<S: AsRef<str>>(
+        &self,
+        transaction: S,
+    ) -> Result<HashMap<Key, HashSet<Key>>> {
+        self.top.datastore.list_metadata_tombstones(transaction)
+    }
+
+    fn commit_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        self.top.datastore.commit_transaction(transaction)
+    }
+
+    fn delete_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        self.top.datastore.delete_transaction(transaction)
+    }
+
+    fn list_transactions(&self) -> Result<HashSet<String>> {
+        self.top.datastore.list_transactions()
+    }
+
+    fn transaction_created<S: AsRef<str>>(&self, transaction: S) -> Result<Option<SystemTime>> {
+        self.top.datastore.transaction_created(transaction)
+    }
+
+    fn list_generations(&self) -> Result<Vec<Generation>> {
+        self.top.datastore.list_generations()
+    }
+
+    fn revert_to_generation(&mut self, id: u64) -> Result<HashSet<Key>> {
+        self.top.datastore.revert_to_generation(id)
+    }
+
+    fn read_journal(&self, filter: &JournalFilter) -> Result<Vec<JournalEntry>> {
+        self.top.datastore.read_journal(filter)
+    }
+
+    fn get_prefix<S: AsRef<str>>(
+        &self,
+        find_prefix: S,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, String>> {
+        let results = self.query_layers(
+            committed,
+            |top| top.get_prefix(&find_prefix, committed),
+            |layer| layer.get_prefix(&find_prefix, &Committed::Live),
+        )?;
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    fn list_matching_keys(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        let results = self.query_layers(
+            committed,
+            |top| top.list_matching_keys(pattern, committed),
+            |layer| layer.list_matching_keys(pattern, &Committed::Live),
+        )?;
+        Ok(results.into_iter().flatten().collect())
+    }
+
+    fn list_matching_metadata<S>(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+        metadata_key_name: &Option<S>,
+    ) -> Result<HashMap<Key, HashSet<Key>>>
+    where
+        S: AsRef<str>,
+    {
+        let results = self.query_layers(
+            committed,
+            |top| top.list_matching_metadata(pattern, committed, metadata_key_name),
+            |layer| layer.list_matching_metadata(pattern, &Committed::Live, metadata_key_name),
+        )?;
+        Ok(merge_metadata(results))
+    }
+
+    fn get_matching(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, String>> {
+        let results = self.query_layers(
+            committed,
+            |top| top.get_matching(pattern, committed),
+            |layer| layer.get_matching(pattern, &Committed::Live),
+        )?;
+        Ok(results.into_iter().flatten().collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryDataStore;
+    use crate::KeyType;
+    use maplit::hashmap;
+
+    fn key(name: &str) -> Key {
+        Key::new(KeyType::Data, name).unwrap()
+    }
+
+    fn layered() -> LayeredDataStore<MemoryDataStore> {
+        let mut defaults = MemoryDataStore::new();
+        let mut generated = MemoryDataStore::new();
+        let services = Key::new(KeyType::Meta, "affected-services").unwrap();
+        for (name, value) in [
+            ("settings.a", "1"),
+            ("settings.b", "1"),
+            ("settings.c", "1"),
+        ] {
+            defaults
+                .set_key(&key(name), value, &Committed::Live)
+                .unwrap();
+        }
+        defaults
+            .set_metadata(&services, &key("settings"), "[\"a\"]", &Committed::Live)
+            .unwrap();
+        generated
+            .set_key(&key("settings.b"), "2", &Committed::Live)
+            .unwrap();
+        LayeredDataStore::new("user", MemoryDataStore::new())
+            .with_layer("defaults", defaults)
+            .with_layer("generated", generated)
+    }
+
+    #[test]
+    fn reads_resolve_top_down() {
+        let mut l = layered();
+        assert_eq!(l.layer_names(), vec!["user", "generated", "defaults"]);
+        l.set_key(&key("settings.c"), "3", &Committed::Live)
+            .unwrap();
+
+        assert_eq!(
+            l.get_prefix_with_provenance("settings.").unwrap(),
+            hashmap!(
+                key("settings.a") => SourcedValue { value: "1".into(), layer: "defaults".into() },
+                key("settings.b") => SourcedValue { value: "2".into(), layer: "generated".into() },
+                key("settings.c") => SourcedValue { value: "3".into(), layer: "user".into() },
+            )
+        );
+        assert_eq!(
+            l.get_key(&key("settings.b"), &Committed::Live).unwrap(),
+            Some("2".to_string())
+        );
+        assert_eq!(l.provenance(&key("settings.c")).unwrap(), Some("user"));
+        assert_eq!(l.provenance(&key("settings.d")).unwrap(), None);
+        assert_eq!(l.get_prefix("", &Committed::Live).unwrap().len(), 3);
+
+        // Lower layers' metadata is inherited too.
+        let services = Key::new(KeyType::Meta, "affected-services").unwrap();
+        assert_eq!(
+            l.get_metadata(&services, &key("settings.c"), &Committed::Live)
+                .unwrap(),
+            Some("[\"a\"]".to_string())
+        );
+    }
+
+    #[test]
+    fn writes_go_to_the_top() {
+        let mut l = layered();
+        let pending = Committed::Pending { tx: "tx".into() };
+        l.set_key(&key("settings.a"), "5", &pending).unwrap();
+        assert_eq!(l.get_prefix("", &pending).unwrap().len(), 1);
+        assert_eq!(
+            l.diff_transaction("tx", "").unwrap().changed[&key("settings.a")].old,
+            "1"
+        );
+        l.commit_transaction("tx").unwrap();
+        assert_eq!(l.provenance(&key("settings.a")).unwrap(), Some("user"));
+        assert_eq!(
+            l.top()
+                .get_key(&key("settings.a"), &Committed::Live)
+                .unwrap(),
+            Some("5".to_string())
+        );
+
+        // Resetting removes the override, and defaults show through again.
+        assert_eq!(
+            l.reset_to_default(&key("settings")).unwrap(),
+            HashSet::from([key("settings.a")])
+        );
+        assert_eq!(
+            l.get_key(&key("settings.a"), &Committed::Live).unwrap(),
+            Some("1".to_string())
+        );
+        assert_eq!(l.provenance(&key("settings.a")).unwrap(), Some("defaults"));
+    }
+}
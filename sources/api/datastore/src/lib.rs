@@ -20,28 +20,170 @@ The `serialization` module provides code to serialize Rust types into a mapping
 
 The `deserialization` module provides code to deserialize datastore-acceptable keys (a.b.c) and values into Rust types.
 
+# Typed access
+
+`DataStoreExt`, implemented for every DataStore, reads and writes typed values so callers don't have to serialize them.
+`get_typed` and `set_typed` handle single keys, and `get_struct` and `set_struct` handle whole structures under a prefix.
+Deserialization errors name the key whose value couldn't be deserialized.
+
+# Key patterns
+
+`KeyPattern` selects keys segment by segment, with the same quoting rules as key names.
+`*` matches any one segment, and `**` matches any number of segments, so `settings.host-containers.*.enabled` selects the `enabled` setting of every host container.
+`list_matching_keys`, `get_matching`, `list_matching_metadata`, and `get_matching_metadata` are the pattern equivalents of the prefix queries.
+
+# Transactional metadata
+
+Metadata, like data, can be set and removed in a pending transaction, so changes to metadata like `affected-services` can be staged and committed along with the data they describe.
+Reading metadata from a pending transaction only returns the metadata set in that transaction.
+Removing metadata in a pending transaction records a metadata tombstone, listed by `list_metadata_tombstones`.
+Committing the transaction applies its metadata and metadata tombstones to live metadata, and deleting it discards them.
+
+# Tombstones
+
+Pending transactions can remove keys as well as set them.
+`set_tombstone` records that a key, and any keys under it, should be removed from live data when the transaction is committed.
+Tombstones are applied before pending keys are set, so a transaction can replace a whole subtree.
+
+# Reviewing transactions
+
+`diff_transaction` compares a pending transaction with live data and returns a `TransactionDiff` listing the keys that committing it would add, change (with old and new values), and remove, along with the metadata that applies to them, like `affected-services`.
+
+# Transaction expiry
+
+Each pending transaction records when it was created, meaning when something was first written to it, and `transaction_created` returns it.
+The `expiry` module's `list_transaction_info` lists pending transactions with their age and number of keys, and `expire_transactions` deletes the ones older than a given age, or with `dry_run`, just reports them.
+This cleans up transactions left behind by clients that were interrupted before committing.
+
+# Generations
+
+Each commit that changes live data creates a new generation, numbered in increasing order, that records the prior values of the keys it changed.
+`list_generations` lists them, and `revert_to_generation` restores live data to the state it was in after the given generation was committed, forgetting the generations after it.
+(Reverting to generation 0 restores the state before the oldest retained commit, if that's still the first one.)
+
+Only the most recent generations are retained - `DEFAULT_GENERATION_LIMIT` by default, or whatever's given to the implementation's `with_generation_limit`.
+Metadata isn't part of generations, so reverting leaves it as it is; its changes are still in the journal.
+
+# Journal
+
+Changes to live data and metadata are recorded in an append-only journal, with the transaction, time, operation, and old and new serialized values of each changed key.
+`read_journal` returns the entries matching a `JournalFilter`, which can select a key prefix and a time range.
+
+# Locking
+
+The `lock` module provides `LockedDataStore`, which wraps any DataStore and takes an advisory file lock for each operation - shared for reads, exclusive for writes - so multiple processes can safely use the same data store.
+For multi-step operations, `lock_shared` and `lock_exclusive` return a guard that holds the lock and gives access to the underlying data store.
+`LockedDataStore::open` opens a `FilesystemDataStore` with a lock file inside it, first completing any interrupted commit and upgrading an older format while holding the exclusive lock.
+
+# Change notifications
+
+The `observe` module provides `ObservedDataStore`, which wraps any DataStore and notifies in-process subscribers after each commit or revert that changes keys under their prefix.
+Subscribers receive a `ChangeEvent` over a channel with the changed keys, and their new values if they asked with `subscribe_with_values`.
+
+`FilesystemDataStore` also counts the commits and reverts that change live data in a change-counter file.
+The `feed` module's `ChangeFeed` polls that file, so other processes can watch for changes without taking a lock or walking the data store.
+
+# Layered data stores
+
+`LayeredDataStore` stacks read-only layers, like variant defaults and generated values, beneath a writable top layer holding the values set by the user.
+Live reads resolve top-down, and `get_prefix_with_provenance` and `provenance` report which layer supplied each value.
+Writes, transactions, and history belong to the top layer, and `reset_to_default` removes the top layer's overrides so lower layers' values apply again.
+
+# Log data store
+
+`LogDataStore` keeps the whole data store in a single append-only log file, with an in-memory index for reads, so prefix queries don't have to walk a directory tree.
+Each record is checksummed and synced before it's applied; a partial record at the end of the log, from an interrupted write, is truncated.
+A damaged record followed by intact ones makes opening the log fail; `LogDataStore::open_salvaging` saves such records to a quarantine directory and loads the rest.
+The log is compacted each time it's opened.
+
+The `convert` module copies live data, pending transactions, and metadata between any two data stores, and the `datastore-convert` binary uses it to convert between the filesystem and log formats.
+History (generations and the journal) isn't copied.
+`cargo bench` compares `get_prefix` latency of the two formats.
+
+# Format versions
+
+`FilesystemDataStore` records its on-disk format version in a `format-version` file, written by `FilesystemDataStore::create`; data stores without one have the original layout, version 1.
+Opening a data store with a newer format version than the code supports fails with an error naming the versions.
+`FilesystemDataStore::new` never changes the data store, so it fails if the data store needs an upgrade or has an interrupted commit to complete; `LockedDataStore::open` does both while holding the exclusive lock, before opening it.
+Tools that only read a data store can use `FilesystemDataStore::open_read_only`, which fails the same way, and writes through it fail.
+The `format` module keeps the registry of format upgrades, each with a matching downgrade, and `set_format_version` moves a data store to a given version, e.g. before rolling back to older software.
+Version 2 moves keys whose segments need splitting to leave room for suffixes; downgrading to version 1 is refused while the data store has split segments, encrypted values, tombstones, or an interrupted commit, since older software would misread them.
+Format upgrades are separate from settings migrations: they change how a data store is laid out on disk, not the settings in it.
+
+# Integrity checking
+
+The `fsck` module checks the on-disk structure of a `FilesystemDataStore` for problems like non-UTF-8 files, undecodable key paths, invalid values, empty directories, and orphaned pending transactions.
+With `Checker::repair`, bad entries are moved into a quarantine directory.
+The `datastore-fsck` binary runs the check, for use at boot or when gathering support information.
+
+# Snapshots
+
+The `snapshot` module captures a whole data store - live data, pending transactions, and metadata - into a `Snapshot` that can be written as JSON or TOML, with an embedded format version, and restored into an empty data store elsewhere.
+The `datastore-snapshot` binary exports and imports snapshots of a `FilesystemDataStore`.
+
+# Sensitive values
+
+Keys like private keys, tokens, and passwords can be marked sensitive by setting their `sensitive` metadata to `true`, which also covers the keys under them.
+Given an `EncryptionKey` read from a key file, `FilesystemDataStore` encrypts sensitive values at rest, and `get_key` decrypts them transparently.
+The journal, snapshots, and transaction diffs redact sensitive values; `Snapshot::capture_unredacted` and the `datastore-snapshot` binary's `--include-sensitive` flag include them.
+
+# Conformance testing
+
+With the `conformance` feature, the `conformance` module provides a suite of checks that any DataStore implementation can run against itself, covering prefix semantics, quoted keys, metadata inheritance, transaction isolation, commit and delete return values, transaction creation times, and error cases.
+The built-in implementations run it in their unit tests.
+
 # Current limitations
 
-* The user (e.g. apiserver) needs to handle locking.
-* There's no support for rolling back transactions.
-* The `serialization` module can't handle complex types under lists; it assumes lists can be serialized as scalars.
+* Lists of complex types are stored with each element under its index, e.g. `a.b.0.c`, and an empty list is stored as `[]` at the list key.  `set_keys` replaces keys of the old shape when a list becomes empty or stops being empty, but removing some elements from a list requires a tombstone on the list key to clear out old indexes.
+* Key names can be up to 4096 bytes, and `FilesystemDataStore` splits segments that are too long for a file name across directories, but a key's whole path, including the data store's base path and the encoding of special characters, must still fit within the operating system's path length limit.
+* `FilesystemDataStore` limits metadata key names to 64 bytes once special characters are encoded, so there's always room for them next to a data key's file name.
 */
 
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+pub mod convert;
 pub mod deserialization;
+pub mod diff;
 pub mod error;
+pub mod expiry;
+pub mod feed;
 pub mod filesystem;
+pub mod format;
+pub mod fsck;
+pub mod journal;
 pub mod key;
+pub mod layered;
+pub mod lock;
+pub mod logstore;
 pub mod memory;
+pub mod observe;
+pub mod pattern;
+#[cfg(test)]
+mod scratch;
+pub mod sensitive;
 pub mod serialization;
+pub mod snapshot;
+pub mod typed;
 
+pub use diff::{TransactionDiff, ValueChange};
 pub use error::{Error, Result};
+pub use expiry::TransactionInfo;
 pub use filesystem::FilesystemDataStore;
+pub use journal::{JournalEntry, JournalFilter};
 pub use key::{Key, KeyType, KEY_SEPARATOR, KEY_SEPARATOR_STR};
+pub use layered::{LayeredDataStore, SourcedValue};
+pub use lock::LockedDataStore;
+pub use logstore::LogDataStore;
+pub use observe::{ChangeEvent, ObservedDataStore, Subscription};
+pub use pattern::KeyPattern;
+pub use sensitive::{EncryptionKey, SensitiveKeys};
+pub use typed::DataStoreExt;
 
 use log::{info, trace};
 use serde::{Deserialize, Serialize};
-use snafu::OptionExt;
+use snafu::{ensure, OptionExt};
 use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
 
 /// Committed represents whether we want to look at pending (uncommitted) or live (committed) data
 /// in the datastore.
@@ -54,6 +196,76 @@ pub enum Committed {
     },
 }
 
+/// The number of committed generations retained for reverting, unless the implementation is
+/// configured otherwise.
+pub const DEFAULT_GENERATION_LIMIT: usize = 10;
+
+/// Generation describes one commit to live data, with enough detail to undo it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generation {
+    /// Generations are numbered in increasing order, starting at 1.
+    pub id: u64,
+    /// The name of the transaction that was committed.
+    pub transaction: String,
+    /// The live values of the changed keys before the commit; None means the key didn't exist.
+    pub prior: HashMap<Key, Option<String>>,
+}
+
+/// Checks that we can revert to the given generation ID, given the retained generations (oldest
+/// first) and the latest generation ID.  We can revert to the latest generation (a no-op) or to
+/// any generation whose successor is still retained.
+pub(crate) fn check_revert_target(generations: &[Generation], latest: u64, id: u64) -> Result<()> {
+    let available = id == latest || (id < latest && generations.iter().any(|g| g.id == id + 1));
+    ensure!(available, error::GenerationUnavailableSnafu { id });
+    Ok(())
+}
+
+/// Returns the keys from the given set that are removed by the given tombstones, meaning they're
+/// either a tombstoned key or under one.
+pub(crate) fn tombstoned_keys<'a, I>(keys: I, tombstones: &HashSet<Key>) -> HashSet<Key>
+where
+    I: IntoIterator<Item = &'a Key>,
+{
+    keys.into_iter()
+        .filter(|key| {
+            tombstones
+                .iter()
+                .any(|t| key.starts_with_segments(t.segments()))
+        })
+        .cloned()
+        .collect()
+}
+
+/// Returns the populated keys whose shape conflicts with setting the given key: keys above it
+/// that have a value, and keys under it.  Keys that are in the given pairs, and so are being set
+/// along with it, aren't included.
+fn replaced_keys<D, S>(
+    datastore: &D,
+    key: &Key,
+    pairs: &HashMap<Key, S>,
+    committed: &Committed,
+) -> Result<HashSet<Key>>
+where
+    D: DataStore + ?Sized,
+{
+    let mut result = HashSet::new();
+    let segments = key.segments();
+    for len in 1..segments.len() {
+        let ancestor = Key::from_segments(KeyType::Data, &segments[..len])?;
+        if !pairs.contains_key(&ancestor) && datastore.key_populated(&ancestor, committed)? {
+            result.insert(ancestor);
+        }
+    }
+    let pattern = KeyPattern::new(format!("{}.**", key.name()))?;
+    result.extend(
+        datastore
+            .list_matching_keys(&pattern, committed)?
+            .into_iter()
+            .filter(|descendant| !pairs.contains_key(descendant)),
+    );
+    Ok(result)
+}
+
 pub trait DataStore {
     /// Returns whether a key is present (has a value) in the datastore.
     fn key_populated(&self, key: &Key, committed: &Committed) -> Result<bool>;
@@ -72,6 +284,7 @@ pub trait DataStore {
     fn list_populated_metadata<S1, S2>(
         &self,
         prefix: S1,
+        committed: &Committed,
         metadata_key_name: &Option<S2>,
     ) -> Result<HashMap<Key, HashSet<Key>>>
     where
@@ -89,7 +302,12 @@ pub trait DataStore {
 
     /// Retrieve the value for a single metadata key from the datastore.  Values will inherit from
     /// earlier in the tree, if more specific values are not found later.
-    fn get_metadata(&self, metadata_key: &Key, data_key: &Key) -> Result<Option<String>> {
+    fn get_metadata(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>> {
         let mut result = Ok(None);
         let mut current_path = Vec::new();
 
@@ -101,7 +319,7 @@ pub trait DataStore {
                 unreachable!("Prefix of Key failed to make Key: {:?}", current_path)
             });
 
-            if let Some(md) = self.get_metadata_raw(metadata_key, &data_key)? {
+            if let Some(md) = self.get_metadata_raw(metadata_key, &data_key, committed)? {
                 result = Ok(Some(md));
             }
         }
@@ -110,27 +328,61 @@ pub trait DataStore {
 
     /// Retrieve the value for a single metadata key from the datastore, without taking into
     /// account inheritance of metadata from earlier in the tree.
-    fn get_metadata_raw(&self, metadata_key: &Key, data_key: &Key) -> Result<Option<String>>;
-    /// Set the value of a single metadata key in the datastore.
+    fn get_metadata_raw(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>>;
+    /// Set the value of a single metadata key in the datastore.  Metadata set in a pending
+    /// transaction is applied to live metadata when the transaction is committed.
     fn set_metadata<S: AsRef<str>>(
         &mut self,
         metadata_key: &Key,
         data_key: &Key,
         value: S,
+        committed: &Committed,
     ) -> Result<()>;
     /// Removes the given metadata key from the given data key in the datastore.  If we
     /// succeeded, we return Ok(()); if the data or metadata key didn't exist, we also return
     /// Ok(()); we return Err only if we failed to check or remove the key.
-    fn unset_metadata(&mut self, metadata_key: &Key, data_key: &Key) -> Result<()>;
+    ///
+    /// In a pending transaction, this also records that the live metadata should be removed
+    /// when the transaction is committed.  Setting the metadata in the transaction again cancels
+    /// the removal.
+    fn unset_metadata(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<()>;
+
+    /// Records in the given pending transaction that the given data key, and any keys under it,
+    /// should be removed from live data when the transaction is committed.
+    fn set_tombstone<S>(&mut self, key: &Key, transaction: S) -> Result<()>
+    where
+        S: Into<String> + AsRef<str>;
+
+    /// Returns the keys recorded for removal in the given pending transaction.
+    fn list_tombstones<S: AsRef<str>>(&self, transaction: S) -> Result<HashSet<Key>>;
 
-    /// Applies pending changes from the given transaction to the live datastore.  Returns the
-    /// list of changed keys.
+    /// Returns the metadata recorded for removal in the given pending transaction, as a mapping
+    /// of data keys to the metadata keys to remove from each.
+    fn list_metadata_tombstones<S: AsRef<str>>(
+        &self,
+        transaction: S,
+    ) -> Result<HashMap<Key, HashSet<Key>>>;
+
+    /// Applies pending changes from the given transaction to the live datastore.  Tombstones are
+    /// applied before pending keys are set, so a transaction can replace a whole subtree.  Pending
+    /// metadata is applied too.  Returns the list of changed data keys, including removed keys.
     fn commit_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
     where
         S: Into<String> + AsRef<str>;
 
-    /// Remove the given pending transaction from the datastore.  Returns the list of removed
-    /// keys.  If the transaction doesn't exist, will return Ok with an empty list.
+    /// Remove the given pending transaction, and any metadata set in it, from the datastore.
+    /// Returns the list of removed keys, including tombstones.  If the transaction doesn't exist, will return Ok with an
+    /// empty list.
     fn delete_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
     where
         S: Into<String> + AsRef<str>;
@@ -138,14 +390,63 @@ pub trait DataStore {
     /// Returns a list of the names of any pending transactions in the data store.
     fn list_transactions(&self) -> Result<HashSet<String>>;
 
+    /// Returns when the given pending transaction was created, meaning when something was first
+    /// written to it, or None if it doesn't exist.
+    fn transaction_created<S: AsRef<str>>(&self, transaction: S) -> Result<Option<SystemTime>>;
+
+    /// Returns the retained generations of live data, oldest first.
+    fn list_generations(&self) -> Result<Vec<Generation>>;
+
+    /// Restores live data to the state it was in after the given generation was committed, and
+    /// forgets the generations after it.  Returns the list of changed keys.  Metadata isn't part
+    /// of generations, so it's left as it is.
+    fn revert_to_generation(&mut self, id: u64) -> Result<HashSet<Key>>;
+
+    /// Returns the journal entries matching the given filter, oldest first.
+    fn read_journal(&self, filter: &JournalFilter) -> Result<Vec<JournalEntry>>;
+
+    /// Compares the given pending transaction with live data, returning the keys that committing
+    /// it would add, change, and remove, and the metadata that applies to them.  Only keys whose
+    /// names start with the given prefix are compared.
+    fn diff_transaction<S1, S2>(&self, transaction: S1, prefix: S2) -> Result<TransactionDiff>
+    where
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        diff::diff_transaction(self, transaction, prefix)
+    }
+
     /// Set multiple data keys at once in the data store.
     ///
+    /// A key can't have both a value and keys under it, so keys whose shape changes are replaced:
+    /// if a given key is under a key that has a value, or has keys under it, those old keys are
+    /// removed first.  This happens when a list of structures goes from empty, which is stored as
+    /// "[]", to having elements, which are stored under their indexes, or back.  In a pending
+    /// transaction, old live keys are removed with a tombstone when the transaction is committed.
+    ///
     /// Implementers can replace the default implementation if there's a faster way than setting
     /// each key individually.
     fn set_keys<S>(&mut self, pairs: &HashMap<Key, S>, committed: &Committed) -> Result<()>
     where
         S: AsRef<str>,
     {
+        let mut replaced = HashSet::new();
+        for key in pairs.keys() {
+            replaced.extend(replaced_keys(self, key, pairs, committed)?);
+            if let Committed::Pending { tx } = committed {
+                for old_key in replaced_keys(self, key, pairs, &Committed::Live)? {
+                    self.set_tombstone(&old_key, tx)?;
+                }
+            }
+        }
+        for key in replaced {
+            trace!(
+                "Removing data key {} replaced by a different shape",
+                key.name()
+            );
+            self.unset_key(&key, committed)?;
+        }
+
         for (key, value) in pairs {
             match committed {
                 Committed::Live => {
@@ -180,22 +481,7 @@ pub trait DataStore {
         committed: &Committed,
     ) -> Result<HashMap<Key, String>> {
         let keys = self.list_populated_keys(&find_prefix, committed)?;
-        trace!("Found populated keys: {:?}", keys);
-        if keys.is_empty() {
-            return Ok(HashMap::new());
-        }
-
-        let mut result = HashMap::new();
-        for key in keys {
-            // Already confirmed key via listing keys, so an error is more serious.
-            trace!("Pulling value from datastore for key: {}", key);
-            let value = self
-                .get_key(&key, committed)?
-                .context(error::ListedKeyNotPresentSnafu { key: key.name() })?;
-
-            result.insert(key, value);
-        }
-        Ok(result)
+        get_listed_keys(self, keys, committed)
     }
 
     /// Retrieves all metadata for data keys starting with the given prefix.  If you specify
@@ -205,50 +491,148 @@ pub trait DataStore {
     fn get_metadata_prefix<S1, S2>(
         &self,
         find_prefix: S1,
+        committed: &Committed,
         metadata_key_name: &Option<S2>,
     ) -> Result<HashMap<Key, HashMap<Key, String>>>
     where
         S1: AsRef<str>,
         S2: AsRef<str>,
     {
-        let meta_map = self.list_populated_metadata(&find_prefix, metadata_key_name)?;
-        trace!("Found populated metadata: {:?}", meta_map);
-        if meta_map.is_empty() {
-            return Ok(HashMap::new());
-        }
+        let meta_map = self.list_populated_metadata(&find_prefix, committed, metadata_key_name)?;
+        get_listed_metadata(self, meta_map, committed, metadata_key_name)
+    }
 
-        let mut result = HashMap::new();
-        for (data_key, meta_keys) in meta_map {
-            for meta_key in meta_keys {
-                // If the user requested specific metadata, move to the next key unless it
-                // matches.
-                if let Some(name) = metadata_key_name {
-                    if name.as_ref() != meta_key.name() {
-                        continue;
-                    }
-                }
+    /// Returns the populated data keys in the datastore that match the given pattern.
+    ///
+    /// Implementers can replace the default implementation if there's a faster way than listing
+    /// the keys under the pattern's literal prefix and checking each one.
+    fn list_matching_keys(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        let keys = self.list_populated_keys(pattern.prefix_name(), committed)?;
+        Ok(keys
+            .into_iter()
+            .filter(|key| pattern.matches(key))
+            .collect())
+    }
 
-                // Already confirmed key via listing keys, so an error is more serious.
-                trace!(
-                    "Pulling metadata '{}' from datastore for key: {}",
-                    meta_key,
-                    &data_key
-                );
-                let value = self.get_metadata(&meta_key, &data_key)?.context(
-                    error::ListedMetaNotPresentSnafu {
-                        meta_key: meta_key.name(),
-                        data_key: data_key.name(),
-                    },
-                )?;
-
-                // Insert a top-level map entry for the data key if we've found metadata.
-                let data_entry = result.entry(data_key.clone()).or_insert_with(HashMap::new);
-
-                data_entry.insert(meta_key, value);
+    /// Finds all populated metadata keys whose data keys match the given pattern.  If you specify
+    /// metadata_key_name, only metadata keys with that name will be returned.
+    ///
+    /// Returns a mapping of the data keys to the set of populated metadata keys for each.
+    fn list_matching_metadata<S>(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+        metadata_key_name: &Option<S>,
+    ) -> Result<HashMap<Key, HashSet<Key>>>
+    where
+        S: AsRef<str>,
+    {
+        let meta_map =
+            self.list_populated_metadata(pattern.prefix_name(), committed, metadata_key_name)?;
+        Ok(meta_map
+            .into_iter()
+            .filter(|(data_key, _)| pattern.matches(data_key))
+            .collect())
+    }
+
+    /// Retrieves all keys matching the given pattern, returning them in a Key -> value map.
+    fn get_matching(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, String>> {
+        let keys = self.list_matching_keys(pattern, committed)?;
+        get_listed_keys(self, keys, committed)
+    }
+
+    /// Retrieves all metadata for data keys matching the given pattern, like
+    /// `get_metadata_prefix`.
+    fn get_matching_metadata<S>(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+        metadata_key_name: &Option<S>,
+    ) -> Result<HashMap<Key, HashMap<Key, String>>>
+    where
+        S: AsRef<str>,
+    {
+        let meta_map = self.list_matching_metadata(pattern, committed, metadata_key_name)?;
+        get_listed_metadata(self, meta_map, committed, metadata_key_name)
+    }
+}
+
+/// Retrieves the values of keys that were just listed, for the default implementations of
+/// `get_prefix` and `get_matching`.
+fn get_listed_keys<D>(
+    datastore: &D,
+    keys: HashSet<Key>,
+    committed: &Committed,
+) -> Result<HashMap<Key, String>>
+where
+    D: DataStore + ?Sized,
+{
+    trace!("Found populated keys: {:?}", keys);
+    let mut result = HashMap::new();
+    for key in keys {
+        // Already confirmed key via listing keys, so an error is more serious.
+        trace!("Pulling value from datastore for key: {}", key);
+        let value = datastore
+            .get_key(&key, committed)?
+            .context(error::ListedKeyNotPresentSnafu { key: key.name() })?;
+
+        result.insert(key, value);
+    }
+    Ok(result)
+}
+
+/// Retrieves the values of metadata that was just listed, for the default implementations of
+/// `get_metadata_prefix` and `get_matching_metadata`.
+fn get_listed_metadata<D, S>(
+    datastore: &D,
+    meta_map: HashMap<Key, HashSet<Key>>,
+    committed: &Committed,
+    metadata_key_name: &Option<S>,
+) -> Result<HashMap<Key, HashMap<Key, String>>>
+where
+    D: DataStore + ?Sized,
+    S: AsRef<str>,
+{
+    trace!("Found populated metadata: {:?}", meta_map);
+    let mut result = HashMap::new();
+    for (data_key, meta_keys) in meta_map {
+        for meta_key in meta_keys {
+            // If the user requested specific metadata, move to the next key unless it
+            // matches.
+            if let Some(name) = metadata_key_name {
+                if name.as_ref() != meta_key.name() {
+                    continue;
+                }
             }
+
+            // Already confirmed key via listing keys, so an error is more serious.
+            trace!(
+                "Pulling metadata '{}' from datastore for key: {}",
+                meta_key,
+                &data_key
+            );
+            let value = datastore
+                .get_metadata(&meta_key, &data_key, committed)?
+                .context(error::ListedMetaNotPresentSnafu {
+                    meta_key: meta_key.name(),
+                    data_key: data_key.name(),
+                })?;
+
+            // Insert a top-level map entry for the data key if we've found metadata.
+            let data_entry = result.entry(data_key.clone()).or_insert_with(HashMap::new);
+
+            data_entry.insert(meta_key, value);
         }
-        Ok(result)
     }
+    Ok(result)
 }
 
 /////
@@ -292,8 +676,10 @@ pub type Value = serde_json::Value;
 #[cfg(test)]
 mod test {
     use super::memory::MemoryDataStore;
+    use super::serialization::to_pairs_with_prefix;
     use super::{Committed, DataStore, Key, KeyType};
     use maplit::{hashmap, hashset};
+    use serde::Serialize;
 
     #[test]
     fn set_unset_keys() {
@@ -336,14 +722,60 @@ mod test {
         let grandchild = Key::new(KeyType::Data, "a.b.c").unwrap();
 
         // Set metadata on parent
-        m.set_metadata(&meta, &parent, "value").unwrap();
+        m.set_metadata(&meta, &parent, "value", &Committed::Live)
+            .unwrap();
         // Metadata shows up on grandchild...
         assert_eq!(
-            m.get_metadata(&meta, &grandchild).unwrap(),
+            m.get_metadata(&meta, &grandchild, &Committed::Live)
+                .unwrap(),
             Some("value".to_string())
         );
         // ...but only through inheritance, not directly.
-        assert_eq!(m.get_metadata_raw(&meta, &grandchild).unwrap(), None);
+        assert_eq!(
+            m.get_metadata_raw(&meta, &grandchild, &Committed::Live)
+                .unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn compound_list_keys() {
+        #[derive(Serialize)]
+        struct Entry {
+            name: String,
+        }
+        let entries = vec![
+            Entry {
+                name: "a".to_string(),
+            },
+            Entry {
+                name: "b".to_string(),
+            },
+        ];
+        let pairs = to_pairs_with_prefix("settings.entries", &entries).unwrap();
+
+        let mut m = MemoryDataStore::new();
+        m.set_keys(&pairs, &Committed::Live).unwrap();
+        let meta = Key::new(KeyType::Meta, "mymeta").unwrap();
+        let list = Key::new(KeyType::Data, "settings.entries").unwrap();
+        m.set_metadata(&meta, &list, "value", &Committed::Live)
+            .unwrap();
+
+        // Elements are listed under the list key...
+        let element = Key::new(KeyType::Data, "settings.entries.1.name").unwrap();
+        assert_eq!(
+            m.list_populated_keys("settings.entries", &Committed::Live)
+                .unwrap(),
+            hashset!(
+                Key::new(KeyType::Data, "settings.entries.0.name").unwrap(),
+                element.clone(),
+            )
+        );
+        // ...and inherit its metadata.
+        assert_eq!(
+            m.get_metadata(&meta, &element, &Committed::Live).unwrap(),
+            Some("value".to_string())
+        );
     }
 
     #[test]
@@ -379,20 +811,22 @@ mod test {
         let mk1 = Key::new(KeyType::Meta, "metatest1").unwrap();
         let mk2 = Key::new(KeyType::Meta, "metatest2").unwrap();
         let mk3 = Key::new(KeyType::Meta, "metatest3").unwrap();
-        m.set_metadata(&mk1, &k1, "41").unwrap();
-        m.set_metadata(&mk2, &k2, "42").unwrap();
-        m.set_metadata(&mk3, &k3, "43").unwrap();
+        m.set_metadata(&mk1, &k1, "41", &Committed::Live).unwrap();
+        m.set_metadata(&mk2, &k2, "42", &Committed::Live).unwrap();
+        m.set_metadata(&mk3, &k3, "43", &Committed::Live).unwrap();
 
         // Check all metadata
         assert_eq!(
-            m.get_metadata_prefix("x.", &None as &Option<&str>).unwrap(),
+            m.get_metadata_prefix("x.", &Committed::Live, &None as &Option<&str>)
+                .unwrap(),
             hashmap!(k1 => hashmap!(mk1 => "41".to_string()),
                      k2.clone() => hashmap!(mk2.clone() => "42".to_string()))
         );
 
         // Check metadata matching a given name
         assert_eq!(
-            m.get_metadata_prefix("x.", &Some("metatest2")).unwrap(),
+            m.get_metadata_prefix("x.", &Committed::Live, &Some("metatest2"))
+                .unwrap(),
             hashmap!(k2 => hashmap!(mk2 => "42".to_string()))
         );
     }
@@ -0,0 +1,548 @@
+//! This module provides a wrapper around any DataStore implementation that uses advisory file
+//! locks to coordinate access between processes, such as the API server, migrations, and
+//! inspection tools that open the same data store.
+//!
+//! Reads take a shared lock and writes take an exclusive lock, each held just for the duration of
+//! the call.  If you need several calls to happen atomically, for example a read-modify-write, use
+//! `lock_shared` or `lock_exclusive` to get a guard that holds the lock and gives access to the
+//! underlying data store until it's dropped.
+//!
+//! The locks are `flock` locks on a lock file, so they're released by the kernel if the holder
+//! exits, and there's never a stale lock to clean up.  The exclusive holder records its process ID
+//! in the lock file just so that, if we time out waiting, the error can tell you who had the lock,
+//! and whether that process is still running.  A lock held on behalf of a process that's gone
+//! means it was inherited by a child that's still running.
+
+use log::{debug, trace, warn};
+use nix::errno::Errno;
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use snafu::{ensure, ResultExt};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use super::journal::{JournalEntry, JournalFilter};
+use super::{
+    error, Committed, DataStore, FilesystemDataStore, Generation, Key, KeyPattern, Result,
+    TransactionDiff,
+};
+
+/// Name of the lock file that `LockedDataStore::open` uses, directly under the base path of a
+/// FilesystemDataStore.
+pub const LOCK_FILE: &str = "lock";
+
+/// How long we wait for a lock unless configured otherwise.
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long we sleep between attempts to take a lock that's held by someone else.
+const LOCK_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// LockMode represents whether a lock can be shared with other readers, or is exclusive.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LockMode {
+    Shared,
+    Exclusive,
+}
+
+/// FileLock holds an flock on an open lock file; the lock is released when it's dropped.
+#[derive(Debug)]
+pub(crate) struct FileLock {
+    file: File,
+    path: PathBuf,
+    mode: LockMode,
+}
+
+impl FileLock {
+    /// Takes a lock on the given file, creating it if needed, waiting up to the given timeout.
+    pub(crate) fn acquire(path: &Path, mode: LockMode, timeout: Duration) -> Result<Self> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)
+            .context(error::IoSnafu { path })?;
+        let arg = match mode {
+            LockMode::Shared => FlockArg::LockSharedNonblock,
+            LockMode::Exclusive => FlockArg::LockExclusiveNonblock,
+        };
+
+        let start = Instant::now();
+        loop {
+            match flock(file.as_raw_fd(), arg) {
+                Ok(()) => break,
+                Err(Errno::EWOULDBLOCK) | Err(Errno::EINTR) => {
+                    ensure!(
+                        start.elapsed() < timeout,
+                        error::LockTimeoutSnafu {
+                            path,
+                            timeout,
+                            holder: describe_holder(path),
+                        }
+                    );
+                    trace!("Waiting for {:?} lock on {}", mode, path.display());
+                    thread::sleep(LOCK_POLL_INTERVAL);
+                }
+                Err(e) => return Err(e).context(error::LockSnafu { path }),
+            }
+        }
+        debug!("Took {:?} lock on {}", mode, path.display());
+
+        let mut lock = Self {
+            file,
+            path: path.to_path_buf(),
+            mode,
+        };
+        if mode == LockMode::Exclusive {
+            lock.record_holder(Some(process::id()))?;
+        }
+        Ok(lock)
+    }
+
+    /// Returns whether the lock is shared or exclusive.
+    pub(crate) fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    /// Replaces the holder recorded in the lock file.
+    fn record_holder(&mut self, pid: Option<u32>) -> Result<()> {
+        let path = &self.path;
+        self.file.set_len(0).context(error::IoSnafu { path })?;
+        self.file
+            .seek(SeekFrom::Start(0))
+            .context(error::IoSnafu { path })?;
+        if let Some(pid) = pid {
+            write!(self.file, "{}", pid).context(error::IoSnafu { path })?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        // Clear our record before the lock is released by closing the file, so a later timeout
+        // doesn't blame us.
+        if self.mode == LockMode::Exclusive {
+            if let Err(e) = self.record_holder(None) {
+                warn!("Unable to clear lock holder record: {}", e);
+            }
+        }
+        trace!("Releasing {:?} lock on {}", self.mode, self.path.display());
+    }
+}
+
+/// Returns the process ID recorded by the exclusive holder of the given lock file, if any.
+fn read_holder(path: &Path) -> Option<u32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Returns whether the given process is running.  (EPERM means it exists but isn't ours.)
+fn process_alive(pid: u32) -> bool {
+    match i32::try_from(pid) {
+        Ok(pid) => !matches!(kill(Pid::from_raw(pid), None), Err(Errno::ESRCH)),
+        Err(_) => false,
+    }
+}
+
+/// Describes the holder of the given lock file, for error messages.
+fn describe_holder(path: &Path) -> String {
+    match read_holder(path) {
+        Some(pid) if process_alive(pid) => format!("exclusive lock held by process {}", pid),
+        Some(pid) => format!(
+            "exclusive lock taken by process {}, which has exited; a child process may have \
+             inherited it",
+            pid
+        ),
+        None => "shared lock held by readers".to_string(),
+    }
+}
+
+/// LockedDataStore wraps a DataStore, taking a lock on the given lock file for every operation.
+#[derive(Debug)]
+pub struct LockedDataStore<D> {
+    inner: D,
+    lock_path: PathBuf,
+    timeout: Duration,
+}
+
+impl<D: DataStore> LockedDataStore<D> {
+    /// Wraps the given data store, using the given path as its lock file, and waiting up to the
+    /// given timeout for locks.  Every process using the data store should use the same lock
+    /// file.  Like `open`, this takes the exclusive lock before returning, so we don't start using
+    /// the data store while another process holds it, for example to recover it.
+    pub fn new<P: AsRef<Path>>(inner: D, lock_path: P, timeout: Duration) -> Result<Self> {
+        let lock_path = lock_path.as_ref();
+        FileLock::acquire(lock_path, LockMode::Exclusive, timeout)?;
+        Ok(Self::wrap(inner, lock_path, timeout))
+    }
+
+    fn wrap(inner: D, lock_path: &Path, timeout: Duration) -> Self {
+        Self {
+            inner,
+            lock_path: lock_path.to_path_buf(),
+            timeout,
+        }
+    }
+
+    /// Takes a shared lock, returning a guard that gives read access to the underlying data
+    /// store until it's dropped.
+    pub fn lock_shared(&self) -> Result<SharedGuard<'_, D>> {
+        let lock = FileLock::acquire(&self.lock_path, LockMode::Shared, self.timeout)?;
+        Ok(SharedGuard {
+            datastore: &self.inner,
+            _lock: lock,
+        })
+    }
+
+    /// Takes an exclusive lock, returning a guard that gives read and write access to the
+    /// underlying data store until it's dropped.
+    pub fn lock_exclusive(&mut self) -> Result<ExclusiveGuard<'_, D>> {
+        let lock = FileLock::acquire(&self.lock_path, LockMode::Exclusive, self.timeout)?;
+        Ok(ExclusiveGuard {
+            datastore: &mut self.inner,
+            _lock: lock,
+        })
+    }
+
+    /// Returns the underlying data store.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+}
+
+impl LockedDataStore<FilesystemDataStore> {
+    /// Opens the FilesystemDataStore at the given path, locked with a lock file inside it.  Any
+    /// recovery of interrupted commits, or format upgrade, is done while holding an exclusive lock.
+    pub fn open<P: AsRef<Path>>(base_path: P, timeout: Duration) -> Result<Self> {
+        let base_path = base_path.as_ref();
+        let lock_path = base_path.join(LOCK_FILE);
+        let lock = FileLock::acquire(&lock_path, LockMode::Exclusive, timeout)?;
+        FilesystemDataStore::recover(base_path, &lock)?;
+        let inner = FilesystemDataStore::new(base_path)?;
+        Ok(Self::wrap(inner, &lock_path, timeout))
+    }
+}
+
+/// SharedGuard holds a shared lock and gives read access to the locked data store.
+#[derive(Debug)]
+pub struct SharedGuard<'a, D> {
+    datastore: &'a D,
+    _lock: FileLock,
+}
+
+impl<D> Deref for SharedGuard<'_, D> {
+    type Target = D;
+
+    fn deref(&self) -> &D {
+        self.datastore
+    }
+}
+
+/// ExclusiveGuard holds an exclusive lock and gives read and write access to the locked data
+/// store.
+#[derive(Debug)]
+pub struct ExclusiveGuard<'a, D> {
+    datastore: &'a mut D,
+    _lock: FileLock,
+}
+
+impl<D> Deref for ExclusiveGuard<'_, D> {
+    type Target = D;
+
+    fn deref(&self) -> &D {
+        self.datastore
+    }
+}
+
+impl<D> DerefMut for ExclusiveGuard<'_, D> {
+    fn deref_mut(&mut self) -> &mut D {
+        self.datastore
+    }
+}
+
+// Each operation takes the lock for just the duration of the call.  The default implementations
+// of multi-key operations are replaced so that they're done under a single lock.
+impl<D: DataStore> DataStore for LockedDataStore<D> {
+    fn key_populated(&self, key: &Key, committed: &Committed) -> Result<bool> {
+        self.lock_shared()?.key_populated(key, committed)
+    }
+
+    fn list_populated_keys<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        self.lock_shared()?.list_populated_keys(prefix, committed)
+    }
+
+    fn list_populated_metadata<S1, S2>(
+        &self,
+        prefix: S1,
+        committed: &Committed,
+        metadata_key_name: &Option<S2>,
+    ) -> Result<HashMap<Key, HashSet<Key>>>
+    where
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        self.lock_shared()?
+            .list_populated_metadata(prefix, committed, metadata_key_name)
+    }
+
+    fn get_key(&self, key: &Key, committed: &Committed) -> Result<Option<String>> {
+        self.lock_shared()?.get_key(key, committed)
+    }
+
+    fn set_key<S: AsRef<str>>(&mut self, key: &Key, value: S, committed: &Committed) -> Result<()> {
+        self.lock_exclusive()?.set_key(key, value, committed)
+    }
+
+    fn unset_key(&mut self, key: &Key, committed: &Committed) -> Result<()> {
+        self.lock_exclusive()?.unset_key(key, committed)
+    }
+
+    fn get_metadata(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>> {
+        self.lock_shared()?
+            .get_metadata(metadata_key, data_key, committed)
+    }
+
+    fn get_metadata_raw(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>> {
+        self.lock_shared()?
+            .get_metadata_raw(metadata_key, data_key, committed)
+    }
+
+    fn set_metadata<S: AsRef<str>>(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        value: S,
+        committed: &Committed,
+    ) -> Result<()> {
+        self.lock_exclusive()?
+            .set_metadata(metadata_key, data_key, value, committed)
+    }
+
+    fn unset_metadata(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<()> {
+        self.lock_exclusive()?
+            .unset_metadata(metadata_key, data_key, committed)
+    }
+
+    fn set_tombstone<S>(&mut self, key: &Key, transaction: S) -> Result<()>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        self.lock_exclusive()?.set_tombstone(key, transaction)
+    }
+
+    fn list_tombstones<S: AsRef<str>>(&self, transaction: S) -> Result<HashSet<Key>> {
+        self.lock_shared()?.list_tombstones(transaction)
+    }
+
+    fn list_metadata_tombstones<S: AsRef<str>>(
+        &self,
+        transaction: S,
+    ) -> Result<HashMap<Key, HashSet<Key>>> {
+        self.lock_shared()?.list_metadata_tombstones(transaction)
+    }
+
+    fn commit_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        self.lock_exclusive()?.commit_transaction(transaction)
+    }
+
+    fn delete_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        self.lock_exclusive()?.delete_transaction(transaction)
+    }
+
+    fn list_transactions(&self) -> Result<HashSet<String>> {
+        self.lock_shared()?.list_transactions()
+    }
+
+    fn transaction_created<S: AsRef<str>>(&self, transaction: S) -> Result<Option<SystemTime>> {
+        self.lock_shared()?.transaction_created(transaction)
+    }
+
+    fn list_generations(&self) -> Result<Vec<Generation>> {
+        self.lock_shared()?.list_generations()
+    }
+
+    fn revert_to_generation(&mut self, id: u64) -> Result<HashSet<Key>> {
+        self.lock_exclusive()?.revert_to_generation(id)
+    }
+
+    fn read_journal(&self, filter: &JournalFilter) -> Result<Vec<JournalEntry>> {
+        self.lock_shared()?.read_journal(filter)
+    }
+
+    fn set_keys<S>(&mut self, pairs: &HashMap<Key, S>, committed: &Committed) -> Result<()>
+    where
+        S: AsRef<str>,
+    {
+        self.lock_exclusive()?.set_keys(pairs, committed)
+    }
+
+    fn unset_keys(&mut self, keys: &HashSet<Key>, committed: &Committed) -> Result<()> {
+        self.lock_exclusive()?.unset_keys(keys, committed)
+    }
+
+    fn get_prefix<S: AsRef<str>>(
+        &self,
+        find_prefix: S,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, String>> {
+        self.lock_shared()?.get_prefix(find_prefix, committed)
+    }
+
+    fn get_metadata_prefix<S1, S2>(
+        &self,
+        find_prefix: S1,
+        committed: &Committed,
+        metadata_key_name: &Option<S2>,
+    ) -> Result<HashMap<Key, HashMap<Key, String>>>
+    where
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        self.lock_shared()?
+            .get_metadata_prefix(find_prefix, committed, metadata_key_name)
+    }
+
+    fn list_matching_keys(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        self.lock_shared()?.list_matching_keys(pattern, committed)
+    }
+
+    fn list_matching_metadata<S>(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+        metadata_key_name: &Option<S>,
+    ) -> Result<HashMap<Key, HashSet<Key>>>
+    where
+        S: AsRef<str>,
+    {
+        self.lock_shared()?
+            .list_matching_metadata(pattern, committed, metadata_key_name)
+    }
+
+    fn get_matching(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, String>> {
+        self.lock_shared()?.get_matching(pattern, committed)
+    }
+
+    fn get_matching_metadata<S>(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+        metadata_key_name: &Option<S>,
+    ) -> Result<HashMap<Key, HashMap<Key, String>>>
+    where
+        S: AsRef<str>,
+    {
+        self.lock_shared()?
+            .get_matching_metadata(pattern, committed, metadata_key_name)
+    }
+
+    fn diff_transaction<S1, S2>(&self, transaction: S1, prefix: S2) -> Result<TransactionDiff>
+    where
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        self.lock_shared()?.diff_transaction(transaction, prefix)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryDataStore;
+    use crate::scratch::TestPath;
+    use crate::{Error, KeyType};
+
+    fn locked(lock: &TestPath) -> LockedDataStore<MemoryDataStore> {
+        LockedDataStore::new(MemoryDataStore::new(), &lock.0, Duration::ZERO).unwrap()
+    }
+
+    #[test]
+    fn shared_readers() {
+        let lock = TestPath::new("shared-readers");
+        let a = locked(&lock);
+        let b = locked(&lock);
+        let _guard = a.lock_shared().unwrap();
+        b.list_transactions().unwrap();
+    }
+
+    #[test]
+    fn exclusive_blocks_others() {
+        let lock = TestPath::new("exclusive-blocks-others");
+        let mut a = locked(&lock);
+        let mut b = locked(&lock);
+        let key = Key::new(KeyType::Data, "settings.a").unwrap();
+
+        {
+            let mut guard = a.lock_exclusive().unwrap();
+            // The guard allows a read-modify-write while others wait.
+            let value = guard.get_key(&key, &Committed::Live).unwrap();
+            assert_eq!(value, None);
+            guard.set_key(&key, "1", &Committed::Live).unwrap();
+
+            assert_eq!(read_holder(&lock.0), Some(process::id()));
+            match b.get_key(&key, &Committed::Live) {
+                Err(Error::LockTimeout { holder, .. }) => {
+                    assert!(holder.contains(&process::id().to_string()))
+                }
+                other => panic!("expected lock timeout, got {:?}", other),
+            }
+        }
+
+        assert_eq!(read_holder(&lock.0), None);
+        b.set_key(&key, "2", &Committed::Live).unwrap();
+    }
+
+    #[test]
+    fn new_waits_for_exclusive_lock() {
+        let lock = TestPath::new("new-waits-for-exclusive-lock");
+        let mut a = locked(&lock);
+        {
+            let _guard = a.lock_exclusive().unwrap();
+            let err =
+                LockedDataStore::new(MemoryDataStore::new(), &lock.0, Duration::ZERO).unwrap_err();
+            assert!(matches!(err, Error::LockTimeout { .. }));
+        }
+        locked(&lock);
+    }
+}
@@ -0,0 +1,943 @@
+//! This implementation of the DataStore trait keeps everything in a single append-only log file,
+//! with an in-memory index for reads.
+//!
+//! Each change is appended to the log as a record, and the file is synced before the change is
+//! applied to the index, so the index never gets ahead of what's on disk.  Records are framed with
+//! their length and a CRC32 checksum, so a partially written record at the end of the file, from
+//! an interrupted append, is detected and truncated.  A bad record followed by intact ones is
+//! corruption, and opening the log fails, unless it's opened with `open_salvaging`, which moves
+//! bad records to a quarantine directory and loads the intact ones.
+//!
+//! The index is a MemoryDataStore, built by replaying the log when it's opened.  The log is then
+//! compacted: it's rewritten to hold just the current state, including pending transactions,
+//! generations, and the journal, and atomically renamed into place.
+
+use log::{debug, trace, warn};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, ResultExt};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use super::journal::{JournalEntry, JournalFilter, JournalRecord};
+use super::memory::MemoryDataStore;
+use super::{
+    error, Committed, DataStore, Generation, Key, KeyPattern, KeyType, Result,
+    DEFAULT_GENERATION_LIMIT,
+};
+
+/// Marks the start of a log file, and its format version.
+const LOG_MAGIC: &[u8] = b"bottlerocket-datastore-log-v1\n";
+
+/// Each record is framed with its length and CRC32, each a little-endian u32.
+const FRAME_HEADER_LEN: usize = 8;
+
+/// Suffix for the temporary file used when compacting.
+const TEMP_FILE_SUFFIX: &str = "~tmp";
+
+/// LogRecord is one change in the log.  Keys are stored by name because Key can't be deserialized
+/// without knowing its type.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "kebab-case")]
+enum LogRecord {
+    SetKey {
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        tx: Option<String>,
+        key: String,
+        value: String,
+    },
+    UnsetKey {
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        tx: Option<String>,
+        key: String,
+    },
+    SetMetadata {
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        tx: Option<String>,
+        /// None when written by compaction; the change was already journaled.
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        timestamp: Option<SystemTime>,
+        data_key: String,
+        metadata_key: String,
+        value: String,
+    },
+    UnsetMetadata {
+        #[serde(skip_serializing_if = "Option::is_none", default)]
+        tx: Option<String>,
+        timestamp: SystemTime,
+        data_key: String,
+        metadata_key: String,
+    },
+    SetTombstone {
+        tx: String,
+        key: String,
+    },
+    /// Written before the first change to a pending transaction.  Transactions from logs written
+    /// before this record existed are treated as created when the log was replayed.
+    CreateTransaction {
+        timestamp: SystemTime,
+        tx: String,
+    },
+    Commit {
+        timestamp: SystemTime,
+        tx: String,
+    },
+    DeleteTransaction {
+        tx: String,
+    },
+    Revert {
+        timestamp: SystemTime,
+        generation: u64,
+    },
+    // These records are only written by compaction, to carry history forward.
+    Generation {
+        id: u64,
+        transaction: String,
+        prior: HashMap<String, Option<String>>,
+    },
+    LatestGeneration {
+        id: u64,
+    },
+    Journal(JournalRecord),
+}
+
+impl LogRecord {
+    /// Returns the Committed matching the given optional transaction name.
+    fn committed(tx: &Option<String>) -> Committed {
+        match tx {
+            Some(tx) => Committed::Pending { tx: tx.clone() },
+            None => Committed::Live,
+        }
+    }
+
+    /// Returns the optional transaction name matching the given Committed.
+    fn tx(committed: &Committed) -> Option<String> {
+        match committed {
+            Committed::Pending { tx } => Some(tx.clone()),
+            Committed::Live => None,
+        }
+    }
+
+    /// Applies this record to the given index, returning any changed keys.
+    fn apply(self, index: &mut MemoryDataStore) -> Result<HashSet<Key>> {
+        let data_key = |name: &str| Key::new(KeyType::Data, name);
+        let meta_key = |name: &str| Key::new(KeyType::Meta, name);
+        match self {
+            LogRecord::SetKey { tx, key, value } => {
+                index.set_key(&data_key(&key)?, value, &Self::committed(&tx))?;
+            }
+            LogRecord::UnsetKey { tx, key } => {
+                index.unset_key(&data_key(&key)?, &Self::committed(&tx))?;
+            }
+            LogRecord::SetMetadata {
+                tx,
+                timestamp,
+                data_key: data,
+                metadata_key: meta,
+                value,
+            } => {
+                index.set_metadata_at(
+                    &meta_key(&meta)?,
+                    &data_key(&data)?,
+                    value,
+                    &Self::committed(&tx),
+                    timestamp,
+                );
+            }
+            LogRecord::UnsetMetadata {
+                tx,
+                timestamp,
+                data_key: data,
+                metadata_key: meta,
+            } => {
+                index.unset_metadata_at(
+                    &meta_key(&meta)?,
+                    &data_key(&data)?,
+                    &Self::committed(&tx),
+                    timestamp,
+                );
+            }
+            LogRecord::SetTombstone { tx, key } => {
+                index.set_tombstone(&data_key(&key)?, tx)?;
+            }
+            LogRecord::CreateTransaction { timestamp, tx } => {
+                index.set_transaction_created(&tx, timestamp);
+            }
+            LogRecord::Commit { timestamp, tx } => {
+                return Ok(index.commit_transaction_at(&tx, timestamp));
+            }
+            LogRecord::DeleteTransaction { tx } => {
+                return index.delete_transaction(tx);
+            }
+            LogRecord::Revert {
+                timestamp,
+                generation,
+            } => {
+                return index.revert_to_generation_at(generation, timestamp);
+            }
+            LogRecord::Generation {
+                id,
+                transaction,
+                prior,
+            } => {
+                let mut prior_keys = HashMap::new();
+                for (name, value) in prior {
+                    prior_keys.insert(data_key(&name)?, value);
+                }
+                index.restore_generation(Generation {
+                    id,
+                    transaction,
+                    prior: prior_keys,
+                });
+            }
+            LogRecord::LatestGeneration { id } => {
+                index.set_latest_generation(id);
+            }
+            LogRecord::Journal(record) => {
+                index.restore_journal_entry(record.into_entry()?);
+            }
+        }
+        Ok(HashSet::new())
+    }
+}
+
+#[derive(Debug)]
+pub struct LogDataStore {
+    path: PathBuf,
+    file: File,
+    index: MemoryDataStore,
+}
+
+impl LogDataStore {
+    /// Opens the log at the given path, creating it if it doesn't exist, and compacts it.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<LogDataStore> {
+        Self::open(path.as_ref(), None)
+    }
+
+    /// Opens the log at the given path like `new`, but if it has bad records followed by intact
+    /// ones, each bad record is saved to a file in the given quarantine directory, named for the
+    /// log and the record's offset, and the intact records are loaded.  Any changes in the bad
+    /// records are lost.
+    pub fn open_salvaging<P, Q>(path: P, quarantine_path: Q) -> Result<LogDataStore>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        Self::open(path.as_ref(), Some(quarantine_path.as_ref()))
+    }
+
+    fn open(path: &Path, quarantine_path: Option<&Path>) -> Result<LogDataStore> {
+        let path = path.to_path_buf();
+        // Keep every generation while replaying; the limit applies again from the next commit.
+        let mut index = MemoryDataStore::new().with_generation_limit(usize::MAX);
+
+        match fs::read(&path) {
+            Ok(bytes) => {
+                for record in read_records(&path, &bytes, quarantine_path)? {
+                    record.apply(&mut index)?;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                debug!("Creating new log at {}", path.display());
+            }
+            Err(e) => return Err(e).context(error::IoSnafu { path }),
+        }
+
+        let file = compact(&path, &index)?;
+        let index = index.with_generation_limit(DEFAULT_GENERATION_LIMIT);
+        Ok(LogDataStore { path, file, index })
+    }
+
+    /// Sets the number of committed generations to retain for reverting.  Older generations are
+    /// forgotten on the next commit.
+    pub fn with_generation_limit(mut self, limit: usize) -> Self {
+        self.index = self.index.with_generation_limit(limit);
+        self
+    }
+
+    /// Durably appends the given record to the log, then applies it to the index.
+    fn append(&mut self, record: LogRecord) -> Result<HashSet<Key>> {
+        let frame = encode_frame(&record)?;
+        let path = &self.path;
+        self.file
+            .write_all(&frame)
+            .context(error::IoSnafu { path })?;
+        self.file.sync_data().context(error::IoSnafu { path })?;
+        trace!("Appended {} byte record to {}", frame.len(), path.display());
+        record.apply(&mut self.index)
+    }
+
+    /// Records the creation of the given transaction, if it's pending and new, so its creation
+    /// time survives replay.
+    fn note_created(&mut self, committed: &Committed) -> Result<()> {
+        if let Committed::Pending { tx } = committed {
+            if self.index.transaction_created(tx)?.is_none() {
+                self.append(LogRecord::CreateTransaction {
+                    timestamp: SystemTime::now(),
+                    tx: tx.clone(),
+                })?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serializes a record and frames it with its length and checksum.
+fn encode_frame(record: &LogRecord) -> Result<Vec<u8>> {
+    let payload = serde_json::to_vec(record).context(error::LogRecordSerializeSnafu)?;
+    let len = u32::try_from(payload.len()).ok().ok_or_else(|| {
+        error::InternalSnafu {
+            msg: "log record too large",
+        }
+        .build()
+    })?;
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&len.to_le_bytes());
+    frame.extend_from_slice(&crc32(&payload).to_le_bytes());
+    frame.extend_from_slice(&payload);
+    Ok(frame)
+}
+
+/// Parses the records in the given log data.  A partial or damaged record at the end of the log
+/// is what we'd see if we were interrupted while appending it, so it's truncated from the file.
+/// A bad record followed by intact ones is an error, unless we're given a quarantine directory;
+/// then it's saved there and skipped.
+fn read_records(
+    path: &Path,
+    bytes: &[u8],
+    quarantine_path: Option<&Path>,
+) -> Result<Vec<LogRecord>> {
+    ensure!(
+        bytes.starts_with(LOG_MAGIC),
+        error::CorruptionSnafu {
+            msg: "missing log header",
+            path,
+        }
+    );
+
+    let mut records = Vec::new();
+    let mut pos = LOG_MAGIC.len();
+    while pos < bytes.len() {
+        if let Some((payload, next)) = intact_frame(bytes, pos) {
+            let record = serde_json::from_slice(payload)
+                .context(error::LogRecordParseSnafu { path, pos })?;
+            records.push(record);
+            pos = next;
+            continue;
+        }
+
+        match (next_intact_frame(bytes, pos + 1), quarantine_path) {
+            (None, _) => {
+                warn!(
+                    "Truncating partial or damaged record at offset {} at end of {}",
+                    pos,
+                    path.display()
+                );
+                truncate(path, pos)?;
+                break;
+            }
+            (Some(next), Some(quarantine_path)) => {
+                quarantine(path, quarantine_path, pos, &bytes[pos..next])?;
+                pos = next;
+            }
+            (Some(_), None) => {
+                return error::CorruptionSnafu {
+                    msg: format!(
+                        "damaged record at offset {}, followed by intact records",
+                        pos
+                    ),
+                    path,
+                }
+                .fail()
+            }
+        }
+    }
+
+    debug!("Read {} records from {}", records.len(), path.display());
+    Ok(records)
+}
+
+/// Returns the payload of the record framed at the given offset, and the offset after it, if the
+/// frame is complete and its checksum matches.  Every record has a payload, so an empty one, like
+/// we'd read from zeroed space left by an interrupted append, isn't intact.
+fn intact_frame(bytes: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    let header = bytes.get(pos..pos.checked_add(FRAME_HEADER_LEN)?)?;
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let checksum = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let start = pos + FRAME_HEADER_LEN;
+    let end = start.checked_add(len)?;
+    let payload = bytes.get(start..end)?;
+    (len > 0 && crc32(payload) == checksum).then_some((payload, end))
+}
+
+/// Returns the offset of the first intact record at or after the given offset, if any.
+fn next_intact_frame(bytes: &[u8], from: usize) -> Option<usize> {
+    (from..bytes.len()).find(|&pos| intact_frame(bytes, pos).is_some())
+}
+
+/// Durably truncates the log at the given path to the given length.
+fn truncate(path: &Path, len: usize) -> Result<()> {
+    let f = fs::OpenOptions::new()
+        .write(true)
+        .open(path)
+        .context(error::IoSnafu { path })?;
+    f.set_len(len as u64).context(error::IoSnafu { path })?;
+    f.sync_all().context(error::IoSnafu { path })
+}
+
+/// Saves a bad record from the given log, at the given offset, to the quarantine directory.
+fn quarantine(path: &Path, quarantine_path: &Path, pos: usize, bytes: &[u8]) -> Result<()> {
+    let name = path
+        .file_name()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy();
+    let target = quarantine_path.join(format!("{}.{}", name, pos));
+    warn!(
+        "Quarantining damaged record at offset {} of {} to {}",
+        pos,
+        path.display(),
+        target.display()
+    );
+    fs::create_dir_all(quarantine_path).context(error::IoSnafu {
+        path: quarantine_path,
+    })?;
+    fs::write(&target, bytes).context(error::IoSnafu { path: &target })
+}
+
+/// Rewrites the log to hold just the current state of the given index, and returns the new log
+/// file, open for appending.
+fn compact(path: &Path, index: &MemoryDataStore) -> Result<File> {
+    let mut records = Vec::new();
+
+    // History comes first, so that replaying the current state doesn't add to it.
+    for generation in index.list_generations()? {
+        records.push(LogRecord::Generation {
+            id: generation.id,
+            transaction: generation.transaction,
+            prior: generation
+                .prior
+                .into_iter()
+                .map(|(key, value)| (key.name().clone(), value))
+                .collect(),
+        });
+    }
+    records.push(LogRecord::LatestGeneration {
+        id: index.latest_generation(),
+    });
+    for entry in index.read_journal(&JournalFilter::new())? {
+        records.push(LogRecord::Journal(JournalRecord::from(&entry)));
+    }
+
+    let mut states = vec![Committed::Live];
+    for tx in index.list_transactions()? {
+        if let Some(timestamp) = index.transaction_created(&tx)? {
+            records.push(LogRecord::CreateTransaction {
+                timestamp,
+                tx: tx.clone(),
+            });
+        }
+        for key in index.list_tombstones(&tx)? {
+            records.push(LogRecord::SetTombstone {
+                tx: tx.clone(),
+                key: key.name().clone(),
+            });
+        }
+        // Pending removals aren't journaled, so their time doesn't matter.
+        for (data_key, meta_keys) in index.list_metadata_tombstones(&tx)? {
+            for metadata_key in meta_keys {
+                records.push(LogRecord::UnsetMetadata {
+                    tx: Some(tx.clone()),
+                    timestamp: SystemTime::now(),
+                    data_key: data_key.name().clone(),
+                    metadata_key: metadata_key.name().clone(),
+                });
+            }
+        }
+        states.push(Committed::Pending { tx });
+    }
+    for committed in &states {
+        for (key, value) in index.get_prefix("", committed)? {
+            records.push(LogRecord::SetKey {
+                tx: LogRecord::tx(committed),
+                key: key.name().clone(),
+                value,
+            });
+        }
+    }
+    for committed in &states {
+        for (data_key, meta_map) in index.get_metadata_prefix("", committed, &None::<&str>)? {
+            for (metadata_key, value) in meta_map {
+                records.push(LogRecord::SetMetadata {
+                    tx: LogRecord::tx(committed),
+                    timestamp: None,
+                    data_key: data_key.name().clone(),
+                    metadata_key: metadata_key.name().clone(),
+                    value,
+                });
+            }
+        }
+    }
+
+    let mut data = LOG_MAGIC.to_vec();
+    for record in &records {
+        data.extend(encode_frame(record)?);
+    }
+
+    let mut temp_path = path.as_os_str().to_os_string();
+    temp_path.push(TEMP_FILE_SUFFIX);
+    let temp_path = PathBuf::from(temp_path);
+    let mut f = File::create(&temp_path).context(error::IoSnafu { path: &temp_path })?;
+    f.write_all(&data)
+        .context(error::IoSnafu { path: &temp_path })?;
+    f.sync_all().context(error::IoSnafu { path: &temp_path })?;
+    fs::rename(&temp_path, path).context(error::IoSnafu { path })?;
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        File::open(parent)
+            .and_then(|d| d.sync_all())
+            .context(error::IoSnafu { path: parent })?;
+    }
+    debug!("Compacted {} to {} records", path.display(), records.len());
+
+    fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .context(error::IoSnafu { path })
+}
+
+/// Computes the CRC-32 (IEEE) checksum of the given data.
+fn crc32(data: &[u8]) -> u32 {
+    const TABLE: [u32; 256] = {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut bit = 0;
+            while bit < 8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0xEDB8_8320
+                } else {
+                    crc >> 1
+                };
+                bit += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    };
+
+    let mut crc = !0u32;
+    for byte in data {
+        crc = TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+// Reads are served from the index; writes are appended to the log and then applied to the index.
+impl DataStore for LogDataStore {
+    fn key_populated(&self, key: &Key, committed: &Committed) -> Result<bool> {
+        self.index.key_populated(key, committed)
+    }
+
+    fn list_populated_keys<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        self.index.list_populated_keys(prefix, committed)
+    }
+
+    fn list_populated_metadata<S1, S2>(
+        &self,
+        prefix: S1,
+        committed: &Committed,
+        metadata_key_name: &Option<S2>,
+    ) -> Result<HashMap<Key, HashSet<Key>>>
+    where
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        self.index
+            .list_populated_metadata(prefix, committed, metadata_key_name)
+    }
+
+    fn list_matching_keys(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        self.index.list_matching_keys(pattern, committed)
+    }
+
+    fn list_matching_metadata<S>(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+        metadata_key_name: &Option<S>,
+    ) -> Result<HashMap<Key, HashSet<Key>>>
+    where
+        S: AsRef<str>,
+    {
+        self.index
+            .list_matching_metadata(pattern, committed, metadata_key_name)
+    }
+
+    fn get_key(&self, key: &Key, committed: &Committed) -> Result<Option<String>> {
+        self.index.get_key(key, committed)
+    }
+
+    fn set_key<S: AsRef<str>>(&mut self, key: &Key, value: S, committed: &Committed) -> Result<()> {
+        self.note_created(committed)?;
+        self.append(LogRecord::SetKey {
+            tx: LogRecord::tx(committed),
+            key: key.name().clone(),
+            value: value.as_ref().to_string(),
+        })?;
+        Ok(())
+    }
+
+    fn unset_key(&mut self, key: &Key, committed: &Committed) -> Result<()> {
+        self.note_created(committed)?;
+        self.append(LogRecord::UnsetKey {
+            tx: LogRecord::tx(committed),
+            key: key.name().clone(),
+        })?;
+        Ok(())
+    }
+
+    fn get_metadata_raw(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>> {
+        self.index
+            .get_metadata_raw(metadata_key, data_key, committed)
+    }
+
+    fn set_metadata<S: AsRef<str>>(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        value: S,
+        committed: &Committed,
+    ) -> Result<()> {
+        self.note_created(committed)?;
+        self.append(LogRecord::SetMetadata {
+            tx: LogRecord::tx(committed),
+            timestamp: Some(SystemTime::now()),
+            data_key: data_key.name().clone(),
+            metadata_key: metadata_key.name().clone(),
+            value: value.as_ref().to_string(),
+        })?;
+        Ok(())
+    }
+
+    fn unset_metadata(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<()> {
+        self.append(LogRecord::UnsetMetadata {
+            tx: LogRecord::tx(committed),
+            timestamp: SystemTime::now(),
+            data_key: data_key.name().clone(),
+            metadata_key: metadata_key.name().clone(),
+        })?;
+        Ok(())
+    }
+
+    fn set_tombstone<S>(&mut self, key: &Key, transaction: S) -> Result<()>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        self.note_created(&Committed::Pending {
+            tx: transaction.as_ref().to_string(),
+        })?;
+        self.append(LogRecord::SetTombstone {
+            tx: transaction.into(),
+            key: key.name().clone(),
+        })?;
+        Ok(())
+    }
+
+    fn list_tombstones<S: AsRef<str>>(&self, transaction: S) -> Result<HashSet<Key>> {
+        self.index.list_tombstones(transaction)
+    }
+
+    fn list_metadata_tombstones<S: AsRef<str>>(
+        &self,
+        transaction: S,
+    ) -> Result<HashMap<Key, HashSet<Key>>> {
+        self.index.list_metadata_tombstones(transaction)
+    }
+
+    fn commit_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        self.append(LogRecord::Commit {
+            timestamp: SystemTime::now(),
+            tx: transaction.into(),
+        })
+    }
+
+    fn delete_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        self.append(LogRecord::DeleteTransaction {
+            tx: transaction.into(),
+        })
+    }
+
+    fn list_transactions(&self) -> Result<HashSet<String>> {
+        self.index.list_transactions()
+    }
+
+    fn transaction_created<S: AsRef<str>>(&self, transaction: S) -> Result<Option<SystemTime>> {
+        self.index.transaction_created(transaction)
+    }
+
+    fn list_generations(&self) -> Result<Vec<Generation>> {
+        self.index.list_generations()
+    }
+
+    /// We check that the generation is available before logging the revert, so an invalid
+    /// request doesn't leave a record that would fail on every replay.
+    fn revert_to_generation(&mut self, id: u64) -> Result<HashSet<Key>> {
+        super::check_revert_target(
+            &self.index.list_generations()?,
+            self.index.latest_generation(),
+            id,
+        )?;
+        self.append(LogRecord::Revert {
+            timestamp: SystemTime::now(),
+            generation: id,
+        })
+    }
+
+    fn read_journal(&self, filter: &JournalFilter) -> Result<Vec<JournalEntry>> {
+        self.index.read_journal(filter)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scratch::TestPath;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn commit_and_delete_transaction() {
+        let log = TestPath::new("commit");
+        let mut l = LogDataStore::new(&log.0).unwrap();
+        let k = Key::new(KeyType::Data, "settings.a.b.c").unwrap();
+        let k2 = Key::new(KeyType::Data, "settings.x.y.z").unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+        let pending2 = Committed::Pending { tx: "tx2".into() };
+        l.set_key(&k, "logvalue", &pending).unwrap();
+        l.set_key(&k2, "logvalue 2", &pending2).unwrap();
+
+        assert!(l.key_populated(&k, &pending).unwrap());
+        assert!(!l.key_populated(&k, &Committed::Live).unwrap());
+        assert_eq!(
+            l.commit_transaction("tx").unwrap(),
+            HashSet::from([k.clone()])
+        );
+        assert!(!l.key_populated(&k, &pending).unwrap());
+        assert!(l.key_populated(&k, &Committed::Live).unwrap());
+
+        assert_eq!(
+            l.delete_transaction("tx2").unwrap(),
+            HashSet::from([k2.clone()])
+        );
+        assert!(!l.key_populated(&k2, &pending2).unwrap());
+        assert!(l.list_transactions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn tombstones() {
+        let log = TestPath::new("tombstones");
+        let mut l = LogDataStore::new(&log.0).unwrap();
+        let a = Key::new(KeyType::Data, "settings.a.x").unwrap();
+        let ab = Key::new(KeyType::Data, "settings.ab").unwrap();
+        l.set_key(&a, "1", &Committed::Live).unwrap();
+        l.set_key(&ab, "2", &Committed::Live).unwrap();
+
+        let tombstone = Key::new(KeyType::Data, "settings.a").unwrap();
+        l.set_tombstone(&tombstone, "tx").unwrap();
+        assert_eq!(
+            l.commit_transaction("tx").unwrap(),
+            HashSet::from([a.clone()])
+        );
+        assert!(!l.key_populated(&a, &Committed::Live).unwrap());
+        assert!(l.key_populated(&ab, &Committed::Live).unwrap());
+    }
+
+    #[test]
+    fn invalid_revert_isnt_logged() {
+        let log = TestPath::new("invalid-revert");
+        let mut l = LogDataStore::new(&log.0).unwrap();
+        l.revert_to_generation(5).unwrap_err();
+        drop(l);
+        // Replaying would fail if the revert had been logged.
+        LogDataStore::new(&log.0).unwrap();
+    }
+
+    #[test]
+    fn reopen_keeps_state_and_history() {
+        let log = TestPath::new("reopen");
+        let a = Key::new(KeyType::Data, "settings.a").unwrap();
+        let b = Key::new(KeyType::Data, "settings.b").unwrap();
+        let meta = Key::new(KeyType::Meta, "affected-services").unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+        let created;
+        {
+            let mut l = LogDataStore::new(&log.0).unwrap();
+            l.set_key(&a, "1", &pending).unwrap();
+            l.commit_transaction("tx").unwrap();
+            l.set_key(&a, "2", &pending).unwrap();
+            l.set_tombstone(&b, "tx").unwrap();
+            l.set_metadata(&meta, &a, "[]", &Committed::Live).unwrap();
+            l.set_metadata(&meta, &b, "[]", &pending).unwrap();
+            created = l.transaction_created("tx").unwrap();
+        }
+
+        let mut l = LogDataStore::new(&log.0).unwrap();
+        assert!(created.is_some());
+        assert_eq!(l.transaction_created("tx").unwrap(), created);
+        assert_eq!(
+            l.get_key(&a, &Committed::Live).unwrap(),
+            Some("1".to_string())
+        );
+        assert_eq!(l.get_key(&a, &pending).unwrap(), Some("2".to_string()));
+        assert_eq!(l.list_tombstones("tx").unwrap(), HashSet::from([b.clone()]));
+        assert_eq!(
+            l.get_metadata_raw(&meta, &a, &Committed::Live).unwrap(),
+            Some("[]".to_string())
+        );
+        assert_eq!(
+            l.get_metadata_raw(&meta, &b, &pending).unwrap(),
+            Some("[]".to_string())
+        );
+        assert_eq!(l.list_generations().unwrap().len(), 1);
+        // Compaction doesn't duplicate history.
+        assert_eq!(l.read_journal(&JournalFilter::new()).unwrap().len(), 2);
+
+        l.commit_transaction("tx").unwrap();
+        assert_eq!(
+            l.get_metadata_raw(&meta, &b, &Committed::Live).unwrap(),
+            Some("[]".to_string())
+        );
+        l.revert_to_generation(1).unwrap();
+        assert_eq!(
+            l.get_key(&a, &Committed::Live).unwrap(),
+            Some("1".to_string())
+        );
+    }
+
+    #[test]
+    fn torn_append_is_ignored() {
+        let log = TestPath::new("torn-append");
+        let a = Key::new(KeyType::Data, "settings.a").unwrap();
+        {
+            let mut l = LogDataStore::new(&log.0).unwrap();
+            l.set_key(&a, "1", &Committed::Live).unwrap();
+        }
+
+        // Simulate an interrupted append of a second record.
+        let frame = encode_frame(&LogRecord::SetKey {
+            tx: None,
+            key: a.name().clone(),
+            value: "2".to_string(),
+        })
+        .unwrap();
+        let mut f = fs::OpenOptions::new().append(true).open(&log.0).unwrap();
+        f.write_all(&frame[..frame.len() - 1]).unwrap();
+        // A crash can also leave zeroed space after the end of the file.
+        f.write_all(&[0; 64]).unwrap();
+
+        let l = LogDataStore::new(&log.0).unwrap();
+        assert_eq!(
+            l.get_key(&a, &Committed::Live).unwrap(),
+            Some("1".to_string())
+        );
+    }
+
+    #[test]
+    fn corrupt_record_is_an_error() {
+        let log = TestPath::new("corrupt-record");
+        let a = Key::new(KeyType::Data, "settings.a").unwrap();
+        {
+            let mut l = LogDataStore::new(&log.0).unwrap();
+            l.set_key(&a, "1", &Committed::Live).unwrap();
+            l.set_key(&a, "2", &Committed::Live).unwrap();
+        }
+        let mut bytes = fs::read(&log.0).unwrap();
+        // Damage the value of the first record.
+        let pos = bytes.windows(3).position(|w| w == b"\"1\"").unwrap();
+        bytes[pos + 1] = b'3';
+        fs::write(&log.0, bytes).unwrap();
+
+        LogDataStore::new(&log.0).unwrap_err();
+
+        // Salvaging quarantines the damaged record, and loads the rest.
+        let quarantine = TestPath::new("corrupt-record-quarantine");
+        let l = LogDataStore::open_salvaging(&log.0, &quarantine.0).unwrap();
+        assert_eq!(
+            l.get_key(&a, &Committed::Live).unwrap(),
+            Some("2".to_string())
+        );
+        let quarantined: Vec<_> = fs::read_dir(&quarantine.0).unwrap().collect();
+        assert_eq!(quarantined.len(), 1);
+        drop(l);
+        LogDataStore::new(&log.0).unwrap();
+    }
+
+    #[test]
+    fn damaged_length_isnt_a_torn_append() {
+        let log = TestPath::new("damaged-length");
+        let (a, b) = (
+            Key::new(KeyType::Data, "settings.a").unwrap(),
+            Key::new(KeyType::Data, "settings.b").unwrap(),
+        );
+        {
+            let mut l = LogDataStore::new(&log.0).unwrap();
+            l.set_key(&a, "1", &Committed::Live).unwrap();
+            l.set_key(&b, "2", &Committed::Live).unwrap();
+        }
+        // Make the first record claim to run past the end of the log, like a partial one would.
+        let mut bytes = fs::read(&log.0).unwrap();
+        let pos = LOG_MAGIC.len();
+        bytes[pos..pos + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&log.0, bytes).unwrap();
+
+        LogDataStore::new(&log.0).unwrap_err();
+        let quarantine = TestPath::new("damaged-length-quarantine");
+        let l = LogDataStore::open_salvaging(&log.0, &quarantine.0).unwrap();
+        assert_eq!(
+            l.get_key(&b, &Committed::Live).unwrap(),
+            Some("2".to_string())
+        );
+        assert_eq!(fs::read_dir(&quarantine.0).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn conformance() {
+        let mut logs = Vec::new();
+        crate::conformance::run_all(|| {
+            let log = TestPath::new("conformance");
+            let l = LogDataStore::new(&log.0).unwrap();
+            logs.push(log);
+            l
+        });
+    }
+}
@@ -1,21 +1,61 @@
 //! In-memory datastore for use in testing other modules.
 //!
-//! Mimics some of the decisions made for FilesystemDataStore, e.g. metadata being committed
-//! immediately.
+//! Mimics some of the decisions made for FilesystemDataStore, e.g. pending metadata being
+//! applied with the rest of its transaction.
 
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::SystemTime;
 
-use super::{Committed, DataStore, Key, Result};
+use super::journal::{self, JournalEntry, JournalFilter, Operation};
+use super::sensitive::SensitiveKeys;
+use super::{
+    check_revert_target, tombstoned_keys, Committed, DataStore, Generation, Key, KeyPattern,
+    Result, DEFAULT_GENERATION_LIMIT,
+};
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct MemoryDataStore {
     // Transaction name -> (key -> data)
     pending: HashMap<String, HashMap<Key, String>>,
+    // Transaction name -> keys to remove on commit
+    tombstones: HashMap<String, HashSet<Key>>,
     // Committed (live) data.
     live: HashMap<Key, String>,
     // Map of data keys to their metadata, which in turn is a mapping of metadata keys to
     // arbitrary (string/serialized) values.
     metadata: HashMap<Key, HashMap<Key, String>>,
+    // Transaction name -> (data key -> metadata)
+    pending_metadata: HashMap<String, HashMap<Key, HashMap<Key, String>>>,
+    // Transaction name -> (data key -> metadata keys to remove on commit)
+    metadata_tombstones: HashMap<String, HashMap<Key, HashSet<Key>>>,
+    // Transaction name -> when something was first written to it
+    created: HashMap<String, SystemTime>,
+    // Retained generations of live data, oldest first.
+    generations: VecDeque<Generation>,
+    // ID of the most recent generation, even if it's no longer retained.
+    latest_generation: u64,
+    // Maximum number of generations to retain.
+    generation_limit: usize,
+    // Journal of changes, oldest first.
+    journal: Vec<JournalEntry>,
+}
+
+impl Default for MemoryDataStore {
+    fn default() -> Self {
+        Self {
+            pending: HashMap::new(),
+            tombstones: HashMap::new(),
+            live: HashMap::new(),
+            metadata: HashMap::new(),
+            pending_metadata: HashMap::new(),
+            metadata_tombstones: HashMap::new(),
+            created: HashMap::new(),
+            generations: VecDeque::new(),
+            latest_generation: 0,
+            generation_limit: DEFAULT_GENERATION_LIMIT,
+            journal: Vec::new(),
+        }
+    }
 }
 
 impl MemoryDataStore {
@@ -23,6 +63,12 @@ impl MemoryDataStore {
         Default::default()
     }
 
+    /// Sets the number of committed generations to retain for reverting.
+    pub fn with_generation_limit(mut self, limit: usize) -> Self {
+        self.generation_limit = limit;
+        self
+    }
+
     fn dataset(&self, committed: &Committed) -> Option<&HashMap<Key, String>> {
         match committed {
             Committed::Live => Some(&self.live),
@@ -30,11 +76,363 @@ impl MemoryDataStore {
         }
     }
 
+    /// Sets or removes (for None) the given keys in live data, returning the changed keys.
+    fn apply_live_changes(&mut self, changes: HashMap<Key, Option<String>>) -> HashSet<Key> {
+        let mut changed = HashSet::new();
+        for (key, value) in changes {
+            match value {
+                Some(value) => self.live.insert(key.clone(), value),
+                None => self.live.remove(&key),
+            };
+            changed.insert(key);
+        }
+        changed
+    }
+
     fn dataset_mut(&mut self, committed: &Committed) -> &mut HashMap<Key, String> {
         match committed {
             Committed::Live => &mut self.live,
-            Committed::Pending { tx } => self.pending.entry(tx.clone()).or_default(),
+            Committed::Pending { tx } => {
+                self.note_created(tx);
+                self.pending.entry(tx.clone()).or_default()
+            }
+        }
+    }
+
+    fn metadata_set(&self, committed: &Committed) -> Option<&HashMap<Key, HashMap<Key, String>>> {
+        match committed {
+            Committed::Live => Some(&self.metadata),
+            Committed::Pending { tx } => self.pending_metadata.get(tx),
+        }
+    }
+
+    fn metadata_set_mut(
+        &mut self,
+        committed: &Committed,
+    ) -> &mut HashMap<Key, HashMap<Key, String>> {
+        match committed {
+            Committed::Live => &mut self.metadata,
+            Committed::Pending { tx } => {
+                self.note_created(tx);
+                self.pending_metadata.entry(tx.clone()).or_default()
+            }
+        }
+    }
+
+    /// Records the creation time of the given transaction, if it's new.
+    fn note_created(&mut self, transaction: &str) {
+        if !self.created.contains_key(transaction) {
+            self.created
+                .insert(transaction.to_string(), SystemTime::now());
+        }
+    }
+
+    /// Lists the populated metadata keys of the data keys selected by the given filter, for
+    /// `list_populated_metadata` and `list_matching_metadata`.
+    fn find_metadata<F, S>(
+        &self,
+        data_key_filter: F,
+        committed: &Committed,
+        metadata_key_name: &Option<S>,
+    ) -> HashMap<Key, HashSet<Key>>
+    where
+        F: Fn(&Key) -> bool,
+        S: AsRef<str>,
+    {
+        let mut result = HashMap::new();
+        let empty = HashMap::new();
+        for (data_key, meta_map) in self.metadata_set(committed).unwrap_or(&empty) {
+            if !data_key_filter(data_key) {
+                continue;
+            }
+
+            let mut meta_for_data = HashSet::new();
+            for meta_key in meta_map.keys() {
+                // Confirm metadata key matches requested name, if any.
+                if let Some(name) = metadata_key_name {
+                    if name.as_ref() != meta_key.name() {
+                        continue;
+                    }
+                }
+                meta_for_data.insert(meta_key.clone());
+            }
+            // Only add an entry for the data key if we found metadata.
+            if !meta_for_data.is_empty() {
+                result.insert(data_key.clone(), meta_for_data);
+            }
         }
+        result
+    }
+}
+
+// Backends that use a MemoryDataStore as an index, like LogDataStore, replay changes using their
+// original times and restore compacted history using these helpers.
+impl MemoryDataStore {
+    /// Commits the given transaction, journaling it with the given time.
+    pub(crate) fn commit_transaction_at(
+        &mut self,
+        transaction: &str,
+        timestamp: SystemTime,
+    ) -> HashSet<Key> {
+        // Remove anything pending for this transaction
+        let pending = self.pending.remove(transaction);
+        let tombstones = self.tombstones.remove(transaction);
+        let pending_metadata = self.pending_metadata.remove(transaction);
+        let metadata_tombstones = self.metadata_tombstones.remove(transaction);
+        self.created.remove(transaction);
+        if pending.is_none()
+            && tombstones.is_none()
+            && pending_metadata.is_none()
+            && metadata_tombstones.is_none()
+        {
+            return HashSet::new();
+        }
+
+        // Tombstones are applied first, so pending keys override removals.
+        let mut changes: HashMap<Key, Option<String>> = HashMap::new();
+        if let Some(tombstones) = tombstones {
+            for key in tombstoned_keys(self.live.keys(), &tombstones) {
+                changes.insert(key, None);
+            }
+        }
+        // Like FilesystemDataStore, we only commit settings; other pending keys are discarded.
+        for (key, value) in pending.unwrap_or_default() {
+            if key.name().starts_with("settings.") {
+                changes.insert(key, Some(value));
+            }
+        }
+
+        // Save the current values of the changed keys so the commit can be reverted.
+        let prior: HashMap<Key, Option<String>> = changes
+            .keys()
+            .map(|k| (k.clone(), self.live.get(k).cloned()))
+            .collect();
+        let mut entries = journal::data_entries(
+            timestamp,
+            Some(transaction),
+            Operation::Commit,
+            &prior,
+            &changes,
+        );
+        // Markings in the transaction apply to its own values.
+        SensitiveKeys::from_metadata(
+            self.metadata
+                .iter()
+                .chain(pending_metadata.iter().flatten()),
+        )
+        .redact_journal(&mut entries);
+        self.journal.extend(entries);
+        if self.generation_limit == 0 {
+            // Generations kept under an earlier limit are forgotten too.
+            self.generations.clear();
+        } else if !changes.is_empty() {
+            self.restore_generation(Generation {
+                id: self.latest_generation + 1,
+                transaction: transaction.to_string(),
+                prior,
+            });
+        }
+
+        // Metadata doesn't have generations, but its changes are journaled with the transaction.
+        let mut metadata_changes: Vec<(Key, Key, String)> = pending_metadata
+            .unwrap_or_default()
+            .into_iter()
+            .flat_map(|(data_key, meta_map)| {
+                meta_map
+                    .into_iter()
+                    .map(move |(meta_key, value)| (data_key.clone(), meta_key, value))
+            })
+            .collect();
+        metadata_changes.sort_by(|a, b| (a.0.name(), a.1.name()).cmp(&(b.0.name(), b.1.name())));
+        for (data_key, metadata_key, value) in metadata_changes {
+            let old_value = self
+                .metadata
+                .entry(data_key.clone())
+                .or_default()
+                .insert(metadata_key.clone(), value.clone());
+            self.journal.push(journal::metadata_entry(
+                timestamp,
+                Some(transaction),
+                &metadata_key,
+                &data_key,
+                old_value,
+                Some(value),
+            ));
+        }
+        let mut metadata_removals: Vec<(Key, Key)> = metadata_tombstones
+            .unwrap_or_default()
+            .into_iter()
+            .flat_map(|(data_key, meta_keys)| {
+                meta_keys
+                    .into_iter()
+                    .map(move |meta_key| (data_key.clone(), meta_key))
+            })
+            .collect();
+        metadata_removals.sort_by(|a, b| (a.0.name(), a.1.name()).cmp(&(b.0.name(), b.1.name())));
+        for (data_key, metadata_key) in metadata_removals {
+            let old_value = self
+                .metadata
+                .get_mut(&data_key)
+                .and_then(|m| m.remove(&metadata_key));
+            if old_value.is_some() {
+                self.journal.push(journal::metadata_entry(
+                    timestamp,
+                    Some(transaction),
+                    &metadata_key,
+                    &data_key,
+                    old_value,
+                    None,
+                ));
+            }
+        }
+
+        // Apply changes to live, and return keys that were committed
+        self.apply_live_changes(changes)
+    }
+
+    /// Reverts to the given generation, journaling it with the given time.
+    pub(crate) fn revert_to_generation_at(
+        &mut self,
+        id: u64,
+        timestamp: SystemTime,
+    ) -> Result<HashSet<Key>> {
+        check_revert_target(
+            self.generations.make_contiguous(),
+            self.latest_generation,
+            id,
+        )?;
+
+        // Undo generations newest first, so the oldest prior value of each key wins.
+        let mut changes = HashMap::new();
+        while self.generations.back().map(|g| g.id > id).unwrap_or(false) {
+            let generation = self.generations.pop_back().expect("checked non-empty");
+            changes.extend(generation.prior);
+        }
+        self.latest_generation = id;
+
+        let prior = changes
+            .keys()
+            .map(|k| (k.clone(), self.live.get(k).cloned()))
+            .collect();
+        let mut entries =
+            journal::data_entries(timestamp, None, Operation::Revert, &prior, &changes);
+        SensitiveKeys::from_metadata(&self.metadata).redact_journal(&mut entries);
+        self.journal.extend(entries);
+
+        Ok(self.apply_live_changes(changes))
+    }
+
+    /// Sets metadata, journaling it with the given time; if None, the change is being restored
+    /// rather than made, so it isn't journaled.  Only changes to live metadata are journaled.
+    pub(crate) fn set_metadata_at<S: AsRef<str>>(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        value: S,
+        committed: &Committed,
+        journal_time: Option<SystemTime>,
+    ) {
+        // If we don't already have a metadata entry for this data key, insert one.
+        let metadata_for_data = self
+            .metadata_set_mut(committed)
+            // Clone data key because we want the HashMap key type to be Key, not &Key, and we
+            // can't pass ownership because we only have a reference from our parameters.
+            .entry(data_key.clone())
+            .or_default();
+
+        let old_value = metadata_for_data.insert(metadata_key.clone(), value.as_ref().to_owned());
+        if let Committed::Pending { tx } = committed {
+            // Setting metadata again cancels its removal.
+            if let Some(meta_keys) = self
+                .metadata_tombstones
+                .get_mut(tx)
+                .and_then(|m| m.get_mut(data_key))
+            {
+                meta_keys.remove(metadata_key);
+            }
+        }
+        if let (Committed::Live, Some(timestamp)) = (committed, journal_time) {
+            self.journal.push(journal::metadata_entry(
+                timestamp,
+                None,
+                metadata_key,
+                data_key,
+                old_value,
+                Some(value.as_ref().to_owned()),
+            ));
+        }
+    }
+
+    /// Removes metadata, journaling it with the given time if it was live.  In a pending
+    /// transaction, the removal of live metadata is recorded for the commit.
+    pub(crate) fn unset_metadata_at(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+        timestamp: SystemTime,
+    ) {
+        if let Committed::Pending { tx } = committed {
+            if let Some(metadata_for_data) = self
+                .pending_metadata
+                .get_mut(tx)
+                .and_then(|m| m.get_mut(data_key))
+            {
+                metadata_for_data.remove(metadata_key);
+            }
+            self.note_created(tx);
+            self.metadata_tombstones
+                .entry(tx.clone())
+                .or_default()
+                .entry(data_key.clone())
+                .or_default()
+                .insert(metadata_key.clone());
+            return;
+        }
+
+        // If we have any metadata for this data key, remove the given metadata key.
+        if let Some(metadata_for_data) = self.metadata.get_mut(data_key) {
+            if let Some(old_value) = metadata_for_data.remove(metadata_key) {
+                self.journal.push(journal::metadata_entry(
+                    timestamp,
+                    None,
+                    metadata_key,
+                    data_key,
+                    Some(old_value),
+                    None,
+                ));
+            }
+        }
+    }
+
+    /// Returns the ID of the most recent generation, even if it's no longer retained.
+    pub(crate) fn latest_generation(&self) -> u64 {
+        self.latest_generation
+    }
+
+    /// Sets the ID of the most recent generation, so IDs continue where they left off.
+    pub(crate) fn set_latest_generation(&mut self, id: u64) {
+        self.latest_generation = id;
+    }
+
+    /// Adds a generation as the latest, forgetting old generations beyond the retention limit.
+    pub(crate) fn restore_generation(&mut self, generation: Generation) {
+        self.latest_generation = generation.id;
+        self.generations.push_back(generation);
+        while self.generations.len() > self.generation_limit {
+            self.generations.pop_front();
+        }
+    }
+
+    /// Sets the creation time of the given transaction, so restored transactions keep their
+    /// original times.
+    pub(crate) fn set_transaction_created(&mut self, transaction: &str, created: SystemTime) {
+        self.created.insert(transaction.to_string(), created);
+    }
+
+    /// Adds an entry to the end of the journal.
+    pub(crate) fn restore_journal_entry(&mut self, entry: JournalEntry) {
+        self.journal.push(entry);
     }
 }
 
@@ -57,36 +455,49 @@ impl DataStore for MemoryDataStore {
     fn list_populated_metadata<S1, S2>(
         &self,
         prefix: S1,
+        committed: &Committed,
         metadata_key_name: &Option<S2>,
     ) -> Result<HashMap<Key, HashSet<Key>>>
     where
         S1: AsRef<str>,
         S2: AsRef<str>,
     {
-        let mut result = HashMap::new();
-        for (data_key, meta_map) in self.metadata.iter() {
-            // Confirm data key matches requested prefix.
-            if !data_key.name().starts_with(prefix.as_ref()) {
-                continue;
-            }
+        // Confirm data key matches requested prefix.
+        Ok(self.find_metadata(
+            |data_key| data_key.name().starts_with(prefix.as_ref()),
+            committed,
+            metadata_key_name,
+        ))
+    }
 
-            let mut meta_for_data = HashSet::new();
-            for meta_key in meta_map.keys() {
-                // Confirm metadata key matches requested name, if any.
-                if let Some(name) = metadata_key_name {
-                    if name.as_ref() != meta_key.name() {
-                        continue;
-                    }
-                }
-                meta_for_data.insert(meta_key.clone());
-            }
-            // Only add an entry for the data key if we found metadata.
-            if !meta_for_data.is_empty() {
-                result.insert(data_key.clone(), meta_for_data);
-            }
-        }
+    fn list_matching_keys(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        let empty = HashMap::new();
+        let dataset = self.dataset(committed).unwrap_or(&empty);
+        Ok(dataset
+            .keys()
+            .filter(|k| pattern.matches(k))
+            .cloned()
+            .collect())
+    }
 
-        Ok(result)
+    fn list_matching_metadata<S>(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+        metadata_key_name: &Option<S>,
+    ) -> Result<HashMap<Key, HashSet<Key>>>
+    where
+        S: AsRef<str>,
+    {
+        Ok(self.find_metadata(
+            |data_key| pattern.matches(data_key),
+            committed,
+            metadata_key_name,
+        ))
     }
 
     fn get_key(&self, key: &Key, committed: &Committed) -> Result<Option<String>> {
@@ -112,8 +523,13 @@ impl DataStore for MemoryDataStore {
         Ok(dataset.contains_key(key))
     }
 
-    fn get_metadata_raw(&self, metadata_key: &Key, data_key: &Key) -> Result<Option<String>> {
-        let metadata_for_data = self.metadata.get(data_key);
+    fn get_metadata_raw(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>> {
+        let metadata_for_data = self.metadata_set(committed).and_then(|m| m.get(data_key));
         // If we have a metadata entry for this data key, then we can try fetching the requested
         // metadata key, otherwise we'll return early with Ok(None).
         let result = metadata_for_data.and_then(|m| m.get(metadata_key));
@@ -125,63 +541,126 @@ impl DataStore for MemoryDataStore {
         metadata_key: &Key,
         data_key: &Key,
         value: S,
+        committed: &Committed,
     ) -> Result<()> {
-        // If we don't already have a metadata entry for this data key, insert one.
-        let metadata_for_data = self
-            .metadata
-            // Clone data key because we want the HashMap key type to be Key, not &Key, and we
-            // can't pass ownership because we only have a reference from our parameters.
-            .entry(data_key.clone())
-            .or_default();
+        self.set_metadata_at(
+            metadata_key,
+            data_key,
+            value,
+            committed,
+            Some(SystemTime::now()),
+        );
+        Ok(())
+    }
 
-        metadata_for_data.insert(metadata_key.clone(), value.as_ref().to_owned());
+    fn unset_metadata(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<()> {
+        self.unset_metadata_at(metadata_key, data_key, committed, SystemTime::now());
         Ok(())
     }
 
-    fn unset_metadata(&mut self, metadata_key: &Key, data_key: &Key) -> Result<()> {
-        // If we have any metadata for this data key, remove the given metadata key.
-        if let Some(metadata_for_data) = self.metadata.get_mut(data_key) {
-            metadata_for_data.remove(metadata_key);
-        }
+    fn set_tombstone<S>(&mut self, key: &Key, transaction: S) -> Result<()>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        self.note_created(transaction.as_ref());
+        self.tombstones
+            .entry(transaction.into())
+            .or_default()
+            .insert(key.clone());
         Ok(())
     }
 
+    fn list_tombstones<S: AsRef<str>>(&self, transaction: S) -> Result<HashSet<Key>> {
+        Ok(self
+            .tombstones
+            .get(transaction.as_ref())
+            .cloned()
+            .unwrap_or_default())
+    }
+
+    fn list_metadata_tombstones<S: AsRef<str>>(
+        &self,
+        transaction: S,
+    ) -> Result<HashMap<Key, HashSet<Key>>> {
+        let mut result = self
+            .metadata_tombstones
+            .get(transaction.as_ref())
+            .cloned()
+            .unwrap_or_default();
+        result.retain(|_, meta_keys| !meta_keys.is_empty());
+        Ok(result)
+    }
+
     fn commit_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
     where
         S: Into<String> + AsRef<str>,
     {
-        // Remove anything pending for this transaction
-        if let Some(pending) = self.pending.remove(transaction.as_ref()) {
-            // Apply pending changes to live
-            self.set_keys(&pending, &Committed::Live)?;
-            // Return keys that were committed
-            Ok(pending.keys().cloned().collect())
-        } else {
-            Ok(HashSet::new())
-        }
+        Ok(self.commit_transaction_at(transaction.as_ref(), SystemTime::now()))
     }
 
     fn delete_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
     where
         S: Into<String> + AsRef<str>,
     {
-        // Remove anything pending for this transaction
+        // Remove anything pending for this transaction, and return the old pending keys
+        let mut removed = HashSet::new();
         if let Some(pending) = self.pending.remove(transaction.as_ref()) {
-            // Return the old pending keys
-            Ok(pending.keys().cloned().collect())
-        } else {
-            Ok(HashSet::new())
+            removed.extend(
+                pending
+                    .into_keys()
+                    .filter(|key| key.name().starts_with("settings.")),
+            );
+        }
+        if let Some(tombstones) = self.tombstones.remove(transaction.as_ref()) {
+            removed.extend(tombstones);
         }
+        self.pending_metadata.remove(transaction.as_ref());
+        self.metadata_tombstones.remove(transaction.as_ref());
+        self.created.remove(transaction.as_ref());
+        Ok(removed)
     }
 
     fn list_transactions(&self) -> Result<HashSet<String>> {
-        Ok(self.pending.keys().cloned().collect())
+        Ok(self
+            .pending
+            .keys()
+            .chain(self.tombstones.keys())
+            .chain(self.pending_metadata.keys())
+            .chain(self.metadata_tombstones.keys())
+            .cloned()
+            .collect())
+    }
+
+    fn transaction_created<S: AsRef<str>>(&self, transaction: S) -> Result<Option<SystemTime>> {
+        Ok(self.created.get(transaction.as_ref()).copied())
+    }
+
+    fn read_journal(&self, filter: &JournalFilter) -> Result<Vec<JournalEntry>> {
+        Ok(self
+            .journal
+            .iter()
+            .filter(|e| filter.matches(e))
+            .cloned()
+            .collect())
+    }
+
+    fn list_generations(&self) -> Result<Vec<Generation>> {
+        Ok(self.generations.iter().cloned().collect())
+    }
+
+    fn revert_to_generation(&mut self, id: u64) -> Result<HashSet<Key>> {
+        self.revert_to_generation_at(id, SystemTime::now())
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::super::{Committed, DataStore, Key, KeyType};
+    use super::super::{Committed, DataStore, Key, KeyPattern, KeyType};
     use super::MemoryDataStore;
     use maplit::hashset;
 
@@ -198,14 +677,17 @@ mod test {
 
         let mdkey = Key::new(KeyType::Meta, "testmd").unwrap();
         let md = "mdval";
-        m.set_metadata(&mdkey, &k, md).unwrap();
+        m.set_metadata(&mdkey, &k, md, &Committed::Live).unwrap();
         assert_eq!(
-            m.get_metadata_raw(&mdkey, &k).unwrap(),
+            m.get_metadata_raw(&mdkey, &k, &Committed::Live).unwrap(),
             Some(md.to_string())
         );
 
-        m.unset_metadata(&mdkey, &k).unwrap();
-        assert_eq!(m.get_metadata_raw(&mdkey, &k).unwrap(), None);
+        m.unset_metadata(&mdkey, &k, &Committed::Live).unwrap();
+        assert_eq!(
+            m.get_metadata_raw(&mdkey, &k, &Committed::Live).unwrap(),
+            None
+        );
 
         m.unset_key(&k, &Committed::Live).unwrap();
         assert_eq!(m.get_key(&k, &Committed::Live).unwrap(), None);
@@ -272,4 +754,123 @@ mod test {
         // Assure other transactions were not deleted
         assert!(m.key_populated(&k2, &pending2).unwrap());
     }
+
+    #[test]
+    fn pending_metadata() {
+        let mut m = MemoryDataStore::new();
+        let k = Key::new(KeyType::Data, "settings.a").unwrap();
+        let meta = Key::new(KeyType::Meta, "affected-services").unwrap();
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        m.set_metadata(&meta, &k, "[\"a\"]", &pending).unwrap();
+        assert_eq!(m.list_transactions().unwrap(), hashset!(tx.to_string()));
+        assert_eq!(m.get_metadata(&meta, &k, &Committed::Live).unwrap(), None);
+
+        // Committing a metadata-only transaction changes no data keys, but applies the metadata.
+        assert!(m.commit_transaction(tx).unwrap().is_empty());
+        assert_eq!(
+            m.get_metadata(&meta, &k, &Committed::Live).unwrap(),
+            Some("[\"a\"]".to_string())
+        );
+        assert_eq!(m.get_metadata(&meta, &k, &pending).unwrap(), None);
+
+        // Deleting the transaction discards its metadata.
+        m.set_metadata(&meta, &k, "[\"b\"]", &pending).unwrap();
+        m.delete_transaction(tx).unwrap();
+        assert!(m.list_transactions().unwrap().is_empty());
+        assert_eq!(
+            m.get_metadata(&meta, &k, &Committed::Live).unwrap(),
+            Some("[\"a\"]".to_string())
+        );
+    }
+
+    #[test]
+    fn revert_generations() {
+        let mut m = MemoryDataStore::new().with_generation_limit(2);
+        let k = Key::new(KeyType::Data, "settings.a").unwrap();
+        for (i, tx) in ["one", "two", "three"].iter().enumerate() {
+            let pending = Committed::Pending { tx: tx.to_string() };
+            m.set_key(&k, i.to_string(), &pending).unwrap();
+            m.commit_transaction(*tx).unwrap();
+        }
+
+        // Only the last two generations are retained, so we can only go back to generation 1.
+        let ids: Vec<u64> = m.list_generations().unwrap().iter().map(|g| g.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+        assert!(m.revert_to_generation(0).is_err());
+
+        assert_eq!(m.revert_to_generation(1).unwrap(), hashset!(k.clone()));
+        assert_eq!(
+            m.get_key(&k, &Committed::Live).unwrap(),
+            Some("0".to_string())
+        );
+        assert!(m.list_generations().unwrap().is_empty());
+
+        // With a limit of 0, the next commit forgets any generations that were kept.
+        let pending = Committed::Pending { tx: "four".into() };
+        m.set_key(&k, "4", &pending).unwrap();
+        m.commit_transaction("four").unwrap();
+        assert_eq!(m.list_generations().unwrap().len(), 1);
+        let mut m = m.with_generation_limit(0);
+        m.set_key(&k, "5", &pending).unwrap();
+        m.commit_transaction("four").unwrap();
+        assert!(m.list_generations().unwrap().is_empty());
+    }
+
+    #[test]
+    fn tombstones() {
+        let mut m = MemoryDataStore::new();
+        let a = Key::new(KeyType::Data, "settings.a.x").unwrap();
+        let ab = Key::new(KeyType::Data, "settings.ab").unwrap();
+        let b = Key::new(KeyType::Data, "settings.b").unwrap();
+        m.set_key(&a, "1", &Committed::Live).unwrap();
+        m.set_key(&ab, "2", &Committed::Live).unwrap();
+
+        let tx = "test transaction";
+        let pending = Committed::Pending { tx: tx.into() };
+        let tombstone = Key::new(KeyType::Data, "settings.a").unwrap();
+        m.set_tombstone(&tombstone, tx).unwrap();
+        assert_eq!(m.list_transactions().unwrap(), hashset!(tx.to_string()));
+        m.set_key(&b, "3", &pending).unwrap();
+
+        // Only keys under the tombstoned key are removed.
+        assert_eq!(m.commit_transaction(tx).unwrap(), hashset!(a.clone(), b));
+        assert!(!m.key_populated(&a, &Committed::Live).unwrap());
+        assert!(m.key_populated(&ab, &Committed::Live).unwrap());
+        assert!(m.list_tombstones(tx).unwrap().is_empty());
+
+        m.set_tombstone(&ab, tx).unwrap();
+        assert_eq!(m.delete_transaction(tx).unwrap(), hashset!(ab.clone()));
+        assert!(m.list_transactions().unwrap().is_empty());
+    }
+
+    #[test]
+    fn matching_keys() {
+        let mut m = MemoryDataStore::new();
+        let enabled = Key::new(KeyType::Data, "settings.a.x.enabled").unwrap();
+        let other = Key::new(KeyType::Data, "settings.ab.x.enabled").unwrap();
+        m.set_key(&enabled, "true", &Committed::Live).unwrap();
+        m.set_key(&other, "true", &Committed::Live).unwrap();
+        let meta = Key::new(KeyType::Meta, "meta").unwrap();
+        m.set_metadata(&meta, &enabled, "1", &Committed::Live)
+            .unwrap();
+        m.set_metadata(&meta, &other, "2", &Committed::Live)
+            .unwrap();
+
+        let pattern = KeyPattern::new("settings.a.**").unwrap();
+        assert_eq!(
+            m.list_matching_keys(&pattern, &Committed::Live).unwrap(),
+            hashset!(enabled.clone())
+        );
+        let metadata = m
+            .get_matching_metadata(&pattern, &Committed::Live, &None::<&str>)
+            .unwrap();
+        assert_eq!(metadata.len(), 1);
+        assert_eq!(metadata[&enabled][&meta], "1");
+    }
+
+    #[test]
+    fn conformance() {
+        crate::conformance::run_all(MemoryDataStore::new);
+    }
 }
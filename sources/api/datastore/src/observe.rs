@@ -0,0 +1,469 @@
+//! This module provides a wrapper around any DataStore implementation that notifies in-process
+//! subscribers when live data changes, so they don't have to poll.
+//!
+//! Each subscription covers a key prefix.  After a commit or revert succeeds, every subscriber
+//! whose prefix covers any of the changed keys is sent a ChangeEvent listing them, and, if it
+//! asked for them, their new values.  Events are delivered over a channel, so subscribers can
+//! wait for them on another thread; a subscription is dropped from the list once its receiving
+//! side is dropped.
+//!
+//! Only changes made through the wrapper are seen.  To watch for changes made by other processes,
+//! see `feed`.
+
+use log::{trace, warn};
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use super::journal::{JournalEntry, JournalFilter};
+use super::{Committed, DataStore, Generation, Key, KeyPattern, Result, TransactionDiff};
+
+/// ChangeEvent describes a change to live data made by a commit or revert.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangeEvent {
+    /// The transaction that was committed, or None for a revert.
+    pub transaction: Option<String>,
+    /// The changed keys under the subscription's prefix, including removed keys.
+    pub keys: HashSet<Key>,
+    /// The new values of the changed keys, if the subscriber asked for them; None means the key
+    /// was removed.
+    pub values: Option<HashMap<Key, Option<String>>>,
+}
+
+/// Subscription receives the ChangeEvents for one subscriber.
+#[derive(Debug)]
+pub struct Subscription {
+    receiver: Receiver<ChangeEvent>,
+}
+
+impl Subscription {
+    /// Returns the next event if one is waiting, without blocking.
+    pub fn try_next(&self) -> Option<ChangeEvent> {
+        self.receiver.try_recv().ok()
+    }
+
+    /// Waits up to the given timeout for the next event.  Returns None if there wasn't one in
+    /// time, or if the data store was dropped.
+    pub fn next_timeout(&self, timeout: Duration) -> Option<ChangeEvent> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => None,
+        }
+    }
+}
+
+/// Iterating waits for each event, and ends when the data store is dropped.
+impl Iterator for Subscription {
+    type Item = ChangeEvent;
+
+    fn next(&mut self) -> Option<ChangeEvent> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// Subscriber is the sending side of a Subscription.
+#[derive(Debug)]
+struct Subscriber {
+    prefix: String,
+    include_values: bool,
+    sender: Sender<ChangeEvent>,
+}
+
+/// ObservedDataStore wraps a DataStore, notifying subscribers of changes to live data.
+#[derive(Debug)]
+pub struct ObservedDataStore<D> {
+    inner: D,
+    // Behind a mutex so callers can subscribe through a shared reference.
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl<D: DataStore> ObservedDataStore<D> {
+    /// Wraps the given data store.
+    pub fn new(inner: D) -> Self {
+        Self {
+            inner,
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Subscribes to changes to keys starting with the given prefix.  Events list the changed
+    /// keys but not their values.
+    pub fn subscribe<S: Into<String>>(&self, prefix: S) -> Subscription {
+        self.add_subscriber(prefix.into(), false)
+    }
+
+    /// Subscribes to changes to keys starting with the given prefix, including the new values of
+    /// the changed keys in each event.
+    pub fn subscribe_with_values<S: Into<String>>(&self, prefix: S) -> Subscription {
+        self.add_subscriber(prefix.into(), true)
+    }
+
+    /// Returns the underlying data store.
+    pub fn into_inner(self) -> D {
+        self.inner
+    }
+
+    fn add_subscriber(&self, prefix: String, include_values: bool) -> Subscription {
+        let (sender, receiver) = mpsc::channel();
+        trace!("Adding subscriber for prefix '{}'", prefix);
+        self.subscribers().push(Subscriber {
+            prefix,
+            include_values,
+            sender,
+        });
+        Subscription { receiver }
+    }
+
+    fn subscribers(&self) -> MutexGuard<'_, Vec<Subscriber>> {
+        // A panic while holding the lock can't leave the list half-changed, so we can keep using
+        // it.
+        self.subscribers
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Sends an event to each subscriber interested in the given changed keys, forgetting any
+    /// whose subscription was dropped.
+    fn notify(&self, transaction: Option<&str>, changed: &HashSet<Key>) {
+        let mut subscribers = self.subscribers();
+        let mut values = HashMap::new();
+        subscribers.retain(|subscriber| {
+            let keys: HashSet<Key> = changed
+                .iter()
+                .filter(|key| key.name().starts_with(&subscriber.prefix))
+                .cloned()
+                .collect();
+            if keys.is_empty() {
+                return true;
+            }
+
+            let event_values = if subscriber.include_values {
+                self.live_values(&keys, &mut values)
+            } else {
+                None
+            };
+            let event = ChangeEvent {
+                transaction: transaction.map(str::to_string),
+                keys,
+                values: event_values,
+            };
+            let subscribed = subscriber.sender.send(event).is_ok();
+            if !subscribed {
+                trace!("Removing subscriber for prefix '{}'", subscriber.prefix);
+            }
+            subscribed
+        });
+    }
+
+    /// Returns the live values of the given keys, using and filling the given cache so each key
+    /// is only read once per change.  The change has already been made, so if we can't read a
+    /// value, we warn and leave the values out of the event rather than fail.
+    fn live_values(
+        &self,
+        keys: &HashSet<Key>,
+        cache: &mut HashMap<Key, Option<String>>,
+    ) -> Option<HashMap<Key, Option<String>>> {
+        let mut values = HashMap::new();
+        for key in keys {
+            let value = match cache.get(key) {
+                Some(value) => value.clone(),
+                None => match self.inner.get_key(key, &Committed::Live) {
+                    Ok(value) => {
+                        cache.insert(key.clone(), value.clone());
+                        value
+                    }
+                    Err(e) => {
+                        warn!("Unable to read changed key {} for subscribers: {}", key, e);
+                        return None;
+                    }
+                },
+            };
+            values.insert(key.clone(), value);
+        }
+        Some(values)
+    }
+}
+
+// Everything is passed through to the underlying data store; commits and reverts also notify
+// subscribers.  The default implementations of multi-key operations are replaced so that any
+// faster implementation in the underlying data store is used.
+impl<D: DataStore> DataStore for ObservedDataStore<D> {
+    fn key_populated(&self, key: &Key, committed: &Committed) -> Result<bool> {
+        self.inner.key_populated(key, committed)
+    }
+
+    fn list_populated_keys<S: AsRef<str>>(
+        &self,
+        prefix: S,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        self.inner.list_populated_keys(prefix, committed)
+    }
+
+    fn list_populated_metadata<S1, S2>(
+        &self,
+        prefix: S1,
+        committed: &Committed,
+        metadata_key_name: &Option<S2>,
+    ) -> Result<HashMap<Key, HashSet<Key>>>
+    where
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        self.inner
+            .list_populated_metadata(prefix, committed, metadata_key_name)
+    }
+
+    fn get_key(&self, key: &Key, committed: &Committed) -> Result<Option<String>> {
+        self.inner.get_key(key, committed)
+    }
+
+    fn set_key<S: AsRef<str>>(&mut self, key: &Key, value: S, committed: &Committed) -> Result<()> {
+        self.inner.set_key(key, value, committed)
+    }
+
+    fn unset_key(&mut self, key: &Key, committed: &Committed) -> Result<()> {
+        self.inner.unset_key(key, committed)
+    }
+
+    fn get_metadata(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>> {
+        self.inner.get_metadata(metadata_key, data_key, committed)
+    }
+
+    fn get_metadata_raw(
+        &self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<Option<String>> {
+        self.inner
+            .get_metadata_raw(metadata_key, data_key, committed)
+    }
+
+    fn set_metadata<S: AsRef<str>>(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        value: S,
+        committed: &Committed,
+    ) -> Result<()> {
+        self.inner
+            .set_metadata(metadata_key, data_key, value, committed)
+    }
+
+    fn unset_metadata(
+        &mut self,
+        metadata_key: &Key,
+        data_key: &Key,
+        committed: &Committed,
+    ) -> Result<()> {
+        self.inner.unset_metadata(metadata_key, data_key, committed)
+    }
+
+    fn set_tombstone<S>(&mut self, key: &Key, transaction: S) -> Result<()>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        self.inner.set_tombstone(key, transaction)
+    }
+
+    fn list_tombstones<S: AsRef<str>>(&self, transaction: S) -> Result<HashSet<Key>> {
+        self.inner.list_tombstones(transaction)
+    }
+
+    fn list_metadata_tombstones<S: AsRef<str>>(
+        &self,
+        transaction: S,
+    ) -> Result<HashMap<Key, HashSet<Key>>> {
+        self.inner.list_metadata_tombstones(transaction)
+    }
+
+    fn commit_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        let name = transaction.as_ref().to_string();
+        let changed = self.inner.commit_transaction(transaction)?;
+        if !changed.is_empty() {
+            self.notify(Some(&name), &changed);
+        }
+        Ok(changed)
+    }
+
+    fn delete_transaction<S>(&mut self, transaction: S) -> Result<HashSet<Key>>
+    where
+        S: Into<String> + AsRef<str>,
+    {
+        self.inner.delete_transaction(transaction)
+    }
+
+    fn list_transactions(&self) -> Result<HashSet<String>> {
+        self.inner.list_transactions()
+    }
+
+    fn transaction_created<S: AsRef<str>>(&self, transaction: S) -> Result<Option<SystemTime>> {
+        self.inner.transaction_created(transaction)
+    }
+
+    fn list_generations(&self) -> Result<Vec<Generation>> {
+        self.inner.list_generations()
+    }
+
+    fn revert_to_generation(&mut self, id: u64) -> Result<HashSet<Key>> {
+        let changed = self.inner.revert_to_generation(id)?;
+        if !changed.is_empty() {
+            self.notify(None, &changed);
+        }
+        Ok(changed)
+    }
+
+    fn read_journal(&self, filter: &JournalFilter) -> Result<Vec<JournalEntry>> {
+        self.inner.read_journal(filter)
+    }
+
+    fn set_keys<S>(&mut self, pairs: &HashMap<Key, S>, committed: &Committed) -> Result<()>
+    where
+        S: AsRef<str>,
+    {
+        self.inner.set_keys(pairs, committed)
+    }
+
+    fn unset_keys(&mut self, keys: &HashSet<Key>, committed: &Committed) -> Result<()> {
+        self.inner.unset_keys(keys, committed)
+    }
+
+    fn get_prefix<S: AsRef<str>>(
+        &self,
+        find_prefix: S,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, String>> {
+        self.inner.get_prefix(find_prefix, committed)
+    }
+
+    fn get_metadata_prefix<S1, S2>(
+        &self,
+        find_prefix: S1,
+        committed: &Committed,
+        metadata_key_name: &Option<S2>,
+    ) -> Result<HashMap<Key, HashMap<Key, String>>>
+    where
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        self.inner
+            .get_metadata_prefix(find_prefix, committed, metadata_key_name)
+    }
+
+    fn list_matching_keys(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+    ) -> Result<HashSet<Key>> {
+        self.inner.list_matching_keys(pattern, committed)
+    }
+
+    fn list_matching_metadata<S>(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+        metadata_key_name: &Option<S>,
+    ) -> Result<HashMap<Key, HashSet<Key>>>
+    where
+        S: AsRef<str>,
+    {
+        self.inner
+            .list_matching_metadata(pattern, committed, metadata_key_name)
+    }
+
+    fn get_matching(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+    ) -> Result<HashMap<Key, String>> {
+        self.inner.get_matching(pattern, committed)
+    }
+
+    fn get_matching_metadata<S>(
+        &self,
+        pattern: &KeyPattern,
+        committed: &Committed,
+        metadata_key_name: &Option<S>,
+    ) -> Result<HashMap<Key, HashMap<Key, String>>>
+    where
+        S: AsRef<str>,
+    {
+        self.inner
+            .get_matching_metadata(pattern, committed, metadata_key_name)
+    }
+
+    fn diff_transaction<S1, S2>(&self, transaction: S1, prefix: S2) -> Result<TransactionDiff>
+    where
+        S1: AsRef<str>,
+        S2: AsRef<str>,
+    {
+        self.inner.diff_transaction(transaction, prefix)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryDataStore;
+    use crate::KeyType;
+    use maplit::{hashmap, hashset};
+    use std::thread;
+
+    #[test]
+    fn notifies_matching_subscribers() {
+        let mut o = ObservedDataStore::new(MemoryDataStore::new());
+        let a = Key::new(KeyType::Data, "settings.a.x").unwrap();
+        let b = Key::new(KeyType::Data, "settings.b").unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+        o.set_key(&a, "1", &Committed::Live).unwrap();
+
+        let all = o.subscribe("settings.");
+        let only_a = o.subscribe_with_values("settings.a");
+        let dropped = o.subscribe("settings.");
+        drop(dropped);
+
+        o.set_key(&b, "2", &pending).unwrap();
+        o.set_tombstone(&a, "tx").unwrap();
+        o.commit_transaction("tx").unwrap();
+        assert_eq!(
+            all.try_next().unwrap(),
+            ChangeEvent {
+                transaction: Some("tx".to_string()),
+                keys: hashset!(a.clone(), b.clone()),
+                values: None,
+            }
+        );
+        assert_eq!(
+            only_a.try_next().unwrap().values,
+            Some(hashmap!(a.clone() => None))
+        );
+        assert_eq!(o.subscribers().len(), 2);
+
+        // Changes to other keys aren't sent, and empty commits aren't changes.
+        o.set_key(&b, "3", &pending).unwrap();
+        o.commit_transaction("tx").unwrap();
+        o.commit_transaction("tx").unwrap();
+        assert!(only_a.try_next().is_none());
+
+        // Reverts are changes too, and events can be waited for on other threads.
+        let waiter = thread::spawn(move || only_a.next_timeout(Duration::from_secs(10)));
+        o.revert_to_generation(0).unwrap();
+        let event = waiter.join().unwrap().unwrap();
+        assert_eq!(event.transaction, None);
+        assert_eq!(event.values, Some(hashmap!(a => Some("1".to_string()))));
+
+        // Iterating ends once the data store is gone.
+        drop(o);
+        assert_eq!(all.count(), 2);
+    }
+}
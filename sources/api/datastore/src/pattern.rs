@@ -0,0 +1,203 @@
+//! A KeyPattern selects data keys by matching their names segment by segment, with wildcards.
+//!
+//! Patterns are written like key names, with the same quoting rules, and two kinds of wildcard
+//! segment:
+//! * `*` matches exactly one segment, including a quoted segment with dots in it
+//! * `**` matches any number of segments, including none
+//!
+//! For example, `settings.host-containers.*.enabled` matches the `enabled` key of each host
+//! container, and `settings.kubernetes.node-labels.*` matches each node label, even ones like
+//! `settings.kubernetes.node-labels."example.com/role"`.  A wildcard can also be quoted, as in
+//! `"*"`, which means the same thing; `*` isn't allowed in key names, so it's never literal.
+//! Wildcards must make up a whole segment.
+//!
+//! Patterns match whole keys, not prefixes; add `.**` to match everything under a key.
+
+use snafu::ensure;
+use std::fmt;
+use std::str::FromStr;
+
+use super::{error, Error, Key, KeyType, Result};
+
+const WILDCARD: &str = "*";
+const RECURSIVE_WILDCARD: &str = "**";
+
+/// PatternSegment is one segment of a KeyPattern.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum PatternSegment {
+    /// Matches a segment with this exact name.
+    Literal(String),
+    /// Matches any single segment.
+    Any,
+    /// Matches zero or more segments.
+    AnyRecursive,
+}
+
+/// KeyPattern matches data keys by segment; see the module documentation for the syntax.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyPattern {
+    name: String,
+    segments: Vec<PatternSegment>,
+    // The literal segments before the first wildcard, as a key.
+    prefix: Option<Key>,
+}
+
+impl KeyPattern {
+    /// Parses a pattern from the given string.
+    pub fn new<S: AsRef<str>>(pattern: S) -> Result<Self> {
+        let name = pattern.as_ref();
+        let raw_segments = Key::parse_segments(name, |c| c == '*' || Key::valid_character(c))?;
+
+        let mut segments = Vec::new();
+        for segment in raw_segments {
+            let parsed = match segment.as_str() {
+                WILDCARD => PatternSegment::Any,
+                RECURSIVE_WILDCARD => PatternSegment::AnyRecursive,
+                _ => {
+                    ensure!(
+                        !segment.contains('*'),
+                        error::InvalidKeySnafu {
+                            name,
+                            msg: format!("wildcard must be a whole segment: '{}'", segment),
+                        }
+                    );
+                    PatternSegment::Literal(segment)
+                }
+            };
+            segments.push(parsed);
+        }
+
+        // This also makes sure the literal part is a valid key, e.g. not too long.
+        let literals: Vec<&str> = segments
+            .iter()
+            .map_while(|segment| match segment {
+                PatternSegment::Literal(name) => Some(name.as_str()),
+                _ => None,
+            })
+            .collect();
+        let prefix = if literals.is_empty() {
+            None
+        } else {
+            Some(Key::from_segments(KeyType::Data, &literals)?)
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            segments,
+            prefix,
+        })
+    }
+
+    /// Returns the pattern as it was given.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns whether the given key matches the pattern.
+    pub fn matches(&self, key: &Key) -> bool {
+        match_segments(&self.segments, key.segments())
+    }
+
+    /// Returns the key made of the literal segments at the start of the pattern, before any
+    /// wildcard, if there are any.  Every matching key starts with these segments, so
+    /// implementations can use this to narrow their search.
+    pub fn literal_prefix(&self) -> Option<&Key> {
+        self.prefix.as_ref()
+    }
+
+    /// Returns the name of `literal_prefix`, or an empty string if there isn't one, for use with
+    /// prefix queries.  Prefix queries match names, not segments, so they may return extra keys,
+    /// and the results still need to be checked with `matches`.
+    pub(crate) fn prefix_name(&self) -> String {
+        match &self.prefix {
+            Some(key) => key.name().clone(),
+            None => String::new(),
+        }
+    }
+}
+
+/// Returns whether the given key segments match the given pattern segments.
+fn match_segments(pattern: &[PatternSegment], key: &[String]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((PatternSegment::AnyRecursive, rest)) => {
+            (0..=key.len()).any(|skip| match_segments(rest, &key[skip..]))
+        }
+        Some((PatternSegment::Any, rest)) => !key.is_empty() && match_segments(rest, &key[1..]),
+        Some((PatternSegment::Literal(name), rest)) => {
+            key.first() == Some(name) && match_segments(rest, &key[1..])
+        }
+    }
+}
+
+impl FromStr for KeyPattern {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::new(s)
+    }
+}
+
+impl fmt::Display for KeyPattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn matches(pattern: &str, key: &str) -> bool {
+        let pattern = KeyPattern::new(pattern).unwrap();
+        pattern.matches(&Key::new(KeyType::Data, key).unwrap())
+    }
+
+    #[test]
+    fn single_wildcard() {
+        let pattern = "settings.host-containers.*.enabled";
+        assert!(matches(pattern, "settings.host-containers.admin.enabled"));
+        assert!(!matches(pattern, "settings.host-containers.admin.source"));
+        assert!(!matches(pattern, "settings.host-containers.enabled"));
+        assert!(!matches(pattern, "settings.host-containers.a.b.enabled"));
+    }
+
+    #[test]
+    fn quoted_segments() {
+        let key = "settings.kubernetes.node-labels.\"example.com/role\"";
+        assert!(matches("settings.kubernetes.node-labels.*", key));
+        assert!(matches("settings.kubernetes.node-labels.\"*\"", key));
+        assert!(matches(
+            "settings.kubernetes.node-labels.\"example.com/role\"",
+            key
+        ));
+        assert!(!matches("settings.kubernetes.node-labels.example", key));
+    }
+
+    #[test]
+    fn recursive_wildcard() {
+        assert!(matches("settings.**", "settings.a.b.c"));
+        assert!(matches("settings.**", "settings"));
+        assert!(matches(
+            "**.enabled",
+            "settings.host-containers.admin.enabled"
+        ));
+        assert!(matches("settings.**.c", "settings.c"));
+        assert!(!matches("settings.**.c", "settings.a.b"));
+    }
+
+    #[test]
+    fn literal_prefix() {
+        let pattern = KeyPattern::new("settings.\"a.b\".*.c").unwrap();
+        let prefix = pattern.literal_prefix().unwrap();
+        assert_eq!(prefix.segments(), &["settings", "a.b"]);
+        assert!(KeyPattern::new("*.a").unwrap().literal_prefix().is_none());
+    }
+
+    #[test]
+    fn invalid() {
+        KeyPattern::new("settings.a*").unwrap_err();
+        KeyPattern::new("settings..a").unwrap_err();
+        KeyPattern::new("settings.a!").unwrap_err();
+    }
+}
@@ -0,0 +1,58 @@
+//! Scratch paths for tests that need files on disk.
+
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::format::{self, CURRENT_FORMAT_VERSION};
+
+/// Counts the scratch paths made by this process, so tests running at the same time never share
+/// one, even if they use the same name.
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// TestPath is a unique path in the temp directory for a test to use as a file or directory.
+/// Whatever is there is removed when it's dropped.
+pub(crate) struct TestPath(pub(crate) PathBuf);
+
+impl TestPath {
+    /// Returns a new path with nothing at it.
+    pub(crate) fn new(name: &str) -> Self {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let path = env::temp_dir().join(format!("datastore-{}-{}-{}", name, process::id(), id));
+        // Clear out anything left by an earlier process with the same ID.
+        let test_path = Self(path);
+        test_path.remove();
+        test_path
+    }
+
+    /// Returns a new path with an empty directory at it.
+    pub(crate) fn dir(name: &str) -> Self {
+        let test_path = Self::new(name);
+        fs::create_dir_all(&test_path.0).unwrap();
+        test_path
+    }
+
+    /// Returns a new path with the layout of an empty FilesystemDataStore at it.
+    pub(crate) fn datastore(name: &str) -> Self {
+        let test_path = Self::new(name);
+        fs::create_dir_all(test_path.0.join("live")).unwrap();
+        format::write_format_version(&test_path.0, CURRENT_FORMAT_VERSION).unwrap();
+        test_path
+    }
+
+    fn remove(&self) {
+        if self.0.is_dir() {
+            let _ = fs::remove_dir_all(&self.0);
+        } else {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+}
+
+impl Drop for TestPath {
+    fn drop(&mut self) {
+        self.remove();
+    }
+}
@@ -0,0 +1,331 @@
+//! Sensitive values, like private keys, bootstrap tokens, and registry passwords, are marked with
+//! metadata so they can be protected.
+//!
+//! A data key is sensitive if it, or a key above it, has "sensitive" metadata set to `true`.  As
+//! with other metadata, the closest setting wins, so `false` exempts part of a sensitive tree.
+//!
+//! FilesystemDataStore encrypts sensitive values at rest when it's given an EncryptionKey, and
+//! decrypts them in `get_key`, so callers see the original values.  Values written before their
+//! key was marked sensitive are encrypted when the marking is set, or committed.
+//!
+//! Output produced by this library replaces sensitive values with REDACTED_VALUE: journal
+//! entries, snapshots (unless captured with `Snapshot::capture_unredacted`), and transaction
+//! diffs.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use log::warn;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use super::journal::JournalEntry;
+use super::{error, Committed, DataStore, Key, Result};
+
+/// The name of the metadata key that marks data keys as sensitive.
+pub const SENSITIVE_METADATA_KEY: &str = "sensitive";
+
+/// The serialized value shown in place of sensitive values.
+pub const REDACTED_VALUE: &str = "\"<redacted>\"";
+
+/// The length, in bytes, of an encryption key file.
+pub const ENCRYPTION_KEY_LEN: usize = 32;
+
+/// Encrypted values are stored as this marker followed by the base64-encoded nonce and sealed
+/// value.  Data store values are JSON, and nothing that starts with the marker is, so a value
+/// that was set can't be mistaken for an encrypted one.
+const ENCRYPTED_VALUE_MARKER: &str = "encrypted:v1:";
+
+/// SensitiveKeys holds the sensitive markings of a data store, to check many keys at once.
+#[derive(Debug, Clone, Default)]
+pub struct SensitiveKeys {
+    /// Whether each marked data key, by segments, is sensitive.
+    marks: HashMap<Vec<String>, bool>,
+}
+
+impl SensitiveKeys {
+    /// Loads the markings that apply to the given data: live markings, plus those of the pending
+    /// transaction if it's pending, which override them.
+    pub fn load<D: DataStore + ?Sized>(datastore: &D, committed: &Committed) -> Result<Self> {
+        let name = Some(SENSITIVE_METADATA_KEY);
+        let mut metadata = datastore.get_metadata_prefix("", &Committed::Live, &name)?;
+        if let Committed::Pending { .. } = committed {
+            for (data_key, meta_map) in datastore.get_metadata_prefix("", committed, &name)? {
+                metadata.entry(data_key).or_default().extend(meta_map);
+            }
+        }
+        Ok(Self::from_metadata(&metadata))
+    }
+
+    /// Builds the markings from metadata, by data key and then metadata key.
+    pub(crate) fn from_metadata<'a, I>(metadata: I) -> Self
+    where
+        I: IntoIterator<Item = (&'a Key, &'a HashMap<Key, String>)>,
+    {
+        let mut marks = HashMap::new();
+        for (data_key, meta_map) in metadata {
+            let value = meta_map
+                .iter()
+                .find(|(meta_key, _)| meta_key.name() == SENSITIVE_METADATA_KEY)
+                .map(|(_, value)| value);
+            if let Some(value) = value {
+                marks.insert(data_key.segments().clone(), parse_mark(data_key, value));
+            }
+        }
+        Self { marks }
+    }
+
+    /// Returns whether the given data key is sensitive.
+    pub fn contains(&self, key: &Key) -> bool {
+        let segments = key.segments();
+        (1..=segments.len())
+            .rev()
+            .find_map(|len| self.marks.get(&segments[..len]))
+            .copied()
+            .unwrap_or(false)
+    }
+
+    /// Replaces the sensitive values in the given map with REDACTED_VALUE.
+    pub fn redact(&self, values: &mut HashMap<Key, String>) {
+        for (key, value) in values.iter_mut() {
+            if self.contains(key) {
+                *value = REDACTED_VALUE.to_string();
+            }
+        }
+    }
+
+    /// Replaces the values in journal entries for sensitive data keys with REDACTED_VALUE.
+    /// Metadata entries aren't sensitive, so they're left alone.
+    pub(crate) fn redact_journal(&self, entries: &mut [JournalEntry]) {
+        for entry in entries {
+            if entry.metadata_key.is_none() && self.contains(&entry.data_key) {
+                for value in [&mut entry.old_value, &mut entry.new_value] {
+                    if value.is_some() {
+                        *value = Some(REDACTED_VALUE.to_string());
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Markings should be JSON booleans; anything else is treated as sensitive, to be safe.
+fn parse_mark(data_key: &Key, value: &str) -> bool {
+    serde_json::from_str(value).unwrap_or_else(|e| {
+        warn!(
+            "Treating '{}' as sensitive because its '{}' metadata isn't a boolean: {}",
+            data_key, SENSITIVE_METADATA_KEY, e
+        );
+        true
+    })
+}
+
+/// Returns whether the given data key is sensitive, checking the pending transaction's markings
+/// before live markings.
+pub fn is_sensitive<D: DataStore + ?Sized>(
+    datastore: &D,
+    key: &Key,
+    committed: &Committed,
+) -> Result<bool> {
+    let metadata_key = Key::new(super::KeyType::Meta, SENSITIVE_METADATA_KEY)?;
+    let mut value = None;
+    if let Committed::Pending { .. } = committed {
+        value = datastore.get_metadata(&metadata_key, key, committed)?;
+    }
+    if value.is_none() {
+        value = datastore.get_metadata(&metadata_key, key, &Committed::Live)?;
+    }
+    Ok(value.map(|v| parse_mark(key, &v)).unwrap_or(false))
+}
+
+/// Returns whether the given serialized value was encrypted by an EncryptionKey.
+pub fn is_encrypted(value: &str) -> bool {
+    encrypted_payload(value).is_some()
+}
+
+/// Returns the base64 payload of an encrypted value.
+fn encrypted_payload(value: &str) -> Option<&str> {
+    value.strip_prefix(ENCRYPTED_VALUE_MARKER)
+}
+
+/// EncryptionKey encrypts sensitive values with AES-256-GCM.  Each value is bound to the name of
+/// its data key, so encrypted values can't be moved between keys.
+pub struct EncryptionKey {
+    key: LessSafeKey,
+    rng: SystemRandom,
+}
+
+impl EncryptionKey {
+    /// Reads a key from a file holding ENCRYPTION_KEY_LEN random bytes.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let bytes = fs::read(path).context(error::IoSnafu { path })?;
+        ensure!(
+            bytes.len() == ENCRYPTION_KEY_LEN,
+            error::EncryptionKeyLengthSnafu {
+                path,
+                len: bytes.len(),
+                expected: ENCRYPTION_KEY_LEN,
+            }
+        );
+        Self::from_bytes(&bytes)
+    }
+
+    /// Generates a new random key and writes it to a new file that only the owner can read.
+    pub fn generate_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let rng = SystemRandom::new();
+        let mut bytes = [0u8; ENCRYPTION_KEY_LEN];
+        rng.fill(&mut bytes)
+            .ok()
+            .context(error::EncryptionKeyGenerateSnafu { path })?;
+
+        let mut f = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .context(error::IoSnafu { path })?;
+        f.write_all(&bytes).context(error::IoSnafu { path })?;
+        f.sync_all().context(error::IoSnafu { path })?;
+        Self::from_bytes(&bytes)
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let key = UnboundKey::new(&AES_256_GCM, bytes)
+            .ok()
+            .context(error::InternalSnafu {
+                msg: "encryption key has the wrong length",
+            })?;
+        Ok(Self {
+            key: LessSafeKey::new(key),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Encrypts the serialized value of the given data key, returning the value to store.
+    pub fn encrypt(&self, key: &Key, value: &str) -> Result<String> {
+        let mut nonce = [0u8; NONCE_LEN];
+        self.rng
+            .fill(&mut nonce)
+            .ok()
+            .context(error::EncryptSnafu { key: key.name() })?;
+        let mut sealed = value.as_bytes().to_vec();
+        self.key
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::from(key.name().as_bytes()),
+                &mut sealed,
+            )
+            .ok()
+            .context(error::EncryptSnafu { key: key.name() })?;
+
+        let mut payload = nonce.to_vec();
+        payload.extend(sealed);
+        Ok(format!(
+            "{}{}",
+            ENCRYPTED_VALUE_MARKER,
+            BASE64.encode(payload)
+        ))
+    }
+
+    /// Decrypts a stored value of the given data key.  Values that aren't encrypted are returned
+    /// as they are.
+    pub fn decrypt(&self, key: &Key, value: String) -> Result<String> {
+        let payload = match encrypted_payload(&value) {
+            Some(payload) => payload,
+            None => return Ok(value),
+        };
+        let mut payload = BASE64
+            .decode(payload)
+            .ok()
+            .context(error::DecryptSnafu { key: key.name() })?;
+        ensure!(
+            payload.len() >= NONCE_LEN,
+            error::DecryptSnafu { key: key.name() }
+        );
+        let mut sealed = payload.split_off(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(&payload)
+            .ok()
+            .context(error::DecryptSnafu { key: key.name() })?;
+        let plain = self
+            .key
+            .open_in_place(nonce, Aad::from(key.name().as_bytes()), &mut sealed)
+            .ok()
+            .context(error::DecryptSnafu { key: key.name() })?;
+        String::from_utf8(plain.to_vec())
+            .ok()
+            .context(error::DecryptSnafu { key: key.name() })
+    }
+}
+
+// The key material must never show up in debug output.
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("EncryptionKey(<redacted>)")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryDataStore;
+    use crate::scratch::TestPath;
+    use crate::KeyType;
+
+    #[test]
+    fn markings_inherit() {
+        let mut m = MemoryDataStore::new();
+        let mk = Key::new(KeyType::Meta, SENSITIVE_METADATA_KEY).unwrap();
+        let secrets = Key::new(KeyType::Data, "settings.secrets").unwrap();
+        let public = Key::new(KeyType::Data, "settings.secrets.public").unwrap();
+        m.set_metadata(&mk, &secrets, "true", &Committed::Live)
+            .unwrap();
+        m.set_metadata(&mk, &public, "false", &Committed::Live)
+            .unwrap();
+
+        let token = Key::new(KeyType::Data, "settings.secrets.token").unwrap();
+        let other = Key::new(KeyType::Data, "settings.motd").unwrap();
+        let sensitive = SensitiveKeys::load(&m, &Committed::Live).unwrap();
+        assert!(sensitive.contains(&token));
+        assert!(!sensitive.contains(&public));
+        assert!(!sensitive.contains(&other));
+        assert!(is_sensitive(&m, &token, &Committed::Live).unwrap());
+        assert!(!is_sensitive(&m, &public, &Committed::Live).unwrap());
+
+        // Pending markings apply to their transaction.
+        let pending = Committed::Pending { tx: "tx".into() };
+        m.set_metadata(&mk, &other, "true", &pending).unwrap();
+        assert!(SensitiveKeys::load(&m, &pending).unwrap().contains(&other));
+        assert!(is_sensitive(&m, &other, &pending).unwrap());
+        assert!(!is_sensitive(&m, &other, &Committed::Live).unwrap());
+    }
+
+    #[test]
+    fn encryption_round_trip() {
+        let path = TestPath::new("key");
+        let encryption_key = EncryptionKey::generate_file(&path.0).unwrap();
+        let encryption_key2 = EncryptionKey::from_file(&path.0).unwrap();
+        assert_eq!(format!("{:?}", encryption_key), "EncryptionKey(<redacted>)");
+
+        let a = Key::new(KeyType::Data, "settings.a").unwrap();
+        let b = Key::new(KeyType::Data, "settings.b").unwrap();
+        let encrypted = encryption_key.encrypt(&a, "\"hunter2\"").unwrap();
+        assert!(is_encrypted(&encrypted));
+        assert!(!encrypted.contains("hunter2"));
+        assert_eq!(
+            encryption_key2.decrypt(&a, encrypted.clone()).unwrap(),
+            "\"hunter2\""
+        );
+        // Values are bound to their key.
+        assert!(encryption_key.decrypt(&b, encrypted).is_err());
+        // Plain values pass through.
+        assert_eq!(encryption_key.decrypt(&a, "42".into()).unwrap(), "42");
+    }
+}
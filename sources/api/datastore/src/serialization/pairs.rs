@@ -55,14 +55,12 @@ where
 /// serializing scalars.
 ///
 /// Caveat: for a list/tuple, the elements inside only have indexes, which doesn't work well with
-/// the data store.  Lists are common enough that we need some answer, so we say that lists can
-/// only contain scalars, not further compound objects.  That way we can serialize the list
-/// directly (see FlatSerializer) rather than as a compound.
+/// the data store.  Lists of scalars are serialized directly as a single value (see
+/// FlatSerializer).  Lists containing compound objects are serialized with the index of each
+/// element as a key segment, so a list of structs at "a.b" becomes "a.b.0.x", "a.b.1.x", etc.
 ///
-/// (We could handle lists as proper compound structures by improving the data store such that it
-/// can store unnamed sub-components, perhaps by using a visible index ("a.b.c[0]", "a.b.c[1]").
-/// It's more common to use a HashMap in the model, and then to use named keys instead of indexes,
-/// which works fine.)
+/// (It's still more common to use a HashMap in the model, and then to use named keys instead of
+/// indexes, which means changes to one entry don't affect the keys of others.)
 struct Serializer<'a> {
     output: &'a mut HashMap<Key, String>,
     prefix: Option<Key>,
@@ -421,8 +419,9 @@ impl<'a> ser::SerializeStruct for Serializer<'a> {
 
 /////
 
-/// This serializes compound structures into a flat blob, for cases where recursively serializing
-/// compound structures doesn't make sense.  See Serializer for detail on why it uses this.
+/// This serializes lists into a flat blob, for cases where recursively serializing compound
+/// structures doesn't make sense.  See Serializer for detail on why it uses this.  If it turns out
+/// that the list contains compound objects, we serialize each element under its index instead.
 ///
 /// Warning; this requires hacks.  serde gives you three callbacks during serialization - starting
 /// the structure, for each element, and ending the structure.  There's no option to handle an
@@ -473,6 +472,26 @@ impl<'a> ser::SerializeSeq for FlatSerializer<'a> {
             })?);
         }
 
+        // Lists of compound objects can't be represented as a single scalar value, so we serialize
+        // each element as its own compound structure, with the index as a key segment.
+        if originals.iter().any(serde_json::Value::is_object) {
+            trace!("Serializing list elements by index");
+            for (index, original) in originals.iter().enumerate() {
+                let index = index.to_string();
+                let element_key = self.prefix.append_segments(&[&index]).map_err(|e| {
+                    error::InvalidKeySnafu {
+                        msg: format!(
+                            "list index '{}' of '{}' not valid as Key: {}",
+                            index, self.prefix, e
+                        ),
+                    }
+                    .into_error(NoSource)
+                })?;
+                original.serialize(Serializer::new(self.output, Some(element_key)))?;
+            }
+            return Ok(());
+        }
+
         trace!("Serializing list");
         self.output.insert(
             self.prefix,
@@ -526,6 +545,32 @@ mod test {
         );
     }
 
+    #[derive(PartialEq, Serialize)]
+    struct Host {
+        name: String,
+        aliases: Vec<String>,
+    }
+
+    #[test]
+    fn compound_list_keys() {
+        let hosts = hashmap!(
+            key!("hosts") => vec![
+                Host { name: "a".to_string(), aliases: vec!["b".to_string()] },
+                Host { name: "c".to_string(), aliases: vec![] },
+            ],
+        );
+        let keys = to_pairs_with_prefix("settings", &hosts).unwrap();
+        assert_eq!(
+            keys,
+            hashmap!(
+                key!("settings.hosts.0.name") => "\"a\"".to_string(),
+                key!("settings.hosts.0.aliases") => "[\"b\"]".to_string(),
+                key!("settings.hosts.1.name") => "\"c\"".to_string(),
+                key!("settings.hosts.1.aliases") => "[]".to_string(),
+            )
+        );
+    }
+
     #[test]
     fn empty_value() {
         let val: toml::Value = toml::from_str("").unwrap();
@@ -0,0 +1,360 @@
+//! A snapshot is a portable copy of a whole data store - live data and metadata, and every pending
+//! transaction with its tombstones, metadata, and metadata tombstones - in a single JSON or TOML
+//! document.
+//!
+//! Snapshots are meant for reproducing issues from one host on another, and for seeding test
+//! fixtures.  They're captured and restored through the DataStore trait, so they work with any
+//! implementation.  Like `convert::copy_data`, history (generations and the journal) isn't
+//! included.
+//!
+//! Keys and values are stored as they are in the data store: key names, and serialized values.
+//! Each snapshot records its format version, and we refuse to restore versions we don't know.
+//!
+//! Values of sensitive keys are redacted unless the snapshot is captured with
+//! `Snapshot::capture_unredacted`.  Restoring a redacted snapshot sets them to the placeholder.
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt};
+use std::collections::BTreeMap;
+
+use super::sensitive::{SensitiveKeys, REDACTED_VALUE};
+use super::{error, Committed, DataStore, Key, KeyType, Result};
+
+/// The version of the snapshot format written by this library.
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+/// The document formats a snapshot can be written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SnapshotFormat {
+    Json,
+    Toml,
+}
+
+/// Snapshot holds the contents of a data store.  Maps are ordered so snapshots of the same data
+/// are identical, and can be compared with diff.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct Snapshot {
+    pub format_version: u32,
+    /// Live data, by key name.
+    #[serde(default)]
+    pub live: BTreeMap<String, String>,
+    /// Pending transactions, by transaction name.
+    #[serde(default)]
+    pub pending: BTreeMap<String, PendingSnapshot>,
+    /// Metadata, by data key name and then metadata key name.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+/// PendingSnapshot holds the contents of a pending transaction.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PendingSnapshot {
+    /// Pending data, by key name.
+    #[serde(default)]
+    pub keys: BTreeMap<String, String>,
+    /// Names of keys the transaction will remove.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tombstones: Vec<String>,
+    /// Names of metadata keys the transaction will remove, by data key name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata_tombstones: BTreeMap<String, Vec<String>>,
+    /// Pending metadata, by data key name and then metadata key name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl Snapshot {
+    /// Captures the contents of the given data store, with the values of sensitive keys
+    /// redacted.
+    pub fn capture<D: DataStore>(datastore: &D) -> Result<Snapshot> {
+        Self::capture_with(datastore, true)
+    }
+
+    /// Captures the contents of the given data store, including the values of sensitive keys.
+    pub fn capture_unredacted<D: DataStore>(datastore: &D) -> Result<Snapshot> {
+        Self::capture_with(datastore, false)
+    }
+
+    fn capture_with<D: DataStore>(datastore: &D, redact: bool) -> Result<Snapshot> {
+        let live = capture_values(datastore, &Committed::Live, redact)?;
+
+        let mut pending = BTreeMap::new();
+        for tx in datastore.list_transactions()? {
+            let committed = Committed::Pending { tx: tx.clone() };
+            let keys = capture_values(datastore, &committed, redact)?;
+            let mut tombstones: Vec<String> = datastore
+                .list_tombstones(&tx)?
+                .into_iter()
+                .map(|key| key.name().clone())
+                .collect();
+            tombstones.sort();
+            let metadata_tombstones = datastore
+                .list_metadata_tombstones(&tx)?
+                .into_iter()
+                .map(|(data_key, meta_keys)| {
+                    let mut names: Vec<String> =
+                        meta_keys.into_iter().map(|k| k.name().clone()).collect();
+                    names.sort();
+                    (data_key.name().clone(), names)
+                })
+                .collect();
+            let metadata = capture_metadata(datastore, &committed)?;
+            pending.insert(
+                tx,
+                PendingSnapshot {
+                    keys,
+                    tombstones,
+                    metadata_tombstones,
+                    metadata,
+                },
+            );
+        }
+
+        let metadata = capture_metadata(datastore, &Committed::Live)?;
+
+        debug!(
+            "Captured snapshot with {} live keys and {} pending transactions",
+            live.len(),
+            pending.len()
+        );
+        Ok(Snapshot {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            live,
+            pending,
+            metadata,
+        })
+    }
+
+    /// Restores the snapshot into the given data store, which must be empty.  Metadata is
+    /// restored before data, so sensitive values are treated as sensitive as they're set.
+    pub fn restore<D: DataStore>(&self, datastore: &mut D) -> Result<()> {
+        ensure!(
+            datastore
+                .list_populated_keys("", &Committed::Live)?
+                .is_empty()
+                && datastore.list_transactions()?.is_empty()
+                && datastore
+                    .list_populated_metadata("", &Committed::Live, &None::<&str>)?
+                    .is_empty(),
+            error::SnapshotTargetNotEmptySnafu
+        );
+
+        restore_metadata(datastore, &self.metadata, &Committed::Live)?;
+        for (name, value) in &self.live {
+            let key = Key::new(KeyType::Data, name)?;
+            datastore.set_key(&key, value, &Committed::Live)?;
+        }
+        for (tx, pending) in &self.pending {
+            let committed = Committed::Pending { tx: tx.clone() };
+            restore_metadata(datastore, &pending.metadata, &committed)?;
+            for (name, value) in &pending.keys {
+                let key = Key::new(KeyType::Data, name)?;
+                datastore.set_key(&key, value, &committed)?;
+            }
+            for name in &pending.tombstones {
+                let key = Key::new(KeyType::Data, name)?;
+                datastore.set_tombstone(&key, tx.as_str())?;
+            }
+            for (data_name, meta_names) in &pending.metadata_tombstones {
+                let data_key = Key::new(KeyType::Data, data_name)?;
+                for meta_name in meta_names {
+                    let metadata_key = Key::new(KeyType::Meta, meta_name)?;
+                    datastore.unset_metadata(&metadata_key, &data_key, &committed)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Serializes the snapshot in the given format.
+    pub fn to_string(&self, format: SnapshotFormat) -> Result<String> {
+        match format {
+            SnapshotFormat::Json => {
+                serde_json::to_string_pretty(self).context(error::SnapshotJsonSerializeSnafu)
+            }
+            SnapshotFormat::Toml => {
+                toml::to_string(self).context(error::SnapshotTomlSerializeSnafu)
+            }
+        }
+    }
+
+    /// Parses a snapshot in the given format, checking that we understand its format version.
+    pub fn from_str(input: &str, format: SnapshotFormat) -> Result<Snapshot> {
+        let snapshot: Snapshot = match format {
+            SnapshotFormat::Json => {
+                serde_json::from_str(input).context(error::SnapshotJsonParseSnafu)?
+            }
+            SnapshotFormat::Toml => toml::from_str(input).context(error::SnapshotTomlParseSnafu)?,
+        };
+        ensure!(
+            snapshot.format_version == SNAPSHOT_FORMAT_VERSION,
+            error::SnapshotVersionSnafu {
+                version: snapshot.format_version,
+                supported: SNAPSHOT_FORMAT_VERSION,
+            }
+        );
+        Ok(snapshot)
+    }
+}
+
+/// Captures the live or pending data of the given data store, by name.  Redacted values aren't
+/// read at all, so they don't need to be decrypted.
+fn capture_values<D: DataStore>(
+    datastore: &D,
+    committed: &Committed,
+    redact: bool,
+) -> Result<BTreeMap<String, String>> {
+    let sensitive = if redact {
+        SensitiveKeys::load(datastore, committed)?
+    } else {
+        SensitiveKeys::default()
+    };
+    let mut values = BTreeMap::new();
+    for key in datastore.list_populated_keys("", committed)? {
+        let value = if sensitive.contains(&key) {
+            REDACTED_VALUE.to_string()
+        } else {
+            datastore
+                .get_key(&key, committed)?
+                .context(error::ListedKeyNotPresentSnafu { key: key.name() })?
+        };
+        values.insert(key.name().clone(), value);
+    }
+    Ok(values)
+}
+
+/// Captures the live or pending metadata of the given data store, by name.
+fn capture_metadata<D: DataStore>(
+    datastore: &D,
+    committed: &Committed,
+) -> Result<BTreeMap<String, BTreeMap<String, String>>> {
+    Ok(datastore
+        .get_metadata_prefix("", committed, &None::<&str>)?
+        .into_iter()
+        .map(|(data_key, meta_map)| (data_key.name().clone(), by_name(meta_map)))
+        .collect())
+}
+
+/// Restores metadata captured by `capture_metadata`.
+fn restore_metadata<D: DataStore>(
+    datastore: &mut D,
+    metadata: &BTreeMap<String, BTreeMap<String, String>>,
+    committed: &Committed,
+) -> Result<()> {
+    for (data_name, meta_map) in metadata {
+        let data_key = Key::new(KeyType::Data, data_name)?;
+        for (meta_name, value) in meta_map {
+            let metadata_key = Key::new(KeyType::Meta, meta_name)?;
+            datastore.set_metadata(&metadata_key, &data_key, value, committed)?;
+        }
+    }
+    Ok(())
+}
+
+/// Converts a map keyed by Key into an ordered map keyed by name.
+fn by_name<I: IntoIterator<Item = (Key, String)>>(map: I) -> BTreeMap<String, String> {
+    map.into_iter()
+        .map(|(key, value)| (key.name().clone(), value))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryDataStore;
+    use crate::scratch::TestPath;
+    use crate::sensitive::SENSITIVE_METADATA_KEY;
+    use crate::{FilesystemDataStore, LogDataStore};
+
+    /// Fills a data store with a bit of everything a snapshot holds.
+    fn populate<D: DataStore>(datastore: &mut D) {
+        let a = Key::new(KeyType::Data, "settings.a").unwrap();
+        let quoted = Key::new(KeyType::Data, "settings.labels.\"x.y/z\"").unwrap();
+        let meta = Key::new(KeyType::Meta, "affected-services").unwrap();
+        let pending = Committed::Pending { tx: "tx".into() };
+        datastore.set_key(&a, "\"1\"", &Committed::Live).unwrap();
+        datastore
+            .set_key(&quoted, "true", &Committed::Live)
+            .unwrap();
+        datastore.set_key(&a, "\"2\"", &pending).unwrap();
+        datastore.set_tombstone(&quoted, "tx").unwrap();
+        datastore
+            .set_metadata(&meta, &a, "[\"a\"]", &Committed::Live)
+            .unwrap();
+        datastore
+            .set_metadata(&meta, &quoted, "[\"b\"]", &pending)
+            .unwrap();
+        datastore.unset_metadata(&meta, &a, &pending).unwrap();
+    }
+
+    /// Captures a snapshot of `source`, round-trips it through each format, and restores it into
+    /// `target`, checking that nothing changed.
+    fn round_trip<S: DataStore, T: DataStore>(source: &S, target: &mut T) {
+        let snapshot = Snapshot::capture(source).unwrap();
+        assert_eq!(snapshot.live.len(), 2);
+        assert_eq!(snapshot.pending["tx"].tombstones.len(), 1);
+        assert_eq!(snapshot.pending["tx"].metadata.len(), 1);
+        assert_eq!(snapshot.pending["tx"].metadata_tombstones.len(), 1);
+
+        for format in [SnapshotFormat::Json, SnapshotFormat::Toml] {
+            let serialized = snapshot.to_string(format).unwrap();
+            assert_eq!(Snapshot::from_str(&serialized, format).unwrap(), snapshot);
+        }
+
+        snapshot.restore(target).unwrap();
+        assert_eq!(Snapshot::capture(target).unwrap(), snapshot);
+        snapshot.restore(target).unwrap_err();
+    }
+
+    #[test]
+    fn memory_round_trip() {
+        let mut source = MemoryDataStore::new();
+        populate(&mut source);
+        round_trip(&source, &mut MemoryDataStore::new());
+    }
+
+    #[test]
+    fn filesystem_round_trip() {
+        let source_path = TestPath::new("fs-source");
+        let target_path = TestPath::datastore("fs-target");
+        let mut source = FilesystemDataStore::new(&source_path.0).unwrap();
+        populate(&mut source);
+        let mut target = FilesystemDataStore::new(&target_path.0).unwrap();
+        round_trip(&source, &mut target);
+    }
+
+    #[test]
+    fn log_round_trip() {
+        let source_path = TestPath::new("log-source");
+        let target_path = TestPath::new("log-target");
+        let mut source = LogDataStore::new(&source_path.0).unwrap();
+        populate(&mut source);
+        let mut target = LogDataStore::new(&target_path.0).unwrap();
+        round_trip(&source, &mut target);
+    }
+
+    #[test]
+    fn redacts_sensitive_values() {
+        let mut m = MemoryDataStore::new();
+        populate(&mut m);
+        let secret = Key::new(KeyType::Data, "settings.secret").unwrap();
+        let sensitive = Key::new(KeyType::Meta, SENSITIVE_METADATA_KEY).unwrap();
+        m.set_key(&secret, "\"hunter2\"", &Committed::Live).unwrap();
+        m.set_metadata(&sensitive, &secret, "true", &Committed::Live)
+            .unwrap();
+
+        let snapshot = Snapshot::capture(&m).unwrap();
+        assert_eq!(snapshot.live["settings.secret"], REDACTED_VALUE);
+        assert_eq!(snapshot.live["settings.a"], "\"1\"");
+        let snapshot = Snapshot::capture_unredacted(&m).unwrap();
+        assert_eq!(snapshot.live["settings.secret"], "\"hunter2\"");
+    }
+
+    #[test]
+    fn unknown_version() {
+        let input = r#"{"format-version": 2, "live": {}}"#;
+        Snapshot::from_str(input, SnapshotFormat::Json).unwrap_err();
+    }
+}
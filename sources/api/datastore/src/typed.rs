@@ -0,0 +1,161 @@
+//! Typed access to data store values, so callers don't have to serialize and deserialize values
+//! around every read and write.
+//!
+//! DataStoreExt is implemented for every DataStore.  `get_typed` and `set_typed` handle a single
+//! key, with the same format as `serialize_scalar` and `deserialize_scalar`.  `get_struct` and
+//! `set_struct` handle a whole structure under a prefix, with the same format as
+//! `deserialization::from_map_with_prefix` and `serialization::to_pairs_with_prefix`.
+//!
+//! Errors name the key that couldn't be deserialized, or for structures, the key of the value or
+//! nested structure that failed.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use snafu::ResultExt;
+use std::collections::HashMap;
+
+use super::deserialization::from_map_with_prefix;
+use super::serialization::to_pairs_with_prefix;
+use super::{
+    deserialize_scalar, error, serialize_scalar, Committed, DataStore, Key, KeyType, Result,
+    ScalarError,
+};
+
+/// DataStoreExt adds typed reads and writes to any DataStore.
+pub trait DataStoreExt: DataStore {
+    /// Returns the deserialized value of the given data key, or None if it isn't set.
+    fn get_typed<T>(&self, key: &Key, committed: &Committed) -> Result<Option<T>>
+    where
+        T: DeserializeOwned,
+    {
+        match self.get_key(key, committed)? {
+            Some(value) => deserialize_scalar::<_, ScalarError>(&value)
+                .context(error::DeserializeKeySnafu { key: key.name() })
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// Serializes the given value and sets it as the value of the given data key.
+    fn set_typed<T>(&mut self, key: &Key, value: &T, committed: &Committed) -> Result<()>
+    where
+        T: Serialize,
+    {
+        let value = serialize_scalar::<_, ScalarError>(value)
+            .context(error::SerializeScalarSnafu { given: key.name() })?;
+        self.set_key(key, value, committed)
+    }
+
+    /// Returns the structure stored under the given prefix, like "settings.ntp", or None if
+    /// nothing is set under it.  The prefix is a whole key; "settings.ntp" doesn't include keys
+    /// under "settings.ntp-extra".
+    fn get_struct<T, S>(&self, prefix: S, committed: &Committed) -> Result<Option<T>>
+    where
+        T: DeserializeOwned,
+        S: AsRef<str>,
+    {
+        let prefix = prefix.as_ref();
+        let prefix_key = Key::new(KeyType::Data, prefix)?;
+        let data: HashMap<Key, String> = self
+            .get_prefix(prefix, committed)?
+            .into_iter()
+            .filter(|(key, _)| key.starts_with_segments(prefix_key.segments()))
+            .collect();
+        if data.is_empty() {
+            return Ok(None);
+        }
+        from_map_with_prefix(Some(prefix.to_string()), &data)
+            .context(error::DeserializePrefixSnafu { prefix })
+            .map(Some)
+    }
+
+    /// Serializes the given structure and sets its values under the given prefix.  Keys under
+    /// the prefix that the structure doesn't include, like fields that are None, are left alone.
+    fn set_struct<T, S>(&mut self, prefix: S, value: &T, committed: &Committed) -> Result<()>
+    where
+        T: Serialize,
+        S: AsRef<str>,
+    {
+        let prefix = prefix.as_ref();
+        let pairs = to_pairs_with_prefix(prefix, value)
+            .context(error::SerializationSnafu { given: prefix })?;
+        self.set_keys(&pairs, committed)
+    }
+}
+
+impl<D: DataStore + ?Sized> DataStoreExt for D {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::MemoryDataStore;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Ntp {
+        servers: Vec<String>,
+        enabled: bool,
+        options: Option<NtpOptions>,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct NtpOptions {
+        burst: bool,
+    }
+
+    #[test]
+    fn typed_round_trip() {
+        let mut m = MemoryDataStore::new();
+        let key = Key::new(KeyType::Data, "settings.motd").unwrap();
+        assert_eq!(m.get_typed::<String>(&key, &Committed::Live).unwrap(), None);
+        m.set_typed(&key, &"hi".to_string(), &Committed::Live)
+            .unwrap();
+        assert_eq!(
+            m.get_key(&key, &Committed::Live).unwrap(),
+            Some("\"hi\"".to_string())
+        );
+        assert_eq!(
+            m.get_typed::<String>(&key, &Committed::Live).unwrap(),
+            Some("hi".to_string())
+        );
+
+        let err = m.get_typed::<u64>(&key, &Committed::Live).unwrap_err();
+        assert!(err.to_string().contains("settings.motd"), "{}", err);
+    }
+
+    #[test]
+    fn struct_round_trip() {
+        let mut m = MemoryDataStore::new();
+        let pending = Committed::Pending { tx: "tx".into() };
+        let ntp = Ntp {
+            servers: vec!["a.example.com".to_string()],
+            enabled: true,
+            options: Some(NtpOptions { burst: false }),
+        };
+        m.set_struct("settings.ntp", &ntp, &pending).unwrap();
+        // Keys that merely share a prefix aren't part of the structure.
+        let other = Key::new(KeyType::Data, "settings.ntp-extra").unwrap();
+        m.set_typed(&other, &1, &pending).unwrap();
+
+        assert_eq!(
+            m.get_struct::<Ntp, _>("settings.ntp", &pending).unwrap(),
+            Some(ntp)
+        );
+        assert_eq!(
+            m.get_struct::<Ntp, _>("settings.ntp", &Committed::Live)
+                .unwrap(),
+            None
+        );
+
+        let burst = Key::new(KeyType::Data, "settings.ntp.options.burst").unwrap();
+        m.set_typed(&burst, &"often", &pending).unwrap();
+        let err = m
+            .get_struct::<Ntp, _>("settings.ntp", &pending)
+            .unwrap_err();
+        assert!(
+            err.to_string().contains("settings.ntp.options.burst"),
+            "{}",
+            err
+        );
+    }
+}
@@ -10,6 +10,8 @@ pub struct Args {
     pub source_datastore: String,
     pub target_datastore: String,
     pub migration_type: MigrationType,
+    /// File holding the key that encrypts sensitive values in both data stores, if any.
+    pub encryption_key_file: Option<String>,
 }
 
 /// Informs the user about proper usage of the program and exits.
@@ -19,7 +21,8 @@ fn usage() -> ! {
         r"Usage: {}
             --source-datastore PATH
             --target-datastore PATH
-            ( --forward | --backward )",
+            ( --forward | --backward )
+            [ --encryption-key-file PATH ]",
         program_name
     );
     process::exit(2);
@@ -36,6 +39,7 @@ pub(crate) fn parse_args(args: env::Args) -> Result<Args> {
     let mut migration_type = None;
     let mut source_datastore = None;
     let mut target_datastore = None;
+    let mut encryption_key_file = None;
 
     let mut iter = args.skip(1);
     while let Some(arg) = iter.next() {
@@ -54,6 +58,13 @@ pub(crate) fn parse_args(args: env::Args) -> Result<Args> {
                     }))
             }
 
+            "--encryption-key-file" => {
+                encryption_key_file =
+                    Some(iter.next().unwrap_or_else(|| {
+                        usage_msg("Did not give argument to --encryption-key-file")
+                    }))
+            }
+
             "--forward" => migration_type = Some(MigrationType::Forward),
             "--backward" => migration_type = Some(MigrationType::Backward),
 
@@ -70,5 +81,6 @@ pub(crate) fn parse_args(args: env::Args) -> Result<Args> {
         source_datastore: source_datastore.unwrap_or_else(|| usage()),
         target_datastore: target_datastore.unwrap_or_else(|| usage()),
         migration_type: migration_type.unwrap_or_else(|| usage()),
+        encryption_key_file,
     })
 }
@@ -8,7 +8,7 @@ use std::collections::HashMap;
 use crate::{error, MigrationData, Result};
 use datastore::{
     deserialize_scalar, serialization::to_pairs_with_prefix, serialize_scalar, Committed,
-    DataStore, Key, KeyType,
+    DataStore, Key, KeyType, Value,
 };
 
 // To get input data from the existing data store, we use datastore methods, because we assume
@@ -48,25 +48,22 @@ pub(crate) fn get_input_data<D: DataStore>(
         data.insert(data_key.name().clone(), value);
     }
 
-    // Metadata isn't committed, it goes live immediately, so we only populate the metadata
-    // output for Committed::Live.
+    // Metadata is transactional like data, so pending transactions have their own metadata.
     let mut metadata = HashMap::new();
-    if let Committed::Live = committed {
-        let raw_metadata = datastore
-            .get_metadata_prefix("", &None as &Option<&str>)
-            .context(error::GetMetadataSnafu)?;
-        for (data_key, meta_map) in raw_metadata.into_iter() {
-            // See notes above about storing key Strings and Values.
-            let data_key_name = data_key.name();
-            let data_entry = metadata
-                .entry(data_key_name.clone())
-                .or_insert_with(HashMap::new);
-            for (metadata_key, value_str) in meta_map.into_iter() {
-                let metadata_key_name = metadata_key.name();
-                let value = deserialize_scalar(&value_str)
-                    .context(error::DeserializeSnafu { input: value_str })?;
-                data_entry.insert(metadata_key_name.clone(), value);
-            }
+    let raw_metadata = datastore
+        .get_metadata_prefix("", committed, &None as &Option<&str>)
+        .context(error::GetMetadataSnafu)?;
+    for (data_key, meta_map) in raw_metadata.into_iter() {
+        // See notes above about storing key Strings and Values.
+        let data_key_name = data_key.name();
+        let data_entry = metadata
+            .entry(data_key_name.clone())
+            .or_insert_with(HashMap::new);
+        for (metadata_key, value_str) in meta_map.into_iter() {
+            let metadata_key_name = metadata_key.name();
+            let value = deserialize_scalar(&value_str)
+                .context(error::DeserializeSnafu { input: value_str })?;
+            data_entry.insert(metadata_key_name.clone(), value);
         }
     }
 
@@ -89,18 +86,22 @@ pub(crate) fn set_output_data<D: DataStore>(
             key_type: KeyType::Data,
             key: data_key_name,
         })?;
+        // Lists of compound values can't be stored as a single scalar; the datastore stores each
+        // element under its index, so we flatten them the same way.
+        if let Value::Array(items) = raw_value {
+            if items.iter().any(Value::is_object) {
+                let pairs = to_pairs_with_prefix(data_key_name, raw_value)
+                    .context(error::SerializeListSnafu { key: data_key_name })?;
+                data.extend(pairs);
+                continue;
+            }
+        }
         let value = serialize_scalar(raw_value).context(error::SerializeSnafu)?;
         data.insert(data_key, value);
     }
 
-    // This is one of the rare cases where we want to set keys directly in the datastore:
-    // * We're operating on a temporary copy of the datastore, so no concurrency issues
-    // * We're either about to reboot or just have, and the settings applier will run afterward
-    datastore
-        .set_keys(&data, committed)
-        .context(error::DataStoreWriteSnafu)?;
-
-    // Set metadata in a loop (currently no batch API)
+    // Set metadata in a loop (currently no batch API).  Metadata is written first so sensitive
+    // keys are known, and encrypted, when the data is written.
     for (data_key_name, meta_map) in &input.metadata {
         let data_key = Key::new(KeyType::Data, data_key_name).context(error::InvalidKeySnafu {
             key_type: KeyType::Data,
@@ -114,10 +115,17 @@ pub(crate) fn set_output_data<D: DataStore>(
                 })?;
             let value = serialize_scalar(&raw_value).context(error::SerializeSnafu)?;
             datastore
-                .set_metadata(&metadata_key, &data_key, value)
+                .set_metadata(&metadata_key, &data_key, value, committed)
                 .context(error::DataStoreWriteSnafu)?;
         }
     }
 
+    // This is one of the rare cases where we want to set keys directly in the datastore:
+    // * We're operating on a temporary copy of the datastore, so no concurrency issues
+    // * We're either about to reboot or just have, and the settings applier will run afterward
+    datastore
+        .set_keys(&data, committed)
+        .context(error::DataStoreWriteSnafu)?;
+
     Ok(())
 }
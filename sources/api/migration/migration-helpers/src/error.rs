@@ -10,6 +10,20 @@ pub enum Error {
     #[snafu(display("Unable to get system release data: {}", source))]
     BottlerocketRelease { source: bottlerocket_release::Error },
 
+    #[snafu(display("Unable to open data store at '{}': {}", path.display(), source))]
+    OpenDataStore {
+        path: PathBuf,
+        #[snafu(source(from(datastore::Error, Box::new)))]
+        source: Box<datastore::Error>,
+    },
+
+    #[snafu(display("Unable to read encryption key from '{}': {}", path.display(), source))]
+    EncryptionKey {
+        path: PathBuf,
+        #[snafu(source(from(datastore::Error, Box::new)))]
+        source: Box<datastore::Error>,
+    },
+
     #[snafu(display("Unable to get {:?} data for migration: {}", committed, source))]
     GetData {
         committed: datastore::Committed,
@@ -35,6 +49,12 @@ pub enum Error {
     #[snafu(display("Unable to serialize datastore for rendering templates: {}", source))]
     SerializeTemplateData { source: serde_json::Error },
 
+    #[snafu(display("Unable to serialize list at '{}': {}", key, source))]
+    SerializeList {
+        key: String,
+        source: datastore::serialization::Error,
+    },
+
     #[snafu(display("Unable to serialize release data: {}", source))]
     SerializeRelease {
         source: datastore::serialization::Error,
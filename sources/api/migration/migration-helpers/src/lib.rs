@@ -19,7 +19,8 @@ use std::collections::HashMap;
 use std::env;
 use std::fmt;
 
-use datastore::{Committed, Value};
+use datastore::lock::DEFAULT_LOCK_TIMEOUT;
+use datastore::{Committed, EncryptionKey, LockedDataStore, Value};
 pub use datastore::{DataStore, FilesystemDataStore};
 
 use args::{parse_args, Args};
@@ -58,6 +59,10 @@ pub type Metadata = HashMap<String, Value>;
 /// MigrationData holds all data that can be migrated in a migration, and serves as the input and
 /// output format of migrations.  A serde Value type is used to hold the arbitrary data of each
 /// key because we can't represent types when they could change in the migration.
+///
+/// Lists of compound values are stored in the datastore with each element under its index, so
+/// they appear here as separate keys, like "settings.a.0.b".  If a migration sets a key to a list
+/// containing objects, it's flattened into indexed keys the same way when it's written.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MigrationData {
     /// Mapping of data key names to their arbitrary values.
@@ -81,12 +86,28 @@ fn validate_migrated_data(_migrated: &MigrationData) -> Result<()> {
     Ok(())
 }
 
+/// Opens the data store at the given path, first completing any interrupted commit or upgrading
+/// its format.  Migrations are run serially, so we only need to hold the lock while that's done.
+fn open_datastore(path: &str) -> Result<DataStoreImplementation> {
+    LockedDataStore::open(path, DEFAULT_LOCK_TIMEOUT)
+        .map(LockedDataStore::into_inner)
+        .context(error::OpenDataStoreSnafu { path })
+}
+
 /// If you need a little more control over a migration than with migrate, or you're using this
 /// module as a library, you can call run_migration directly with the arguments that would
 /// normally be parsed from the migration binary's command line.
 pub fn run_migration(mut migration: impl Migration, args: &Args) -> Result<()> {
-    let source = DataStoreImplementation::new(&args.source_datastore);
-    let mut target = DataStoreImplementation::new(&args.target_datastore);
+    let mut source = open_datastore(&args.source_datastore)?;
+    let mut target = open_datastore(&args.target_datastore)?;
+
+    // Sensitive values are decrypted for the migration, and encrypted again when written.
+    if let Some(path) = &args.encryption_key_file {
+        let read_key =
+            || EncryptionKey::from_file(path).context(error::EncryptionKeySnafu { path });
+        source = source.with_encryption_key(read_key()?);
+        target = target.with_encryption_key(read_key()?);
+    }
 
     // Run for live data and for each pending transaction
     let mut committeds = vec![Committed::Live];